@@ -0,0 +1,95 @@
+//! Collects the diagnostics of a file which are reported in quick succession,
+//! so that they are sent together instead of one by one, see
+//! `FlycheckConfig::batch_delay`.
+use std::time::{Duration, Instant};
+
+use lsp_types::{CodeActionOrCommand, Diagnostic, Url};
+
+use crate::CheckRunId;
+
+/// Diagnostics of a single file of a single run which are sent together.
+#[derive(Debug)]
+pub(crate) struct Batch {
+    pub(crate) run: CheckRunId,
+    pub(crate) url: Url,
+    /// Whether the diagnostics the file had before have to be cleared first.
+    pub(crate) clear: bool,
+    pub(crate) diagnostics: Vec<(Diagnostic, Vec<CodeActionOrCommand>)>,
+    /// When the first of the diagnostics was reported.
+    started: Instant,
+}
+
+/// Holds back the diagnostics of a file until one of another file is reported
+/// or `delay` passed since the first of them, whichever comes first. Cargo
+/// reports the diagnostics of a crate file by file, so this usually gathers
+/// all of a file's diagnostics without delaying them noticeably.
+#[derive(Debug)]
+pub(crate) struct Batcher {
+    delay: Duration,
+    pending: Option<Batch>,
+}
+
+impl Batcher {
+    pub(crate) fn new(delay: Duration) -> Batcher {
+        Batcher { delay, pending: None }
+    }
+
+    pub(crate) fn set_delay(&mut self, delay: Duration) {
+        self.delay = delay;
+    }
+
+    /// Adds a diagnostic of `url` reported at `now`, returning the batch of
+    /// another file or run which has to be sent first. `clear` is only
+    /// looked at for the first diagnostic of a batch.
+    pub(crate) fn add(
+        &mut self,
+        run: CheckRunId,
+        url: Url,
+        clear: bool,
+        diagnostic: (Diagnostic, Vec<CodeActionOrCommand>),
+        now: Instant,
+    ) -> Option<Batch> {
+        let done = match &self.pending {
+            Some(batch) if batch.run == run && batch.url == url => None,
+            _ => self.pending.take(),
+        };
+        let batch = self.pending.get_or_insert_with(|| Batch {
+            run,
+            url,
+            clear,
+            diagnostics: Vec::new(),
+            started: now,
+        });
+        batch.diagnostics.push(diagnostic);
+        done
+    }
+
+    /// How long after `now` the pending batch is due, `None` if there is
+    /// none.
+    pub(crate) fn due_in(&self, now: Instant) -> Option<Duration> {
+        let batch = self.pending.as_ref()?;
+        Some((batch.started + self.delay).saturating_duration_since(now))
+    }
+
+    /// Takes the pending batch if it's due at `now`.
+    pub(crate) fn take_due(&mut self, now: Instant) -> Option<Batch> {
+        if self.due_in(now)? > Duration::from_secs(0) {
+            return None;
+        }
+        self.pending.take()
+    }
+
+    /// Drops the pending batch if it holds diagnostics of `url`, f.x. because
+    /// they are replaced.
+    pub(crate) fn discard(&mut self, url: &Url) {
+        if matches!(&self.pending, Some(batch) if batch.url == *url) {
+            self.pending = None;
+        }
+    }
+
+    /// Takes the pending batch whether it's due or not, f.x. because the
+    /// check ended.
+    pub(crate) fn flush(&mut self) -> Option<Batch> {
+        self.pending.take()
+    }
+}
@@ -0,0 +1,81 @@
+//! Splits a command given as a single string, f.x. from an editor setting,
+//! into its arguments, following the quoting rules of POSIX shells.
+use std::fmt;
+
+/// Describes why a command line couldn't be split into arguments.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ParseError {
+    /// A quote, either `'` or `"`, is missing its closing counterpart.
+    UnterminatedQuote(char),
+    /// The command line ends with a backslash that doesn't escape anything.
+    TrailingBackslash,
+    /// The command line doesn't contain a command.
+    EmptyCommand,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::UnterminatedQuote(quote) => write!(f, "missing closing {}", quote),
+            ParseError::TrailingBackslash => {
+                write!(f, "trailing backslash doesn't escape anything")
+            }
+            ParseError::EmptyCommand => write!(f, "the command is empty"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Splits `command_line` into arguments at unquoted whitespace.
+///
+/// Single quotes keep everything between them as is, double quotes allow
+/// escaping `"` and `\` with a backslash and a backslash outside of quotes
+/// escapes any character. Quotes don't separate arguments, `--x="a b"`
+/// becomes `--x=a b`.
+pub fn parse_command_line(command_line: &str) -> Result<Vec<String>, ParseError> {
+    let mut args = Vec::new();
+    // `None` between arguments, distinguishes `""` from no argument at all.
+    let mut current: Option<String> = None;
+    let mut chars = command_line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => args.extend(current.take()),
+            '\'' => {
+                let arg = current.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => arg.push(c),
+                        None => return Err(ParseError::UnterminatedQuote('\'')),
+                    }
+                }
+            }
+            '"' => {
+                let arg = current.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ '"') | Some(c @ '\\') => arg.push(c),
+                            Some(c) => {
+                                arg.push('\\');
+                                arg.push(c);
+                            }
+                            None => return Err(ParseError::UnterminatedQuote('"')),
+                        },
+                        Some(c) => arg.push(c),
+                        None => return Err(ParseError::UnterminatedQuote('"')),
+                    }
+                }
+            }
+            '\\' => match chars.next() {
+                Some(c) => current.get_or_insert_with(String::new).push(c),
+                None => return Err(ParseError::TrailingBackslash),
+            },
+            c => current.get_or_insert_with(String::new).push(c),
+        }
+    }
+    args.extend(current);
+    Ok(args)
+}
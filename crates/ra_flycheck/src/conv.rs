@@ -9,6 +9,7 @@ use lsp_types::{
     Location, NumberOrString, Position, Range, TextEdit, Url, WorkspaceEdit,
 };
 use std::{
+    cmp::Ordering,
     collections::HashMap,
     fmt::Write,
     path::{Component, Path, PathBuf, Prefix},
@@ -266,7 +267,9 @@ pub(crate) fn map_rust_diagnostic_to_lsp(
                 severity,
                 code: code.clone().map(NumberOrString::String),
                 source: Some(source.clone()),
-                message,
+                // Colors should be turned off already, but the child messages
+                // and labels end up in hovers, so make sure.
+                message: strip_ansi_codes(&message),
                 related_information: if !related_information.is_empty() {
                     Some(related_information.clone())
                 } else {
@@ -280,6 +283,160 @@ pub(crate) fn map_rust_diagnostic_to_lsp(
         .collect()
 }
 
+/// A future incompatibility report of cargo, listing packages with code a
+/// future version of Rust will reject. `cargo_metadata` doesn't know about
+/// these messages yet.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct FutureIncompatReport {
+    /// The id to pass to `cargo report future-incompatibilities`.
+    id: Option<u64>,
+    items: Vec<FutureIncompatItem>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct FutureIncompatItem {
+    package_id: String,
+    message: String,
+}
+
+impl FutureIncompatReport {
+    /// Parses a line of cargo's JSON output, `None` if it's not a report.
+    pub(crate) fn parse(line: &str) -> Option<FutureIncompatReport> {
+        let value: serde_json::Value = serde_json::from_str(line).ok()?;
+        if value["reason"] != "future-incompat-report" {
+            return None;
+        }
+        let items = value["future_incompat_report"]
+            .as_array()?
+            .iter()
+            .filter_map(|item| {
+                Some(FutureIncompatItem {
+                    package_id: item["package_id"].as_str()?.to_string(),
+                    message: item["diagnostic"]["message"].as_str()?.to_string(),
+                })
+            })
+            .collect();
+        Some(FutureIncompatReport { id: value["id"].as_u64(), items })
+    }
+}
+
+/// Converts a future incompatibility report into a warning for each affected
+/// package. It's attached to the manifest of path dependencies, and to the
+/// manifest of the workspace for all others.
+pub(crate) fn map_future_incompat_report_to_lsp(
+    report: &FutureIncompatReport,
+    workspace_root: &Path,
+) -> Vec<(Url, Diagnostic)> {
+    let mut packages: Vec<(&str, Vec<&str>)> = Vec::new();
+    for item in &report.items {
+        match packages.iter_mut().find(|(package_id, _)| *package_id == item.package_id) {
+            Some((_, messages)) if messages.contains(&item.message.as_str()) => {}
+            Some((_, messages)) => messages.push(&item.message),
+            None => packages.push((&item.package_id, vec![&item.message])),
+        }
+    }
+    let command = match report.id {
+        Some(id) => format!("cargo report future-incompatibilities --id {}", id),
+        None => "cargo report future-incompatibilities".to_string(),
+    };
+    packages
+        .into_iter()
+        .filter_map(|(package_id, messages)| {
+            let (name, manifest_path) = package_name_and_manifest(package_id, workspace_root);
+            let url = url_from_path_with_drive_lowercasing(manifest_path).ok()?;
+            let mut message = format!(
+                "{} contains code that will be rejected by a future version of Rust:",
+                name
+            );
+            for line in messages {
+                write!(message, "\n{}", line).unwrap();
+            }
+            write!(message, "\nrun `{}` for details", command).unwrap();
+            let diagnostic = Diagnostic {
+                range: Range::new(Position::new(0, 0), Position::new(0, 0)),
+                severity: Some(DiagnosticSeverity::Warning),
+                code: None,
+                source: Some("cargo".to_string()),
+                message,
+                related_information: None,
+                tags: None,
+            };
+            Some((url, diagnostic))
+        })
+        .collect()
+}
+
+/// The code of the diagnostic standing in for the ones of a file which were
+/// left out, see `FlycheckConfig::max_diagnostics_per_file`, so that clients
+/// can tell it apart.
+pub const SUPPRESSED_DIAGNOSTICS_CODE: &str = "suppressed-diagnostics";
+
+/// An information at the top of a file telling that `count` of its
+/// diagnostics reported by the check with `source` were left out.
+pub(crate) fn suppressed_diagnostics_marker(count: usize, source: &str) -> Diagnostic {
+    let message = if count == 1 {
+        "1 additional diagnostic was suppressed".to_string()
+    } else {
+        format!("{} additional diagnostics were suppressed", count)
+    };
+    Diagnostic {
+        range: Range::new(Position::new(0, 0), Position::new(0, 0)),
+        severity: Some(DiagnosticSeverity::Information),
+        code: Some(NumberOrString::String(SUPPRESSED_DIAGNOSTICS_CODE.to_string())),
+        source: Some(source.to_string()),
+        message: format!("{} (raise checkOnSave.maxDiagnosticsPerFile)", message),
+        related_information: None,
+        tags: None,
+    }
+}
+
+/// Splits a package id like `foo 0.1.0 (path+file:///ws/foo)` into the name
+/// shown to the user and the manifest diagnostics about the package go to.
+fn package_name_and_manifest(package_id: &str, workspace_root: &Path) -> (String, PathBuf) {
+    let mut parts = package_id.splitn(3, ' ');
+    let name = parts.next().unwrap_or(package_id);
+    let name = match parts.next() {
+        Some(version) => format!("`{} v{}`", name, version),
+        None => format!("`{}`", name),
+    };
+    let package_dir = parts
+        .next()
+        .map(|source| source.trim_start_matches('(').trim_end_matches(')'))
+        .filter(|source| source.starts_with("path+"))
+        .and_then(|source| Url::parse(&source["path+".len()..]).ok())
+        .and_then(|url| url.to_file_path().ok());
+    let manifest_path = match package_dir {
+        Some(package_dir) => package_dir.join("Cargo.toml"),
+        None => workspace_root.join("Cargo.toml"),
+    };
+    (name, manifest_path)
+}
+
+/// Removes ANSI escape sequences, like color codes, from `text`.
+pub(crate) fn strip_ansi_codes(text: &str) -> String {
+    if !text.contains('\x1b') {
+        return text.to_string();
+    }
+    let mut res = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            res.push(c);
+            continue;
+        }
+        // Control sequences look like `ESC [ <parameters> <final byte>`,
+        // other escapes consist of a single character after `ESC`.
+        if let Some('[') = chars.next() {
+            for c in chars.by_ref() {
+                if ('\x40'..='\x7e').contains(&c) {
+                    break;
+                }
+            }
+        }
+    }
+    res
+}
+
 /// Returns a `Url` object from a given path, will lowercase drive letters if present.
 /// This will only happen when processing windows paths.
 ///
@@ -320,6 +477,32 @@ pub fn url_from_path_with_drive_lowercasing(
     }
 }
 
+/// The order the diagnostics of a batch or of `CheckTask::SetDiagnostics` are
+/// sent in: by file, then by where they start, then by code and message, so
+/// that the same diagnostics are in the same order whichever order cargo
+/// reported them in. The end of their range and their source only break ties.
+/// Diagnostics which are sent one by one can be sorted with it as well.
+pub fn compare_diagnostics(a: (&Url, &Diagnostic), b: (&Url, &Diagnostic)) -> Ordering {
+    order_key(a.0, a.1).cmp(&order_key(b.0, b.1))
+}
+
+fn order_key<'a>(url: &'a Url, diagnostic: &'a Diagnostic) -> impl Ord + 'a {
+    let Range { start, end } = diagnostic.range;
+    let code = match &diagnostic.code {
+        None => (None, None),
+        Some(NumberOrString::Number(number)) => (Some(*number), None),
+        Some(NumberOrString::String(string)) => (None, Some(string.as_str())),
+    };
+    (
+        url.as_str(),
+        (start.line, start.character),
+        code,
+        diagnostic.message.as_str(),
+        (end.line, end.character),
+        diagnostic.source.as_deref(),
+    )
+}
+
 // `Url` is not able to parse windows paths on unix machines.
 #[cfg(target_os = "windows")]
 #[cfg(test)]
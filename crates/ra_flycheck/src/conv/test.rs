@@ -1070,3 +1070,131 @@ fn snap_multi_line_fix() {
     let diag = map_rust_diagnostic_to_lsp(&diag, &workspace_root);
     insta::assert_debug_snapshot!(diag);
 }
+
+#[test]
+#[cfg(not(windows))]
+fn colored_messages_are_stripped() {
+    let diag = parse_diagnostic(
+        r##"{
+            "message": "unused variable: `x`",
+            "code": {
+                "code": "unused_variables",
+                "explanation": null
+            },
+            "level": "warning",
+            "spans": [
+                {
+                    "file_name": "src/lib.rs",
+                    "byte_start": 17,
+                    "byte_end": 18,
+                    "line_start": 2,
+                    "line_end": 2,
+                    "column_start": 9,
+                    "column_end": 10,
+                    "is_primary": true,
+                    "text": [
+                        {
+                            "text": "    let x = 1;",
+                            "highlight_start": 9,
+                            "highlight_end": 10
+                        }
+                    ],
+                    "label": "\u001b[0m\u001b[1m\u001b[33mhelp: consider prefixing with an underscore\u001b[0m",
+                    "suggested_replacement": null,
+                    "suggestion_applicability": null,
+                    "expansion": null
+                }
+            ],
+            "children": [],
+            "rendered": "\u001b[0m\u001b[1m\u001b[33mwarning\u001b[0m\u001b[0m\u001b[1m: unused variable: `x`\u001b[0m\n"
+        }
+        "##,
+    );
+
+    let workspace_root = PathBuf::from("/test/");
+    let diag = map_rust_diagnostic_to_lsp(&diag, &workspace_root);
+    assert_eq!(
+        diag[0].diagnostic.message,
+        "unused variable: `x`\nhelp: consider prefixing with an underscore"
+    );
+}
+
+/// A future incompatibility report about the path dependency `bar` and the
+/// registry dependency `baz`.
+#[cfg(not(windows))]
+const FUTURE_INCOMPAT_REPORT: &str = r##"{
+    "reason": "future-incompat-report",
+    "id": 1,
+    "future_incompat_report": [
+        {
+            "package_id": "bar 0.1.0 (path+file:///test/bar)",
+            "future_breakage_date": null,
+            "diagnostic": {
+                "message": "trailing semicolon in macro used in expression position",
+                "code": {
+                    "code": "semicolon_in_expressions_from_macros",
+                    "explanation": null
+                },
+                "level": "warning",
+                "spans": [],
+                "children": [],
+                "rendered": "warning: trailing semicolon in macro used in expression position\n"
+            }
+        },
+        {
+            "package_id": "baz 0.2.3 (registry+https://github.com/rust-lang/crates.io-index)",
+            "future_breakage_date": null,
+            "diagnostic": {
+                "message": "the type `bool` does not permit being left uninitialized",
+                "code": {
+                    "code": "invalid_value",
+                    "explanation": null
+                },
+                "level": "warning",
+                "spans": [],
+                "children": [],
+                "rendered": "warning: the type `bool` does not permit being left uninitialized\n"
+            }
+        },
+        {
+            "package_id": "bar 0.1.0 (path+file:///test/bar)",
+            "future_breakage_date": null,
+            "diagnostic": {
+                "message": "trailing semicolon in macro used in expression position",
+                "code": {
+                    "code": "semicolon_in_expressions_from_macros",
+                    "explanation": null
+                },
+                "level": "warning",
+                "spans": [],
+                "children": [],
+                "rendered": "warning: trailing semicolon in macro used in expression position\n"
+            }
+        }
+    ]
+}"##;
+
+#[test]
+#[cfg(not(windows))]
+fn future_incompat_reports_are_attached_to_manifests() {
+    let report = FutureIncompatReport::parse(FUTURE_INCOMPAT_REPORT).unwrap();
+    let workspace_root = PathBuf::from("/test/");
+    let mapped = map_future_incompat_report_to_lsp(&report, &workspace_root);
+    let urls: Vec<&str> = mapped.iter().map(|(url, _)| url.as_str()).collect();
+    assert_eq!(urls, vec!["file:///test/bar/Cargo.toml", "file:///test/Cargo.toml"]);
+    // Repeated problems of a package are only mentioned once.
+    assert_eq!(
+        mapped[0].1.message,
+        "`bar v0.1.0` contains code that will be rejected by a future version of Rust:\n\
+         trailing semicolon in macro used in expression position\n\
+         run `cargo report future-incompatibilities --id 1` for details"
+    );
+    assert!(mapped.iter().all(|(_, it)| it.severity == Some(DiagnosticSeverity::Warning)));
+}
+
+#[test]
+#[cfg(not(windows))]
+fn other_messages_are_not_future_incompat_reports() {
+    assert_eq!(FutureIncompatReport::parse(r#"{"reason":"build-finished","success":true}"#), None);
+    assert_eq!(FutureIncompatReport::parse("warning: unused variable"), None);
+}
@@ -0,0 +1,113 @@
+//! Recognizes diagnostics a run reports more than once, f.x. for the library
+//! and its unit tests with `--all-targets`, or for a module shared by several
+//! integration tests, see `FlycheckConfig::merge_duplicate_targets`.
+use std::collections::HashMap;
+
+use lsp_types::{CodeActionOrCommand, Diagnostic, NumberOrString, Url};
+
+/// Diagnostics are the same if they only differ in the target reporting them.
+/// The source is part of it, so that the secondary check can report a warning
+/// of the primary one again.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct DiagnosticKey {
+    url: Url,
+    range: (u64, u64, u64, u64),
+    code: Option<String>,
+    source: Option<String>,
+    message: String,
+}
+
+impl DiagnosticKey {
+    fn new(url: &Url, diagnostic: &Diagnostic) -> DiagnosticKey {
+        let range = diagnostic.range;
+        DiagnosticKey {
+            url: url.clone(),
+            range: (range.start.line, range.start.character, range.end.line, range.end.character),
+            code: diagnostic.code.as_ref().map(|code| match code {
+                NumberOrString::Number(number) => number.to_string(),
+                NumberOrString::String(string) => string.clone(),
+            }),
+            source: diagnostic.source.clone(),
+            message: diagnostic.message.clone(),
+        }
+    }
+}
+
+/// See [`Duplicates::record`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Reported {
+    /// The run didn't report the diagnostic before.
+    First,
+    /// The run reported the diagnostic for the same target before.
+    Again,
+    /// The run reported the diagnostic for other targets before.
+    ForAnotherTarget,
+}
+
+/// The diagnostics of a single run, see [`Duplicates::clear`].
+#[derive(Debug, Default)]
+pub(crate) struct Duplicates {
+    /// The targets which reported each diagnostic.
+    targets: HashMap<DiagnosticKey, Vec<String>>,
+    /// The diagnostics which were sent for each file, as they were reported
+    /// the first time, see [`Duplicates::sent`].
+    sent: HashMap<Url, Vec<(DiagnosticKey, Diagnostic, Vec<CodeActionOrCommand>)>>,
+}
+
+impl Duplicates {
+    /// Forgets about the diagnostics of the previous run.
+    pub(crate) fn clear(&mut self) {
+        self.targets.clear();
+        self.sent.clear();
+    }
+
+    /// Records a diagnostic of `url` reported for `target`, returning whether
+    /// it was reported before.
+    pub(crate) fn record(&mut self, url: &Url, diagnostic: &Diagnostic, target: &str) -> Reported {
+        let targets = self.targets.entry(DiagnosticKey::new(url, diagnostic)).or_default();
+        let reported = if targets.is_empty() {
+            Reported::First
+        } else if targets.iter().any(|it| it == target) {
+            return Reported::Again;
+        } else {
+            Reported::ForAnotherTarget
+        };
+        targets.push(target.to_string());
+        reported
+    }
+
+    /// Records that a diagnostic of `url` was sent, to send it again with
+    /// the targets listed in its message, see [`Duplicates::merged`].
+    pub(crate) fn sent(
+        &mut self,
+        url: &Url,
+        diagnostic: &Diagnostic,
+        fixes: &[CodeActionOrCommand],
+    ) {
+        let key = DiagnosticKey::new(url, diagnostic);
+        let sent = self.sent.entry(url.clone()).or_default();
+        sent.push((key, diagnostic.clone(), fixes.to_vec()));
+    }
+
+    /// The diagnostics which were sent for `url`. The ones reported by more
+    /// than one target list them at the end of their message.
+    pub(crate) fn merged(&self, url: &Url) -> Vec<(Diagnostic, Vec<CodeActionOrCommand>)> {
+        let sent = match self.sent.get(url) {
+            Some(sent) => sent,
+            None => return Vec::new(),
+        };
+        sent.iter()
+            .map(|(key, diagnostic, fixes)| {
+                let mut diagnostic = diagnostic.clone();
+                match self.targets.get(key) {
+                    Some(targets) if targets.len() > 1 => {
+                        diagnostic.message =
+                            format!("{} ({})", diagnostic.message, targets.join(", "));
+                    }
+                    _ => {}
+                }
+                (diagnostic, fixes.clone())
+            })
+            .collect()
+    }
+}
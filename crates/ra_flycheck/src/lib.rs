@@ -1,340 +1,3778 @@
 //! cargo_check provides the functionality needed to run `cargo check` or
 //! another compatible command (f.x. clippy) in a background thread and provide
 //! LSP diagnostics based on the output of the command.
+mod batch;
+mod command_line;
 mod conv;
+mod duplicates;
+mod packages;
+mod partial;
+mod process;
+mod progress;
+mod schedule;
+mod snapshot;
+#[cfg(test)]
+mod test;
+#[cfg(feature = "fs-watch")]
+mod watch;
 
 use std::{
+    any::Any,
+    cell::Cell,
+    collections::{HashMap, HashSet, VecDeque},
     env,
+    ffi::OsString,
+    fmt, fs,
     io::{self, BufRead, BufReader},
-    path::PathBuf,
+    iter, mem,
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
     process::{Command, Stdio},
-    time::Instant,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
 };
 
 use cargo_metadata::Message;
-use crossbeam_channel::{never, select, unbounded, Receiver, RecvError, Sender};
+use crossbeam_channel::{
+    after, bounded, never, select, unbounded, Receiver, RecvError, RecvTimeoutError, Sender,
+    TryRecvError,
+};
+use globset::Glob;
 use lsp_types::{
-    CodeAction, CodeActionOrCommand, Diagnostic, Url, WorkDoneProgress, WorkDoneProgressBegin,
-    WorkDoneProgressEnd, WorkDoneProgressReport,
+    CodeAction, CodeActionOrCommand, Diagnostic, DiagnosticSeverity, Url, WorkDoneProgress,
+    WorkDoneProgressBegin, WorkDoneProgressEnd, WorkDoneProgressReport,
+};
+#[cfg(feature = "serde")]
+use serde_crate::{Deserialize, Serialize};
+
+use crate::{
+    batch::{Batch, Batcher},
+    conv::{
+        map_future_incompat_report_to_lsp, map_rust_diagnostic_to_lsp, strip_ansi_codes,
+        suppressed_diagnostics_marker, FutureIncompatReport, MappedRustDiagnostic,
+    },
+    duplicates::{Duplicates, Reported},
+    packages::{is_manifest, ChangeFilter, Changes, Package, PackageIndex, RunScope},
+    process::ProcessGroupChild,
+    progress::{percentage, run_metadata},
+    schedule::{Debounce, Schedule},
+    snapshot::Snapshots,
+};
+
+pub use crate::{
+    command_line::{parse_command_line, ParseError},
+    conv::{
+        compare_diagnostics, url_from_path_with_drive_lowercasing, SUPPRESSED_DIAGNOSTICS_CODE,
+    },
+    partial::{ListOverride, PartialFlycheckConfig},
 };
 
-use crate::conv::{map_rust_diagnostic_to_lsp, MappedRustDiagnostic};
+/// Upper bound for `jobs`, anything above is most likely a typo.
+const MAX_JOBS: u32 = 1024;
+
+/// Number of trailing stderr lines kept for error messages, the interesting
+/// part of cargo's output is usually at its end.
+const STDERR_TAIL_LINES: usize = 50;
+
+/// Target of the log records of [`FlycheckConfig::forward_stderr_to_log`].
+const STDERR_LOG_TARGET: &str = "cargo-watch";
+
+/// Parts of cargo's error output showing that a file in the target directory
+/// was in use by another process.
+const CONTENTION_ERRORS: &[&str] = &[
+    ".cargo-lock",
+    "Resource temporarily unavailable",
+    "Text file busy",
+    "being used by another process",
+];
 
-pub use crate::conv::url_from_path_with_drive_lowercasing;
+/// Number of skipped lines of output shown to the user, see
+/// [`unparsed_output_warning`].
+const SKIPPED_LINE_SAMPLES: usize = 3;
+
+/// Characters of each skipped line shown to the user.
+const SKIPPED_LINE_SAMPLE_LENGTH: usize = 200;
+
+/// Default for [`FlycheckConfig::max_line_length`].
+const DEFAULT_MAX_LINE_LENGTH: usize = 8 * 1024 * 1024;
+
+/// Default for [`FlycheckConfig::message_buffer`].
+const DEFAULT_MESSAGE_BUFFER: usize = 1024;
+
+/// Default for [`FlycheckConfig::max_diagnostics_per_file`], a generated file
+/// with thousands of warnings makes the editor unusable otherwise.
+const DEFAULT_MAX_DIAGNOSTICS_PER_FILE: usize = 200;
+
+/// Default for [`FlycheckConfig::termination_grace_period`].
+const DEFAULT_TERMINATION_GRACE_PERIOD: Duration = Duration::from_secs(2);
+
+/// How often checks which are allowed to finish after a restart are looked
+/// at, to clean up the ones that did.
+const REAP_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How often a running check looks for its cancellation or timeout while cargo
+/// is quiet.
+const CANCELLATION_POLL_INTERVAL: Duration = Duration::from_millis(50);
 
 #[derive(Clone, Debug, PartialEq, Eq)]
-pub enum FlycheckConfig {
-    CargoCommand { command: String, all_targets: bool, extra_args: Vec<String> },
-    CustomCommand { command: String, args: Vec<String> },
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate", default)
+)]
+pub struct FlycheckConfig {
+    pub command: FlycheckCommand,
+    /// Environment variables set for the check process only.
+    pub extra_env: Vec<(String, String)>,
+    /// Inherited environment variables removed for the check process. They
+    /// are removed before `extra_env` is applied, so a variable can be both
+    /// cleared and given an explicit value.
+    pub unset_env: Vec<String>,
+    /// Flags appended to `RUSTFLAGS` for the check process.
+    pub rustflags: Option<String>,
+    /// Use `rustflags` as the whole `RUSTFLAGS` value instead of appending to
+    /// the existing one.
+    pub replace_rustflags: bool,
+    /// Target directory used by the check, relative paths are resolved
+    /// against the workspace root.
+    pub target_dir: Option<PathBuf>,
+    /// Use `target/rust-analyzer` when `target_dir` is not set, so that the
+    /// check doesn't block on the lock held by the user's own cargo builds.
+    pub use_separate_target_dir: bool,
+    /// Program and arguments the check command is run through, f.x.
+    /// `["docker", "exec", "builder"]`. Its output has to be passed through
+    /// unchanged.
+    pub wrapper: Vec<String>,
+    /// Directory the check runs in, relative paths are resolved against the
+    /// workspace root. Defaults to the workspace root, which is still used
+    /// for `--manifest-path` and for the paths of the diagnostics.
+    pub working_dir: Option<PathBuf>,
+    /// Forces incremental compilation on or off through `CARGO_INCREMENTAL`,
+    /// `None` keeps the inherited setting.
+    pub incremental: Option<bool>,
+    /// Turns off colored output by setting `CARGO_TERM_COLOR=never`, so that
+    /// the rendered diagnostics don't depend on the user's environment.
+    pub sanitize_output: bool,
+    /// A slower check, f.x. clippy, which is run once no update arrived for
+    /// `idle_delay` after this check. Its diagnostics are added to the ones of
+    /// this check and use the command name as their source.
+    pub secondary: Option<Box<FlycheckConfig>>,
+    pub idle_delay: Duration,
+    /// Waits until no update arrived for this long before starting a check,
+    /// so that f.x. saving several files at once runs a single check.
+    pub debounce: Duration,
+    /// Lets the debounce delay grow up to this while updates keep arriving
+    /// in quick succession, f.x. during a branch switch. It's back to
+    /// `debounce` once they stop. Has no effect unless it's above `debounce`.
+    pub max_debounce: Duration,
+    /// Starts a check no sooner than this after the previous one started or
+    /// ended, f.x. to save battery, however often updates arrive. Updates in
+    /// between are checked at once when it's over. This takes precedence
+    /// over `debounce` and `cancel_on_update`.
+    pub min_interval: Option<Duration>,
+    /// Checks the whole workspace again once no check ran for this long,
+    /// for diagnostics depending on more than the files, f.x. code generated
+    /// by another process. Paused checks aren't run, and nothing is checked
+    /// before the first update.
+    pub recheck_interval: Option<Duration>,
+    /// Stops checks running longer than this, f.x. because a build script
+    /// waits on the network. `None` lets checks run as long as they need.
+    pub timeout: Option<Duration>,
+    /// Runs a check which failed because another cargo process used the
+    /// target directory at the same time once more after this delay. `None`
+    /// reports these failures right away.
+    pub retry_delay: Option<Duration>,
+    /// Diagnostics of a file reported within this long of its first one are
+    /// sent together with [`CheckTask::AddDiagnostics`], unless one of
+    /// another file is reported in between. `None` sends each diagnostic on
+    /// its own with [`CheckTask::AddDiagnostic`].
+    pub batch_delay: Option<Duration>,
+    /// Runs the check at a reduced priority, so that it doesn't slow down
+    /// the editor or builds in the foreground.
+    pub low_priority: bool,
+    /// What happens to a running check when a new one is started or the
+    /// [`Flycheck`] is dropped.
+    pub on_restart: RestartBehavior,
+    /// Stops the running check when an update arrives, to check the changes
+    /// right away. Otherwise the update waits until the running check ended.
+    pub cancel_on_update: bool,
+    /// Changes the check command right before it's run, f.x. to use a
+    /// credential helper or sandbox no other option covers.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub command_hook: Option<CommandHook>,
+    /// Lines of output longer than this many bytes are skipped, so that f.x.
+    /// a runaway proc macro can't use up all the memory.
+    pub max_line_length: usize,
+    /// Number of messages buffered while they are handled, once it's reached
+    /// cargo has to wait before printing more.
+    pub message_buffer: usize,
+    /// Diagnostics of a single check beyond this many are not reported,
+    /// counting all files together.
+    pub max_diagnostics: Option<usize>,
+    /// Diagnostics of a single check for a single file beyond this many are
+    /// not reported either, one with [`SUPPRESSED_DIAGNOSTICS_CODE`] at the top
+    /// of the file tells how many were left out instead.
+    pub max_diagnostics_per_file: Option<usize>,
+    /// Stops the check once `max_diagnostics` is reached, instead of letting
+    /// it finish.
+    pub stop_at_diagnostic_limit: bool,
+    /// Runs `cargo metadata` before the check to count the units it goes
+    /// through, so that its progress can be shown as a percentage. Failing to
+    /// do so only loses the percentage, custom commands never have one.
+    pub estimate_progress: bool,
+    /// Shows cargo's future incompatibility reports as warnings on the
+    /// manifest of the affected package.
+    pub report_future_incompat: bool,
+    /// How long a check which is stopped, f.x. because it timed out, gets to
+    /// exit cleanly before it's killed. Checks are always killed right away
+    /// on Windows.
+    pub termination_grace_period: Duration,
+    /// Logs every line cargo prints to stderr at info level with the
+    /// `cargo-watch` target, to find out why a check doesn't show anything.
+    /// The end of stderr is part of the error of failed checks either way.
+    pub forward_stderr_to_log: bool,
+    /// Checks only the workspace members owning the files of an update, see
+    /// [`Flycheck::update_with_files`]. Members depending on them are not
+    /// checked again, so their diagnostics can be outdated until the next
+    /// check of the whole workspace.
+    pub check_changed_packages: bool,
+    /// Checks the workspace right away, as if an update arrived when the
+    /// [`Flycheck`] is created, instead of waiting for the first one. This
+    /// includes restarts after the check thread crashed.
+    pub check_on_start: bool,
+    /// Globs of further files whose updates lead to a check, f.x.
+    /// `migrations/*.sql` for queries checked by a macro. They are matched
+    /// against the paths relative to the workspace root. Besides these, only
+    /// Rust files in the workspace root, manifests and build scripts do, so
+    /// saving a Rust file of a path dependency outside the root doesn't.
+    pub watched_globs: Vec<String>,
+    /// Leaves out updates of files whose contents are the same as during the
+    /// last successful check, f.x. because formatting on save didn't change
+    /// anything. The files of an update are read to find out.
+    pub skip_unchanged_files: bool,
+    /// When the diagnostics of a run are sent, see [`PublishMode`].
+    pub publish_mode: PublishMode,
+    /// Diagnostics a run reports again for other targets, f.x. for a module
+    /// shared by integration tests, are shown once either way. With this,
+    /// the targets which reported them are listed at the end of their
+    /// message, otherwise only the first report is kept.
+    pub merge_duplicate_targets: bool,
 }
 
-/// Flycheck wraps the shared state and communication machinery used for
-/// running `cargo check` (or other compatible command) and providing
-/// diagnostics based on the output.
-/// The spawned thread is shut down when this struct is dropped.
-#[derive(Debug)]
-pub struct Flycheck {
-    // XXX: drop order is significant
-    cmd_send: Sender<CheckCommand>,
-    handle: jod_thread::JoinHandle<()>,
-    pub task_recv: Receiver<CheckTask>,
+/// See [`FlycheckConfig::command_hook`]. Hooks are equal only if they are the
+/// same closure.
+#[derive(Clone)]
+pub struct CommandHook(Arc<dyn Fn(&mut Command) + Send + Sync>);
+
+impl CommandHook {
+    pub fn new(hook: impl Fn(&mut Command) + Send + Sync + 'static) -> CommandHook {
+        CommandHook(Arc::new(hook))
+    }
 }
 
-impl Flycheck {
-    pub fn new(config: FlycheckConfig, workspace_root: PathBuf) -> Flycheck {
-        let (task_send, task_recv) = unbounded::<CheckTask>();
-        let (cmd_send, cmd_recv) = unbounded::<CheckCommand>();
-        let handle = jod_thread::spawn(move || {
-            FlycheckThread::new(config, workspace_root).run(&task_send, &cmd_recv);
-        });
-        Flycheck { task_recv, cmd_send, handle }
+impl fmt::Debug for CommandHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("CommandHook(..)")
     }
+}
 
-    /// Schedule a re-start of the cargo check worker.
-    pub fn update(&self) {
-        self.cmd_send.send(CheckCommand::Update).unwrap();
+impl PartialEq for CommandHook {
+    fn eq(&self, other: &CommandHook) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
     }
 }
 
-#[derive(Debug)]
-pub enum CheckTask {
-    /// Request a clearing of all cached diagnostics from the check watcher
-    ClearDiagnostics,
+impl Eq for CommandHook {}
 
-    /// Request adding a diagnostic with fixes included to a file
-    AddDiagnostic { url: Url, diagnostic: Diagnostic, fixes: Vec<CodeActionOrCommand> },
+/// See [`FlycheckConfig::on_restart`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate", rename_all = "snake_case")
+)]
+pub enum RestartBehavior {
+    /// Kills the running check right away.
+    KillImmediately,
+    /// Lets the running check finish in the background, killing it only once
+    /// it's still running after `max`. Its diagnostics are discarded, but
+    /// interrupting cargo can leave broken incremental artifacts behind.
+    WaitForFinish { max: Duration },
+}
 
-    /// Request check progress notification to client
-    Status(WorkDoneProgress),
+impl Default for RestartBehavior {
+    fn default() -> Self {
+        RestartBehavior::KillImmediately
+    }
 }
 
-pub enum CheckCommand {
-    /// Request re-start of check thread
-    Update,
+/// See [`FlycheckConfig::publish_mode`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate", rename_all = "snake_case")
+)]
+pub enum PublishMode {
+    /// Sends every diagnostic as soon as cargo reports it. The diagnostics a
+    /// file had before are cleared right before its first new one, see
+    /// [`CheckTask::ClearForFile`].
+    Incremental,
+    /// Collects the diagnostics until the run is complete, including its
+    /// secondary check, and sends them all at once with
+    /// [`CheckTask::SetDiagnostics`]. The diagnostics of runs which are
+    /// cancelled or replaced are never sent.
+    AtEnd,
 }
 
-struct FlycheckThread {
-    config: FlycheckConfig,
-    workspace_root: PathBuf,
-    last_update_req: Option<Instant>,
-    // XXX: drop order is significant
-    message_recv: Receiver<CheckEvent>,
-    /// WatchThread exists to wrap around the communication needed to be able to
-    /// run `cargo check` without blocking. Currently the Rust standard library
-    /// doesn't provide a way to read sub-process output without blocking, so we
-    /// have to wrap sub-processes output handling in a thread and pass messages
-    /// back over a channel.
-    check_process: Option<jod_thread::JoinHandle<()>>,
+impl Default for PublishMode {
+    fn default() -> Self {
+        PublishMode::Incremental
+    }
 }
 
-impl FlycheckThread {
-    fn new(config: FlycheckConfig, workspace_root: PathBuf) -> FlycheckThread {
-        FlycheckThread {
-            config,
-            workspace_root,
-            last_update_req: None,
-            message_recv: never(),
-            check_process: None,
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate", tag = "kind", rename_all = "snake_case")
+)]
+pub enum FlycheckCommand {
+    CargoCommand {
+        #[cfg_attr(feature = "serde", serde(default = "default_cargo_command"))]
+        command: String,
+        /// Cargo executable to run, falls back to `$CARGO` and then to
+        /// `cargo` from `PATH`.
+        #[cfg_attr(feature = "serde", serde(default))]
+        cargo_binary: Option<PathBuf>,
+        /// Rustup toolchain to check with, passed as `+<toolchain>` to the
+        /// cargo proxy.
+        #[cfg_attr(feature = "serde", serde(default))]
+        toolchain: Option<String>,
+        /// Workspace members to check, an empty list checks the whole
+        /// workspace.
+        ///
+        /// Diagnostics are still cleared for the whole workspace before each
+        /// run. As the member list doesn't change during the lifetime of a
+        /// `Flycheck`, no other packages can have diagnostics to keep.
+        #[cfg_attr(feature = "serde", serde(default))]
+        packages: Vec<String>,
+        /// Workspace members to skip, only valid when checking the whole
+        /// workspace.
+        #[cfg_attr(feature = "serde", serde(default))]
+        exclude: Vec<String>,
+        #[cfg_attr(feature = "serde", serde(default))]
+        all_targets: bool,
+        /// Kinds of targets to check when `all_targets` is not set, an empty
+        /// list keeps cargo's default selection.
+        #[cfg_attr(feature = "serde", serde(default))]
+        targets: Vec<CheckTarget>,
+        /// Overrides the target selection, can be changed between runs with
+        /// [`Flycheck::set_scope`].
+        #[cfg_attr(feature = "serde", serde(default))]
+        scope: CheckScope,
+        #[cfg_attr(feature = "serde", serde(default))]
+        target_triple: Option<String>,
+        /// Profile to check with, `None` means cargo's default.
+        #[cfg_attr(feature = "serde", serde(default))]
+        profile: Option<String>,
+        /// Number of parallel jobs, `None` means cargo's default.
+        #[cfg_attr(feature = "serde", serde(default))]
+        jobs: Option<u32>,
+        /// Run without accessing the network, dependencies have to be
+        /// fetched beforehand.
+        #[cfg_attr(feature = "serde", serde(default))]
+        offline: bool,
+        /// Fail instead of updating an out of date `Cargo.lock`.
+        #[cfg_attr(feature = "serde", serde(default))]
+        locked: bool,
+        /// Equivalent to `locked` and `offline` combined.
+        #[cfg_attr(feature = "serde", serde(default))]
+        frozen: bool,
+        /// Keep checking the remaining crates after one of them failed to
+        /// build. Dropped when the cargo version doesn't understand it.
+        #[cfg_attr(feature = "serde", serde(default))]
+        keep_going: bool,
+        /// Checks the tests with `--tests --profile test`, so that code behind
+        /// `#[cfg(test)]` gets diagnostics. Overrides the targets, the scope
+        /// and the profile.
+        #[cfg_attr(feature = "serde", serde(default))]
+        tests_only: bool,
+        #[cfg_attr(feature = "serde", serde(default))]
+        no_default_features: bool,
+        #[cfg_attr(feature = "serde", serde(default))]
+        all_features: bool,
+        #[cfg_attr(feature = "serde", serde(default))]
+        features: Vec<String>,
+        #[cfg_attr(feature = "serde", serde(default))]
+        extra_args: Vec<String>,
+        /// Arguments passed after a `--` separator, f.x. lint levels for
+        /// clippy.
+        #[cfg_attr(feature = "serde", serde(default))]
+        trailing_args: Vec<String>,
+        /// Pass `--message-format=json`, disable when the command picks a
+        /// JSON message format on its own.
+        #[cfg_attr(feature = "serde", serde(default = "default_true"))]
+        inject_message_format: bool,
+        /// Pass `--all` instead of `--workspace`, which cargo only knows since
+        /// 1.39. Turned on for the following checks when cargo rejects
+        /// `--workspace`.
+        #[cfg_attr(feature = "serde", serde(default))]
+        legacy_workspace_flag: bool,
+    },
+    /// Runs `command` with `args` in the workspace root without adding any
+    /// arguments of our own.
+    ///
+    /// The command has to print cargo's JSON messages, as produced by
+    /// `--message-format=json`, to stdout with one message per line. Lines
+    /// which can't be parsed as such are logged and skipped, and stderr is
+    /// only used for error reporting when the command fails.
+    CustomCommand { command: String, args: Vec<String> },
+}
+
+impl Default for FlycheckConfig {
+    fn default() -> Self {
+        FlycheckConfig {
+            command: FlycheckCommand::CargoCommand {
+                command: default_cargo_command(),
+                cargo_binary: None,
+                toolchain: None,
+                packages: Vec::new(),
+                exclude: Vec::new(),
+                all_targets: false,
+                targets: Vec::new(),
+                scope: CheckScope::Workspace,
+                target_triple: None,
+                profile: None,
+                jobs: None,
+                offline: false,
+                locked: false,
+                frozen: false,
+                keep_going: false,
+                tests_only: false,
+                no_default_features: false,
+                all_features: false,
+                features: Vec::new(),
+                extra_args: Vec::new(),
+                trailing_args: Vec::new(),
+                inject_message_format: true,
+                legacy_workspace_flag: false,
+            },
+            extra_env: Vec::new(),
+            unset_env: Vec::new(),
+            rustflags: None,
+            replace_rustflags: false,
+            target_dir: None,
+            use_separate_target_dir: false,
+            wrapper: Vec::new(),
+            working_dir: None,
+            incremental: None,
+            sanitize_output: true,
+            secondary: None,
+            idle_delay: Duration::from_secs(2),
+            debounce: Duration::from_secs(0),
+            max_debounce: Duration::from_secs(0),
+            min_interval: None,
+            recheck_interval: None,
+            timeout: None,
+            retry_delay: Some(Duration::from_secs(2)),
+            batch_delay: Some(Duration::from_millis(50)),
+            low_priority: false,
+            on_restart: RestartBehavior::KillImmediately,
+            cancel_on_update: true,
+            command_hook: None,
+            max_line_length: DEFAULT_MAX_LINE_LENGTH,
+            message_buffer: DEFAULT_MESSAGE_BUFFER,
+            max_diagnostics: None,
+            max_diagnostics_per_file: Some(DEFAULT_MAX_DIAGNOSTICS_PER_FILE),
+            stop_at_diagnostic_limit: false,
+            estimate_progress: false,
+            report_future_incompat: false,
+            termination_grace_period: DEFAULT_TERMINATION_GRACE_PERIOD,
+            forward_stderr_to_log: false,
+            check_changed_packages: false,
+            check_on_start: false,
+            watched_globs: Vec::new(),
+            skip_unchanged_files: false,
+            publish_mode: PublishMode::Incremental,
+            merge_duplicate_targets: false,
         }
     }
+}
 
-    fn run(&mut self, task_send: &Sender<CheckTask>, cmd_recv: &Receiver<CheckCommand>) {
-        // If we rerun the thread, we need to discard the previous check results first
-        self.clean_previous_results(task_send);
+fn default_cargo_command() -> String {
+    "check".to_string()
+}
 
-        loop {
-            select! {
-                recv(&cmd_recv) -> cmd => match cmd {
-                    Ok(cmd) => self.handle_command(cmd),
-                    Err(RecvError) => {
-                        // Command channel has closed, so shut down
-                        break;
-                    },
-                },
-                recv(self.message_recv) -> msg => match msg {
-                    Ok(msg) => self.handle_message(msg, task_send),
-                    Err(RecvError) => {
-                        // Watcher finished, replace it with a never channel to
-                        // avoid busy-waiting.
-                        self.message_recv = never();
-                        self.check_process = None;
-                    },
+#[cfg(feature = "serde")]
+fn default_true() -> bool {
+    true
+}
+
+/// Builds a [`FlycheckConfig`] starting from a plain `cargo check`, see
+/// [`FlycheckConfig::builder`].
+///
+/// Options of cargo commands are ignored after switching to a custom command.
+#[derive(Clone, Debug, Default)]
+pub struct FlycheckConfigBuilder {
+    config: FlycheckConfig,
+}
+
+// Options wrapped in an `Option` take the inner value, so that f.x.
+// `.profile("release")` works.
+macro_rules! cargo_options {
+    ($($name:ident: $ty:ty,)* ; $($opt_name:ident: Option<$opt_ty:ty>,)*) => {
+        $(
+            pub fn $name(mut self, value: impl Into<$ty>) -> FlycheckConfigBuilder {
+                if let FlycheckCommand::CargoCommand { $name, .. } = &mut self.config.command {
+                    *$name = value.into();
                 }
-            };
+                self
+            }
+        )*
+        $(
+            pub fn $opt_name(mut self, value: impl Into<$opt_ty>) -> FlycheckConfigBuilder {
+                if let FlycheckCommand::CargoCommand { $opt_name, .. } = &mut self.config.command {
+                    *$opt_name = Some(value.into());
+                }
+                self
+            }
+        )*
+    };
+}
 
-            if self.should_recheck() {
-                self.last_update_req = None;
-                task_send.send(CheckTask::ClearDiagnostics).unwrap();
-                self.restart_check_process();
+macro_rules! options {
+    ($($name:ident: $ty:ty,)* ; $($opt_name:ident: Option<$opt_ty:ty>,)*) => {
+        $(
+            pub fn $name(mut self, value: impl Into<$ty>) -> FlycheckConfigBuilder {
+                self.config.$name = value.into();
+                self
             }
-        }
+        )*
+        $(
+            pub fn $opt_name(mut self, value: impl Into<$opt_ty>) -> FlycheckConfigBuilder {
+                self.config.$opt_name = Some(value.into());
+                self
+            }
+        )*
+    };
+}
+
+impl FlycheckConfigBuilder {
+    cargo_options! {
+        command: String,
+        packages: Vec<String>,
+        exclude: Vec<String>,
+        all_targets: bool,
+        targets: Vec<CheckTarget>,
+        scope: CheckScope,
+        offline: bool,
+        locked: bool,
+        frozen: bool,
+        keep_going: bool,
+        tests_only: bool,
+        no_default_features: bool,
+        all_features: bool,
+        features: Vec<String>,
+        extra_args: Vec<String>,
+        trailing_args: Vec<String>,
+        inject_message_format: bool,
+        legacy_workspace_flag: bool,
+        ;
+        cargo_binary: Option<PathBuf>,
+        toolchain: Option<String>,
+        target_triple: Option<String>,
+        profile: Option<String>,
     }
 
-    fn clean_previous_results(&self, task_send: &Sender<CheckTask>) {
-        task_send.send(CheckTask::ClearDiagnostics).unwrap();
-        task_send
-            .send(CheckTask::Status(WorkDoneProgress::End(WorkDoneProgressEnd { message: None })))
-            .unwrap();
+    options! {
+        extra_env: Vec<(String, String)>,
+        unset_env: Vec<String>,
+        replace_rustflags: bool,
+        use_separate_target_dir: bool,
+        wrapper: Vec<String>,
+        sanitize_output: bool,
+        idle_delay: Duration,
+        debounce: Duration,
+        max_debounce: Duration,
+        low_priority: bool,
+        on_restart: RestartBehavior,
+        cancel_on_update: bool,
+        stop_at_diagnostic_limit: bool,
+        estimate_progress: bool,
+        report_future_incompat: bool,
+        termination_grace_period: Duration,
+        forward_stderr_to_log: bool,
+        check_changed_packages: bool,
+        check_on_start: bool,
+        watched_globs: Vec<String>,
+        skip_unchanged_files: bool,
+        publish_mode: PublishMode,
+        merge_duplicate_targets: bool,
+        ;
+        rustflags: Option<String>,
+        target_dir: Option<PathBuf>,
+        working_dir: Option<PathBuf>,
+        incremental: Option<bool>,
+        min_interval: Option<Duration>,
+        recheck_interval: Option<Duration>,
+        timeout: Option<Duration>,
+        retry_delay: Option<Duration>,
+        batch_delay: Option<Duration>,
     }
 
-    fn should_recheck(&mut self) -> bool {
-        if let Some(_last_update_req) = &self.last_update_req {
-            // We currently only request an update on save, as we need up to
-            // date source on disk for cargo check to do it's magic, so we
-            // don't really need to debounce the requests at this point.
-            return true;
+    // Not generated, as `impl Into<u32>` would reject plain integer literals.
+    pub fn jobs(mut self, value: u32) -> FlycheckConfigBuilder {
+        if let FlycheckCommand::CargoCommand { jobs, .. } = &mut self.config.command {
+            *jobs = Some(value);
         }
-        false
+        self
     }
 
-    fn handle_command(&mut self, cmd: CheckCommand) {
-        match cmd {
-            CheckCommand::Update => self.last_update_req = Some(Instant::now()),
-        }
+    // Not generated for the same reason as `jobs`.
+    pub fn max_line_length(mut self, value: usize) -> FlycheckConfigBuilder {
+        self.config.max_line_length = value;
+        self
     }
 
-    fn handle_message(&self, msg: CheckEvent, task_send: &Sender<CheckTask>) {
-        match msg {
-            CheckEvent::Begin => {
-                task_send
-                    .send(CheckTask::Status(WorkDoneProgress::Begin(WorkDoneProgressBegin {
-                        title: "Running 'cargo check'".to_string(),
-                        cancellable: Some(false),
-                        message: None,
-                        percentage: None,
-                    })))
-                    .unwrap();
-            }
+    pub fn message_buffer(mut self, value: usize) -> FlycheckConfigBuilder {
+        self.config.message_buffer = value;
+        self
+    }
 
-            CheckEvent::End => {
-                task_send
-                    .send(CheckTask::Status(WorkDoneProgress::End(WorkDoneProgressEnd {
-                        message: None,
-                    })))
-                    .unwrap();
-            }
+    pub fn max_diagnostics(mut self, value: usize) -> FlycheckConfigBuilder {
+        self.config.max_diagnostics = Some(value);
+        self
+    }
 
-            CheckEvent::Msg(Message::CompilerArtifact(msg)) => {
-                task_send
-                    .send(CheckTask::Status(WorkDoneProgress::Report(WorkDoneProgressReport {
-                        cancellable: Some(false),
-                        message: Some(msg.target.name),
-                        percentage: None,
-                    })))
-                    .unwrap();
-            }
+    pub fn max_diagnostics_per_file(mut self, value: usize) -> FlycheckConfigBuilder {
+        self.config.max_diagnostics_per_file = Some(value);
+        self
+    }
 
-            CheckEvent::Msg(Message::CompilerMessage(msg)) => {
-                let map_result = map_rust_diagnostic_to_lsp(&msg.message, &self.workspace_root);
-                if map_result.is_empty() {
-                    return;
-                }
+    /// Runs `secondary` after this check once no update arrived for
+    /// `idle_delay`.
+    pub fn secondary(mut self, secondary: FlycheckConfig) -> FlycheckConfigBuilder {
+        self.config.secondary = Some(Box::new(secondary));
+        self
+    }
 
-                for MappedRustDiagnostic { location, diagnostic, fixes } in map_result {
-                    let fixes = fixes
-                        .into_iter()
-                        .map(|fix| {
-                            CodeAction { diagnostics: Some(vec![diagnostic.clone()]), ..fix }.into()
-                        })
-                        .collect();
+    pub fn command_hook(
+        mut self,
+        hook: impl Fn(&mut Command) + Send + Sync + 'static,
+    ) -> FlycheckConfigBuilder {
+        self.config.command_hook = Some(CommandHook::new(hook));
+        self
+    }
 
-                    task_send
-                        .send(CheckTask::AddDiagnostic { url: location.uri, diagnostic, fixes })
-                        .unwrap();
-                }
+    /// Runs `command` with `args` instead of cargo.
+    pub fn custom_command(
+        mut self,
+        command: impl Into<String>,
+        args: Vec<String>,
+    ) -> FlycheckConfigBuilder {
+        self.config.command = FlycheckCommand::CustomCommand { command: command.into(), args };
+        self
+    }
+
+    pub fn build(self) -> FlycheckConfig {
+        self.config
+    }
+}
+
+/// Describes why a [`FlycheckConfig`] can't be turned into a sensible
+/// command.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConfigError {
+    /// Packages can only be excluded when checking the whole workspace.
+    ExcludeWithPackages(Vec<String>),
+    /// The number of jobs is zero or unreasonably big.
+    InvalidJobs(u32),
+    /// A flag in the extra arguments contradicts one passed by us.
+    ConflictingFlags { flag: String, conflicts_with: String },
+    /// A glob in `watched_globs` can't be parsed.
+    InvalidGlob { glob: String, error: String },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::ExcludeWithPackages(exclude) => write!(
+                f,
+                "can't exclude {:?} when checking specific packages, \
+                 cargo only supports `--exclude` together with `--workspace`",
+                exclude
+            ),
+            ConfigError::InvalidJobs(jobs) => write!(
+                f,
+                "invalid number of jobs {}, expected a value between 1 and {}",
+                jobs, MAX_JOBS
+            ),
+            ConfigError::ConflictingFlags { flag, conflicts_with } => {
+                write!(f, "`{}` in the extra args conflicts with {}", flag, conflicts_with)
             }
+            ConfigError::InvalidGlob { glob, error } => {
+                write!(f, "invalid watched glob `{}`: {}", glob, error)
+            }
+        }
+    }
+}
 
-            CheckEvent::Msg(Message::BuildScriptExecuted(_msg)) => {}
-            CheckEvent::Msg(Message::Unknown) => {}
+impl std::error::Error for ConfigError {}
+
+/// Kinds of targets the check can be restricted to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate", rename_all = "snake_case")
+)]
+pub enum CheckTarget {
+    Lib,
+    Bins,
+    Tests,
+    Examples,
+    Benches,
+}
+
+/// How much of the workspace a check covers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate", rename_all = "snake_case")
+)]
+pub enum CheckScope {
+    /// Check the targets selected by `all_targets` and `targets`.
+    Workspace,
+    /// Only check libraries and binaries, skipping tests, examples and
+    /// benches for faster checks of big workspaces.
+    LibOnly,
+    /// Check all targets.
+    AllTargets,
+}
+
+impl Default for CheckScope {
+    fn default() -> Self {
+        CheckScope::Workspace
+    }
+}
+
+impl CheckTarget {
+    const ALL: [CheckTarget; 5] = [
+        CheckTarget::Lib,
+        CheckTarget::Bins,
+        CheckTarget::Tests,
+        CheckTarget::Examples,
+        CheckTarget::Benches,
+    ];
+
+    fn flag(self) -> &'static str {
+        match self {
+            CheckTarget::Lib => "--lib",
+            CheckTarget::Bins => "--bins",
+            CheckTarget::Tests => "--tests",
+            CheckTarget::Examples => "--examples",
+            CheckTarget::Benches => "--benches",
         }
     }
+}
 
-    fn restart_check_process(&mut self) {
-        // First, clear and cancel the old thread
-        self.message_recv = never();
-        self.check_process = None;
+impl FlycheckConfig {
+    pub fn builder() -> FlycheckConfigBuilder {
+        FlycheckConfigBuilder::default()
+    }
 
-        let mut cmd = match &self.config {
-            FlycheckConfig::CargoCommand { command, all_targets, extra_args } => {
-                let mut cmd = Command::new(cargo_binary());
-                cmd.arg(command);
-                cmd.args(&["--workspace", "--message-format=json", "--manifest-path"]);
-                cmd.arg(self.workspace_root.join("Cargo.toml"));
-                if *all_targets {
-                    cmd.arg("--all-targets");
-                }
-                cmd.args(extra_args);
-                cmd
+    /// Creates a config running the cargo subcommand given by
+    /// `command_line`, f.x. `clippy --features "foo bar"`, see
+    /// [`parse_command_line`].
+    pub fn from_command_line(command_line: &str) -> Result<FlycheckConfig, ParseError> {
+        let mut args = parse_command_line(command_line)?;
+        if args.is_empty() {
+            return Err(ParseError::EmptyCommand);
+        }
+        let command = args.remove(0);
+        Ok(FlycheckConfig::builder().command(command).extra_args(args).build())
+    }
+
+    /// Parses a config from TOML, missing options keep their defaults.
+    ///
+    /// ```toml
+    /// extra_env = [["RUST_LOG", "warn"]]
+    ///
+    /// [command]
+    /// kind = "cargo_command"
+    /// command = "clippy"
+    /// features = ["serde"]
+    /// ```
+    #[cfg(feature = "serde")]
+    pub fn from_toml_str(s: &str) -> Result<FlycheckConfig, toml::de::Error> {
+        toml::from_str(s)
+    }
+
+    /// Builds the command running the check for the workspace at
+    /// `workspace_root`.
+    fn to_command(&self, workspace_root: &Path) -> Result<Command, String> {
+        self.validate().map_err(|err| err.to_string())?;
+        let (program, args) = self.command_line(workspace_root);
+        if let FlycheckCommand::CargoCommand { extra_args, .. } = &self.command {
+            if extra_args.iter().any(|it| it.contains("json-render-diagnostics")) {
+                log::warn!(
+                    "`json-render-diagnostics` keeps diagnostics out of the JSON output, none will be shown"
+                );
             }
-            FlycheckConfig::CustomCommand { command, args } => {
-                let mut cmd = Command::new(command);
-                cmd.args(args);
+        }
+        if let Some(manifest_path) = self.manifest_path(workspace_root) {
+            if !manifest_path.is_file() {
+                return Err(format!(
+                    "can't run the check, {} does not exist",
+                    manifest_path.display()
+                ));
+            }
+        }
+        self.command_for(program, args, workspace_root)
+    }
+
+    /// Builds a command running `program` with `args` the way the check is
+    /// run, through the wrapper and with the configured environment.
+    fn command_for(
+        &self,
+        program: PathBuf,
+        args: Vec<OsString>,
+        workspace_root: &Path,
+    ) -> Result<Command, String> {
+        let working_dir = self.working_dir(workspace_root);
+        let mut cmd = match self.wrapper.split_first() {
+            Some((wrapper, wrapper_args)) => {
+                let mut cmd = Command::new(wrapper);
+                cmd.args(wrapper_args).arg(program);
                 cmd
             }
+            None => Command::new(program),
         };
-        cmd.current_dir(&self.workspace_root);
-
-        let (message_send, message_recv) = unbounded();
-        self.message_recv = message_recv;
-        self.check_process = Some(jod_thread::spawn(move || {
-            // If we trigger an error here, we will do so in the loop instead,
-            // which will break out of the loop, and continue the shutdown
-            let _ = message_send.send(CheckEvent::Begin);
+        cmd.args(args);
+        for key in &self.unset_env {
+            cmd.env_remove(key);
+        }
+        if self.sanitize_output {
+            cmd.env("CARGO_TERM_COLOR", "never");
+        }
+        if let Some(incremental) = self.incremental {
+            cmd.env("CARGO_INCREMENTAL", if incremental { "1" } else { "0" });
+        }
+        cmd.envs(self.extra_env.iter().map(|(key, value)| (key, value)));
+        if let Some(rustflags) = self.effective_rustflags(env::var("RUSTFLAGS").ok()) {
+            cmd.env("RUSTFLAGS", rustflags);
+        }
+        if let Some(target_dir) = self.target_dir(workspace_root) {
+            fs::create_dir_all(&target_dir).map_err(|err| {
+                format!("failed to create target directory {}: {}", target_dir.display(), err)
+            })?;
+            // The environment variable works for custom commands wrapping
+            // cargo as well.
+            cmd.env("CARGO_TARGET_DIR", target_dir);
+        }
+        cmd.current_dir(working_dir);
+        Ok(cmd)
+    }
 
-            let res = run_cargo(cmd, &mut |message| {
-                // Skip certain kinds of messages to only spend time on what's useful
-                match &message {
-                    Message::CompilerArtifact(artifact) if artifact.fresh => return true,
-                    Message::BuildScriptExecuted(_) => return true,
-                    Message::Unknown => return true,
-                    _ => {}
-                }
+    /// The directory the check is run in, the workspace root by default.
+    fn working_dir(&self, workspace_root: &Path) -> PathBuf {
+        match &self.working_dir {
+            Some(working_dir) => workspace_root.join(working_dir),
+            None => workspace_root.to_path_buf(),
+        }
+    }
 
-                // if the send channel was closed, we want to shutdown
-                message_send.send(CheckEvent::Msg(message)).is_ok()
-            });
+    /// The manifest cargo is pointed at, `None` for custom commands.
+    fn manifest_path(&self, workspace_root: &Path) -> Option<PathBuf> {
+        match &self.command {
+            // Cargo resolves a relative `--manifest-path` against its own
+            // working directory.
+            FlycheckCommand::CargoCommand { extra_args, .. } => {
+                Some(match flag_value(extra_args, "--manifest-path") {
+                    Some(path) => self.working_dir(workspace_root).join(path),
+                    None => workspace_root.join("Cargo.toml"),
+                })
+            }
+            FlycheckCommand::CustomCommand { .. } => None,
+        }
+    }
 
-            if let Err(err) = res {
-                // FIXME: make the `message_send` to be `Sender<Result<CheckEvent, CargoError>>`
-                // to display user-caused misconfiguration errors instead of just logging them here
-                log::error!("Cargo watcher failed {:?}", err);
+    /// Suggests how to fix the check command not being found.
+    fn not_found_hint(&self) -> &'static str {
+        match &self.command {
+            FlycheckCommand::CargoCommand { .. } if self.wrapper.is_empty() => {
+                "hint: install Rust with rustup (https://rustup.rs), or add cargo to `PATH` if it is installed already"
             }
+            _ => "hint: make sure the program is installed and in `PATH`",
+        }
+    }
 
-            // We can ignore any error here, as we are already in the progress
-            // of shutting down.
-            let _ = message_send.send(CheckEvent::End);
-        }))
+    /// The toolchain the check runs with, the one rustup picks for the
+    /// workspace if none is set.
+    fn toolchain_name(&self, workspace_root: &Path) -> Option<String> {
+        if let FlycheckCommand::CargoCommand { toolchain: Some(toolchain), .. } = &self.command {
+            return Some(toolchain.trim_start_matches('+').to_string());
+        }
+        let output = process::configure(&mut Command::new("rustup"), false)
+            .args(&["show", "active-toolchain"])
+            .current_dir(workspace_root)
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .output()
+            .ok()?;
+        // F.x. `stable-x86_64-unknown-linux-gnu (default)`.
+        let stdout = String::from_utf8(output.stdout).ok()?;
+        let toolchain = stdout.split_whitespace().next()?;
+        if output.status.success() {
+            Some(toolchain.to_string())
+        } else {
+            None
+        }
     }
-}
 
-#[derive(Debug)]
-pub struct DiagnosticWithFixes {
-    diagnostic: Diagnostic,
-    fixes: Vec<CodeAction>,
-}
+    /// Returns the target directory for the check if it differs from cargo's
+    /// default.
+    fn target_dir(&self, workspace_root: &Path) -> Option<PathBuf> {
+        let target_dir = match &self.target_dir {
+            Some(target_dir) => target_dir.clone(),
+            None if self.use_separate_target_dir => Path::new("target").join("rust-analyzer"),
+            None => return None,
+        };
+        Some(workspace_root.join(target_dir))
+    }
 
-enum CheckEvent {
-    Begin,
-    Msg(cargo_metadata::Message),
-    End,
-}
+    /// Returns the `RUSTFLAGS` value for the check process if it has to be
+    /// changed, given the value `inherited` from our own environment.
+    fn effective_rustflags(&self, inherited: Option<String>) -> Option<String> {
+        let rustflags = self.rustflags.as_ref()?;
+        if self.replace_rustflags {
+            return Some(rustflags.clone());
+        }
+        let existing = match self.extra_env.iter().rev().find(|(key, _)| key == "RUSTFLAGS") {
+            Some((_, value)) => Some(value.clone()),
+            None if self.unset_env.iter().any(|it| it == "RUSTFLAGS") => None,
+            None => inherited,
+        };
+        match existing {
+            Some(existing) if !existing.trim().is_empty() => {
+                Some(format!("{} {}", existing.trim_end(), rustflags))
+            }
+            _ => Some(rustflags.clone()),
+        }
+    }
 
-fn run_cargo(
-    mut command: Command,
-    on_message: &mut dyn FnMut(cargo_metadata::Message) -> bool,
-) -> io::Result<()> {
-    let mut child =
-        command.stdout(Stdio::piped()).stderr(Stdio::null()).stdin(Stdio::null()).spawn()?;
-
-    // We manually read a line at a time, instead of using serde's
-    // stream deserializers, because the deserializer cannot recover
-    // from an error, resulting in it getting stuck, because we try to
-    // be resillient against failures.
-    //
-    // Because cargo only outputs one JSON object per line, we can
-    // simply skip a line if it doesn't parse, which just ignores any
-    // erroneus output.
-    let stdout = BufReader::new(child.stdout.take().unwrap());
-    let mut read_at_least_one_message = false;
-
-    for line in stdout.lines() {
-        let line = line?;
+    /// Checks the config for options which contradict each other or can't be
+    /// passed to cargo.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        for glob in &self.watched_globs {
+            if let Err(err) = Glob::new(glob) {
+                return Err(ConfigError::InvalidGlob {
+                    glob: glob.clone(),
+                    error: err.to_string(),
+                });
+            }
+        }
+        let (packages, exclude, target_triple, profile, jobs, all_features, extra_args) =
+            match &self.command {
+                FlycheckCommand::CargoCommand {
+                    packages,
+                    exclude,
+                    target_triple,
+                    profile,
+                    jobs,
+                    all_features,
+                    extra_args,
+                    ..
+                } => (packages, exclude, target_triple, profile, jobs, all_features, extra_args),
+                FlycheckCommand::CustomCommand { .. } => return Ok(()),
+            };
+        let inject_message_format = match &self.command {
+            FlycheckCommand::CargoCommand { inject_message_format, .. } => *inject_message_format,
+            FlycheckCommand::CustomCommand { .. } => false,
+        };
+        if !packages.is_empty() && !exclude.is_empty() {
+            return Err(ConfigError::ExcludeWithPackages(exclude.clone()));
+        }
+        if let Some(jobs) = *jobs {
+            if jobs == 0 || jobs > MAX_JOBS {
+                return Err(ConfigError::InvalidJobs(jobs));
+            }
+        }
+        let conflict = |flag: &str, conflicts_with: &str| {
+            Err(ConfigError::ConflictingFlags {
+                flag: flag.to_string(),
+                conflicts_with: conflicts_with.to_string(),
+            })
+        };
+        if packages.is_empty() {
+            for &flag in &["-p", "--package"] {
+                if has_flag(extra_args, flag) {
+                    return conflict(flag, "`--workspace`");
+                }
+            }
+        } else {
+            for &flag in &["--workspace", "--all"] {
+                if has_flag(extra_args, flag) {
+                    return conflict(flag, "`-p`");
+                }
+            }
+        }
+        if *all_features && has_flag(extra_args, "--no-default-features") {
+            return conflict("--no-default-features", "`--all-features`");
+        }
+        // Flags passed by us as well as in the extra args are only passed
+        // once, see `merge_args`, as long as both agree on the value.
+        if inject_message_format {
+            if let Some(format) = flag_value(extra_args, "--message-format") {
+                if !format.starts_with("json") {
+                    return conflict("--message-format", "the JSON output of the check");
+                }
+            }
+        }
+        if let (Some(ours), Some(theirs)) = (target_triple, flag_value(extra_args, "--target")) {
+            if ours != theirs {
+                return conflict("--target", "the configured target");
+            }
+        }
+        if let Some(jobs) = jobs {
+            for &flag in &["-j", "--jobs"] {
+                if let Some(theirs) = flag_value(extra_args, flag) {
+                    if theirs != jobs.to_string() {
+                        return conflict(flag, "the configured number of jobs");
+                    }
+                }
+            }
+        }
+        match profile.as_deref() {
+            None | Some("dev") => {}
+            Some(profile) => {
+                if has_flag(extra_args, "--release") && profile != "release" {
+                    return conflict("--release", "the configured profile");
+                }
+                if let Some(theirs) = flag_value(extra_args, "--profile") {
+                    if theirs != profile {
+                        return conflict("--profile", "the configured profile");
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
 
-        let message = serde_json::from_str::<cargo_metadata::Message>(&line);
-        let message = match message {
-            Ok(message) => message,
-            Err(err) => {
-                log::error!("Invalid json from cargo check, ignoring ({}): {:?} ", err, line);
-                continue;
+    /// Returns a copy of the config passing `extra_args` to the check.
+    fn with_extra_args(&self, extra_args: Vec<String>) -> FlycheckConfig {
+        let mut config = self.clone();
+        if !extra_args.is_empty() {
+            match &mut config.command {
+                FlycheckCommand::CargoCommand { extra_args: args, .. }
+                | FlycheckCommand::CustomCommand { args, .. } => args.extend(extra_args),
             }
+        }
+        config
+    }
+
+    /// Returns the flag passed by us which cargo rejected with `stderr`, if
+    /// older cargo versions can be checked without it.
+    fn rejected_flag(&self, stderr: &str) -> Option<&'static str> {
+        let (packages, keep_going, tests_only, legacy_workspace_flag) = match &self.command {
+            FlycheckCommand::CargoCommand {
+                packages,
+                keep_going,
+                tests_only,
+                legacy_workspace_flag,
+                ..
+            } => (packages, *keep_going, *tests_only, *legacy_workspace_flag),
+            FlycheckCommand::CustomCommand { .. } => return None,
         };
+        if keep_going && rejects_flag(stderr, "--keep-going") {
+            Some("--keep-going")
+        } else if tests_only && rejects_flag(stderr, "--profile") {
+            Some("--profile")
+        } else if packages.is_empty()
+            && !legacy_workspace_flag
+            && rejects_flag(stderr, "--workspace")
+        {
+            Some("--workspace")
+        } else {
+            None
+        }
+    }
 
-        read_at_least_one_message = true;
+    /// Whether the diagnostics of checks run with `old` can differ from the
+    /// ones of checks run with `new`. Options which only decide when and how
+    /// long checks run, like `debounce`, can change without checking again.
+    pub fn requires_restart(old: &FlycheckConfig, new: &FlycheckConfig) -> bool {
+        let new = FlycheckConfig {
+            idle_delay: old.idle_delay,
+            debounce: old.debounce,
+            max_debounce: old.max_debounce,
+            min_interval: old.min_interval,
+            recheck_interval: old.recheck_interval,
+            timeout: old.timeout,
+            retry_delay: old.retry_delay,
+            batch_delay: old.batch_delay,
+            low_priority: old.low_priority,
+            on_restart: old.on_restart,
+            cancel_on_update: old.cancel_on_update,
+            message_buffer: old.message_buffer,
+            estimate_progress: old.estimate_progress,
+            termination_grace_period: old.termination_grace_period,
+            forward_stderr_to_log: old.forward_stderr_to_log,
+            check_changed_packages: old.check_changed_packages,
+            check_on_start: old.check_on_start,
+            watched_globs: old.watched_globs.clone(),
+            skip_unchanged_files: old.skip_unchanged_files,
+            publish_mode: old.publish_mode,
+            ..new.clone()
+        };
+        new != *old
+    }
 
-        if !on_message(message) {
-            break;
+    /// Returns a copy of the config checking `packages` only, unless it's
+    /// limited to certain packages already.
+    fn with_packages(&self, packages: &[String]) -> FlycheckConfig {
+        let mut config = self.clone();
+        if let FlycheckCommand::CargoCommand { packages: checked, exclude, .. } =
+            &mut config.command
+        {
+            if checked.is_empty() && !packages.is_empty() {
+                *checked = packages.to_vec();
+                exclude.clear();
+            }
         }
+        config
     }
 
-    // It is okay to ignore the result, as it only errors if the process is already dead
-    let _ = child.kill();
+    /// Returns a copy of the config using what older cargo versions support
+    /// instead of `flag`, see [`FlycheckConfig::rejected_flag`].
+    fn without_flag(&self, flag: &str) -> FlycheckConfig {
+        let mut config = self.clone();
+        if let FlycheckCommand::CargoCommand {
+            keep_going,
+            tests_only,
+            targets,
+            scope,
+            profile,
+            legacy_workspace_flag,
+            ..
+        } = &mut config.command
+        {
+            match flag {
+                "--keep-going" => *keep_going = false,
+                // The tests are still checked, just not with the test profile.
+                "--profile" if *tests_only => {
+                    *tests_only = false;
+                    *targets = vec![CheckTarget::Tests];
+                    *scope = CheckScope::Workspace;
+                    *profile = None;
+                }
+                "--workspace" => *legacy_workspace_flag = true,
+                _ => {}
+            }
+        }
+        config
+    }
 
-    let exit_status = child.wait()?;
-    if !exit_status.success() && !read_at_least_one_message {
-        // FIXME: Read the stderr to display the reason, see `read2()` reference in PR comment:
-        // https://github.com/rust-analyzer/rust-analyzer/pull/3632#discussion_r395605298
-        return Err(io::Error::new(
-            io::ErrorKind::Other,
-            format!(
-                "the command produced no valid metadata (exit code: {:?}): {:?}",
-                exit_status, command
-            ),
-        ));
+    /// Returns a copy of the config without any of the `flags` cargo rejected
+    /// before, see [`FlycheckConfig::without_flag`].
+    fn without_flags(&self, flags: &[&str]) -> FlycheckConfig {
+        flags.iter().fold(self.clone(), |config, flag| config.without_flag(flag))
+    }
+
+    fn run_options(&self) -> RunOptions {
+        RunOptions {
+            timeout: self.timeout,
+            low_priority: self.low_priority,
+            max_line_length: self.max_line_length,
+            message_buffer: self.message_buffer,
+            termination_grace_period: self.termination_grace_period,
+            forward_stderr_to_log: self.forward_stderr_to_log,
+        }
     }
 
-    Ok(())
+    /// Short name of the check, f.x. `clippy`.
+    fn command_name(&self) -> &str {
+        match &self.command {
+            FlycheckCommand::CargoCommand { command, .. } => command,
+            FlycheckCommand::CustomCommand { command, .. } => command,
+        }
+    }
+
+    /// Returns the program and the arguments used to run the check for the
+    /// workspace at `workspace_root`. The config is expected to be
+    /// [validated](FlycheckConfig::validate) already.
+    fn command_line(&self, workspace_root: &Path) -> (PathBuf, Vec<OsString>) {
+        match &self.command {
+            FlycheckCommand::CargoCommand {
+                command,
+                cargo_binary,
+                toolchain,
+                packages,
+                exclude,
+                all_targets,
+                targets,
+                scope,
+                target_triple,
+                profile,
+                jobs,
+                offline,
+                locked,
+                frozen,
+                keep_going,
+                tests_only,
+                no_default_features,
+                all_features,
+                features,
+                extra_args,
+                trailing_args,
+                inject_message_format,
+                legacy_workspace_flag,
+            } => {
+                let mut args: Vec<OsString> = Vec::new();
+                if let Some(toolchain) = toolchain {
+                    args.push(format!("+{}", toolchain.trim_start_matches('+')).into());
+                }
+                args.push(command.into());
+                if packages.is_empty() {
+                    args.push(if *legacy_workspace_flag { "--all" } else { "--workspace" }.into());
+                    for package in exclude {
+                        args.push("--exclude".into());
+                        args.push(package.into());
+                    }
+                } else {
+                    for package in packages {
+                        args.push("-p".into());
+                        args.push(package.into());
+                    }
+                }
+                if *inject_message_format {
+                    args.push("--message-format=json".into());
+                }
+                args.push("--manifest-path".into());
+                args.push(workspace_root.join("Cargo.toml").into());
+                match scope {
+                    _ if *tests_only => args.push("--tests".into()),
+                    CheckScope::Workspace if *all_targets => {
+                        if !targets.is_empty() {
+                            log::warn!(
+                                "`--all-targets` is enabled, ignoring targets {:?}",
+                                targets
+                            );
+                        }
+                        args.push("--all-targets".into());
+                    }
+                    CheckScope::Workspace => {
+                        // Iterating over all kinds keeps the order stable and
+                        // drops duplicates.
+                        for target in CheckTarget::ALL.iter().filter(|it| targets.contains(it)) {
+                            args.push(target.flag().into());
+                        }
+                    }
+                    CheckScope::LibOnly => {
+                        args.push("--lib".into());
+                        args.push("--bins".into());
+                    }
+                    CheckScope::AllTargets => args.push("--all-targets".into()),
+                }
+                if let Some(target) = target_triple {
+                    args.push("--target".into());
+                    args.push(target.into());
+                }
+                if let Some(jobs) = jobs {
+                    args.push("-j".into());
+                    args.push(jobs.to_string().into());
+                }
+                match profile.as_deref() {
+                    _ if *tests_only => {
+                        if let Some(profile) = profile {
+                            log::warn!("checking the tests only, ignoring profile {:?}", profile);
+                        }
+                        args.push("--profile".into());
+                        args.push("test".into());
+                    }
+                    None | Some("dev") => {}
+                    Some("release") => args.push("--release".into()),
+                    Some(profile) => {
+                        args.push("--profile".into());
+                        args.push(profile.into());
+                    }
+                }
+                if *offline {
+                    args.push("--offline".into());
+                }
+                if *locked {
+                    args.push("--locked".into());
+                }
+                if *frozen {
+                    args.push("--frozen".into());
+                }
+                if *keep_going {
+                    args.push("--keep-going".into());
+                }
+                if *all_features {
+                    if *no_default_features {
+                        log::warn!("`--all-features` is enabled, ignoring `--no-default-features`");
+                    }
+                    if !features.is_empty() {
+                        log::warn!("`--all-features` is enabled, ignoring features {:?}", features);
+                    }
+                    args.push("--all-features".into());
+                } else if *no_default_features {
+                    args.push("--no-default-features".into());
+                }
+                if !*all_features && !features.is_empty() {
+                    if has_flag(extra_args, "--features") {
+                        // Cargo would merge both lists, which is rarely what
+                        // the user wants, so let the explicit arguments win.
+                        log::warn!(
+                            "`--features` is already passed in extra args, ignoring {:?}",
+                            features
+                        );
+                    } else {
+                        args.push("--features".into());
+                        args.push(features.join(",").into());
+                    }
+                }
+                let mut args = merge_args(args, extra_args);
+                args.extend(extra_args.iter().map(|it| it.into()));
+                if !trailing_args.is_empty() {
+                    if !extra_args.iter().any(|it| it == "--") {
+                        args.push("--".into());
+                    }
+                    args.extend(trailing_args.iter().map(|it| it.into()));
+                }
+                let program = match cargo_binary {
+                    Some(cargo_binary) => cargo_binary.clone(),
+                    None => default_cargo_binary(),
+                };
+                (program, args)
+            }
+            FlycheckCommand::CustomCommand { command, args } => {
+                (command.into(), args.iter().map(|it| it.into()).collect())
+            }
+        }
+    }
+}
+
+/// Flycheck wraps the shared state and communication machinery used for
+/// running `cargo check` (or other compatible command) and providing
+/// diagnostics based on the output.
+/// The spawned thread is shut down when this struct is dropped.
+#[derive(Debug)]
+pub struct Flycheck {
+    // XXX: drop order is significant
+    /// Dropped first, as it holds on to a sender of the commands the check
+    /// thread waits for.
+    #[cfg(feature = "fs-watch")]
+    watcher: Option<watch::FileWatcher>,
+    cmd_send: Sender<CheckCommand>,
+    handle: jod_thread::JoinHandle<()>,
+    pub task_recv: Receiver<CheckTask>,
+    status: Arc<Mutex<CheckStatus>>,
+    /// The last config passed to [`Flycheck::reconfigure`], so that the
+    /// thread keeps it when it starts over after a panic.
+    config: Arc<Mutex<FlycheckConfig>>,
+}
+
+impl Flycheck {
+    pub fn new(config: FlycheckConfig, workspace_root: PathBuf) -> Flycheck {
+        let (task_send, task_recv) = unbounded::<CheckTask>();
+        let task_send = TaskSender { sender: task_send, disconnected: Cell::new(false) };
+        let (cmd_send, cmd_recv) = unbounded::<CheckCommand>();
+        let status = Arc::new(Mutex::new(CheckStatus::default()));
+        let thread_status = Arc::clone(&status);
+        let config = Arc::new(Mutex::new(config));
+        let thread_config = Arc::clone(&config);
+        let handle = jod_thread::spawn(move || {
+            // A bug shouldn't stop the checks for the rest of the session, so
+            // the thread starts over with fresh state after a panic.
+            run_restarting(&task_send, |task_send| {
+                let config = thread_config.lock().unwrap().clone();
+                FlycheckThread::new(config, workspace_root.clone(), thread_status.clone())
+                    .run(task_send, &cmd_recv)
+            })
+        });
+        Flycheck {
+            #[cfg(feature = "fs-watch")]
+            watcher: None,
+            task_recv,
+            cmd_send,
+            handle,
+            status,
+            config,
+        }
+    }
+
+    /// Like [`Flycheck::new`], but updates the checks on its own whenever a
+    /// file in `workspace_root` changes, for users which aren't told about
+    /// saved files. Files in `target` and the ones ignored by git are left
+    /// out.
+    #[cfg(feature = "fs-watch")]
+    pub fn with_file_watching(
+        config: FlycheckConfig,
+        workspace_root: PathBuf,
+    ) -> Result<Flycheck, notify::Error> {
+        let mut flycheck = Flycheck::new(config, workspace_root.clone());
+        let watcher = watch::FileWatcher::new(&workspace_root, flycheck.cmd_send.clone())?;
+        flycheck.watcher = Some(watcher);
+        Ok(flycheck)
+    }
+
+    /// What the checks are doing right now.
+    pub fn status(&self) -> CheckStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    /// Schedule a re-start of the cargo check worker.
+    pub fn update(&self) {
+        self.update_with_args(Vec::new());
+    }
+
+    /// Schedule a re-start of the cargo check worker, passing `extra_args` to
+    /// the next check only.
+    pub fn update_with_args(&self, extra_args: Vec<String>) {
+        self.send(CheckCommand::Update { extra_args, changed_files: Vec::new() });
+    }
+
+    /// Check the whole workspace right away, f.x. because the user asked
+    /// for it, without waiting for the debounce delay or the minimal interval
+    /// between checks. The running check is replaced only with
+    /// [`FlycheckConfig::cancel_on_update`], otherwise the check starts once
+    /// it ended.
+    pub fn update_now(&self) {
+        self.send(CheckCommand::UpdateNow);
+    }
+
+    /// Schedule a re-start of the cargo check worker after `changed_files`
+    /// were saved, see [`FlycheckConfig::check_changed_packages`].
+    pub fn update_with_files(&self, changed_files: Vec<PathBuf>) {
+        self.send(CheckCommand::Update { extra_args: Vec::new(), changed_files });
+    }
+
+    /// Change the scope of the following checks, the running check is not
+    /// affected.
+    pub fn set_scope(&self, scope: CheckScope) {
+        self.send(CheckCommand::SetScope(scope));
+    }
+
+    /// Stop the running check, f.x. so that it doesn't compete with a build
+    /// for the target directory or because its progress was cancelled in the
+    /// editor. Its diagnostics so far are kept, the next check runs after the
+    /// next update.
+    pub fn cancel(&self) {
+        self.send(CheckCommand::Cancel);
+    }
+
+    /// Clear all diagnostics of the checks right away, f.x. because they
+    /// aren't wanted anymore. `cancel` stops the running check as well,
+    /// otherwise the diagnostics it reports from now on are shown.
+    pub fn clear_diagnostics(&self, cancel: bool) {
+        self.send(CheckCommand::ClearDiagnostics { cancel });
+    }
+
+    /// Pause or resume the checks. While paused, the running check is
+    /// stopped and updates only lead to a check once the checks are resumed.
+    pub fn set_enabled(&self, enabled: bool) {
+        let cmd = if enabled { CheckCommand::Resume } else { CheckCommand::Pause };
+        self.send(cmd);
+    }
+
+    /// Use `config` for the following checks. The running check is replaced
+    /// and the diagnostics are cleared if it reports different diagnostics,
+    /// see [`FlycheckConfig::requires_restart`].
+    pub fn reconfigure(&self, config: &FlycheckConfig) {
+        *self.config.lock().unwrap() = config.clone();
+        self.send(CheckCommand::UpdateConfig(Box::new(config.clone())));
+    }
+
+    /// Run a single check with `config` right away, f.x. clippy with extra
+    /// lints, replacing the running check. Updates which arrive in the
+    /// meantime are checked with the usual config once it ended.
+    pub fn run_once(&self, config: &FlycheckConfig) {
+        self.send(CheckCommand::RunOnce(Box::new(config.clone())));
+    }
+
+    /// The thread stops once nobody receives its tasks, f.x. while the server
+    /// shuts down, commands sent after that are dropped.
+    fn send(&self, cmd: CheckCommand) {
+        if self.cmd_send.send(cmd).is_err() {
+            log::warn!("the check thread has stopped, ignoring the command");
+        }
+    }
+}
+
+/// Sends the tasks of the check thread, which stops once they can't be
+/// received anymore.
+struct TaskSender {
+    sender: Sender<CheckTask>,
+    disconnected: Cell<bool>,
+}
+
+impl TaskSender {
+    fn send(&self, task: CheckTask) {
+        if self.sender.send(task).is_err() {
+            self.disconnected.set(true);
+        }
+    }
+
+    fn is_disconnected(&self) -> bool {
+        self.disconnected.get()
+    }
+}
+
+#[derive(Debug)]
+pub enum CheckTask {
+    /// Request a clearing of all cached diagnostics from the check watcher,
+    /// diagnostics of other runs than `run` are outdated from now on
+    ClearDiagnostics { run: CheckRunId },
+
+    /// Request a clearing of the cached diagnostics of a file, which are
+    /// replaced by the ones of `run`. Sent before the first diagnostic of the
+    /// file in `run`, or once `run` is complete if it reported none, so that
+    /// the diagnostics of other files don't disappear while checking
+    ClearForFile { run: CheckRunId, url: Url },
+
+    /// Request replacing the cached diagnostics of the files in `diagnostics`
+    /// at once, files which have none anymore are included as well. Sent once
+    /// `run` is complete with [`PublishMode::AtEnd`], or when diagnostics of
+    /// `run` which were sent already changed, see
+    /// [`FlycheckConfig::merge_duplicate_targets`]
+    SetDiagnostics {
+        run: CheckRunId,
+        diagnostics: HashMap<Url, Vec<(Diagnostic, Vec<CodeActionOrCommand>)>>,
+    },
+
+    /// Request adding a diagnostic with fixes included to a file
+    AddDiagnostic {
+        run: CheckRunId,
+        url: Url,
+        diagnostic: Diagnostic,
+        fixes: Vec<CodeActionOrCommand>,
+    },
+
+    /// Request adding several diagnostics with fixes included to a file at
+    /// once, see [`FlycheckConfig::batch_delay`]
+    AddDiagnostics {
+        run: CheckRunId,
+        url: Url,
+        diagnostics: Vec<(Diagnostic, Vec<CodeActionOrCommand>)>,
+    },
+
+    /// Request check progress notification to client
+    Status(WorkDoneProgress),
+
+    /// Request showing an error to the user, f.x. because the check command
+    /// could not be run
+    Error { message: String, kind: CheckErrorKind },
+
+    /// Request showing a warning to the user, f.x. because the check command
+    /// printed something else than cargo's JSON messages
+    Warning(String),
+
+    /// Request showing an invalid check configuration to the user, no check
+    /// is run until the configuration changes
+    InvalidConfig(ConfigError),
+
+    /// Request showing the outcome of a check, sent after its progress ended
+    Finished(CheckSummary),
+}
+
+/// Who has to fix what a [`CheckTask::Error`] reports.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CheckErrorKind {
+    /// The check configuration, f.x. it runs clippy which isn't installed, or
+    /// passes flags cargo doesn't know.
+    Config,
+    /// The machine or the workspace, f.x. cargo isn't installed, a manifest
+    /// is broken or the check timed out.
+    Environment,
+    /// rust-analyzer, f.x. the check panicked.
+    Internal,
+}
+
+/// See [`Flycheck::status`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CheckStatus {
+    pub activity: CheckActivity,
+    /// The crate the running check started on last, according to cargo.
+    pub current_crate: Option<String>,
+    /// When the running check began.
+    pub started_at: Option<Instant>,
+    /// The outcome of the last check which ended, and when it did.
+    pub last_summary: Option<CheckSummary>,
+    pub last_finished_at: Option<Instant>,
+}
+
+/// See [`CheckStatus::activity`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CheckActivity {
+    /// No check is running or waiting to be started.
+    Idle,
+    Running,
+    /// No check is running, but one will be started, f.x. once the debounce
+    /// delay passed or the checks are resumed.
+    Queued,
+}
+
+impl Default for CheckActivity {
+    fn default() -> Self {
+        CheckActivity::Idle
+    }
+}
+
+impl fmt::Display for CheckStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.activity, self.started_at) {
+            (CheckActivity::Running, Some(started_at)) => {
+                write!(f, "running for {:.1}s", started_at.elapsed().as_secs_f32())?;
+                if let Some(current_crate) = &self.current_crate {
+                    write!(f, ", checking {}", current_crate)?;
+                }
+            }
+            (CheckActivity::Running, None) => f.write_str("running")?,
+            (CheckActivity::Queued, _) => f.write_str("queued")?,
+            (CheckActivity::Idle, _) => f.write_str("idle")?,
+        }
+        if let (Some(summary), Some(finished_at)) = (&self.last_summary, self.last_finished_at) {
+            write!(
+                f,
+                ", last check {} {:.1}s ago ({} errors, {} warnings)",
+                if summary.cancelled { "cancelled" } else { "finished" },
+                finished_at.elapsed().as_secs_f32(),
+                summary.errors,
+                summary.warnings
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Identifies the diagnostics of one update of the checks, including the ones
+/// of its secondary check and retries. Every update gets a higher one than
+/// the previous, so that diagnostics of a run which was replaced can be told
+/// apart from the current ones.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CheckRunId(pub u64);
+
+/// The outcome of a single check.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CheckSummary {
+    pub run: CheckRunId,
+    /// Exit code of the check command, `None` if it couldn't be started or
+    /// was killed.
+    pub exit_code: Option<i32>,
+    pub duration: Duration,
+    /// Number of compiler messages reported as errors.
+    pub errors: usize,
+    /// Number of compiler messages reported as warnings.
+    pub warnings: usize,
+    /// Number of compiler messages by the tool and the severity they were
+    /// reported with, see [`DiagnosticCounts`].
+    pub counts: DiagnosticCounts,
+    /// Number of output lines which weren't valid cargo JSON messages or were
+    /// too long to be read.
+    pub skipped_lines: usize,
+    /// Number of valid cargo JSON messages, including the ones which weren't
+    /// shown.
+    pub messages: usize,
+    /// Whether the check was stopped with [`Flycheck::cancel`].
+    pub cancelled: bool,
+    /// Whether the check was requested with [`Flycheck::run_once`].
+    pub one_off: bool,
+    /// Directories of the packages the check was limited to, `None` if it
+    /// checked the whole workspace.
+    pub scope: Option<Vec<PathBuf>>,
+}
+
+/// Number of compiler messages of a check, each counted once even if it's
+/// shown at several locations. Messages reported again for other targets are
+/// not counted again, while messages left out because of
+/// [`FlycheckConfig::max_diagnostics`] are, so that the counts are the same
+/// whatever the limit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DiagnosticCounts {
+    /// Messages of rustc, including future incompatibility reports, and of
+    /// other tools not using a lint namespace.
+    pub rustc: SeverityCounts,
+    /// Messages of clippy lints, whose codes start with `clippy::`.
+    pub clippy: SeverityCounts,
+}
+
+impl DiagnosticCounts {
+    pub fn errors(&self) -> usize {
+        self.rustc.errors + self.clippy.errors
+    }
+
+    pub fn warnings(&self) -> usize {
+        self.rustc.warnings + self.clippy.warnings
+    }
+
+    fn add(&mut self, code: Option<&str>, severity: Option<DiagnosticSeverity>) {
+        let counts = match code {
+            Some(code) if code.starts_with("clippy::") => &mut self.clippy,
+            _ => &mut self.rustc,
+        };
+        match severity {
+            Some(DiagnosticSeverity::Error) => counts.errors += 1,
+            Some(DiagnosticSeverity::Warning) => counts.warnings += 1,
+            Some(DiagnosticSeverity::Information) => counts.infos += 1,
+            Some(DiagnosticSeverity::Hint) => counts.hints += 1,
+            None => {}
+        }
+    }
+}
+
+/// See [`DiagnosticCounts`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SeverityCounts {
+    pub errors: usize,
+    pub warnings: usize,
+    /// Top-level notes, which are rare.
+    pub infos: usize,
+    /// Top-level help messages, which are rare.
+    pub hints: usize,
+}
+
+pub enum CheckCommand {
+    /// Request re-start of check thread, `extra_args` are passed to the next
+    /// check only. `changed_files` may limit the check to the packages owning
+    /// them, none means anything could have changed
+    Update { extra_args: Vec<String>, changed_files: Vec<PathBuf> },
+
+    /// Request checking the whole workspace as soon as possible, regardless
+    /// of the debounce delay and the minimal interval between checks
+    UpdateNow,
+
+    /// Request a different scope for the next checks
+    SetScope(CheckScope),
+
+    /// Request stopping the running check without starting another one
+    Cancel,
+
+    /// Request clearing the diagnostics right away, `cancel` stops the
+    /// running check as well, otherwise it keeps reporting diagnostics
+    ClearDiagnostics { cancel: bool },
+
+    /// Request stopping the running check and not starting any until
+    /// `Resume`, updates in between are checked then
+    Pause,
+
+    /// Request starting checks again after `Pause`
+    Resume,
+
+    /// Request using a different config, checking again if the diagnostics
+    /// could differ
+    UpdateConfig(Box<FlycheckConfig>),
+
+    /// Request a single check with a different config right away, replacing
+    /// the running check
+    RunOnce(Box<FlycheckConfig>),
+}
+
+struct FlycheckThread {
+    config: FlycheckConfig,
+    workspace_root: PathBuf,
+    /// When the next check for an update is due.
+    schedule: Schedule,
+    /// Arguments for the next check requested with the last update.
+    next_extra_args: Vec<String>,
+    /// Files changed by the updates since the last check started.
+    pending_changes: Changes,
+    /// Files changed before the running check, until all of its checks
+    /// ended. A check replacing it has to cover them as well, as their
+    /// diagnostics were cleared already.
+    running_changes: Changes,
+    /// Workspace members checked by the current update, all of them if it's
+    /// empty.
+    run_packages: Vec<String>,
+    /// The members the changed files are looked up in, loaded on demand.
+    package_index: Option<PackageIndex>,
+    /// Leaves out updates of files which can't change the diagnostics.
+    change_filter: ChangeFilter,
+    /// The contents of the changed files, see
+    /// [`FlycheckConfig::skip_unchanged_files`].
+    snapshots: Snapshots,
+    /// Shared with the [`Flycheck`], `activity` is the last one written to
+    /// it.
+    status: Arc<Mutex<CheckStatus>>,
+    activity: CheckActivity,
+    // XXX: drop order is significant
+    message_recv: Receiver<CheckEvent>,
+    /// WatchThread exists to wrap around the communication needed to be able to
+    /// run `cargo check` without blocking. Currently the Rust standard library
+    /// doesn't provide a way to read sub-process output without blocking, so we
+    /// have to wrap sub-processes output handling in a thread and pass messages
+    /// back over a channel.
+    check_process: Option<jod_thread::JoinHandle<()>>,
+    /// Stops `check_process`, so that dropping it doesn't block until cargo
+    /// prints something.
+    cancel_check: CancellationToken,
+    /// Checks which were replaced, until their thread is done. Joining a
+    /// killed check can take as long as cargo's grace period, which would
+    /// block the handling of commands.
+    finishing: Vec<FinishingCheck>,
+    /// Fires once `finishing` should be looked at again.
+    reap_timer: Receiver<Instant>,
+    /// The kind of check `check_process` is running.
+    current_run: CheckRun,
+    /// The config of the next one-off check.
+    one_off: Option<FlycheckConfig>,
+    /// Flags cargo rejected, the following checks are run without them, see
+    /// [`FlycheckConfig::rejected_flag`]. Kept apart from `config`, which
+    /// stays the one of the user.
+    rejected_flags: Vec<&'static str>,
+    /// The update the diagnostics which are reported belong to.
+    run_id: CheckRunId,
+    /// Files whose diagnostics are shown, see [`CheckTask::ClearForFile`].
+    shown_files: HashSet<Url>,
+    /// Files the current run reported diagnostics for, their diagnostics of
+    /// the previous runs were cleared already.
+    run_files: HashSet<Url>,
+    /// The packages the current run is limited to, `None` if it checks the
+    /// whole workspace.
+    run_scope: Option<RunScope>,
+    /// The diagnostics of the current run, while they are only sent once
+    /// it's complete, see [`PublishMode::AtEnd`].
+    unpublished: Option<HashMap<Url, Vec<(Diagnostic, Vec<CodeActionOrCommand>)>>>,
+    /// The diagnostics the current run reported, to leave out the ones it
+    /// reports again for other targets.
+    duplicates: Duplicates,
+    /// Holds back diagnostics to send them together, see
+    /// [`FlycheckConfig::batch_delay`].
+    batcher: Batcher,
+    /// Fires once the diagnostics held back by `batcher` are due.
+    batch_timer: Receiver<Instant>,
+    /// Fires once the secondary check is due.
+    secondary_timer: Receiver<Instant>,
+    /// Fires once the check for the last update is due, see
+    /// [`Schedule::due_in`].
+    schedule_timer: Receiver<Instant>,
+    /// When the running check started waiting for the lock of another cargo
+    /// process, if it's still waiting.
+    lock_wait_started: Option<Instant>,
+    /// How long the running check has waited for cargo locks so far.
+    lock_wait: Duration,
+    /// When the running check began, `None` once its progress has ended.
+    check_started: Option<Instant>,
+    /// Whether the running check reported an error.
+    check_failed: bool,
+    /// Number of messages reported by the running check so far.
+    counts: DiagnosticCounts,
+    /// Number of diagnostics reported and left out because of
+    /// `max_diagnostics` by the running check.
+    diagnostics: usize,
+    suppressed_diagnostics: usize,
+    /// The same for each file, because of `max_diagnostics_per_file`.
+    file_diagnostics: HashMap<Url, usize>,
+    suppressed_file_diagnostics: HashMap<Url, usize>,
+    /// Number of units the running check is expected to go through, if it
+    /// could be estimated, and the number of those which are done.
+    total_units: Option<usize>,
+    finished_units: usize,
+    /// The config of the running check, kept to retry it.
+    current_config: FlycheckConfig,
+    /// Whether the running check is the retry of a failed one.
+    is_retry: bool,
+    /// Fires once the failed check is retried, see
+    /// [`FlycheckConfig::retry_delay`].
+    retry_timer: Receiver<Instant>,
+    retry_pending: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum CheckRun {
+    Primary,
+    Secondary,
+    /// See [`Flycheck::run_once`].
+    OneOff,
+}
+
+impl FlycheckThread {
+    fn new(
+        config: FlycheckConfig,
+        workspace_root: PathBuf,
+        status: Arc<Mutex<CheckStatus>>,
+    ) -> FlycheckThread {
+        let debounce = Debounce::new(config.debounce, config.max_debounce);
+        let mut schedule = Schedule::new(debounce, config.cancel_on_update);
+        schedule.set_min_interval(config.min_interval);
+        schedule.set_recheck_interval(config.recheck_interval);
+        let change_filter = ChangeFilter::new(&workspace_root, &config.watched_globs);
+        let batcher = Batcher::new(config.batch_delay.unwrap_or_default());
+        FlycheckThread {
+            current_config: config.clone(),
+            config,
+            workspace_root,
+            schedule,
+            next_extra_args: Vec::new(),
+            pending_changes: Changes::Nothing,
+            running_changes: Changes::Nothing,
+            run_packages: Vec::new(),
+            package_index: None,
+            change_filter,
+            snapshots: Snapshots::default(),
+            status,
+            activity: CheckActivity::Idle,
+            message_recv: never(),
+            check_process: None,
+            finishing: Vec::new(),
+            reap_timer: never(),
+            cancel_check: CancellationToken::default(),
+            current_run: CheckRun::Primary,
+            one_off: None,
+            rejected_flags: Vec::new(),
+            run_id: CheckRunId::default(),
+            shown_files: HashSet::new(),
+            run_files: HashSet::new(),
+            run_scope: None,
+            unpublished: None,
+            duplicates: Duplicates::default(),
+            batcher,
+            batch_timer: never(),
+            secondary_timer: never(),
+            schedule_timer: never(),
+            lock_wait_started: None,
+            lock_wait: Duration::from_secs(0),
+            check_started: None,
+            check_failed: false,
+            counts: DiagnosticCounts::default(),
+            diagnostics: 0,
+            suppressed_diagnostics: 0,
+            file_diagnostics: HashMap::new(),
+            suppressed_file_diagnostics: HashMap::new(),
+            total_units: None,
+            finished_units: 0,
+            is_retry: false,
+            retry_timer: never(),
+            retry_pending: false,
+        }
+    }
+
+    fn run(&mut self, task_send: &TaskSender, cmd_recv: &Receiver<CheckCommand>) {
+        // If we rerun the thread, we need to discard the previous check results first
+        self.clean_previous_results(task_send);
+        if self.config.check_on_start {
+            let update = CheckCommand::Update { extra_args: Vec::new(), changed_files: Vec::new() };
+            self.handle_command(update, task_send);
+        }
+
+        loop {
+            // Nobody is interested in the checks anymore.
+            if task_send.is_disconnected() {
+                break;
+            }
+            if self.schedule.start_due(Instant::now()) {
+                // A pending or running secondary check is outdated as well,
+                // as is a pending retry.
+                self.secondary_timer = never();
+                self.retry_timer = never();
+                self.retry_pending = false;
+                self.run_id.0 += 1;
+                self.snapshots.started();
+                let scope = self.scope_run();
+                self.begin_run(scope, task_send);
+                self.start_check(CheckRun::Primary, task_send);
+                if self.check_process.is_none() {
+                    self.schedule.finished(Instant::now());
+                }
+            }
+            self.schedule_timer = match self.schedule.due_in(Instant::now()) {
+                Some(wait) => after(wait),
+                None => never(),
+            };
+            self.batch_timer = match self.batcher.due_in(Instant::now()) {
+                Some(wait) => after(wait),
+                None => never(),
+            };
+
+            select! {
+                recv(&cmd_recv) -> cmd => match cmd {
+                    Ok(cmd) => self.handle_command(cmd, task_send),
+                    Err(RecvError) => {
+                        // Command channel has closed, so shut down
+                        break;
+                    },
+                },
+                recv(self.message_recv) -> msg => match msg {
+                    Ok(msg) => self.handle_message(msg, task_send),
+                    Err(RecvError) => {
+                        // Watcher finished, replace it with a never channel to
+                        // avoid busy-waiting.
+                        self.message_recv = never();
+                        self.check_process = None;
+                        self.schedule.finished(Instant::now());
+                    },
+                },
+                // Only wakes the loop up, see `Schedule::start_due`.
+                recv(self.schedule_timer) -> _ => self.schedule_timer = never(),
+                recv(self.batch_timer) -> _ => {
+                    self.batch_timer = never();
+                    if let Some(batch) = self.batcher.take_due(Instant::now()) {
+                        send_batch(batch, task_send);
+                    }
+                },
+                recv(self.secondary_timer) -> _ => {
+                    self.secondary_timer = never();
+                    self.start_check(CheckRun::Secondary, task_send);
+                },
+                recv(self.reap_timer) -> _ => self.reap_finished_checks(),
+                recv(self.retry_timer) -> _ => {
+                    self.retry_timer = never();
+                    self.retry_pending = false;
+                    let config = self.current_config.clone();
+                    if let Err(message) = self.restart_check_process(self.current_run, &config) {
+                        let kind = CheckErrorKind::Environment;
+                        task_send.send(CheckTask::Error { message, kind });
+                    }
+                    self.is_retry = true;
+                },
+            };
+            self.update_activity();
+        }
+    }
+
+    /// Writes the activity to the status if it changed, which is cheap
+    /// enough to be done for every event.
+    fn update_activity(&mut self) {
+        let activity = if self.check_started.is_some() {
+            CheckActivity::Running
+        } else if self.schedule.is_pending() || self.retry_pending {
+            CheckActivity::Queued
+        } else {
+            CheckActivity::Idle
+        };
+        if activity != self.activity {
+            self.activity = activity;
+            self.status.lock().unwrap().activity = activity;
+        }
+    }
+
+    /// Records the end of the running check in the status.
+    fn finish_status(&self, summary: &CheckSummary) {
+        let mut status = self.status.lock().unwrap();
+        status.current_crate = None;
+        status.started_at = None;
+        status.last_summary = Some(summary.clone());
+        status.last_finished_at = Some(Instant::now());
+    }
+
+    fn start_check(&mut self, run: CheckRun, task_send: &TaskSender) {
+        let config = match run {
+            CheckRun::Primary => {
+                let extra_args = mem::take(&mut self.next_extra_args);
+                self.config.with_extra_args(extra_args).with_packages(&self.run_packages)
+            }
+            CheckRun::Secondary => match &self.config.secondary {
+                Some(secondary) => secondary.with_packages(&self.run_packages),
+                None => return,
+            },
+            CheckRun::OneOff => match self.one_off.take() {
+                Some(config) => config,
+                None => return,
+            },
+        };
+        if let Err(err) = config.validate() {
+            task_send.send(CheckTask::InvalidConfig(err));
+            return;
+        }
+        let config = config.without_flags(&self.rejected_flags);
+        self.end_cancelled_check(task_send);
+        // The config is valid, so the command can only fail to be built
+        // because of the workspace, f.x. a missing manifest.
+        if let Err(message) = self.restart_check_process(run, &config) {
+            task_send.send(CheckTask::Error { message, kind: CheckErrorKind::Environment });
+        }
+    }
+
+    /// Picks the packages checked for the pending changes, `None` if the
+    /// whole workspace is checked.
+    fn scope_run(&mut self) -> Option<RunScope> {
+        let mut changes = mem::take(&mut self.pending_changes);
+        changes.add(mem::take(&mut self.running_changes));
+        let packages = self.changed_packages(&changes);
+        self.running_changes = changes;
+        let packages = packages.unwrap_or_default();
+        self.run_packages = packages.iter().map(|package| package.name.clone()).collect();
+        match &self.package_index {
+            Some(index) if !packages.is_empty() => Some(RunScope::new(index.clone(), packages)),
+            _ => None,
+        }
+    }
+
+    /// The workspace members owning the changed files, `None` if the whole
+    /// workspace has to be checked.
+    fn changed_packages(&mut self, changes: &Changes) -> Option<Vec<Package>> {
+        if changes.include_manifest() {
+            // The members are looked up again for the next changes.
+            log::debug!("a manifest changed, checking the whole workspace");
+            return None;
+        }
+        let files = match changes {
+            Changes::Files(files) if self.config.check_changed_packages => files,
+            _ => return None,
+        };
+        let exclude = match &self.config.command {
+            FlycheckCommand::CargoCommand { packages, exclude, .. } if packages.is_empty() => {
+                exclude.clone()
+            }
+            _ => return None,
+        };
+        if self.package_index.is_none() {
+            self.package_index = self.load_package_index();
+        }
+        let mut owners = self.package_index.as_ref()?.owners(files)?;
+        owners.retain(|package| !exclude.contains(&package.name));
+        if owners.is_empty() {
+            None
+        } else {
+            Some(owners)
+        }
+    }
+
+    /// Runs `cargo metadata` to find the workspace members, `None` if that
+    /// fails.
+    fn load_package_index(&self) -> Option<PackageIndex> {
+        let command = match self.config.metadata_command(&self.workspace_root, false)? {
+            Ok(command) => command,
+            Err(err) => {
+                log::warn!("can't find the packages of the changed files: {}", err);
+                return None;
+            }
+        };
+        match run_metadata(command, &CancellationToken::default(), self.config.low_priority) {
+            Ok(metadata) => Some(PackageIndex::from_metadata(&metadata)),
+            Err(err) => {
+                log::warn!("can't find the packages of the changed files: {}", err);
+                None
+            }
+        }
+    }
+
+    /// Ends the progress of the running check, which is about to be stopped,
+    /// as its own end is never seen. Returns how long it ran, if it did.
+    fn end_cancelled_check(&mut self, task_send: &TaskSender) -> Option<Duration> {
+        let elapsed = self.check_started.take()?.elapsed();
+        let message = end_message(
+            CheckOutcome::Cancelled,
+            elapsed,
+            self.counts.errors(),
+            self.counts.warnings(),
+            self.lock_wait,
+        );
+        task_send.send(CheckTask::Status(WorkDoneProgress::End(WorkDoneProgressEnd {
+            message: Some(message),
+        })));
+        Some(elapsed)
+    }
+
+    /// Stops the running check without starting another one, until the next
+    /// update.
+    fn abort_check(&mut self, task_send: &TaskSender) {
+        self.flush_diagnostics(task_send);
+        self.schedule.cancel();
+        self.schedule_timer = never();
+        self.secondary_timer = never();
+        self.retry_timer = never();
+        self.retry_pending = false;
+        // Its packages have to be checked with the next update.
+        self.pending_changes.add(mem::take(&mut self.running_changes));
+        self.snapshots.invalidate();
+        if let Some(duration) = self.end_cancelled_check(task_send) {
+            let summary = CheckSummary {
+                run: self.run_id,
+                duration,
+                errors: self.counts.errors(),
+                warnings: self.counts.warnings(),
+                counts: self.counts,
+                cancelled: true,
+                one_off: self.current_run == CheckRun::OneOff,
+                scope: self.run_scope.as_ref().map(RunScope::roots),
+                ..CheckSummary::default()
+            };
+            self.finish_status(&summary);
+            task_send.send(CheckTask::Finished(summary));
+        }
+        self.kill_check();
+    }
+
+    fn clean_previous_results(&self, task_send: &TaskSender) {
+        task_send.send(CheckTask::ClearDiagnostics { run: self.run_id });
+        task_send
+            .send(CheckTask::Status(WorkDoneProgress::End(WorkDoneProgressEnd { message: None })));
+        // The last summary is still worth showing.
+        let mut status = self.status.lock().unwrap();
+        status.activity = self.activity;
+        status.current_crate = None;
+        status.started_at = None;
+    }
+
+    fn handle_command(&mut self, cmd: CheckCommand, task_send: &TaskSender) {
+        match cmd {
+            CheckCommand::Update { extra_args, changed_files } => {
+                let filter = &self.change_filter;
+                let (changed_files, ignored): (Vec<_>, Vec<_>) =
+                    changed_files.into_iter().partition(|file| filter.is_relevant(file));
+                if !ignored.is_empty() {
+                    log::trace!("ignoring the update of {:?}", ignored);
+                    if changed_files.is_empty() {
+                        return;
+                    }
+                }
+                if self.config.skip_unchanged_files
+                    && !changed_files.is_empty()
+                    && !self.snapshots.update(&changed_files)
+                {
+                    log::debug!("ignoring the update of unchanged {:?}", changed_files);
+                    return;
+                }
+                // The members may have changed.
+                if changed_files.iter().any(|file| is_manifest(file)) {
+                    self.package_index = None;
+                }
+                self.schedule.update(Instant::now());
+                self.next_extra_args = extra_args;
+                self.pending_changes.add(Changes::of(changed_files));
+            }
+            CheckCommand::UpdateNow => {
+                self.schedule.update_now(Instant::now());
+                self.next_extra_args.clear();
+                self.pending_changes.add(Changes::Everything);
+            }
+            CheckCommand::Cancel => self.abort_check(task_send),
+            CheckCommand::ClearDiagnostics { cancel } => {
+                if cancel {
+                    self.abort_check(task_send);
+                }
+                // Unchanged files have to be checked again to get their
+                // diagnostics back.
+                self.snapshots.invalidate();
+                self.flush_diagnostics(task_send);
+                self.forget_shown_files();
+                task_send.send(CheckTask::ClearDiagnostics { run: self.run_id });
+            }
+            CheckCommand::Pause => {
+                self.abort_check(task_send);
+                self.schedule.set_paused(true);
+            }
+            CheckCommand::Resume => self.schedule.set_paused(false),
+            CheckCommand::RunOnce(config) => {
+                // Outdated by the one-off check.
+                self.secondary_timer = never();
+                self.retry_timer = never();
+                self.retry_pending = false;
+                self.run_id.0 += 1;
+                self.run_packages.clear();
+                // Its diagnostics replace the ones of the usual checks.
+                self.snapshots.invalidate();
+                self.begin_run(None, task_send);
+                self.one_off = Some(*config);
+                self.start_check(CheckRun::OneOff, task_send);
+                if self.check_process.is_none() {
+                    self.schedule.finished(Instant::now());
+                } else {
+                    self.schedule.hold_reruns();
+                }
+            }
+            CheckCommand::UpdateConfig(config) => {
+                let restart = FlycheckConfig::requires_restart(&self.config, &config);
+                let debounce = Debounce::new(config.debounce, config.max_debounce);
+                self.schedule.set_options(debounce, config.cancel_on_update);
+                self.schedule.set_min_interval(config.min_interval);
+                self.schedule.set_recheck_interval(config.recheck_interval);
+                self.batcher.set_delay(config.batch_delay.unwrap_or_default());
+                self.change_filter = ChangeFilter::new(&self.workspace_root, &config.watched_globs);
+                self.config = *config;
+                if restart {
+                    // The members may differ for another manifest.
+                    self.package_index = None;
+                    self.abort_check(task_send);
+                    let update =
+                        CheckCommand::Update { extra_args: Vec::new(), changed_files: Vec::new() };
+                    self.handle_command(update, task_send);
+                }
+            }
+            CheckCommand::SetScope(new_scope) => match &mut self.config.command {
+                FlycheckCommand::CargoCommand { scope, .. } => *scope = new_scope,
+                FlycheckCommand::CustomCommand { .. } => {
+                    log::warn!("can't change the scope of a custom check command")
+                }
+            },
+        }
+    }
+
+    fn handle_message(&mut self, msg: CheckEvent, task_send: &TaskSender) {
+        // Cargo only prints something else once it got the lock.
+        if let Some(started) = self.lock_wait_started.take() {
+            self.lock_wait += started.elapsed();
+        }
+        match msg {
+            CheckEvent::Begin => {
+                self.lock_wait = Duration::from_secs(0);
+                self.check_started = Some(Instant::now());
+                {
+                    let mut status = self.status.lock().unwrap();
+                    status.current_crate = None;
+                    status.started_at = self.check_started;
+                }
+                self.check_failed = false;
+                self.counts = DiagnosticCounts::default();
+                self.diagnostics = 0;
+                self.suppressed_diagnostics = 0;
+                self.file_diagnostics.clear();
+                self.suppressed_file_diagnostics.clear();
+                self.total_units = None;
+                self.finished_units = 0;
+                // Clients may ignore the percentage of later reports otherwise.
+                let initial_percentage =
+                    if self.current_config.estimates_progress() { Some(0.0) } else { None };
+                task_send.send(CheckTask::Status(WorkDoneProgress::Begin(WorkDoneProgressBegin {
+                    title: "Running 'cargo check'".to_string(),
+                    cancellable: Some(true),
+                    message: None,
+                    percentage: initial_percentage,
+                })));
+            }
+
+            CheckEvent::Error { message, kind } => {
+                self.check_failed = true;
+                task_send.send(CheckTask::Error { message, kind });
+            }
+
+            CheckEvent::Warning(message) => {
+                task_send.send(CheckTask::Warning(message));
+            }
+
+            CheckEvent::Units(total) => self.total_units = Some(total),
+
+            // The cargo version doesn't change between checks, so the
+            // following ones don't have to try the flag again.
+            CheckEvent::RejectedFlag(flag) => {
+                self.current_config = self.current_config.without_flag(flag);
+                if !self.rejected_flags.contains(&flag) {
+                    self.rejected_flags.push(flag);
+                }
+            }
+
+            CheckEvent::FreshArtifact => self.finished_units += 1,
+
+            CheckEvent::Contention(message) => {
+                self.check_failed = true;
+                match self.current_config.retry_delay {
+                    Some(delay) if !self.is_retry => {
+                        log::info!("retrying the check in {:?}: {}", delay, message);
+                        self.retry_timer = after(delay);
+                        self.retry_pending = true;
+                    }
+                    _ => task_send
+                        .send(CheckTask::Error { message, kind: CheckErrorKind::Environment }),
+                }
+            }
+
+            CheckEvent::StderrLine(line) => {
+                log::debug!("check stderr: {}", line);
+                // Another cargo process, f.x. a build in the terminal, uses
+                // the same target directory or package cache.
+                if line.trim_start().starts_with("Blocking waiting for file lock on") {
+                    self.lock_wait_started = Some(Instant::now());
+                    task_send.send(CheckTask::Status(WorkDoneProgress::Report(
+                        WorkDoneProgressReport {
+                            cancellable: Some(true),
+                            message: Some("waiting for cargo lock...".to_string()),
+                            percentage: percentage(self.finished_units, self.total_units),
+                        },
+                    )));
+                } else if let Some(name) = started_crate(&line) {
+                    self.status.lock().unwrap().current_crate = Some(name.to_string());
+                    // Artifacts are only reported once a crate is done, which
+                    // can take minutes for the last ones.
+                    task_send.send(CheckTask::Status(WorkDoneProgress::Report(
+                        WorkDoneProgressReport {
+                            cancellable: Some(true),
+                            message: Some(format!("checking {}...", name)),
+                            percentage: percentage(self.finished_units, self.total_units),
+                        },
+                    )));
+                }
+            }
+
+            CheckEvent::End(mut summary) => {
+                self.add_suppressed_markers(task_send);
+                self.flush_diagnostics(task_send);
+                let outcome =
+                    if self.check_failed { CheckOutcome::Failed } else { CheckOutcome::Finished };
+                let elapsed = match self.check_started.take() {
+                    Some(started) => started.elapsed(),
+                    None => summary.duration,
+                };
+                let (errors, warnings) = (self.counts.errors(), self.counts.warnings());
+                let message = end_message(outcome, elapsed, errors, warnings, self.lock_wait);
+                task_send.send(CheckTask::Status(WorkDoneProgress::End(WorkDoneProgressEnd {
+                    message: Some(message),
+                })));
+                if let Some(warning) = self.diagnostic_limit_warning() {
+                    task_send.send(CheckTask::Warning(warning));
+                }
+                summary.run = self.run_id;
+                summary.errors = self.counts.errors();
+                summary.warnings = self.counts.warnings();
+                summary.counts = self.counts;
+                summary.one_off = self.current_run == CheckRun::OneOff;
+                summary.scope = self.run_scope.as_ref().map(RunScope::roots);
+                // The run is complete once its secondary check ended as well.
+                let secondary_follows =
+                    self.current_run == CheckRun::Primary && self.config.secondary.is_some();
+                let run_complete = !secondary_follows && !self.retry_pending;
+                if run_complete {
+                    self.complete_run(task_send);
+                }
+                self.finish_status(&summary);
+                task_send.send(CheckTask::Finished(summary));
+                if self.current_run == CheckRun::Primary && !self.retry_pending {
+                    if self.check_failed {
+                        self.snapshots.invalidate();
+                    } else {
+                        self.snapshots.succeeded();
+                    }
+                }
+                if secondary_follows && !self.retry_pending {
+                    self.secondary_timer = after(self.config.idle_delay);
+                } else if run_complete {
+                    self.running_changes = Changes::Nothing;
+                }
+            }
+
+            CheckEvent::Msg(Message::CompilerArtifact(msg)) => {
+                self.finished_units += 1;
+                task_send.send(CheckTask::Status(WorkDoneProgress::Report(
+                    WorkDoneProgressReport {
+                        cancellable: Some(true),
+                        message: Some(msg.target.name),
+                        percentage: percentage(self.finished_units, self.total_units),
+                    },
+                )));
+            }
+
+            CheckEvent::Msg(Message::CompilerMessage(msg)) => {
+                let map_result = map_rust_diagnostic_to_lsp(&msg.message, &self.workspace_root);
+                if map_result.is_empty() {
+                    return;
+                }
+                let source = self.diagnostic_source();
+                let target = format!(
+                    "{} `{}`",
+                    msg.target.kind.first().map_or("target", String::as_str),
+                    msg.target.name
+                );
+                for (i, mapped) in map_result.into_iter().enumerate() {
+                    let MappedRustDiagnostic { location, mut diagnostic, fixes } = mapped;
+                    let url = location.uri;
+                    if let Some(source) = &source {
+                        diagnostic.source = Some(source.clone());
+                    }
+                    match self.duplicates.record(&url, &diagnostic, &target) {
+                        Reported::First => {}
+                        Reported::Again => continue,
+                        Reported::ForAnotherTarget => {
+                            if self.config.merge_duplicate_targets {
+                                let merged = self.duplicates.merged(&url);
+                                self.replace_diagnostics(url, merged, task_send);
+                            }
+                            continue;
+                        }
+                    }
+                    // Only counted once, even if the message maps to
+                    // multiple locations.
+                    if i == 0 {
+                        let code = msg.message.code.as_ref().map(|it| it.code.as_str());
+                        self.counts.add(code, diagnostic.severity);
+                    }
+                    self.report_diagnostic(url, diagnostic, fixes, task_send);
+                }
+            }
+
+            CheckEvent::FutureIncompatReport(report) => {
+                let mapped = map_future_incompat_report_to_lsp(&report, &self.workspace_root);
+                self.counts.rustc.warnings += mapped.len();
+                for (url, diagnostic) in mapped {
+                    self.report_diagnostic(url, diagnostic, Vec::new(), task_send);
+                }
+            }
+
+            CheckEvent::Msg(Message::BuildScriptExecuted(_msg)) => {}
+            CheckEvent::Msg(Message::Unknown) => {}
+        }
+    }
+
+    /// Sends a diagnostic of the running check unless it's beyond
+    /// `max_diagnostics` or `max_diagnostics_per_file`, stopping the check
+    /// once it reaches the limit if `stop_at_diagnostic_limit` is set.
+    fn report_diagnostic(
+        &mut self,
+        url: Url,
+        diagnostic: Diagnostic,
+        fixes: Vec<CodeAction>,
+        task_send: &TaskSender,
+    ) {
+        if matches!(self.current_config.max_diagnostics, Some(max) if self.diagnostics >= max) {
+            self.suppressed_diagnostics += 1;
+            return;
+        }
+        if let Some(max) = self.current_config.max_diagnostics_per_file {
+            let sent = self.file_diagnostics.entry(url.clone()).or_default();
+            if *sent >= max {
+                *self.suppressed_file_diagnostics.entry(url).or_default() += 1;
+                return;
+            }
+            *sent += 1;
+        }
+        self.diagnostics += 1;
+        let fixes: Vec<CodeActionOrCommand> = fixes
+            .into_iter()
+            .map(|fix| CodeAction { diagnostics: Some(vec![diagnostic.clone()]), ..fix }.into())
+            .collect();
+
+        if self.config.merge_duplicate_targets {
+            self.duplicates.sent(&url, &diagnostic, &fixes);
+        }
+        self.add_diagnostic(url, diagnostic, fixes, task_send);
+        if self.current_config.stop_at_diagnostic_limit
+            && Some(self.diagnostics) == self.current_config.max_diagnostics
+        {
+            self.cancel_check.cancel();
+        }
+    }
+
+    /// The source of the diagnostics of the running check, `None` if it's the
+    /// one of the compiler, f.x. `rustc`.
+    fn diagnostic_source(&self) -> Option<String> {
+        match (self.current_run, &self.config.secondary) {
+            (CheckRun::Secondary, Some(secondary)) => Some(secondary.command_name().to_string()),
+            (CheckRun::OneOff, _) => Some(self.current_config.command_name().to_string()),
+            _ => None,
+        }
+    }
+
+    /// Tells how many diagnostics of each file the check which just ended left
+    /// out because of `max_diagnostics_per_file`.
+    fn add_suppressed_markers(&mut self, task_send: &TaskSender) {
+        let source = self.diagnostic_source().unwrap_or_else(|| "cargo".to_string());
+        let mut suppressed: Vec<_> = self.suppressed_file_diagnostics.drain().collect();
+        suppressed.sort();
+        for (url, count) in suppressed {
+            let marker = suppressed_diagnostics_marker(count, &source);
+            self.add_diagnostic(url, marker, Vec::new(), task_send);
+        }
+    }
+
+    /// Starts replacing the shown diagnostics with the ones of a new run,
+    /// which can only clear the ones of files in `scope`, if it's set.
+    fn begin_run(&mut self, scope: Option<RunScope>, task_send: &TaskSender) {
+        self.flush_diagnostics(task_send);
+        self.run_files.clear();
+        self.run_scope = scope;
+        self.duplicates.clear();
+        self.unpublished = match self.config.publish_mode {
+            PublishMode::Incremental => None,
+            PublishMode::AtEnd => Some(HashMap::new()),
+        };
+    }
+
+    /// Sends a diagnostic of the current run, clearing the ones the file
+    /// had before if it's the first one. It's held back if it's sent with
+    /// others, see [`FlycheckConfig::batch_delay`] and [`PublishMode`].
+    fn add_diagnostic(
+        &mut self,
+        url: Url,
+        diagnostic: Diagnostic,
+        fixes: Vec<CodeActionOrCommand>,
+        task_send: &TaskSender,
+    ) {
+        let first = self.run_files.insert(url.clone());
+        if let Some(unpublished) = &mut self.unpublished {
+            unpublished.entry(url).or_default().push((diagnostic, fixes));
+            return;
+        }
+        let clear = first && !self.shown_files.insert(url.clone());
+        if self.config.batch_delay.is_some() {
+            let diagnostic = (diagnostic, fixes);
+            let now = Instant::now();
+            if let Some(batch) = self.batcher.add(self.run_id, url, clear, diagnostic, now) {
+                send_batch(batch, task_send);
+            }
+            return;
+        }
+        if clear {
+            task_send.send(CheckTask::ClearForFile { run: self.run_id, url: url.clone() });
+        }
+        task_send.send(CheckTask::AddDiagnostic { run: self.run_id, url, diagnostic, fixes });
+    }
+
+    /// Replaces the diagnostics of `url` the current run reported so far,
+    /// f.x. because they list the targets reporting them now.
+    fn replace_diagnostics(
+        &mut self,
+        url: Url,
+        diagnostics: Vec<(Diagnostic, Vec<CodeActionOrCommand>)>,
+        task_send: &TaskSender,
+    ) {
+        if let Some(unpublished) = &mut self.unpublished {
+            unpublished.insert(url, diagnostics);
+            return;
+        }
+        self.batcher.discard(&url);
+        self.shown_files.insert(url.clone());
+        let mut diagnostics = diagnostics;
+        sort_diagnostics(&url, &mut diagnostics);
+        let diagnostics = iter::once((url, diagnostics)).collect();
+        task_send.send(CheckTask::SetDiagnostics { run: self.run_id, diagnostics });
+    }
+
+    /// Sends the diagnostics held back to batch them right away.
+    fn flush_diagnostics(&mut self, task_send: &TaskSender) {
+        if let Some(batch) = self.batcher.flush() {
+            send_batch(batch, task_send);
+        }
+    }
+
+    /// Clears the diagnostics of the files in the scope of the current run
+    /// which it didn't report any for, and sends the unpublished ones, once
+    /// it's complete.
+    fn complete_run(&mut self, task_send: &TaskSender) {
+        let run_files = &self.run_files;
+        let scope = self.run_scope.as_ref();
+        let (cleared, kept): (Vec<Url>, HashSet<Url>) = mem::take(&mut self.shown_files)
+            .into_iter()
+            .partition(|url| !run_files.contains(url) && is_in_scope(url, scope));
+        self.shown_files = kept;
+        match self.unpublished.take() {
+            Some(mut diagnostics) => {
+                self.shown_files.extend(diagnostics.keys().cloned());
+                for (url, diagnostics) in &mut diagnostics {
+                    sort_diagnostics(url, diagnostics);
+                }
+                diagnostics.extend(cleared.into_iter().map(|url| (url, Vec::new())));
+                task_send.send(CheckTask::SetDiagnostics { run: self.run_id, diagnostics });
+            }
+            None => {
+                for url in cleared {
+                    task_send.send(CheckTask::ClearForFile { run: self.run_id, url });
+                }
+            }
+        }
+    }
+
+    /// Forgets about the files with diagnostics, all of them were cleared.
+    fn forget_shown_files(&mut self) {
+        self.shown_files.clear();
+        self.run_files.clear();
+    }
+
+    /// Tells the user that diagnostics of the check which just ended were left
+    /// out, if it reached `max_diagnostics`.
+    fn diagnostic_limit_warning(&self) -> Option<String> {
+        let max = self.current_config.max_diagnostics?;
+        if self.diagnostics < max {
+            return None;
+        }
+        if self.current_config.stop_at_diagnostic_limit {
+            Some(format!("cargo check was stopped after reporting {} diagnostics", max))
+        } else if self.suppressed_diagnostics > 0 {
+            Some(format!(
+                "cargo check reported {} more diagnostics, only the first {} are shown",
+                self.suppressed_diagnostics, max
+            ))
+        } else {
+            None
+        }
+    }
+
+    /// Stops the running check as configured by `on_restart`, without
+    /// waiting for it.
+    fn stop_check(&mut self) {
+        match self.config.on_restart {
+            RestartBehavior::KillImmediately => self.kill_check(),
+            RestartBehavior::WaitForFinish { max } => {
+                let message_recv = mem::replace(&mut self.message_recv, never());
+                let check_process = match self.check_process.take() {
+                    Some(check_process) => check_process,
+                    None => return,
+                };
+                self.cancel_check.cancel_at(Instant::now() + max);
+                self.finish_in_background(message_recv, check_process);
+            }
+        }
+    }
+
+    /// Stops the running check right away, whatever `on_restart` says.
+    fn kill_check(&mut self) {
+        let message_recv = mem::replace(&mut self.message_recv, never());
+        if let Some(check_process) = self.check_process.take() {
+            self.cancel_check.cancel();
+            self.finish_in_background(message_recv, check_process);
+        }
+    }
+
+    /// Keeps the stopped check in `finishing` until its thread is done.
+    fn finish_in_background(
+        &mut self,
+        message_recv: Receiver<CheckEvent>,
+        check_process: jod_thread::JoinHandle<()>,
+    ) {
+        if self.finishing.is_empty() {
+            self.reap_timer = after(REAP_INTERVAL);
+        }
+        let cancel = self.cancel_check.clone();
+        self.finishing.push(FinishingCheck { message_recv, cancel, check_process });
+    }
+
+    /// Cleans up the checks which finished after a restart.
+    fn reap_finished_checks(&mut self) {
+        self.finishing.retain(|check| loop {
+            match check.message_recv.try_recv() {
+                Ok(_) => {}
+                Err(TryRecvError::Empty) => break true,
+                // The thread is done, so dropping it doesn't block.
+                Err(TryRecvError::Disconnected) => break false,
+            }
+        });
+        self.reap_timer = if self.finishing.is_empty() { never() } else { after(REAP_INTERVAL) };
+    }
+
+    fn restart_check_process(
+        &mut self,
+        run: CheckRun,
+        config: &FlycheckConfig,
+    ) -> Result<(), String> {
+        // First, clear and stop the old thread
+        self.stop_check();
+
+        let cmd = config.to_command(&self.workspace_root)?;
+        let cancel = CancellationToken::default();
+        self.cancel_check = cancel.clone();
+        self.current_config = config.clone();
+        self.is_retry = false;
+        let mut config = config.clone();
+        let workspace_root = self.workspace_root.clone();
+        self.current_run = run;
+
+        // Bounded, so that a flood of diagnostics makes cargo wait instead of
+        // piling up in memory.
+        let (message_send, message_recv) = bounded(config.message_buffer);
+        self.message_recv = message_recv;
+        self.schedule.started();
+        self.check_process = Some(jod_thread::spawn(move || {
+            // If we trigger an error here, we will do so in the loop instead,
+            // which will break out of the loop, and continue the shutdown
+            let _ = message_send.send(CheckEvent::Begin);
+            let started = Instant::now();
+            if config.estimates_progress() {
+                if let Some(units) = estimate_units(&config, &workspace_root, &cancel) {
+                    let _ = message_send.send(CheckEvent::Units(units));
+                }
+            }
+
+            // Reported like any other failure, so that the progress still ends.
+            let outcome = panic::catch_unwind(AssertUnwindSafe(|| {
+                let report_future_incompat = config.report_future_incompat;
+                let mut on_message = |message: CargoMessage| {
+                    let message = match message {
+                        CargoMessage::Cargo(message) => message,
+                        CargoMessage::FutureIncompatReport(report) => {
+                            if report_future_incompat {
+                                let _ = message_send.send(CheckEvent::FutureIncompatReport(report));
+                            }
+                            return true;
+                        }
+                    };
+                    // Skip certain kinds of messages to only spend time on what's useful
+                    match &message {
+                        // Still part of the progress.
+                        Message::CompilerArtifact(artifact) if artifact.fresh => {
+                            let _ = message_send.send(CheckEvent::FreshArtifact);
+                            return true;
+                        }
+                        Message::BuildScriptExecuted(_) => return true,
+                        Message::Unknown => return true,
+                        _ => {}
+                    }
+
+                    // The check is stopped through `cancel` only, as one which is
+                    // allowed to finish has no receiver anymore.
+                    let _ = message_send.send(CheckEvent::Msg(message));
+                    true
+                };
+                let mut on_stderr_line = |line: &str| {
+                    let _ = message_send.send(CheckEvent::StderrLine(line.to_string()));
+                };
+                let options = config.run_options();
+                let hook = config.command_hook.clone();
+                let configure = hook.as_ref().map(|hook| &*hook.0 as &dyn Fn(&mut Command));
+                let mut res = run_cargo(
+                    cmd,
+                    &mut on_message,
+                    &mut on_stderr_line,
+                    &cancel,
+                    options,
+                    configure,
+                );
+                while let Err(CargoError::NonZeroExit { stderr, .. }) = &res {
+                    let flag = match config.rejected_flag(stderr) {
+                        Some(flag) => flag,
+                        None => break,
+                    };
+                    log::warn!("cargo doesn't support `{}`, checking without it", flag);
+                    config = config.without_flag(flag);
+                    let _ = message_send.send(CheckEvent::RejectedFlag(flag));
+                    res = config
+                        .to_command(&workspace_root)
+                        .map_err(|err| CargoError::Io(io::Error::new(io::ErrorKind::Other, err)))
+                        .and_then(|cmd| {
+                            run_cargo(
+                                cmd,
+                                &mut on_message,
+                                &mut on_stderr_line,
+                                &cancel,
+                                options,
+                                configure,
+                            )
+                        });
+                }
+
+                let mut summary = CheckSummary::default();
+                match &res {
+                    Ok(run) => {
+                        summary.exit_code = run.exit_code;
+                        summary.skipped_lines = run.skipped_lines;
+                        summary.messages = run.messages;
+                    }
+                    Err(CargoError::NonZeroExit { code, .. })
+                    | Err(CargoError::HookedCommandFailed { code, .. }) => {
+                        summary.exit_code = *code
+                    }
+                    Err(_) => {}
+                }
+                match res {
+                    Ok(run) => {
+                        if let Some(warning) = unparsed_output_warning(&run) {
+                            log::warn!("{}", warning);
+                            let _ = message_send.send(CheckEvent::Warning(warning));
+                        }
+                    }
+                    // A new check replaces this one.
+                    Err(CargoError::Cancelled) => log::debug!("check was cancelled"),
+                    Err(err) if err.is_contention() => {
+                        log::warn!("check failed due to another cargo process: {}", err);
+                        let _ = message_send
+                            .send(CheckEvent::Contention(format!("cargo check failed: {}", err)));
+                    }
+                    Err(err) => {
+                        let (message, kind) = check_error(&err, &config, &workspace_root);
+                        match kind {
+                            CheckErrorKind::Internal => log::error!("check failed: {:?}", err),
+                            _ => log::warn!("check failed: {}", err),
+                        }
+                        let _ = message_send.send(CheckEvent::Error { message, kind });
+                    }
+                }
+                summary
+            }));
+            let mut summary = outcome.unwrap_or_else(|payload| {
+                let message = panic_message(&*payload);
+                log::error!("the check panicked: {}", message);
+                let _ = message_send.send(CheckEvent::Error {
+                    message: format!("cargo check crashed: {}", message),
+                    kind: CheckErrorKind::Internal,
+                });
+                CheckSummary::default()
+            });
+
+            // We can ignore any error here, as we are already in the progress
+            // of shutting down.
+            summary.duration = started.elapsed();
+            let _ = message_send.send(CheckEvent::End(summary));
+        }));
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct DiagnosticWithFixes {
+    diagnostic: Diagnostic,
+    fixes: Vec<CodeAction>,
+}
+
+enum CheckEvent {
+    Begin,
+    Msg(cargo_metadata::Message),
+    /// A line of cargo's human readable output, f.x. `Checking foo v0.1.0`.
+    StderrLine(String),
+    Error {
+        message: String,
+        kind: CheckErrorKind,
+    },
+    Warning(String),
+    /// The number of units the check is expected to go through, see
+    /// [`FlycheckConfig::estimate_progress`].
+    Units(usize),
+    /// An artifact which was up to date already, its message is skipped.
+    FreshArtifact,
+    FutureIncompatReport(FutureIncompatReport),
+    /// Cargo rejected a flag, the check was run again without it, see
+    /// [`FlycheckConfig::rejected_flag`].
+    RejectedFlag(&'static str),
+    /// The check failed because another process used the target directory
+    /// at the same time, so running it again is likely to work.
+    Contention(String),
+    /// The check is done, the diagnostic counts of `CheckSummary` are filled
+    /// in by the `FlycheckThread`.
+    End(CheckSummary),
+}
+
+/// Runs `cargo metadata` to estimate the number of units `config` goes
+/// through, `None` if that fails.
+fn estimate_units(
+    config: &FlycheckConfig,
+    workspace_root: &Path,
+    cancel: &CancellationToken,
+) -> Option<usize> {
+    // Only the workspace is needed if the dependencies aren't checked.
+    let command = match config.metadata_command(workspace_root, config.checks_dependencies())? {
+        Ok(command) => command,
+        Err(err) => {
+            log::warn!("can't estimate the progress of the check: {}", err);
+            return None;
+        }
+    };
+    match run_metadata(command, cancel, config.low_priority) {
+        Ok(metadata) => Some(config.count_units(&metadata)),
+        Err(err) => {
+            log::warn!("can't estimate the progress of the check: {}", err);
+            None
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum CheckOutcome {
+    Finished,
+    /// The check couldn't be run or cargo failed without any diagnostics.
+    Failed,
+    /// A newer check replaced this one, or it was cancelled.
+    Cancelled,
+}
+
+/// The message ending the progress of a check, f.x. `cargo check finished
+/// in 12.3s (3 errors, 7 warnings)`.
+fn end_message(
+    outcome: CheckOutcome,
+    elapsed: Duration,
+    errors: usize,
+    warnings: usize,
+    lock_wait: Duration,
+) -> String {
+    fn count(n: usize, what: &str) -> String {
+        format!("{} {}{}", n, what, if n == 1 { "" } else { "s" })
+    }
+
+    let elapsed = elapsed.as_secs_f32();
+    let mut message = match outcome {
+        CheckOutcome::Finished => format!(
+            "cargo check finished in {:.1}s ({}, {})",
+            elapsed,
+            count(errors, "error"),
+            count(warnings, "warning")
+        ),
+        CheckOutcome::Failed => format!("cargo check failed after {:.1}s", elapsed),
+        CheckOutcome::Cancelled => format!("cargo check was cancelled after {:.1}s", elapsed),
+    };
+    if lock_wait > Duration::from_secs(0) {
+        message.push_str(&format!(", waited {:.1}s for cargo lock", lock_wait.as_secs_f32()));
+    }
+    message
+}
+
+/// A check which was killed or is allowed to finish after a restart, see
+/// [`RestartBehavior::WaitForFinish`].
+struct FinishingCheck {
+    /// Kept to notice when the check is done, its messages are discarded.
+    message_recv: Receiver<CheckEvent>,
+    cancel: CancellationToken,
+    check_process: jod_thread::JoinHandle<()>,
+}
+
+impl Drop for FlycheckThread {
+    fn drop(&mut self) {
+        self.stop_check();
+        for check in self.finishing.drain(..) {
+            if check.cancel.is_cancelled() {
+                // Killed checks are done after the grace period at the
+                // latest, so that no process outlives the `Flycheck`. Without
+                // a receiver they can't wait for room in the channel.
+                drop(check.message_recv);
+                drop(check.check_process);
+            } else {
+                // Checks which are allowed to finish stop by themselves once
+                // their time is up, joining them would block the shutdown
+                // until then.
+                check.check_process.detach();
+            }
+        }
+    }
+}
+
+/// Requests a running check to stop, see [`run_cargo`].
+#[derive(Clone, Debug, Default)]
+struct CancellationToken(Arc<Mutex<Option<Instant>>>);
+
+impl CancellationToken {
+    fn cancel(&self) {
+        self.cancel_at(Instant::now());
+    }
+
+    /// Requests the check to stop once `deadline` has passed.
+    fn cancel_at(&self, deadline: Instant) {
+        let mut cancel_at = self.0.lock().unwrap();
+        match *cancel_at {
+            // An earlier request wins.
+            Some(current) if current <= deadline => {}
+            _ => *cancel_at = Some(deadline),
+        }
+    }
+
+    fn is_cancelled(&self) -> bool {
+        match *self.0.lock().unwrap() {
+            Some(deadline) => Instant::now() >= deadline,
+            None => false,
+        }
+    }
+}
+
+/// Options of a single run of the check command, see
+/// [`FlycheckConfig::run_options`].
+#[derive(Clone, Copy, Debug)]
+struct RunOptions {
+    timeout: Option<Duration>,
+    low_priority: bool,
+    max_line_length: usize,
+    message_buffer: usize,
+    termination_grace_period: Duration,
+    forward_stderr_to_log: bool,
+}
+
+impl Default for RunOptions {
+    fn default() -> Self {
+        FlycheckConfig::default().run_options()
+    }
+}
+
+/// The outcome of a successful [`run_cargo`].
+#[derive(Clone, Debug)]
+struct RunSummary {
+    /// `None` if the command was killed, f.x. after `on_message` stopped the
+    /// run.
+    exit_code: Option<i32>,
+    /// Number of valid cargo JSON messages.
+    messages: usize,
+    /// Number of stdout lines which weren't valid cargo JSON messages or were
+    /// longer than `max_line_length`.
+    skipped_lines: usize,
+    /// The beginning of the first few skipped lines.
+    skipped_samples: Vec<String>,
+    /// How long the command ran.
+    duration: Duration,
+    /// Whether `on_message` stopped the run before the command exited.
+    stopped_early: bool,
+}
+
+/// Explains that the check command printed something else than cargo's JSON
+/// messages, f.x. because a wrapper script writes to stdout, if most of its
+/// output was skipped.
+fn unparsed_output_warning(run: &RunSummary) -> Option<String> {
+    if run.skipped_lines == 0 || run.skipped_lines < run.messages {
+        return None;
+    }
+    let mut warning = format!(
+        "the check command printed {} lines which aren't cargo JSON messages and only {} \
+         valid ones, make sure it prints messages with `--message-format=json` to stdout",
+        run.skipped_lines, run.messages
+    );
+    if !run.skipped_samples.is_empty() {
+        warning.push_str(", the skipped lines start with:");
+        for sample in &run.skipped_samples {
+            warning.push_str("\n    ");
+            warning.push_str(sample);
+        }
+    }
+    Some(warning)
+}
+
+/// Describes why running the check failed.
+#[derive(Debug)]
+enum CargoError {
+    /// The check command couldn't be started, f.x. because cargo is not
+    /// installed.
+    SpawnFailed(io::Error),
+    /// The check command failed without printing any messages, most likely
+    /// because the workspace couldn't be built at all. `code` is `None` if
+    /// the command was killed by a signal, `stderr` contains the end of its
+    /// error output.
+    NonZeroExit { code: Option<i32>, stderr: String },
+    /// The check command was changed by the
+    /// [command hook](FlycheckConfig::command_hook) and didn't print any
+    /// valid messages. `command` is the changed command.
+    HookedCommandFailed { command: String, code: Option<i32>, stderr: String },
+    /// Reading the output of the check command failed.
+    Io(io::Error),
+    /// The check was stopped through its [`CancellationToken`].
+    Cancelled,
+    /// The check didn't finish within the configured timeout and was killed.
+    TimedOut(Duration),
+}
+
+impl CargoError {
+    /// Whether cargo failed because another process, f.x. a build in the
+    /// terminal, used the target directory at the same time.
+    fn is_contention(&self) -> bool {
+        match self {
+            CargoError::NonZeroExit { stderr, .. } => {
+                CONTENTION_ERRORS.iter().any(|error| stderr.contains(error))
+            }
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for CargoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CargoError::SpawnFailed(err) => write!(f, "{}", err),
+            CargoError::NonZeroExit { code, stderr } => {
+                match code {
+                    Some(code) => write!(f, "the check command failed with exit code {}", code)?,
+                    None => write!(f, "the check command was killed by a signal")?,
+                }
+                if stderr.is_empty() {
+                    return write!(f, " without printing any messages");
+                }
+                write!(f, ":\n{}", stderr)?;
+                if let Some(hint) = failure_hint(stderr) {
+                    write!(f, "\n{}", hint)?;
+                }
+                Ok(())
+            }
+            CargoError::HookedCommandFailed { command, code, stderr } => {
+                write!(
+                    f,
+                    "{} (changed by the command hook) printed no valid messages (exit code: {:?})",
+                    command, code
+                )?;
+                if !stderr.is_empty() {
+                    write!(f, ":\n{}", stderr)?;
+                }
+                Ok(())
+            }
+            CargoError::Io(err) => write!(f, "{}", err),
+            CargoError::Cancelled => write!(f, "the check was cancelled"),
+            CargoError::TimedOut(elapsed) => {
+                write!(f, "the check was killed after running for {:.1}s", elapsed.as_secs_f32())
+            }
+        }
+    }
+}
+
+impl std::error::Error for CargoError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CargoError::SpawnFailed(err) | CargoError::Io(err) => Some(err),
+            CargoError::NonZeroExit { .. }
+            | CargoError::HookedCommandFailed { .. }
+            | CargoError::Cancelled
+            | CargoError::TimedOut(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for CargoError {
+    fn from(err: io::Error) -> CargoError {
+        CargoError::Io(err)
+    }
+}
+
+/// A message printed by the check command.
+#[derive(Debug)]
+enum CargoMessage {
+    Cargo(Message),
+    /// Not known to `cargo_metadata` yet, so it's parsed by us.
+    FutureIncompatReport(FutureIncompatReport),
+}
+
+/// A line of output of the check process.
+enum OutputLine {
+    Stdout(io::Result<String>),
+    /// The last line of stdout, which didn't end in a line break.
+    StdoutUnterminated(String),
+    /// A line of stdout which was skipped for being longer than the limit.
+    StdoutTooLong(usize),
+    Stderr(String),
+}
+
+enum BoundedLine {
+    Line(Vec<u8>),
+    /// The last line, which didn't end in a line break.
+    Unterminated(Vec<u8>),
+    /// The length of a line which was skipped.
+    TooLong(usize),
+    Eof,
+}
+
+/// Reads the next line without its line break from `reader`, only keeping it
+/// in memory if it's no longer than `max_length` bytes.
+fn read_bounded_line(reader: &mut impl BufRead, max_length: usize) -> io::Result<BoundedLine> {
+    let mut line = Vec::new();
+    let mut length = 0;
+    let terminated = loop {
+        let available = match reader.fill_buf() {
+            Ok(available) => available,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        if available.is_empty() && length == 0 {
+            return Ok(BoundedLine::Eof);
+        }
+        let (chunk, used, terminated, done) = match available.iter().position(|&b| b == b'\n') {
+            Some(end) => (&available[..end], end + 1, true, true),
+            None => (available, available.len(), false, available.is_empty()),
+        };
+        length += chunk.len();
+        if length <= max_length {
+            line.extend_from_slice(chunk);
+        } else if !line.is_empty() {
+            line = Vec::new();
+        }
+        reader.consume(used);
+        if done {
+            break terminated;
+        }
+    };
+    if length > max_length {
+        return Ok(BoundedLine::TooLong(length));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(if terminated { BoundedLine::Line(line) } else { BoundedLine::Unterminated(line) })
+}
+
+fn run_cargo(
+    mut command: Command,
+    on_message: &mut dyn FnMut(CargoMessage) -> bool,
+    on_stderr_line: &mut dyn FnMut(&str),
+    cancel: &CancellationToken,
+    options: RunOptions,
+    configure: Option<&dyn Fn(&mut Command)>,
+) -> Result<RunSummary, CargoError> {
+    let started = Instant::now();
+    // Set up before the pipes, so that the hook can't redirect the output.
+    if let Some(configure) = configure {
+        configure(&mut command);
+    }
+    let mut child = ProcessGroupChild::spawn(
+        command.stdout(Stdio::piped()).stderr(Stdio::piped()).stdin(Stdio::null()),
+        options.low_priority,
+    )
+    .map_err(|err| {
+        let message = format!("failed to spawn {:?}: {}", command, err);
+        CargoError::SpawnFailed(io::Error::new(err.kind(), message))
+    })?;
+
+    // Both pipes are drained on their own threads, otherwise cargo blocks once
+    // the buffer of the one we aren't reading fills up. They share a channel,
+    // so that lines of both are handled in the order they arrive in. The
+    // threads are not joined, as they only finish once cargo closes the pipes.
+    // Once the channel is full they block, which makes cargo wait as well.
+    let (line_send, line_recv) = bounded(options.message_buffer);
+    let max_line_length = options.max_line_length;
+    let mut stdout = BufReader::new(child.stdout.take().unwrap());
+    let stdout_send = line_send.clone();
+    thread::spawn(move || loop {
+        let line = match read_bounded_line(&mut stdout, max_line_length) {
+            Ok(BoundedLine::Line(line)) => OutputLine::Stdout(
+                String::from_utf8(line)
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)),
+            ),
+            // Lossy, as the line may end in the middle of a character.
+            Ok(BoundedLine::Unterminated(line)) => {
+                OutputLine::StdoutUnterminated(String::from_utf8_lossy(&line).into_owned())
+            }
+            Ok(BoundedLine::TooLong(length)) => OutputLine::StdoutTooLong(length),
+            Ok(BoundedLine::Eof) => break,
+            Err(err) => OutputLine::Stdout(Err(err)),
+        };
+        let is_err = match &line {
+            OutputLine::Stdout(line) => line.is_err(),
+            _ => false,
+        };
+        if stdout_send.send(line).is_err() || is_err {
+            break;
+        }
+    });
+    let mut stderr = BufReader::new(child.stderr.take().unwrap());
+    thread::spawn(move || loop {
+        let line = match read_bounded_line(&mut stderr, max_line_length) {
+            Ok(BoundedLine::Line(line)) | Ok(BoundedLine::Unterminated(line)) => {
+                String::from_utf8_lossy(&line).into_owned()
+            }
+            Ok(BoundedLine::TooLong(length)) => {
+                log::warn!("skipped a line of {} bytes on the stderr of the check", length);
+                continue;
+            }
+            Ok(BoundedLine::Eof) | Err(_) => break,
+        };
+        if line_send.send(OutputLine::Stderr(line)).is_err() {
+            break;
+        }
+    });
+
+    let mut stderr_tail = VecDeque::with_capacity(STDERR_TAIL_LINES);
+    let mut omitted_stderr_lines = 0;
+    let mut messages = 0;
+    let mut stopped_early = false;
+    let mut skipped_lines = 0;
+    let mut skipped_samples = Vec::new();
+    let mut unparsed_last_line = None;
+    let mut res = Ok(());
+
+    loop {
+        // Cancellation and timeouts have to be noticed even when cargo doesn't
+        // print anything for a long time, f.x. while rustc checks a big crate.
+        let line = match line_recv.recv_timeout(CANCELLATION_POLL_INTERVAL) {
+            _ if cancel.is_cancelled() => {
+                let _ = child.stop(options.termination_grace_period);
+                return Err(CargoError::Cancelled);
+            }
+            _ if matches!(options.timeout, Some(timeout) if started.elapsed() >= timeout) => {
+                let _ = child.stop(options.termination_grace_period);
+                return Err(CargoError::TimedOut(started.elapsed()));
+            }
+            Ok(line) => line,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        let (line, terminated) = match line {
+            OutputLine::Stdout(Ok(line)) => (line, true),
+            OutputLine::StdoutUnterminated(line) => (line, false),
+            OutputLine::Stdout(Err(err)) => {
+                res = Err(CargoError::Io(err));
+                break;
+            }
+            OutputLine::StdoutTooLong(length) => {
+                log::warn!("skipped a line of {} bytes printed by the check", length);
+                skipped_lines += 1;
+                if skipped_samples.len() < SKIPPED_LINE_SAMPLES {
+                    skipped_samples.push(format!("({} bytes long)", length));
+                }
+                continue;
+            }
+            OutputLine::Stderr(line) => {
+                if options.forward_stderr_to_log {
+                    log::info!(target: STDERR_LOG_TARGET, "{}", line);
+                }
+                on_stderr_line(&line);
+                if stderr_tail.len() == STDERR_TAIL_LINES {
+                    stderr_tail.pop_front();
+                    omitted_stderr_lines += 1;
+                }
+                stderr_tail.push_back(line);
+                continue;
+            }
+        };
+
+        // We manually read a line at a time, instead of using serde's
+        // stream deserializers, because the deserializer cannot recover
+        // from an error, resulting in it getting stuck, because we try to
+        // be resillient against failures.
+        //
+        // Because cargo only outputs one JSON object per line, we can
+        // simply skip a line if it doesn't parse, which just ignores any
+        // erroneus output.
+        let message = serde_json::from_str::<cargo_metadata::Message>(&line);
+        let mut message = match message {
+            Ok(message) => message,
+            // Most likely cargo was killed while printing the line, which is
+            // only known once it has exited.
+            Err(err) if !terminated => {
+                unparsed_last_line = Some((err, line));
+                continue;
+            }
+            Err(err) => {
+                log::error!("Invalid json from cargo check, ignoring ({}): {:?} ", err, line);
+                skipped_lines += 1;
+                if skipped_samples.len() < SKIPPED_LINE_SAMPLES {
+                    skipped_samples.push(line.chars().take(SKIPPED_LINE_SAMPLE_LENGTH).collect());
+                }
+                continue;
+            }
+        };
+
+        messages += 1;
+        // `--message-format=json-diagnostic-rendered-ansi` colors the
+        // rendered diagnostics, which is only useful for terminals.
+        if let Message::CompilerMessage(msg) = &mut message {
+            strip_rendered_ansi_codes(&mut msg.message);
+        }
+        let message = match message {
+            Message::Unknown => match FutureIncompatReport::parse(&line) {
+                Some(report) => CargoMessage::FutureIncompatReport(report),
+                None => CargoMessage::Cargo(message),
+            },
+            message => CargoMessage::Cargo(message),
+        };
+
+        if !on_message(message) {
+            stopped_early = true;
+            break;
+        }
+    }
+
+    // Stops rustc and build scripts as well if we stopped reading early.
+    // Otherwise cargo is done, and whatever it left running on purpose is
+    // left alone. If waiting fails or anything above panics, `child` is still
+    // waited for when it's dropped.
+    let exit_status = if stopped_early || res.is_err() {
+        child.stop(options.termination_grace_period)?
+    } else {
+        child.wait()?
+    };
+    res?;
+    if let Some((err, line)) = unparsed_last_line {
+        if exit_status.success() {
+            log::error!("Invalid json from cargo check, ignoring ({}): {:?} ", err, line);
+            skipped_lines += 1;
+            if skipped_samples.len() < SKIPPED_LINE_SAMPLES {
+                skipped_samples.push(line.chars().take(SKIPPED_LINE_SAMPLE_LENGTH).collect());
+            }
+        } else {
+            log::debug!("cargo check died while printing a message, ignoring it: {:?}", line);
+        }
+    }
+    if (!exit_status.success() || configure.is_some()) && messages == 0 {
+        let mut stderr = String::new();
+        if omitted_stderr_lines > 0 {
+            stderr.push_str(&format!("({} earlier lines omitted)\n", omitted_stderr_lines));
+        }
+        for line in stderr_tail {
+            stderr.push_str(&line);
+            stderr.push('\n');
+        }
+        stderr.truncate(stderr.trim_end().len());
+        let code = exit_status.code();
+        if configure.is_some() {
+            // The hook might be the reason for the failure.
+            let command = format!("{:?}", command);
+            return Err(CargoError::HookedCommandFailed { command, code, stderr });
+        }
+        return Err(CargoError::NonZeroExit { code, stderr });
+    }
+
+    Ok(RunSummary {
+        exit_code: exit_status.code(),
+        messages,
+        skipped_lines,
+        skipped_samples,
+        duration: started.elapsed(),
+        stopped_early,
+    })
+}
+
+fn strip_rendered_ansi_codes(diagnostic: &mut cargo_metadata::diagnostic::Diagnostic) {
+    if let Some(rendered) = &mut diagnostic.rendered {
+        *rendered = strip_ansi_codes(rendered);
+    }
+    diagnostic.children.iter_mut().for_each(strip_rendered_ansi_codes);
+}
+
+/// Whether cargo refused to run because it doesn't know about `flag`, either
+/// because it's too old or because the flag is still unstable.
+fn rejects_flag(stderr: &str, flag: &str) -> bool {
+    let flag = format!("'{}'", flag);
+    stderr.lines().any(|line| {
+        (line.contains(&flag) || line.contains(&flag.replace('\'', "`")))
+            && (line.contains("wasn't expected")
+                || line.contains("unexpected argument")
+                || line.to_lowercase().contains("unrecognized option")
+                || line.contains("is unstable"))
+    })
+}
+
+/// The name of the crate cargo started to work on according to `line` of its
+/// human readable output, f.x. `   Checking foo v0.1.0 (/ws/foo)`.
+fn started_crate(line: &str) -> Option<&str> {
+    let mut words = line.split_whitespace();
+    match (words.next(), words.next(), words.next()) {
+        (Some("Checking"), Some(name), Some(version))
+        | (Some("Compiling"), Some(name), Some(version))
+            if version.starts_with('v') =>
+        {
+            Some(name)
+        }
+        _ => None,
+    }
+}
+
+/// Calls `run` again whenever it panics, telling the user about it, until it
+/// returns or nobody receives the tasks anymore.
+fn run_restarting(task_send: &TaskSender, mut run: impl FnMut(&TaskSender)) {
+    loop {
+        let payload = match panic::catch_unwind(AssertUnwindSafe(|| run(task_send))) {
+            Ok(()) => break,
+            Err(payload) => payload,
+        };
+        let message = panic_message(&*payload);
+        log::error!("the check thread panicked: {}", message);
+        let message = format!("cargo check crashed and was restarted: {}", message);
+        task_send.send(CheckTask::Error { message, kind: CheckErrorKind::Internal });
+        if task_send.is_disconnected() {
+            break;
+        }
+    }
+}
+
+/// The message of a panic, if it was started with one.
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic"
+    }
+}
+
+/// The error shown to the user for a check run with `config` which failed
+/// with `err`, other than being cancelled.
+fn check_error(
+    err: &CargoError,
+    config: &FlycheckConfig,
+    workspace_root: &Path,
+) -> (String, CheckErrorKind) {
+    match err {
+        CargoError::SpawnFailed(io_err) => {
+            let mut message = format!("cargo check could not be started: {}", io_err);
+            if io_err.kind() == io::ErrorKind::NotFound {
+                message.push('\n');
+                message.push_str(config.not_found_hint());
+            }
+            // Cargo is expected to be installed, custom commands and wrappers
+            // were configured.
+            let kind = match &config.command {
+                FlycheckCommand::CargoCommand { .. } if config.wrapper.is_empty() => {
+                    CheckErrorKind::Environment
+                }
+                _ => CheckErrorKind::Config,
+            };
+            (message, kind)
+        }
+        CargoError::NonZeroExit { stderr, .. } => {
+            if let Some(message) =
+                missing_clippy_message(stderr, || config.toolchain_name(workspace_root))
+            {
+                return (message, CheckErrorKind::Config);
+            }
+            if let Some(message) = config
+                .manifest_path(workspace_root)
+                .and_then(|manifest_path| broken_manifest_message(stderr, &manifest_path))
+            {
+                return (message, CheckErrorKind::Environment);
+            }
+            let kind = if rejects_any_flag(stderr) {
+                CheckErrorKind::Config
+            } else {
+                CheckErrorKind::Environment
+            };
+            (format!("cargo check failed: {}", err), kind)
+        }
+        CargoError::HookedCommandFailed { .. } => {
+            (format!("cargo check failed: {}", err), CheckErrorKind::Config)
+        }
+        CargoError::TimedOut(_) => {
+            (format!("cargo check timed out: {}", err), CheckErrorKind::Environment)
+        }
+        // Reading the output of the check failed, cancelled checks aren't
+        // reported at all.
+        CargoError::Io(_) | CargoError::Cancelled => {
+            (format!("cargo check failed: {}", err), CheckErrorKind::Internal)
+        }
+    }
+}
+
+/// Whether cargo rejected a flag of the check command, f.x. one of the
+/// `extra_args`.
+fn rejects_any_flag(stderr: &str) -> bool {
+    stderr.lines().any(|line| {
+        line.contains("wasn't expected")
+            || line.contains("unexpected argument")
+            || line.to_lowercase().contains("unrecognized option")
+    })
+}
+
+/// Quotes the error of cargo failing to read a manifest, together with the
+/// manifest it was pointed at, which isn't necessarily the broken one.
+fn broken_manifest_message(stderr: &str, manifest_path: &Path) -> Option<String> {
+    let start = stderr
+        .find("failed to parse manifest")
+        .or_else(|| stderr.find("failed to load manifest"))?;
+    let error: Vec<_> =
+        stderr[start..].lines().map(str::trim).filter(|line| !line.is_empty()).collect();
+    Some(format!(
+        "cargo check failed to read the manifest of the workspace at {}:\n{}",
+        manifest_path.display(),
+        error.join("\n")
+    ))
+}
+
+/// Tells how to install clippy if `stderr` shows that cargo doesn't know the
+/// `clippy` subcommand. `toolchain` is only asked for the name of the active
+/// toolchain then.
+fn missing_clippy_message(
+    stderr: &str,
+    toolchain: impl FnOnce() -> Option<String>,
+) -> Option<String> {
+    let missing = stderr.lines().any(|line| {
+        (line.contains("no such subcommand") || line.contains("no such command"))
+            && line.contains("clippy")
+    });
+    if !missing {
+        return None;
+    }
+    let install = match toolchain() {
+        Some(toolchain) => format!("rustup component add clippy --toolchain {}", toolchain),
+        None => "rustup component add clippy".to_string(),
+    };
+    Some(format!("cargo clippy is not installed, install it with `{}`", install))
+}
+
+/// Suggests a fix for cargo failures which are caused by the environment
+/// rather than by the code being checked.
+fn failure_hint(stderr: &str) -> Option<&'static str> {
+    if stderr.contains("--offline") {
+        return Some("hint: run `cargo fetch` to download the missing dependencies");
+    }
+    if stderr.contains("lock file") && stderr.contains("needs to be updated") {
+        return Some("hint: run `cargo update` or a build to bring `Cargo.lock` up to date");
+    }
+    if stderr.contains("toolchain") && stderr.contains("is not installed") {
+        return Some("hint: install the toolchain with `rustup toolchain install`");
+    }
+    None
+}
+
+/// Sends the diagnostics of `batch`, after clearing the ones the file had
+/// before if it has to.
+fn send_batch(batch: Batch, task_send: &TaskSender) {
+    let Batch { run, url, clear, mut diagnostics, .. } = batch;
+    sort_diagnostics(&url, &mut diagnostics);
+    if clear {
+        task_send.send(CheckTask::ClearForFile { run, url: url.clone() });
+    }
+    task_send.send(CheckTask::AddDiagnostics { run, url, diagnostics });
+}
+
+/// Sorts the diagnostics of `url` with [`compare_diagnostics`].
+fn sort_diagnostics(url: &Url, diagnostics: &mut [(Diagnostic, Vec<CodeActionOrCommand>)]) {
+    diagnostics.sort_by(|(a, _), (b, _)| compare_diagnostics((url, a), (url, b)));
+}
+
+/// Whether the diagnostics of the file `url` points to are replaced by the
+/// ones of a run limited to `scope`, a missing scope includes everything.
+fn is_in_scope(url: &Url, scope: Option<&RunScope>) -> bool {
+    match (scope, url.to_file_path()) {
+        (None, _) => true,
+        (Some(scope), Ok(path)) => scope.includes(&path),
+        (Some(_), Err(())) => false,
+    }
+}
+
+/// Checks whether `flag` is present in `args`, either on its own or in the
+/// `--flag=value` form, or like `-j4` for `-j`.
+fn has_flag(args: &[String], flag: &str) -> bool {
+    args.iter().any(|arg| arg == flag || attached_value(arg, flag).is_some())
+}
+
+/// Returns the value of `flag` in `args`, either from `--flag=value`, from
+/// `-j4` for `-j` or from the argument following `--flag`.
+fn flag_value<'a>(args: &'a [String], flag: &str) -> Option<&'a str> {
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == flag {
+            return args.next().map(|it| it.as_str());
+        }
+        if let Some(value) = attached_value(arg, flag) {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// Returns the value attached to `flag` in `arg`, like `4` in `--jobs=4`, or
+/// in `-j4` as cargo accepts it for the number of jobs.
+fn attached_value<'a>(arg: &'a str, flag: &str) -> Option<&'a str> {
+    if !arg.starts_with(flag) {
+        return None;
+    }
+    let rest = &arg[flag.len()..];
+    if rest.starts_with('=') {
+        Some(&rest[1..])
+    } else if flag == "-j" && !rest.is_empty() && rest.chars().all(|c| c.is_ascii_digit()) {
+        Some(rest)
+    } else {
+        None
+    }
+}
+
+/// Flags passed by us which take their value as a separate argument.
+const VALUE_FLAGS: &[&str] = &["--manifest-path", "--target", "-j", "--profile"];
+
+/// Drops the flags from `injected` which the user passes in `extra_args` as
+/// well, so cargo doesn't see them twice. For `--manifest-path` the user's
+/// path takes precedence, other values are checked to agree in `validate`.
+///
+/// Package selection and features are left alone, as cargo merges multiple
+/// occurrences of those.
+fn merge_args(injected: Vec<OsString>, extra_args: &[String]) -> Vec<OsString> {
+    let mut res = Vec::with_capacity(injected.len());
+    let mut injected = injected.into_iter();
+    while let Some(arg) = injected.next() {
+        let flag = match arg.to_str() {
+            Some(flag)
+                if flag.starts_with('-') && !["-p", "--exclude", "--features"].contains(&flag) =>
+            {
+                flag.split('=').next().unwrap_or(flag)
+            }
+            _ => {
+                res.push(arg);
+                continue;
+            }
+        };
+        let user_passes_it = match flag {
+            "-j" => has_flag(extra_args, "-j") || has_flag(extra_args, "--jobs"),
+            "--release" => has_flag(extra_args, "--release") || has_flag(extra_args, "--profile"),
+            "--profile" => has_flag(extra_args, "--profile") || has_flag(extra_args, "--release"),
+            _ => has_flag(extra_args, flag),
+        };
+        if !user_passes_it {
+            res.push(arg);
+            continue;
+        }
+        if VALUE_FLAGS.contains(&flag) && !arg.to_str().unwrap_or_default().contains('=') {
+            injected.next();
+        }
+    }
+    res
 }
 
-fn cargo_binary() -> String {
-    env::var("CARGO").unwrap_or_else(|_| "cargo".to_string())
+fn default_cargo_binary() -> PathBuf {
+    env::var_os("CARGO").map(PathBuf::from).unwrap_or_else(|| PathBuf::from("cargo"))
 }
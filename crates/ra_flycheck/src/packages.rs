@@ -0,0 +1,205 @@
+//! Finds the workspace members owning the changed files, so that a check can
+//! be limited to them, see `FlycheckConfig::check_changed_packages`, and
+//! leaves out the files which can't change the diagnostics at all.
+use std::{
+    mem,
+    path::{Path, PathBuf},
+};
+
+use cargo_metadata::Metadata;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+
+/// Files changed since the last check started.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Changes {
+    Nothing,
+    Files(Vec<PathBuf>),
+    /// The whole workspace has to be checked, f.x. because the update didn't
+    /// say what changed.
+    Everything,
+}
+
+impl Default for Changes {
+    fn default() -> Self {
+        Changes::Nothing
+    }
+}
+
+impl Changes {
+    /// The changes of an update of `files`, none meaning anything could have
+    /// changed.
+    pub(crate) fn of(files: Vec<PathBuf>) -> Changes {
+        if files.is_empty() {
+            Changes::Everything
+        } else {
+            Changes::Files(files)
+        }
+    }
+
+    pub(crate) fn add(&mut self, other: Changes) {
+        *self = match (mem::take(self), other) {
+            (Changes::Everything, _) | (_, Changes::Everything) => Changes::Everything,
+            (Changes::Nothing, other) | (other, Changes::Nothing) => other,
+            (Changes::Files(mut files), Changes::Files(other)) => {
+                for file in other {
+                    if !files.contains(&file) {
+                        files.push(file);
+                    }
+                }
+                Changes::Files(files)
+            }
+        };
+    }
+
+    /// Whether a manifest changed, so that the members or their features may
+    /// be different and the whole workspace has to be checked.
+    pub(crate) fn include_manifest(&self) -> bool {
+        match self {
+            Changes::Nothing => false,
+            Changes::Files(files) => files.iter().any(|file| is_manifest(file)),
+            Changes::Everything => true,
+        }
+    }
+}
+
+/// A workspace member and the directory of its manifest.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Package {
+    pub(crate) name: String,
+    pub(crate) root: PathBuf,
+}
+
+/// The workspace members according to `cargo metadata`, kept until a
+/// manifest changes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct PackageIndex {
+    packages: Vec<Package>,
+}
+
+impl PackageIndex {
+    pub(crate) fn from_metadata(metadata: &Metadata) -> PackageIndex {
+        let packages = metadata
+            .packages
+            .iter()
+            .filter(|package| metadata.workspace_members.contains(&package.id))
+            .filter_map(|package| {
+                let root = package.manifest_path.parent()?.to_path_buf();
+                Some(Package { name: package.name.clone(), root })
+            })
+            .collect();
+        PackageIndex { packages }
+    }
+
+    /// The members owning `files`, in the order of the metadata. `None` if
+    /// the whole workspace has to be checked, because a file belongs to no
+    /// member or is shared by all of them, like a manifest.
+    pub(crate) fn owners(&self, files: &[PathBuf]) -> Option<Vec<Package>> {
+        let mut owners: Vec<&Package> = Vec::new();
+        for file in files {
+            if is_manifest(file) {
+                return None;
+            }
+            let owner = self.owner(file)?;
+            if !owners.contains(&owner) {
+                owners.push(owner);
+            }
+        }
+        let owners = self.packages.iter().filter(|package| owners.contains(package));
+        Some(owners.cloned().collect())
+    }
+
+    /// The member owning `file`, `None` if it belongs to none.
+    pub(crate) fn owner(&self, file: &Path) -> Option<&Package> {
+        // Nested members own their files, not the one around them.
+        self.packages
+            .iter()
+            .filter(|package| file.starts_with(&package.root))
+            .max_by_key(|package| package.root.components().count())
+    }
+}
+
+/// The members a run is limited to, with the members of the workspace when
+/// it started, to tell which files the run can clear the diagnostics of.
+#[derive(Clone, Debug)]
+pub(crate) struct RunScope {
+    index: PackageIndex,
+    members: Vec<Package>,
+}
+
+impl RunScope {
+    pub(crate) fn new(index: PackageIndex, members: Vec<Package>) -> RunScope {
+        RunScope { index, members }
+    }
+
+    /// The directories of the members, see `CheckSummary::scope`.
+    pub(crate) fn roots(&self) -> Vec<PathBuf> {
+        self.members.iter().map(|package| package.root.clone()).collect()
+    }
+
+    /// Whether `file` belongs to one of the members, so that the run replaces
+    /// its diagnostics. Files which don't exist anymore belong to all of
+    /// them, as a file moved to another member leaves its old path behind,
+    /// and nothing reports diagnostics for it anymore. Members themselves
+    /// only change with the manifests, which checks the whole workspace.
+    pub(crate) fn includes(&self, file: &Path) -> bool {
+        if !file.exists() {
+            return true;
+        }
+        match self.index.owner(file) {
+            Some(owner) => self.members.contains(owner),
+            None => false,
+        }
+    }
+}
+
+/// Whether changing `file` can change the members or their dependencies.
+pub(crate) fn is_manifest(file: &Path) -> bool {
+    match file.file_name().and_then(|name| name.to_str()) {
+        Some(name) => name == "Cargo.toml" || name == "Cargo.lock",
+        None => false,
+    }
+}
+
+/// Decides which changed files can change the diagnostics, see
+/// `FlycheckConfig::watched_globs`.
+#[derive(Clone, Debug)]
+pub(crate) struct ChangeFilter {
+    workspace_root: PathBuf,
+    watched: GlobSet,
+}
+
+impl ChangeFilter {
+    /// Globs which can't be parsed are left out, `FlycheckConfig::validate`
+    /// reports them.
+    pub(crate) fn new(workspace_root: &Path, globs: &[String]) -> ChangeFilter {
+        let mut builder = GlobSetBuilder::new();
+        for glob in globs {
+            match Glob::new(glob) {
+                Ok(glob) => {
+                    builder.add(glob);
+                }
+                Err(err) => log::warn!("ignoring the watched glob `{}`: {}", glob, err),
+            }
+        }
+        let watched = builder.build().unwrap_or_else(|err| {
+            log::warn!("ignoring the watched globs: {}", err);
+            GlobSet::empty()
+        });
+        ChangeFilter { workspace_root: workspace_root.to_path_buf(), watched }
+    }
+
+    pub(crate) fn is_relevant(&self, file: &Path) -> bool {
+        // Only the manifests and build scripts of path dependencies outside
+        // the workspace root are relevant, their other Rust files aren't.
+        if is_manifest(file) || file.file_name().map_or(false, |name| name == "build.rs") {
+            return true;
+        }
+        match file.strip_prefix(&self.workspace_root) {
+            Ok(relative) => {
+                relative.extension().map_or(false, |ext| ext == "rs")
+                    || self.watched.is_match(relative)
+            }
+            Err(_) => false,
+        }
+    }
+}
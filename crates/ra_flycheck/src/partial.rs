@@ -0,0 +1,239 @@
+//! Partial check configurations, used to layer f.x. workspace settings on top
+//! of the user's global settings.
+use std::{path::PathBuf, time::Duration};
+
+#[cfg(feature = "serde")]
+use serde_crate::{Deserialize, Serialize};
+
+use crate::{
+    CheckScope, CheckTarget, CommandHook, FlycheckCommand, FlycheckConfig, PublishMode,
+    RestartBehavior,
+};
+
+/// Overrides for the options of a [`FlycheckConfig`], see
+/// [`FlycheckConfig::merge`]. `None` keeps the option of the merged config,
+/// so options can't be unset by an override.
+///
+/// Options of cargo commands are ignored when the merged config runs a custom
+/// command.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate", default)
+)]
+pub struct PartialFlycheckConfig {
+    /// Switches to running `command` with `args` instead of cargo.
+    pub custom_command: Option<(String, Vec<String>)>,
+
+    pub command: Option<String>,
+    pub cargo_binary: Option<PathBuf>,
+    pub toolchain: Option<String>,
+    pub packages: Option<ListOverride<String>>,
+    pub exclude: Option<ListOverride<String>>,
+    pub all_targets: Option<bool>,
+    pub targets: Option<ListOverride<CheckTarget>>,
+    pub scope: Option<CheckScope>,
+    pub target_triple: Option<String>,
+    pub profile: Option<String>,
+    pub jobs: Option<u32>,
+    pub offline: Option<bool>,
+    pub locked: Option<bool>,
+    pub frozen: Option<bool>,
+    pub keep_going: Option<bool>,
+    pub tests_only: Option<bool>,
+    pub no_default_features: Option<bool>,
+    pub all_features: Option<bool>,
+    pub features: Option<ListOverride<String>>,
+    pub extra_args: Option<ListOverride<String>>,
+    pub trailing_args: Option<ListOverride<String>>,
+    pub inject_message_format: Option<bool>,
+    pub legacy_workspace_flag: Option<bool>,
+
+    /// Appended variables take precedence over inherited ones with the same
+    /// name.
+    pub extra_env: Option<ListOverride<(String, String)>>,
+    pub unset_env: Option<ListOverride<String>>,
+    pub rustflags: Option<String>,
+    pub replace_rustflags: Option<bool>,
+    pub target_dir: Option<PathBuf>,
+    pub use_separate_target_dir: Option<bool>,
+    pub wrapper: Option<ListOverride<String>>,
+    pub working_dir: Option<PathBuf>,
+    pub incremental: Option<bool>,
+    pub sanitize_output: Option<bool>,
+    /// Replaces the secondary check as a whole.
+    pub secondary: Option<FlycheckConfig>,
+    pub idle_delay: Option<Duration>,
+    pub debounce: Option<Duration>,
+    pub max_debounce: Option<Duration>,
+    pub min_interval: Option<Duration>,
+    pub recheck_interval: Option<Duration>,
+    pub timeout: Option<Duration>,
+    pub retry_delay: Option<Duration>,
+    pub batch_delay: Option<Duration>,
+    pub low_priority: Option<bool>,
+    pub on_restart: Option<RestartBehavior>,
+    pub cancel_on_update: Option<bool>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub command_hook: Option<CommandHook>,
+    pub max_line_length: Option<usize>,
+    pub message_buffer: Option<usize>,
+    pub max_diagnostics: Option<usize>,
+    pub max_diagnostics_per_file: Option<usize>,
+    pub stop_at_diagnostic_limit: Option<bool>,
+    pub estimate_progress: Option<bool>,
+    pub report_future_incompat: Option<bool>,
+    pub termination_grace_period: Option<Duration>,
+    pub forward_stderr_to_log: Option<bool>,
+    pub check_changed_packages: Option<bool>,
+    pub check_on_start: Option<bool>,
+    pub watched_globs: Option<ListOverride<String>>,
+    pub skip_unchanged_files: Option<bool>,
+    pub publish_mode: Option<PublishMode>,
+    pub merge_duplicate_targets: Option<bool>,
+}
+
+/// How a list in a [`PartialFlycheckConfig`] is combined with the list it
+/// overrides.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate", rename_all = "snake_case")
+)]
+pub enum ListOverride<T> {
+    /// Drops the overridden items.
+    Replace(Vec<T>),
+    /// Keeps the overridden items, adding these after them.
+    Append(Vec<T>),
+}
+
+impl<T: Clone> ListOverride<T> {
+    fn apply(&self, list: &mut Vec<T>) {
+        match self {
+            ListOverride::Replace(items) => *list = items.clone(),
+            ListOverride::Append(items) => list.extend(items.iter().cloned()),
+        }
+    }
+}
+
+fn set<T: Clone>(slot: &mut T, value: &Option<T>) {
+    if let Some(value) = value {
+        *slot = value.clone();
+    }
+}
+
+fn set_some<T: Clone>(slot: &mut Option<T>, value: &Option<T>) {
+    if let Some(value) = value {
+        *slot = Some(value.clone());
+    }
+}
+
+fn apply<T: Clone>(list: &mut Vec<T>, value: &Option<ListOverride<T>>) {
+    if let Some(value) = value {
+        value.apply(list);
+    }
+}
+
+impl FlycheckConfig {
+    /// Returns this config with the options set in `overrides` replaced.
+    pub fn merge(&self, overrides: &PartialFlycheckConfig) -> FlycheckConfig {
+        let mut res = self.clone();
+        if let Some((command, args)) = &overrides.custom_command {
+            res.command =
+                FlycheckCommand::CustomCommand { command: command.clone(), args: args.clone() };
+        }
+        if let FlycheckCommand::CargoCommand {
+            command,
+            cargo_binary,
+            toolchain,
+            packages,
+            exclude,
+            all_targets,
+            targets,
+            scope,
+            target_triple,
+            profile,
+            jobs,
+            offline,
+            locked,
+            frozen,
+            keep_going,
+            tests_only,
+            no_default_features,
+            all_features,
+            features,
+            extra_args,
+            trailing_args,
+            inject_message_format,
+            legacy_workspace_flag,
+        } = &mut res.command
+        {
+            set(command, &overrides.command);
+            set_some(cargo_binary, &overrides.cargo_binary);
+            set_some(toolchain, &overrides.toolchain);
+            apply(packages, &overrides.packages);
+            apply(exclude, &overrides.exclude);
+            set(all_targets, &overrides.all_targets);
+            apply(targets, &overrides.targets);
+            set(scope, &overrides.scope);
+            set_some(target_triple, &overrides.target_triple);
+            set_some(profile, &overrides.profile);
+            set_some(jobs, &overrides.jobs);
+            set(offline, &overrides.offline);
+            set(locked, &overrides.locked);
+            set(frozen, &overrides.frozen);
+            set(keep_going, &overrides.keep_going);
+            set(tests_only, &overrides.tests_only);
+            set(no_default_features, &overrides.no_default_features);
+            set(all_features, &overrides.all_features);
+            apply(features, &overrides.features);
+            apply(extra_args, &overrides.extra_args);
+            apply(trailing_args, &overrides.trailing_args);
+            set(inject_message_format, &overrides.inject_message_format);
+            set(legacy_workspace_flag, &overrides.legacy_workspace_flag);
+        }
+        apply(&mut res.extra_env, &overrides.extra_env);
+        apply(&mut res.unset_env, &overrides.unset_env);
+        set_some(&mut res.rustflags, &overrides.rustflags);
+        set(&mut res.replace_rustflags, &overrides.replace_rustflags);
+        set_some(&mut res.target_dir, &overrides.target_dir);
+        set(&mut res.use_separate_target_dir, &overrides.use_separate_target_dir);
+        apply(&mut res.wrapper, &overrides.wrapper);
+        set_some(&mut res.working_dir, &overrides.working_dir);
+        set_some(&mut res.incremental, &overrides.incremental);
+        set(&mut res.sanitize_output, &overrides.sanitize_output);
+        if let Some(secondary) = &overrides.secondary {
+            res.secondary = Some(Box::new(secondary.clone()));
+        }
+        set(&mut res.idle_delay, &overrides.idle_delay);
+        set(&mut res.debounce, &overrides.debounce);
+        set(&mut res.max_debounce, &overrides.max_debounce);
+        set_some(&mut res.min_interval, &overrides.min_interval);
+        set_some(&mut res.recheck_interval, &overrides.recheck_interval);
+        set_some(&mut res.timeout, &overrides.timeout);
+        set_some(&mut res.retry_delay, &overrides.retry_delay);
+        set_some(&mut res.batch_delay, &overrides.batch_delay);
+        set(&mut res.low_priority, &overrides.low_priority);
+        set(&mut res.on_restart, &overrides.on_restart);
+        set(&mut res.cancel_on_update, &overrides.cancel_on_update);
+        set_some(&mut res.command_hook, &overrides.command_hook);
+        set(&mut res.max_line_length, &overrides.max_line_length);
+        set(&mut res.message_buffer, &overrides.message_buffer);
+        set_some(&mut res.max_diagnostics, &overrides.max_diagnostics);
+        set_some(&mut res.max_diagnostics_per_file, &overrides.max_diagnostics_per_file);
+        set(&mut res.stop_at_diagnostic_limit, &overrides.stop_at_diagnostic_limit);
+        set(&mut res.estimate_progress, &overrides.estimate_progress);
+        set(&mut res.report_future_incompat, &overrides.report_future_incompat);
+        set(&mut res.termination_grace_period, &overrides.termination_grace_period);
+        set(&mut res.forward_stderr_to_log, &overrides.forward_stderr_to_log);
+        set(&mut res.check_changed_packages, &overrides.check_changed_packages);
+        set(&mut res.check_on_start, &overrides.check_on_start);
+        apply(&mut res.watched_globs, &overrides.watched_globs);
+        set(&mut res.skip_unchanged_files, &overrides.skip_unchanged_files);
+        set(&mut res.publish_mode, &overrides.publish_mode);
+        set(&mut res.merge_duplicate_targets, &overrides.merge_duplicate_targets);
+        res
+    }
+}
@@ -0,0 +1,317 @@
+//! Runs the check in its own process group, so that killing it also stops the
+//! rustc and build script processes spawned by cargo. Otherwise those keep
+//! running and hold the lock on the target directory, blocking the next check.
+use std::{
+    io,
+    ops::{Deref, DerefMut},
+    process::{Child, Command, ExitStatus},
+    time::Duration,
+};
+
+/// A child process which is killed together with its descendants when it's
+/// dropped or [killed](ProcessGroupChild::kill). It's waited for once it's
+/// dropped at the latest, so that no zombie process is left behind.
+#[derive(Debug)]
+pub(crate) struct ProcessGroupChild {
+    child: Child,
+    /// Whether the exit status was collected already.
+    reaped: bool,
+    #[cfg(windows)]
+    job: imp::Job,
+}
+
+impl ProcessGroupChild {
+    /// Spawns `command`, running it and its descendants at a reduced priority
+    /// if `low_priority` is set. Failing to lower the priority is only logged.
+    pub(crate) fn spawn(
+        command: &mut Command,
+        low_priority: bool,
+    ) -> io::Result<ProcessGroupChild> {
+        imp::spawn(configure(command, low_priority), low_priority)
+    }
+
+    /// Kills the process and its descendants. Does nothing once the process
+    /// was waited for, as its id may belong to another process by then.
+    pub(crate) fn kill(&mut self) -> io::Result<()> {
+        if self.reaped {
+            return Ok(());
+        }
+        imp::kill(self)
+    }
+
+    /// Asks the process and its descendants to exit, so that cargo doesn't
+    /// leave broken incremental artifacts or lock files behind. They are
+    /// killed if the process is still running after `grace_period`, or right
+    /// away where they can't be asked.
+    pub(crate) fn terminate(&mut self, grace_period: Duration) -> io::Result<()> {
+        if self.reaped {
+            return Ok(());
+        }
+        imp::terminate(self, grace_period)
+    }
+
+    /// Waits for the process to exit. Shadows [`Child::wait`], so that the
+    /// process isn't waited for again when it's dropped.
+    pub(crate) fn wait(&mut self) -> io::Result<ExitStatus> {
+        let status = self.child.wait()?;
+        self.reaped = true;
+        Ok(status)
+    }
+
+    /// [Terminates](ProcessGroupChild::terminate) the process and waits for
+    /// it to exit. Meant for checks which are aborted, use
+    /// [`wait`](ProcessGroupChild::wait) to leave the descendants of a
+    /// finished check alone.
+    pub(crate) fn stop(&mut self, grace_period: Duration) -> io::Result<ExitStatus> {
+        // Only fails if the process is gone already.
+        let _ = self.terminate(grace_period);
+        self.wait()
+    }
+}
+
+/// Sets up `command` like every process started for the checks, f.x. so that
+/// no console window pops up for it on Windows. Already done by
+/// [`ProcessGroupChild::spawn`].
+pub(crate) fn configure(command: &mut Command, low_priority: bool) -> &mut Command {
+    imp::configure(command, low_priority)
+}
+
+#[cfg(all(windows, test))]
+pub(crate) use imp::creation_flags;
+
+impl Deref for ProcessGroupChild {
+    type Target = Child;
+    fn deref(&self) -> &Child {
+        &self.child
+    }
+}
+
+impl DerefMut for ProcessGroupChild {
+    fn deref_mut(&mut self) -> &mut Child {
+        &mut self.child
+    }
+}
+
+impl Drop for ProcessGroupChild {
+    fn drop(&mut self) {
+        // Processes which were started by the check and outlived it are
+        // stopped as well, unless the process was waited for already.
+        let _ = self.kill();
+        // Doesn't block for long, the process was just killed.
+        if !self.reaped {
+            let _ = self.child.wait();
+        }
+    }
+}
+
+#[cfg(unix)]
+mod imp {
+    use std::{
+        io, mem,
+        os::unix::process::CommandExt,
+        process::Command,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use super::ProcessGroupChild;
+
+    /// Niceness of low priority checks, the same `nice` uses by default.
+    const LOW_PRIORITY_NICENESS: libc::c_int = 10;
+
+    /// How often a terminated process is looked at to see whether it exited.
+    const EXIT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+    // The priority can only be lowered once the process exists, see `spawn`.
+    pub(super) fn configure(command: &mut Command, _low_priority: bool) -> &mut Command {
+        command
+    }
+
+    pub(super) fn spawn(
+        command: &mut Command,
+        low_priority: bool,
+    ) -> io::Result<ProcessGroupChild> {
+        // Safety: `setpgid` is async-signal-safe, so it can be called between
+        // `fork` and `exec`.
+        unsafe {
+            command.pre_exec(|| {
+                if libc::setpgid(0, 0) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let child = command.spawn()?;
+        if low_priority {
+            // Processes started by the child inherit its priority, and the
+            // ones it started already are part of its group.
+            let pgid = child.id() as libc::id_t;
+            if unsafe { libc::setpriority(libc::PRIO_PGRP, pgid, LOW_PRIORITY_NICENESS) } == -1 {
+                log::warn!(
+                    "couldn't lower the priority of the check: {}",
+                    io::Error::last_os_error()
+                );
+            }
+        }
+        Ok(ProcessGroupChild { child, reaped: false })
+    }
+
+    pub(super) fn kill(child: &mut ProcessGroupChild) -> io::Result<()> {
+        signal_group(child, libc::SIGKILL)
+    }
+
+    pub(super) fn terminate(
+        child: &mut ProcessGroupChild,
+        grace_period: Duration,
+    ) -> io::Result<()> {
+        signal_group(child, libc::SIGTERM)?;
+        let deadline = Instant::now() + grace_period;
+        while !has_exited(child)? && Instant::now() < deadline {
+            thread::sleep(EXIT_POLL_INTERVAL);
+        }
+        // Only checks which are aborted get here, so descendants ignoring the
+        // signal are killed even if the child exited. It's not waited for
+        // yet, so the group id wasn't reused.
+        signal_group(child, libc::SIGKILL)
+    }
+
+    /// Whether the child exited, without waiting for it, so that its id
+    /// stays reserved until [`ProcessGroupChild::wait`].
+    fn has_exited(child: &ProcessGroupChild) -> io::Result<bool> {
+        let pid = child.child.id() as libc::id_t;
+        // Only filled in if the child exited.
+        let mut info: libc::siginfo_t = unsafe { mem::zeroed() };
+        let options = libc::WEXITED | libc::WNOHANG | libc::WNOWAIT;
+        if unsafe { libc::waitid(libc::P_PID, pid, &mut info, options) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(info.si_signo == libc::SIGCHLD)
+    }
+
+    fn signal_group(child: &ProcessGroupChild, signal: libc::c_int) -> io::Result<()> {
+        // The group id is the pid of the child, see `spawn`.
+        let pgid = child.child.id() as libc::pid_t;
+        if unsafe { libc::killpg(pgid, signal) } == -1 {
+            let err = io::Error::last_os_error();
+            // The whole group has exited already.
+            if err.raw_os_error() != Some(libc::ESRCH) {
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(windows)]
+mod imp {
+    use std::{
+        io, mem,
+        os::windows::{io::AsRawHandle, process::CommandExt},
+        process::Command,
+        ptr,
+        time::Duration,
+    };
+
+    use winapi::{
+        shared::minwindef::{DWORD, LPVOID},
+        um::{
+            handleapi::CloseHandle,
+            jobapi2::{
+                AssignProcessToJobObject, CreateJobObjectW, SetInformationJobObject,
+                TerminateJobObject,
+            },
+            winbase::{BELOW_NORMAL_PRIORITY_CLASS, CREATE_NO_WINDOW},
+            winnt::{
+                JobObjectExtendedLimitInformation, HANDLE, JOBOBJECT_EXTENDED_LIMIT_INFORMATION,
+                JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE,
+            },
+        },
+    };
+
+    use super::ProcessGroupChild;
+
+    /// A job object killing all of its processes once it is closed.
+    #[derive(Debug)]
+    pub(super) struct Job(HANDLE);
+
+    // The handle is only used to terminate or close the job.
+    unsafe impl Send for Job {}
+
+    impl Job {
+        fn new() -> io::Result<Job> {
+            unsafe {
+                let handle = CreateJobObjectW(ptr::null_mut(), ptr::null());
+                if handle.is_null() {
+                    return Err(io::Error::last_os_error());
+                }
+                let job = Job(handle);
+                let mut info: JOBOBJECT_EXTENDED_LIMIT_INFORMATION = mem::zeroed();
+                info.BasicLimitInformation.LimitFlags = JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE;
+                let res = SetInformationJobObject(
+                    job.0,
+                    JobObjectExtendedLimitInformation,
+                    &mut info as *mut _ as LPVOID,
+                    mem::size_of_val(&info) as DWORD,
+                );
+                if res == 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(job)
+            }
+        }
+    }
+
+    impl Drop for Job {
+        fn drop(&mut self) {
+            unsafe { CloseHandle(self.0) };
+        }
+    }
+
+    /// The flags processes for the checks are created with, the priority is
+    /// inherited by the processes the child starts.
+    pub(crate) fn creation_flags(low_priority: bool) -> DWORD {
+        let mut flags = CREATE_NO_WINDOW;
+        if low_priority {
+            flags |= BELOW_NORMAL_PRIORITY_CLASS;
+        }
+        flags
+    }
+
+    pub(super) fn configure(command: &mut Command, low_priority: bool) -> &mut Command {
+        // Replaces any flags set before, so all of them are set at once.
+        command.creation_flags(creation_flags(low_priority))
+    }
+
+    pub(super) fn spawn(
+        command: &mut Command,
+        _low_priority: bool,
+    ) -> io::Result<ProcessGroupChild> {
+        let job = Job::new()?;
+        let mut child = command.spawn()?;
+        // Processes started by the child after this are part of the job as
+        // well. Cargo doesn't spawn anything before reading its arguments, so
+        // there's no race in practice.
+        if unsafe { AssignProcessToJobObject(job.0, child.as_raw_handle() as HANDLE) } == 0 {
+            let err = io::Error::last_os_error();
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(err);
+        }
+        Ok(ProcessGroupChild { child, job, reaped: false })
+    }
+
+    pub(super) fn kill(child: &mut ProcessGroupChild) -> io::Result<()> {
+        if unsafe { TerminateJobObject(child.job.0, 1) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    // Console programs can't be asked to exit without a console of their own.
+    pub(super) fn terminate(
+        child: &mut ProcessGroupChild,
+        _grace_period: Duration,
+    ) -> io::Result<()> {
+        kill(child)
+    }
+}
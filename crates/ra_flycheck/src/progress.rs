@@ -0,0 +1,198 @@
+//! Estimates how far a check has come. Before the check, `cargo metadata`
+//! tells which units cargo has to go through, each of them is reported as an
+//! artifact once it's done, even if it was fresh already.
+use std::{
+    ffi::OsString,
+    io::Read,
+    path::Path,
+    process::{Command, Stdio},
+    thread,
+};
+
+use cargo_metadata::{Metadata, Target};
+use crossbeam_channel::{bounded, RecvTimeoutError};
+
+use crate::{
+    default_cargo_binary, has_flag, process::ProcessGroupChild, CancellationToken, CheckScope,
+    CheckTarget, FlycheckCommand, FlycheckConfig, CANCELLATION_POLL_INTERVAL,
+};
+
+impl FlycheckConfig {
+    /// Whether the progress of the check is shown as a percentage, see
+    /// [`FlycheckConfig::estimate_progress`].
+    pub(crate) fn estimates_progress(&self) -> bool {
+        self.estimate_progress && matches!(self.command, FlycheckCommand::CargoCommand { .. })
+    }
+
+    /// Builds the `cargo metadata` command listing the packages of the check,
+    /// `None` for custom commands. The dependencies are left out unless
+    /// `with_deps` is set.
+    pub(crate) fn metadata_command(
+        &self,
+        workspace_root: &Path,
+        with_deps: bool,
+    ) -> Option<Result<Command, String>> {
+        let (cargo_binary, toolchain, offline, locked, frozen) = match &self.command {
+            FlycheckCommand::CargoCommand {
+                cargo_binary,
+                toolchain,
+                offline,
+                locked,
+                frozen,
+                ..
+            } => (cargo_binary, toolchain, *offline, *locked, *frozen),
+            FlycheckCommand::CustomCommand { .. } => return None,
+        };
+        let mut args: Vec<OsString> = Vec::new();
+        if let Some(toolchain) = toolchain {
+            args.push(format!("+{}", toolchain.trim_start_matches('+')).into());
+        }
+        args.push("metadata".into());
+        args.push("--format-version".into());
+        args.push("1".into());
+        args.push("--manifest-path".into());
+        args.push(self.manifest_path(workspace_root)?.into());
+        if !with_deps {
+            args.push("--no-deps".into());
+        }
+        if offline {
+            args.push("--offline".into());
+        }
+        if locked {
+            args.push("--locked".into());
+        }
+        if frozen {
+            args.push("--frozen".into());
+        }
+        let program = match cargo_binary {
+            Some(cargo_binary) => cargo_binary.clone(),
+            None => default_cargo_binary(),
+        };
+        Some(self.command_for(program, args, workspace_root))
+    }
+
+    /// Whether the check goes through the dependencies as well, which clippy
+    /// skips with `--no-deps`.
+    pub(crate) fn checks_dependencies(&self) -> bool {
+        match &self.command {
+            FlycheckCommand::CargoCommand { extra_args, trailing_args, .. } => {
+                !has_flag(extra_args, "--no-deps") && !has_flag(trailing_args, "--no-deps")
+            }
+            FlycheckCommand::CustomCommand { .. } => true,
+        }
+    }
+
+    /// Number of artifacts the check is expected to report according to
+    /// `metadata`. Only an estimate, f.x. dependencies of other platforms are
+    /// counted as well.
+    pub(crate) fn count_units(&self, metadata: &Metadata) -> usize {
+        let (packages, exclude) = match &self.command {
+            FlycheckCommand::CargoCommand { packages, exclude, .. } => (packages, exclude),
+            FlycheckCommand::CustomCommand { .. } => return 0,
+        };
+        let checked_targets = self.checked_targets();
+        let checks_dependencies = self.checks_dependencies();
+        let mut units = 0;
+        for package in &metadata.packages {
+            let is_member = metadata.workspace_members.contains(&package.id);
+            let is_selected = if packages.is_empty() {
+                !exclude.contains(&package.name)
+            } else {
+                packages.contains(&package.name)
+            };
+            let is_checked = is_member && is_selected;
+            let counted = package.targets.iter().filter(|target| match target_kind(target) {
+                TargetKind::BuildScript => is_checked || checks_dependencies,
+                TargetKind::Target(_) if !is_checked => checks_dependencies && is_lib(target),
+                TargetKind::Target(kind) => checked_targets.contains(&kind),
+            });
+            units += counted.count();
+        }
+        units
+    }
+
+    /// Kinds of targets of the workspace members which are checked.
+    fn checked_targets(&self) -> Vec<CheckTarget> {
+        let (all_targets, targets, scope, tests_only) = match &self.command {
+            FlycheckCommand::CargoCommand { all_targets, targets, scope, tests_only, .. } => {
+                (*all_targets, targets, *scope, *tests_only)
+            }
+            FlycheckCommand::CustomCommand { .. } => return Vec::new(),
+        };
+        match scope {
+            // The library and binaries are checked in test mode as well.
+            _ if tests_only => vec![CheckTarget::Lib, CheckTarget::Bins, CheckTarget::Tests],
+            CheckScope::Workspace if all_targets => CheckTarget::ALL.to_vec(),
+            // Cargo's default selection.
+            CheckScope::Workspace if targets.is_empty() => {
+                vec![CheckTarget::Lib, CheckTarget::Bins]
+            }
+            CheckScope::Workspace => targets.clone(),
+            CheckScope::LibOnly => vec![CheckTarget::Lib, CheckTarget::Bins],
+            CheckScope::AllTargets => CheckTarget::ALL.to_vec(),
+        }
+    }
+}
+
+enum TargetKind {
+    BuildScript,
+    Target(CheckTarget),
+}
+
+fn target_kind(target: &Target) -> TargetKind {
+    match target.kind.first().map(String::as_str) {
+        Some("custom-build") => TargetKind::BuildScript,
+        Some("bin") => TargetKind::Target(CheckTarget::Bins),
+        Some("test") => TargetKind::Target(CheckTarget::Tests),
+        Some("example") => TargetKind::Target(CheckTarget::Examples),
+        Some("bench") => TargetKind::Target(CheckTarget::Benches),
+        // `lib`, `rlib`, `proc-macro` and the other library kinds.
+        _ => TargetKind::Target(CheckTarget::Lib),
+    }
+}
+
+fn is_lib(target: &Target) -> bool {
+    matches!(target_kind(target), TargetKind::Target(CheckTarget::Lib))
+}
+
+/// Runs `command`, a `cargo metadata` command, until it's done or `cancel` is
+/// requested.
+pub(crate) fn run_metadata(
+    mut command: Command,
+    cancel: &CancellationToken,
+    low_priority: bool,
+) -> Result<Metadata, String> {
+    let mut child = ProcessGroupChild::spawn(
+        command.stdout(Stdio::piped()).stderr(Stdio::null()).stdin(Stdio::null()),
+        low_priority,
+    )
+    .map_err(|err| format!("failed to spawn {:?}: {}", command, err))?;
+    let mut stdout = child.stdout.take().unwrap();
+    let (output_send, output_recv) = bounded(1);
+    thread::spawn(move || {
+        let mut output = String::new();
+        let _ = output_send.send(stdout.read_to_string(&mut output).map(|_| output));
+    });
+    let output = loop {
+        match output_recv.recv_timeout(CANCELLATION_POLL_INTERVAL) {
+            _ if cancel.is_cancelled() => return Err("cancelled".to_string()),
+            Ok(output) => break output.map_err(|err| err.to_string())?,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => return Err("no output".to_string()),
+        }
+    };
+    let status = child.wait().map_err(|err| err.to_string())?;
+    if !status.success() {
+        return Err(format!("{:?} failed: {}", command, status));
+    }
+    serde_json::from_str(&output).map_err(|err| err.to_string())
+}
+
+/// The percentage of `total` units which are `done`, `None` if nothing is
+/// known about the total.
+pub(crate) fn percentage(done: usize, total: Option<usize>) -> Option<f64> {
+    match total {
+        Some(total) if total > 0 => Some((100.0 * done as f64 / total as f64).min(100.0)),
+        _ => None,
+    }
+}
@@ -0,0 +1,245 @@
+//! Decides when checks are started, so that the last update is always
+//! followed by exactly one complete check which started after it, however
+//! many updates arrive while checks are running.
+use std::time::{Duration, Instant};
+
+/// Updates closer together than this are part of a burst, even if the
+/// debounce delay is shorter.
+const BURST_GAP: Duration = Duration::from_millis(100);
+
+/// The delay before a check starts after an update. It doubles with every
+/// update arriving before the previous one's delay passed, f.x. during a
+/// branch switch, up to `max`, and is back to `base` once updates stop for
+/// long enough.
+#[derive(Clone, Debug)]
+pub(crate) struct Debounce {
+    base: Duration,
+    max: Duration,
+    current: Duration,
+    last_update: Option<Instant>,
+}
+
+impl Debounce {
+    /// A `max` which isn't above `base` keeps the delay fixed.
+    pub(crate) fn new(base: Duration, max: Duration) -> Debounce {
+        Debounce { base, max: max.max(base), current: base, last_update: None }
+    }
+
+    /// The delay for the last update.
+    pub(crate) fn delay(&self) -> Duration {
+        self.current
+    }
+
+    /// Records an update which arrived at `now`, returning its delay.
+    pub(crate) fn update(&mut self, now: Instant) -> Duration {
+        let in_burst = match self.last_update {
+            Some(last_update) => {
+                now.saturating_duration_since(last_update) < self.current.max(BURST_GAP)
+            }
+            None => false,
+        };
+        self.current = if in_burst && self.max > self.base {
+            (self.current * 2).max(BURST_GAP).min(self.max)
+        } else {
+            self.base
+        };
+        self.last_update = Some(now);
+        self.current
+    }
+}
+
+/// See [`Schedule`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum CheckState {
+    /// No check is running and none has to be started.
+    Idle,
+    /// A check has to be started once no update arrived for the debounce
+    /// delay.
+    Pending { last_update: Instant },
+    /// A check is running and no update arrived since it started.
+    Running,
+    /// An update arrived while the check was running, so another one has to
+    /// follow.
+    RunningWithPendingRerun { last_update: Instant },
+}
+
+/// The state machine behind the `debounce`, `cancel_on_update`,
+/// `min_interval` and `recheck_interval` options of the checks.
+/// `min_interval` wins, neither an update nor a check replacing the running
+/// one can start a check earlier.
+#[derive(Clone, Debug)]
+pub(crate) struct Schedule {
+    state: CheckState,
+    debounce: Debounce,
+    cancel_on_update: bool,
+    min_interval: Option<Duration>,
+    /// Checks again once no check ran for this long, counting from the end
+    /// of the last one. Nothing is checked before the first update.
+    recheck_interval: Option<Duration>,
+    /// When the last check started or ended, whichever was later.
+    last_run: Option<Instant>,
+    /// Updates wait for the running check to end even with
+    /// `cancel_on_update`, see [`Schedule::hold_reruns`].
+    holding_reruns: bool,
+    /// The pending check doesn't wait for the debounce delay or
+    /// `min_interval`, see [`Schedule::update_now`].
+    immediate: bool,
+    /// No check is due while paused, updates are still recorded.
+    paused: bool,
+}
+
+impl Schedule {
+    pub(crate) fn new(debounce: Debounce, cancel_on_update: bool) -> Schedule {
+        Schedule {
+            state: CheckState::Idle,
+            debounce,
+            cancel_on_update,
+            min_interval: None,
+            recheck_interval: None,
+            last_run: None,
+            holding_reruns: false,
+            immediate: false,
+            paused: false,
+        }
+    }
+
+    pub(crate) fn set_min_interval(&mut self, min_interval: Option<Duration>) {
+        self.min_interval = min_interval;
+    }
+
+    pub(crate) fn set_recheck_interval(&mut self, recheck_interval: Option<Duration>) {
+        self.recheck_interval = recheck_interval;
+    }
+
+    /// Changes the options of the following updates, the state is kept.
+    pub(crate) fn set_options(&mut self, debounce: Debounce, cancel_on_update: bool) {
+        self.debounce = debounce;
+        self.cancel_on_update = cancel_on_update;
+    }
+
+    pub(crate) fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    #[cfg(test)]
+    pub(crate) fn state(&self) -> CheckState {
+        self.state
+    }
+
+    /// Records an update which arrived at `now` and whose check starts as
+    /// soon as nothing else keeps it from starting, like a running check
+    /// without `cancel_on_update`. Later updates don't delay it.
+    pub(crate) fn update_now(&mut self, now: Instant) {
+        self.record_update(now);
+        self.immediate = true;
+    }
+
+    fn record_update(&mut self, now: Instant) {
+        self.state = match self.state {
+            CheckState::Idle | CheckState::Pending { .. } => {
+                CheckState::Pending { last_update: now }
+            }
+            CheckState::Running | CheckState::RunningWithPendingRerun { .. } => {
+                CheckState::RunningWithPendingRerun { last_update: now }
+            }
+        };
+    }
+
+    /// Whether a check has to be started for an update, now or later.
+    pub(crate) fn is_pending(&self) -> bool {
+        matches!(
+            self.state,
+            CheckState::Pending { .. } | CheckState::RunningWithPendingRerun { .. }
+        )
+    }
+
+    /// Records an update which arrived at `now`, returning how long to wait
+    /// before its check is due.
+    pub(crate) fn update(&mut self, now: Instant) -> Duration {
+        let delay = self.debounce.update(now);
+        self.record_update(now);
+        delay
+    }
+
+    /// How long after `now` the check for the last update or the periodic
+    /// check is due, `None` if none is, or only once the running check ended.
+    pub(crate) fn due_in(&self, now: Instant) -> Option<Duration> {
+        if self.paused {
+            return None;
+        }
+        let mut due = match self.state {
+            CheckState::Pending { last_update } => last_update + self.debounce.delay(),
+            CheckState::RunningWithPendingRerun { last_update }
+                if self.cancel_on_update && !self.holding_reruns =>
+            {
+                last_update + self.debounce.delay()
+            }
+            // Nothing changed, but the diagnostics may depend on more than
+            // the files, f.x. generated code.
+            CheckState::Idle => match (self.recheck_interval, self.last_run) {
+                (Some(recheck_interval), Some(last_run)) => last_run + recheck_interval,
+                _ => return None,
+            },
+            _ => return None,
+        };
+        if self.immediate {
+            return Some(Duration::from_secs(0));
+        }
+        if let (Some(min_interval), Some(last_run)) = (self.min_interval, self.last_run) {
+            due = due.max(last_run + min_interval);
+        }
+        Some(due.saturating_duration_since(now))
+    }
+
+    /// Whether a check for the last update is due at `now`. It's considered
+    /// running from then on, replacing the running one if there is one.
+    pub(crate) fn start_due(&mut self, now: Instant) -> bool {
+        if self.due_in(now) != Some(Duration::from_secs(0)) {
+            return false;
+        }
+        self.state = CheckState::Running;
+        self.last_run = Some(now);
+        self.holding_reruns = false;
+        self.immediate = false;
+        true
+    }
+
+    /// Records that a check started for another reason than an update, f.x.
+    /// the secondary check.
+    pub(crate) fn started(&mut self) {
+        self.state = match self.state {
+            CheckState::Idle | CheckState::Running => CheckState::Running,
+            CheckState::Pending { last_update }
+            | CheckState::RunningWithPendingRerun { last_update } => {
+                CheckState::RunningWithPendingRerun { last_update }
+            }
+        };
+    }
+
+    /// Keeps updates from replacing the running check until it ended, f.x.
+    /// a one-off check the user asked for.
+    pub(crate) fn hold_reruns(&mut self) {
+        self.holding_reruns = true;
+    }
+
+    /// Forgets about the last update, the running check is stopped.
+    pub(crate) fn cancel(&mut self) {
+        self.state = CheckState::Idle;
+        self.holding_reruns = false;
+        self.immediate = false;
+    }
+
+    /// Records that the running check ended at `now` or couldn't be
+    /// started.
+    pub(crate) fn finished(&mut self, now: Instant) {
+        self.last_run = Some(now);
+        self.holding_reruns = false;
+        self.state = match self.state {
+            CheckState::Idle | CheckState::Running => CheckState::Idle,
+            CheckState::Pending { last_update }
+            | CheckState::RunningWithPendingRerun { last_update } => {
+                CheckState::Pending { last_update }
+            }
+        };
+    }
+}
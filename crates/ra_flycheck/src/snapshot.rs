@@ -0,0 +1,72 @@
+//! Remembers the contents of the changed files the last successful check
+//! saw, so that saving a file without changing it, f.x. when formatting on
+//! save, doesn't start another check, see
+//! `FlycheckConfig::skip_unchanged_files`.
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    fs,
+    hash::{Hash, Hasher},
+    mem,
+    path::{Path, PathBuf},
+};
+
+/// Hashes of file contents, moving from `pending` to `running` when a check
+/// starts and to `checked` when it succeeds.
+#[derive(Debug, Default)]
+pub(crate) struct Snapshots {
+    pending: HashMap<PathBuf, u64>,
+    running: HashMap<PathBuf, u64>,
+    checked: HashMap<PathBuf, u64>,
+}
+
+impl Snapshots {
+    /// Records the contents of `files`, returning whether any of them differ
+    /// from what the pending or the last successful check saw. Files which
+    /// can't be read always count as changed.
+    pub(crate) fn update(&mut self, files: &[PathBuf]) -> bool {
+        let mut changed = false;
+        for file in files {
+            let hash = match hash_file(file) {
+                Some(hash) => hash,
+                None => {
+                    changed = true;
+                    continue;
+                }
+            };
+            let known = self.pending.get(file).or_else(|| self.checked.get(file));
+            if known != Some(&hash) {
+                changed = true;
+                self.pending.insert(file.clone(), hash);
+            }
+        }
+        changed
+    }
+
+    /// Records that a check started, including the pending contents as well
+    /// as the ones of the check it replaces, if any.
+    pub(crate) fn started(&mut self) {
+        let pending = mem::take(&mut self.pending);
+        self.running.extend(pending);
+    }
+
+    /// Records that the running check succeeded, so that its contents don't
+    /// have to be checked again.
+    pub(crate) fn succeeded(&mut self) {
+        let running = mem::take(&mut self.running);
+        self.checked.extend(running);
+    }
+
+    /// Forgets what was checked, after a check failed or was cancelled and
+    /// its diagnostics can be incomplete.
+    pub(crate) fn invalidate(&mut self) {
+        self.running.clear();
+        self.checked.clear();
+    }
+}
+
+fn hash_file(path: &Path) -> Option<u64> {
+    let contents = fs::read(path).ok()?;
+    let mut hasher = DefaultHasher::new();
+    contents.hash(&mut hasher);
+    Some(hasher.finish())
+}
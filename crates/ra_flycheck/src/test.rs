@@ -0,0 +1,5434 @@
+//! Tests for building and running the check process.
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    env,
+    ffi::OsString,
+    fs, io,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+use cargo_metadata::Message;
+use crossbeam_channel::{bounded, never, unbounded};
+use lsp_types::{Url, WorkDoneProgress};
+use tempfile::TempDir;
+
+use crate::{
+    batch::Batcher,
+    check_error, compare_diagnostics,
+    conv::{strip_ansi_codes, suppressed_diagnostics_marker},
+    end_message, has_flag, merge_args, missing_clippy_message,
+    packages::{ChangeFilter, Changes, PackageIndex, RunScope},
+    parse_command_line,
+    process::ProcessGroupChild,
+    progress::percentage,
+    read_bounded_line, rejects_flag, run_cargo, run_restarting,
+    schedule::{CheckState, Debounce, Schedule},
+    snapshot::Snapshots,
+    started_crate, unparsed_output_warning, BoundedLine, CancellationToken, CargoError,
+    CargoMessage, CheckActivity, CheckCommand, CheckErrorKind, CheckOutcome, CheckRunId,
+    CheckScope, CheckStatus, CheckSummary, CheckTarget, CheckTask, ConfigError, DiagnosticCounts,
+    Flycheck, FlycheckCommand, FlycheckConfig, ListOverride, ParseError, PartialFlycheckConfig,
+    PublishMode, RestartBehavior, RunOptions, SeverityCounts, TaskSender,
+};
+
+#[cfg(feature = "fs-watch")]
+use crate::watch::IgnoredFiles;
+
+fn cargo_command() -> FlycheckConfig {
+    FlycheckConfig::default()
+}
+
+fn strings(items: &[&str]) -> Vec<String> {
+    items.iter().map(|it| it.to_string()).collect()
+}
+
+fn check_args(config: &FlycheckConfig) -> Vec<String> {
+    let (_program, args) = config.command_line(Path::new("/ws"));
+    args.into_iter().map(|it| it.into_string().unwrap()).collect()
+}
+
+/// Arguments after the fixed `check --workspace --message-format=json
+/// --manifest-path <path>` prefix.
+fn extra_check_args(config: &FlycheckConfig) -> Vec<String> {
+    check_args(config).split_off(5)
+}
+
+/// Returns a command running `script` with `sh`, standing in for cargo.
+fn fake_cargo(dir: &TempDir, script: &str) -> Command {
+    let path = dir.path().join("fake-cargo.sh");
+    fs::write(&path, script).unwrap();
+    let mut cmd = Command::new("sh");
+    cmd.arg(path);
+    cmd
+}
+
+fn manifest_path() -> String {
+    Path::new("/ws").join("Cargo.toml").to_str().unwrap().to_string()
+}
+
+#[test]
+fn no_features() {
+    assert_eq!(
+        check_args(&cargo_command()),
+        vec!["check", "--workspace", "--message-format=json", "--manifest-path", &manifest_path()]
+    );
+}
+
+#[test]
+fn single_feature() {
+    let mut config = cargo_command();
+    if let FlycheckCommand::CargoCommand { features, .. } = &mut config.command {
+        *features = strings(&["foo"]);
+    }
+    assert_eq!(extra_check_args(&config), vec!["--features", "foo"]);
+}
+
+#[test]
+fn multiple_features() {
+    let mut config = cargo_command();
+    if let FlycheckCommand::CargoCommand { features, extra_args, .. } = &mut config.command {
+        *features = strings(&["foo", "bar", "baz"]);
+        *extra_args = strings(&["-v"]);
+    }
+    assert_eq!(extra_check_args(&config), vec!["--features", "foo,bar,baz", "-v"]);
+}
+
+#[test]
+fn features_in_extra_args_take_precedence() {
+    let mut config = cargo_command();
+    if let FlycheckCommand::CargoCommand { features, extra_args, .. } = &mut config.command {
+        *features = strings(&["foo"]);
+        *extra_args = strings(&["--features", "bar"]);
+    }
+    assert_eq!(extra_check_args(&config), vec!["--features", "bar"]);
+
+    if let FlycheckCommand::CargoCommand { extra_args, .. } = &mut config.command {
+        *extra_args = strings(&["--features=bar"]);
+    }
+    assert_eq!(extra_check_args(&config), vec!["--features=bar"]);
+}
+
+#[test]
+fn all_features() {
+    let mut config = cargo_command();
+    if let FlycheckCommand::CargoCommand { all_features, .. } = &mut config.command {
+        *all_features = true;
+    }
+    assert_eq!(extra_check_args(&config), vec!["--all-features"]);
+}
+
+#[test]
+fn all_features_overrides_features() {
+    let mut config = cargo_command();
+    if let FlycheckCommand::CargoCommand { all_features, features, all_targets, .. } =
+        &mut config.command
+    {
+        *all_features = true;
+        *all_targets = true;
+        *features = strings(&["foo", "bar"]);
+    }
+    assert_eq!(extra_check_args(&config), vec!["--all-targets", "--all-features"]);
+}
+
+#[test]
+fn no_default_features() {
+    let mut config = cargo_command();
+    if let FlycheckCommand::CargoCommand { no_default_features, .. } = &mut config.command {
+        *no_default_features = true;
+    }
+    assert_eq!(extra_check_args(&config), vec!["--no-default-features"]);
+}
+
+#[test]
+fn no_default_features_with_features() {
+    let mut config = cargo_command();
+    if let FlycheckCommand::CargoCommand { no_default_features, features, extra_args, .. } =
+        &mut config.command
+    {
+        *no_default_features = true;
+        *features = strings(&["fast-backend"]);
+        *extra_args = strings(&["-v"]);
+    }
+    assert_eq!(
+        extra_check_args(&config),
+        vec!["--no-default-features", "--features", "fast-backend", "-v"]
+    );
+}
+
+#[test]
+fn all_features_overrides_no_default_features() {
+    let mut config = cargo_command();
+    if let FlycheckCommand::CargoCommand { no_default_features, all_features, .. } =
+        &mut config.command
+    {
+        *no_default_features = true;
+        *all_features = true;
+    }
+    assert_eq!(extra_check_args(&config), vec!["--all-features"]);
+}
+
+#[test]
+fn targets() {
+    let cases: &[(&[CheckTarget], &[&str])] = &[
+        (&[], &[]),
+        (&[CheckTarget::Tests], &["--tests"]),
+        (&[CheckTarget::Lib, CheckTarget::Bins], &["--lib", "--bins"]),
+        (&[CheckTarget::Benches, CheckTarget::Lib, CheckTarget::Benches], &["--lib", "--benches"]),
+        (
+            &[CheckTarget::Examples, CheckTarget::Tests, CheckTarget::Bins, CheckTarget::Lib],
+            &["--lib", "--bins", "--tests", "--examples"],
+        ),
+    ];
+    for (kinds, expected) in cases {
+        let mut config = cargo_command();
+        if let FlycheckCommand::CargoCommand { targets, .. } = &mut config.command {
+            *targets = kinds.to_vec();
+        }
+        assert_eq!(extra_check_args(&config), strings(expected));
+    }
+}
+
+#[test]
+fn all_targets_overrides_targets() {
+    let mut config = cargo_command();
+    if let FlycheckCommand::CargoCommand { all_targets, targets, .. } = &mut config.command {
+        *all_targets = true;
+        *targets = vec![CheckTarget::Lib];
+    }
+    assert_eq!(extra_check_args(&config), vec!["--all-targets"]);
+}
+
+#[test]
+fn scopes() {
+    let cases: &[(CheckScope, bool, &[&str])] = &[
+        (CheckScope::Workspace, false, &["--tests"]),
+        (CheckScope::Workspace, true, &["--all-targets"]),
+        (CheckScope::LibOnly, false, &["--lib", "--bins"]),
+        (CheckScope::LibOnly, true, &["--lib", "--bins"]),
+        (CheckScope::AllTargets, false, &["--all-targets"]),
+    ];
+    for &(new_scope, new_all_targets, expected) in cases {
+        let mut config = cargo_command();
+        if let FlycheckCommand::CargoCommand { scope, all_targets, targets, .. } =
+            &mut config.command
+        {
+            *scope = new_scope;
+            *all_targets = new_all_targets;
+            *targets = vec![CheckTarget::Tests];
+        }
+        assert_eq!(extra_check_args(&config), strings(expected), "{:?}", new_scope);
+    }
+}
+
+#[test]
+fn trailing_args_follow_a_separator() {
+    let mut config = cargo_command();
+    if let FlycheckCommand::CargoCommand { command, extra_args, trailing_args, .. } =
+        &mut config.command
+    {
+        *command = "clippy".to_string();
+        *extra_args = strings(&["--locked"]);
+        *trailing_args =
+            strings(&["-W", "clippy::pedantic", "-A", "clippy::module_name_repetitions"]);
+    }
+    assert_eq!(
+        extra_check_args(&config),
+        vec!["--locked", "--", "-W", "clippy::pedantic", "-A", "clippy::module_name_repetitions"]
+    );
+}
+
+#[test]
+fn separator_is_only_added_once() {
+    let mut config = cargo_command();
+    if let FlycheckCommand::CargoCommand { extra_args, trailing_args, .. } = &mut config.command {
+        *extra_args = strings(&["--", "-D", "warnings"]);
+        *trailing_args = strings(&["-W", "clippy::pedantic"]);
+    }
+    assert_eq!(extra_check_args(&config), vec!["--", "-D", "warnings", "-W", "clippy::pedantic"]);
+}
+
+#[test]
+fn no_separator_without_trailing_args() {
+    let mut config = cargo_command();
+    if let FlycheckCommand::CargoCommand { extra_args, .. } = &mut config.command {
+        *extra_args = strings(&["--locked"]);
+    }
+    assert_eq!(extra_check_args(&config), vec!["--locked"]);
+}
+
+#[test]
+fn message_format_can_be_left_to_the_command() {
+    let mut config = cargo_command();
+    if let FlycheckCommand::CargoCommand { inject_message_format, extra_args, .. } =
+        &mut config.command
+    {
+        *inject_message_format = false;
+        *extra_args = strings(&["--message-format=json-diagnostic-rendered-ansi"]);
+    }
+    assert_eq!(config.validate(), Ok(()));
+    assert_eq!(
+        check_args(&config),
+        vec![
+            "check",
+            "--workspace",
+            "--manifest-path",
+            &manifest_path(),
+            "--message-format=json-diagnostic-rendered-ansi"
+        ]
+    );
+}
+
+#[test]
+fn ansi_codes_are_stripped() {
+    assert_eq!(strip_ansi_codes("no colors"), "no colors");
+    assert_eq!(
+        strip_ansi_codes("\x1b[0m\x1b[1m\x1b[33mwarning\x1b[0m\x1b[0m: unused"),
+        "warning: unused"
+    );
+    assert_eq!(strip_ansi_codes("\x1b[38;5;12m-->\x1b[0m src/lib.rs"), "--> src/lib.rs");
+    assert_eq!(strip_ansi_codes("trailing \x1b["), "trailing ");
+}
+
+#[test]
+fn keep_going() {
+    let config = FlycheckConfig::builder().keep_going(true).build();
+    assert_eq!(extra_check_args(&config), vec!["--keep-going"]);
+}
+
+#[test]
+fn tests_only() {
+    let config =
+        FlycheckConfig::builder().tests_only(true).all_targets(true).profile("release").build();
+    assert_eq!(extra_check_args(&config), vec!["--tests", "--profile", "test"]);
+}
+
+#[test]
+fn keep_going_rejections_are_detected() {
+    assert!(rejects_flag(
+        "error: Found argument '--keep-going' which wasn't expected, or isn't valid in this context",
+        "--keep-going"
+    ));
+    assert!(rejects_flag("error: unexpected argument '--keep-going' found", "--keep-going"));
+    assert!(rejects_flag(
+        "error: the `--keep-going` flag is unstable, pass `-Z unstable-options` to enable it",
+        "--keep-going"
+    ));
+    assert!(!rejects_flag("error: could not compile `foo`", "--keep-going"));
+}
+
+#[test]
+fn workspace_rejections_are_detected() {
+    assert!(rejects_flag("error: Unrecognized option: 'workspace'", "workspace"));
+    assert!(rejects_flag("error: unrecognized option '--workspace'", "--workspace"));
+    assert!(!rejects_flag("error: unrecognized option '--offline'", "--workspace"));
+}
+
+#[test]
+fn legacy_workspace_flag() {
+    let config = FlycheckConfig::builder().legacy_workspace_flag(true).build();
+    assert_eq!(check_args(&config)[1], "--all");
+    let config = config.without_flag("--workspace");
+    assert_eq!(check_args(&config)[1], "--all");
+    let config = FlycheckConfig::builder().build().without_flag("--workspace");
+    assert_eq!(check_args(&config)[1], "--all");
+}
+
+#[test]
+fn only_the_tests_only_config_drops_its_profile() {
+    let config = FlycheckConfig::builder().profile("release").build();
+    assert_eq!(config.without_flag("--profile"), config);
+    let config = FlycheckConfig::builder().tests_only(true).profile("release").build();
+    assert_eq!(extra_check_args(&config.without_flag("--profile")), vec!["--tests"]);
+}
+
+#[test]
+fn target_triple() {
+    let mut config = cargo_command();
+    if let FlycheckCommand::CargoCommand { target_triple, all_targets, features, .. } =
+        &mut config.command
+    {
+        *target_triple = Some("thumbv7em-none-eabihf".to_string());
+        *all_targets = true;
+        *features = strings(&["foo"]);
+    }
+    assert_eq!(
+        extra_check_args(&config),
+        vec!["--all-targets", "--target", "thumbv7em-none-eabihf", "--features", "foo"]
+    );
+}
+
+#[test]
+fn profiles() {
+    let profile_args = |profile: &str| {
+        let mut config = cargo_command();
+        if let FlycheckCommand::CargoCommand { profile: p, all_targets, extra_args, .. } =
+            &mut config.command
+        {
+            *p = Some(profile.to_string());
+            *all_targets = true;
+            *extra_args = strings(&["-v"]);
+        }
+        extra_check_args(&config)
+    };
+    assert_eq!(profile_args("dev"), vec!["--all-targets", "-v"]);
+    assert_eq!(profile_args("release"), vec!["--all-targets", "--release", "-v"]);
+    assert_eq!(profile_args("editor"), vec!["--all-targets", "--profile", "editor", "-v"]);
+}
+
+#[test]
+fn packages_replace_workspace() {
+    let mut config = cargo_command();
+    if let FlycheckCommand::CargoCommand { packages, all_targets, .. } = &mut config.command {
+        *packages = strings(&["foo", "bar"]);
+        *all_targets = true;
+    }
+    assert_eq!(
+        check_args(&config),
+        vec![
+            "check",
+            "-p",
+            "foo",
+            "-p",
+            "bar",
+            "--message-format=json",
+            "--manifest-path",
+            &manifest_path(),
+            "--all-targets"
+        ]
+    );
+}
+
+#[test]
+fn builder_defaults_to_cargo_check() {
+    let config = FlycheckConfig::builder().build();
+    assert_eq!(config, FlycheckConfig::default());
+    assert_eq!(
+        check_args(&config),
+        vec!["check", "--workspace", "--message-format=json", "--manifest-path", &manifest_path()]
+    );
+    assert_eq!(config.extra_env, Vec::new());
+    assert_eq!(config.wrapper, Vec::<String>::new());
+}
+
+#[test]
+fn builder_overrides_defaults() {
+    let config = FlycheckConfig::builder()
+        .command("clippy")
+        .all_targets(true)
+        .profile("release")
+        .jobs(4)
+        .use_separate_target_dir(true)
+        .build();
+    assert_eq!(extra_check_args(&config), vec!["--all-targets", "-j", "4", "--release"]);
+    assert_eq!(check_args(&config)[0], "clippy");
+    assert!(config.use_separate_target_dir);
+}
+
+#[test]
+fn builder_custom_command_ignores_cargo_options() {
+    let config = FlycheckConfig::builder()
+        .custom_command("./x.py", strings(&["check", "--json-output"]))
+        .all_targets(true)
+        .rustflags("-Dwarnings")
+        .build();
+    assert_eq!(
+        config.command,
+        FlycheckCommand::CustomCommand {
+            command: "./x.py".to_string(),
+            args: strings(&["check", "--json-output"])
+        }
+    );
+    assert_eq!(config.rustflags.as_deref(), Some("-Dwarnings"));
+}
+
+#[test]
+fn empty_overrides_keep_the_config() {
+    let config = FlycheckConfig::builder()
+        .command("clippy")
+        .features(strings(&["foo"]))
+        .rustflags("--cfg editor")
+        .build();
+    assert_eq!(config.merge(&PartialFlycheckConfig::default()), config);
+}
+
+#[test]
+fn overrides_replace_options() {
+    let secondary = FlycheckConfig::builder().command("clippy").build();
+    let overrides = PartialFlycheckConfig {
+        command: Some("clippy".to_string()),
+        cargo_binary: Some("/opt/cargo".into()),
+        toolchain: Some("nightly".to_string()),
+        all_targets: Some(true),
+        scope: Some(CheckScope::LibOnly),
+        target_triple: Some("wasm32-unknown-unknown".to_string()),
+        profile: Some("release".to_string()),
+        jobs: Some(2),
+        offline: Some(true),
+        locked: Some(true),
+        frozen: Some(true),
+        keep_going: Some(true),
+        tests_only: Some(true),
+        no_default_features: Some(true),
+        all_features: Some(true),
+        inject_message_format: Some(false),
+        rustflags: Some("--cfg editor".to_string()),
+        replace_rustflags: Some(true),
+        target_dir: Some("target/ra".into()),
+        use_separate_target_dir: Some(true),
+        working_dir: Some("..".into()),
+        incremental: Some(false),
+        sanitize_output: Some(false),
+        secondary: Some(secondary.clone()),
+        idle_delay: Some(Duration::from_secs(5)),
+        ..PartialFlycheckConfig::default()
+    };
+    let expected = FlycheckConfig::builder()
+        .command("clippy")
+        .cargo_binary("/opt/cargo")
+        .toolchain("nightly")
+        .all_targets(true)
+        .scope(CheckScope::LibOnly)
+        .target_triple("wasm32-unknown-unknown")
+        .profile("release")
+        .jobs(2)
+        .offline(true)
+        .locked(true)
+        .frozen(true)
+        .keep_going(true)
+        .tests_only(true)
+        .no_default_features(true)
+        .all_features(true)
+        .inject_message_format(false)
+        .rustflags("--cfg editor")
+        .replace_rustflags(true)
+        .target_dir("target/ra")
+        .use_separate_target_dir(true)
+        .working_dir("..")
+        .incremental(false)
+        .sanitize_output(false)
+        .secondary(secondary)
+        .idle_delay(Duration::from_secs(5))
+        .build();
+    assert_eq!(cargo_command().merge(&overrides), expected);
+}
+
+#[test]
+fn list_overrides_replace_or_append() {
+    let config = FlycheckConfig::builder()
+        .packages(strings(&["a"]))
+        .exclude(strings(&["b"]))
+        .targets(vec![CheckTarget::Lib])
+        .features(strings(&["c"]))
+        .extra_args(strings(&["-v"]))
+        .trailing_args(strings(&["-W", "x"]))
+        .extra_env(vec![("A".to_string(), "1".to_string())])
+        .unset_env(strings(&["B"]))
+        .wrapper(strings(&["nice"]))
+        .build();
+
+    let replace = |items: &[&str]| Some(ListOverride::Replace(strings(items)));
+    let overrides = PartialFlycheckConfig {
+        packages: replace(&["d"]),
+        exclude: replace(&[]),
+        targets: Some(ListOverride::Replace(vec![CheckTarget::Tests])),
+        features: replace(&["e"]),
+        extra_args: replace(&["-q"]),
+        trailing_args: replace(&["-D", "y"]),
+        extra_env: Some(ListOverride::Replace(vec![("C".to_string(), "2".to_string())])),
+        unset_env: replace(&["D"]),
+        wrapper: replace(&["ionice"]),
+        ..PartialFlycheckConfig::default()
+    };
+    let expected = FlycheckConfig::builder()
+        .packages(strings(&["d"]))
+        .targets(vec![CheckTarget::Tests])
+        .features(strings(&["e"]))
+        .extra_args(strings(&["-q"]))
+        .trailing_args(strings(&["-D", "y"]))
+        .extra_env(vec![("C".to_string(), "2".to_string())])
+        .unset_env(strings(&["D"]))
+        .wrapper(strings(&["ionice"]))
+        .build();
+    assert_eq!(config.merge(&overrides), expected);
+
+    let append = |items: &[&str]| Some(ListOverride::Append(strings(items)));
+    let overrides = PartialFlycheckConfig {
+        packages: append(&["d"]),
+        exclude: append(&["f"]),
+        targets: Some(ListOverride::Append(vec![CheckTarget::Tests])),
+        features: append(&["e"]),
+        extra_args: append(&["-q"]),
+        trailing_args: append(&["-D", "y"]),
+        extra_env: Some(ListOverride::Append(vec![("A".to_string(), "2".to_string())])),
+        unset_env: append(&["D"]),
+        wrapper: append(&["-n", "10"]),
+        ..PartialFlycheckConfig::default()
+    };
+    let expected = FlycheckConfig::builder()
+        .packages(strings(&["a", "d"]))
+        .exclude(strings(&["b", "f"]))
+        .targets(vec![CheckTarget::Lib, CheckTarget::Tests])
+        .features(strings(&["c", "e"]))
+        .extra_args(strings(&["-v", "-q"]))
+        .trailing_args(strings(&["-W", "x", "-D", "y"]))
+        .extra_env(vec![("A".to_string(), "1".to_string()), ("A".to_string(), "2".to_string())])
+        .unset_env(strings(&["B", "D"]))
+        .wrapper(strings(&["nice", "-n", "10"]))
+        .build();
+    assert_eq!(config.merge(&overrides), expected);
+}
+
+#[test]
+fn overrides_can_switch_to_a_custom_command() {
+    let overrides = PartialFlycheckConfig {
+        custom_command: Some(("./x.py".to_string(), strings(&["check"]))),
+        features: Some(ListOverride::Append(strings(&["foo"]))),
+        rustflags: Some("--cfg editor".to_string()),
+        ..PartialFlycheckConfig::default()
+    };
+    let merged = cargo_command().merge(&overrides);
+    assert_eq!(
+        merged,
+        FlycheckConfig::builder()
+            .custom_command("./x.py", strings(&["check"]))
+            .rustflags("--cfg editor")
+            .build()
+    );
+    // Cargo options don't switch back to cargo.
+    let overrides = PartialFlycheckConfig {
+        command: Some("clippy".to_string()),
+        ..PartialFlycheckConfig::default()
+    };
+    assert_eq!(merged.merge(&overrides), merged);
+}
+
+#[test]
+fn command_line_is_split_at_whitespace() {
+    assert_eq!(parse_command_line("").unwrap(), Vec::<String>::new());
+    assert_eq!(parse_command_line("  clippy \t -v\n").unwrap(), strings(&["clippy", "-v"]));
+}
+
+#[test]
+fn command_line_quotes() {
+    assert_eq!(
+        parse_command_line(r#"clippy --features "foo bar" --target-dir '/tmp/my dir'"#).unwrap(),
+        strings(&["clippy", "--features", "foo bar", "--target-dir", "/tmp/my dir"])
+    );
+    assert_eq!(parse_command_line(r#"--cfg="a b"c"#).unwrap(), strings(&["--cfg=a bc"]));
+    assert_eq!(parse_command_line(r#"a "" ''"#).unwrap(), strings(&["a", "", ""]));
+    assert_eq!(parse_command_line(r#"'"' "'""#).unwrap(), strings(&["\"", "'"]));
+    assert_eq!(parse_command_line(r#"'a\"b'"#).unwrap(), strings(&[r#"a\"b"#]));
+}
+
+#[test]
+fn command_line_backslashes() {
+    assert_eq!(parse_command_line(r#"a\ b c"#).unwrap(), strings(&["a b", "c"]));
+    assert_eq!(parse_command_line(r#"\"a\'"#).unwrap(), strings(&["\"a'"]));
+    assert_eq!(parse_command_line(r#""a\"b\\c\d""#).unwrap(), strings(&[r#"a"b\c\d"#]));
+}
+
+#[test]
+fn command_line_errors() {
+    assert_eq!(parse_command_line(r#"check\"#), Err(ParseError::TrailingBackslash));
+    assert_eq!(parse_command_line(r#"check "foo"#), Err(ParseError::UnterminatedQuote('"')));
+    assert_eq!(parse_command_line(r#"check "foo\"#), Err(ParseError::UnterminatedQuote('"')));
+    assert_eq!(parse_command_line("check 'foo"), Err(ParseError::UnterminatedQuote('\'')));
+    assert_eq!(FlycheckConfig::from_command_line("  "), Err(ParseError::EmptyCommand));
+}
+
+#[test]
+fn config_from_command_line() {
+    let config = FlycheckConfig::from_command_line(r#"clippy --features "foo bar""#).unwrap();
+    assert_eq!(
+        config,
+        FlycheckConfig::builder()
+            .command("clippy")
+            .extra_args(strings(&["--features", "foo bar"]))
+            .build()
+    );
+}
+
+/// Returns the `--manifest-path` argument for a workspace at `root`.
+fn manifest_arg(root: &str) -> String {
+    let (_program, args) = cargo_command().command_line(Path::new(root));
+    args[4].to_str().unwrap().to_string()
+}
+
+#[test]
+#[cfg(unix)]
+fn manifest_path_is_joined_to_unix_roots() {
+    assert_eq!(manifest_arg("/ws/"), "/ws/Cargo.toml");
+    assert_eq!(manifest_arg("/home/me/my project"), "/home/me/my project/Cargo.toml");
+}
+
+#[test]
+#[cfg(windows)]
+fn manifest_path_is_joined_to_windows_roots() {
+    assert_eq!(manifest_arg(r"C:\ws\"), r"C:\ws\Cargo.toml");
+    assert_eq!(manifest_arg(r"C:\Users\me\my project"), r"C:\Users\me\my project\Cargo.toml");
+}
+
+#[test]
+fn missing_manifest_is_reported() {
+    let dir = TempDir::new().unwrap();
+    let root = dir.path().join("my project");
+    fs::create_dir(&root).unwrap();
+    let err = cargo_command().to_command(&root).unwrap_err();
+    assert_eq!(
+        err,
+        format!("can't run the check, {} does not exist", root.join("Cargo.toml").display())
+    );
+
+    fs::write(root.join("Cargo.toml"), "").unwrap();
+    assert!(cargo_command().to_command(&root).is_ok());
+}
+
+#[test]
+fn jobs() {
+    let mut config = cargo_command();
+    if let FlycheckCommand::CargoCommand { jobs, .. } = &mut config.command {
+        *jobs = Some(2);
+    }
+    assert_eq!(extra_check_args(&config), vec!["-j", "2"]);
+}
+
+#[test]
+fn invalid_jobs_are_rejected() {
+    for &invalid in &[0, 100_000] {
+        let mut config = cargo_command();
+        if let FlycheckCommand::CargoCommand { jobs, .. } = &mut config.command {
+            *jobs = Some(invalid);
+        }
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            format!("invalid number of jobs {}, expected a value between 1 and 1024", invalid)
+        );
+    }
+}
+
+#[test]
+fn offline() {
+    let mut config = cargo_command();
+    if let FlycheckCommand::CargoCommand { offline, .. } = &mut config.command {
+        *offline = true;
+    }
+    assert_eq!(extra_check_args(&config), vec!["--offline"]);
+}
+
+#[test]
+fn locked_and_frozen() {
+    let mut config = cargo_command();
+    if let FlycheckCommand::CargoCommand { locked, .. } = &mut config.command {
+        *locked = true;
+    }
+    assert_eq!(extra_check_args(&config), vec!["--locked"]);
+
+    let mut config = cargo_command();
+    if let FlycheckCommand::CargoCommand { frozen, .. } = &mut config.command {
+        *frozen = true;
+    }
+    assert_eq!(extra_check_args(&config), vec!["--frozen"]);
+}
+
+#[test]
+fn cargo_binary() {
+    let mut config = cargo_command();
+    if let FlycheckCommand::CargoCommand { cargo_binary, .. } = &mut config.command {
+        *cargo_binary = Some(PathBuf::from("/opt/toolchain/bin/cargo"));
+    }
+    let (program, _args) = config.command_line(Path::new("/ws"));
+    assert_eq!(program, Path::new("/opt/toolchain/bin/cargo"));
+}
+
+#[test]
+fn missing_cargo_binary_is_reported() {
+    let dir = TempDir::new().unwrap();
+    let mut config = cargo_command();
+    fs::write(dir.path().join("Cargo.toml"), "").unwrap();
+    let missing = dir.path().join("no-such-cargo");
+    if let FlycheckCommand::CargoCommand { cargo_binary, .. } = &mut config.command {
+        *cargo_binary = Some(missing);
+    }
+    let cmd = config.to_command(dir.path()).unwrap();
+    let err = run_cargo(
+        cmd,
+        &mut |_| true,
+        &mut |_| (),
+        &CancellationToken::default(),
+        RunOptions::default(),
+        None,
+    )
+    .unwrap_err();
+    match &err {
+        CargoError::SpawnFailed(err) => assert_eq!(err.kind(), io::ErrorKind::NotFound),
+        err => panic!("expected a spawn failure, got {:?}", err),
+    }
+    let err = err.to_string();
+    assert!(err.starts_with("failed to spawn"), "{}", err);
+    assert!(err.contains("no-such-cargo"), "{}", err);
+}
+
+#[test]
+#[cfg(unix)]
+fn failed_checks_report_the_exit_code_and_stderr() {
+    let dir = TempDir::new().unwrap();
+    let cmd = fake_cargo(&dir, "echo 'error: failed to parse manifest' >&2\nexit 101\n");
+    let err = run_cargo(
+        cmd,
+        &mut |_| true,
+        &mut |_| (),
+        &CancellationToken::default(),
+        RunOptions::default(),
+        None,
+    )
+    .unwrap_err();
+    match &err {
+        CargoError::NonZeroExit { code, stderr } => {
+            assert_eq!(*code, Some(101));
+            assert_eq!(stderr, "error: failed to parse manifest");
+        }
+        err => panic!("expected a failed check, got {:?}", err),
+    }
+    assert_eq!(
+        err.to_string(),
+        "the check command failed with exit code 101:\nerror: failed to parse manifest"
+    );
+}
+
+/// Records the log messages of the thread it's called on, see
+/// `logged_stderr`.
+struct CapturingLogger;
+
+thread_local! {
+    static LOGGED: RefCell<Vec<(log::Level, String, String)>> = RefCell::new(Vec::new());
+}
+
+impl log::Log for CapturingLogger {
+    fn enabled(&self, _: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        let record = (record.level(), record.target().to_string(), record.args().to_string());
+        LOGGED.with(|logged| logged.borrow_mut().push(record));
+    }
+
+    fn flush(&self) {}
+}
+
+/// Runs `f` and returns the stderr lines it logged.
+fn logged_stderr(f: impl FnOnce()) -> Vec<(log::Level, String)> {
+    static LOGGER: CapturingLogger = CapturingLogger;
+    static INSTALL: std::sync::Once = std::sync::Once::new();
+    INSTALL.call_once(|| {
+        log::set_logger(&LOGGER).unwrap();
+        log::set_max_level(log::LevelFilter::Trace);
+    });
+    LOGGED.with(|logged| logged.borrow_mut().clear());
+    f();
+    LOGGED.with(|logged| {
+        logged
+            .borrow()
+            .iter()
+            .filter(|(_, target, _)| target == "cargo-watch")
+            .map(|(level, _, message)| (*level, message.clone()))
+            .collect()
+    })
+}
+
+#[test]
+#[cfg(unix)]
+fn stderr_is_forwarded_to_the_log() {
+    let dir = TempDir::new().unwrap();
+    let script = format!(
+        "echo '   Compiling foo v0.1.0' >&2\necho '{}'\necho 'warning: build failed' >&2\n",
+        UNUSED_VARIABLE
+    );
+    let mut messages = 0;
+    let logged = logged_stderr(|| {
+        run_cargo(
+            fake_cargo(&dir, &script),
+            &mut |_| {
+                messages += 1;
+                true
+            },
+            &mut |_| (),
+            &CancellationToken::default(),
+            RunOptions { forward_stderr_to_log: true, ..RunOptions::default() },
+            None,
+        )
+        .unwrap();
+    });
+    assert_eq!(
+        logged,
+        vec![
+            (log::Level::Info, "   Compiling foo v0.1.0".to_string()),
+            (log::Level::Info, "warning: build failed".to_string()),
+        ]
+    );
+    // The messages on stdout are parsed as usual.
+    assert_eq!(messages, 1);
+}
+
+#[test]
+#[cfg(unix)]
+fn stderr_is_not_logged_by_default() {
+    let dir = TempDir::new().unwrap();
+    let logged = logged_stderr(|| {
+        run_cargo(
+            fake_cargo(&dir, "echo '   Compiling foo v0.1.0' >&2\n"),
+            &mut |_| true,
+            &mut |_| (),
+            &CancellationToken::default(),
+            RunOptions::default(),
+            None,
+        )
+        .unwrap();
+    });
+    assert_eq!(logged, vec![]);
+}
+
+#[test]
+fn toolchain_comes_first() {
+    for &name in &["nightly", "+nightly"] {
+        let mut config = cargo_command();
+        if let FlycheckCommand::CargoCommand { toolchain, .. } = &mut config.command {
+            *toolchain = Some(name.to_string());
+        }
+        assert_eq!(check_args(&config)[..2], ["+nightly", "check"]);
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn missing_toolchain_suggests_installing() {
+    let dir = TempDir::new().unwrap();
+    let cmd = fake_cargo(
+        &dir,
+        r#"
+echo "error: toolchain 'nightly-x86_64-unknown-linux-gnu' is not installed" >&2
+exit 1
+"#,
+    );
+    let err = run_cargo(
+        cmd,
+        &mut |_| true,
+        &mut |_| (),
+        &CancellationToken::default(),
+        RunOptions::default(),
+        None,
+    )
+    .unwrap_err()
+    .to_string();
+    assert!(err.contains("'nightly-x86_64-unknown-linux-gnu' is not installed"), "{}", err);
+    assert!(err.contains("rustup toolchain install"), "{}", err);
+}
+
+#[test]
+fn exclude_packages() {
+    let mut config = cargo_command();
+    if let FlycheckCommand::CargoCommand { exclude, .. } = &mut config.command {
+        *exclude = strings(&["generated", "bindings"]);
+    }
+    assert_eq!(
+        &check_args(&config)[..6],
+        &["check", "--workspace", "--exclude", "generated", "--exclude", "bindings"]
+    );
+}
+
+#[test]
+fn exclude_conflicts_with_packages() {
+    let mut config = cargo_command();
+    if let FlycheckCommand::CargoCommand { packages, exclude, .. } = &mut config.command {
+        *packages = strings(&["foo"]);
+        *exclude = strings(&["generated"]);
+    }
+    assert_eq!(
+        config.validate().unwrap_err().to_string(),
+        "can't exclude [\"generated\"] when checking specific packages, \
+         cargo only supports `--exclude` together with `--workspace`"
+    );
+}
+
+#[test]
+fn flags_passed_by_the_user_are_not_duplicated() {
+    let config = FlycheckConfig::builder()
+        .all_targets(true)
+        .target_triple("wasm32-unknown-unknown")
+        .jobs(4)
+        .profile("release")
+        .offline(true)
+        .extra_args(strings(&[
+            "--all-targets",
+            "--message-format=json-diagnostic-rendered-ansi",
+            "--target",
+            "wasm32-unknown-unknown",
+            "--jobs=4",
+            "--release",
+            "--offline",
+        ]))
+        .build();
+    assert_eq!(config.validate(), Ok(()));
+    assert_eq!(
+        check_args(&config),
+        vec![
+            "check",
+            "--workspace",
+            "--manifest-path",
+            &manifest_path(),
+            "--all-targets",
+            "--message-format=json-diagnostic-rendered-ansi",
+            "--target",
+            "wasm32-unknown-unknown",
+            "--jobs=4",
+            "--release",
+            "--offline",
+        ]
+    );
+}
+
+#[test]
+fn attached_jobs_of_the_user_are_not_duplicated() {
+    let config = FlycheckConfig::builder().jobs(4).extra_args(strings(&["-j4"])).build();
+    assert_eq!(config.validate(), Ok(()));
+    assert_eq!(extra_check_args(&config), vec!["-j4"]);
+    let injected = strings(&["check", "-j", "4", "--offline"]);
+    let injected: Vec<OsString> = injected.into_iter().map(OsString::from).collect();
+    assert_eq!(merge_args(injected, &strings(&["-j4"])), vec!["check", "--offline"]);
+    // Only numbers are attached to `-j`.
+    assert!(!has_flag(&strings(&["-jobs"]), "-j"));
+
+    let config = FlycheckConfig::builder().jobs(4).extra_args(strings(&["-j8"])).build();
+    assert_eq!(
+        config.validate().unwrap_err().to_string(),
+        "`-j` in the extra args conflicts with the configured number of jobs"
+    );
+}
+
+#[test]
+fn manifest_path_of_the_user_takes_precedence() {
+    for extra_args in
+        &[&["--manifest-path", "sub/Cargo.toml"][..], &["--manifest-path=sub/Cargo.toml"]]
+    {
+        let config = FlycheckConfig::builder().extra_args(strings(extra_args)).build();
+        assert_eq!(config.validate(), Ok(()));
+        let mut expected = strings(&["check", "--workspace", "--message-format=json"]);
+        expected.extend(strings(extra_args));
+        assert_eq!(check_args(&config), expected);
+    }
+}
+
+#[test]
+fn flags_with_different_values_are_rejected() {
+    let config =
+        FlycheckConfig::builder().extra_args(strings(&["--message-format", "short"])).build();
+    assert_eq!(
+        config.validate().unwrap_err().to_string(),
+        "`--message-format` in the extra args conflicts with the JSON output of the check"
+    );
+
+    let config = FlycheckConfig::builder()
+        .target_triple("wasm32-unknown-unknown")
+        .extra_args(strings(&["--target=x86_64-unknown-linux-gnu"]))
+        .build();
+    assert_eq!(
+        config.validate().unwrap_err().to_string(),
+        "`--target` in the extra args conflicts with the configured target"
+    );
+
+    let config = FlycheckConfig::builder().jobs(4).extra_args(strings(&["-j", "8"])).build();
+    assert_eq!(
+        config.validate().unwrap_err().to_string(),
+        "`-j` in the extra args conflicts with the configured number of jobs"
+    );
+}
+
+#[test]
+fn conflicting_flags_are_rejected() {
+    let mut config = cargo_command();
+    if let FlycheckCommand::CargoCommand { extra_args, .. } = &mut config.command {
+        *extra_args = strings(&["-p", "foo"]);
+    }
+    assert_eq!(
+        config.validate().unwrap_err().to_string(),
+        "`-p` in the extra args conflicts with `--workspace`"
+    );
+
+    let mut config = cargo_command();
+    if let FlycheckCommand::CargoCommand { packages, extra_args, .. } = &mut config.command {
+        *packages = strings(&["foo"]);
+        *extra_args = strings(&["--workspace"]);
+    }
+    assert_eq!(
+        config.validate().unwrap_err().to_string(),
+        "`--workspace` in the extra args conflicts with `-p`"
+    );
+
+    let mut config = cargo_command();
+    if let FlycheckCommand::CargoCommand { all_features, extra_args, .. } = &mut config.command {
+        *all_features = true;
+        *extra_args = strings(&["--no-default-features"]);
+    }
+    assert_eq!(
+        config.validate().unwrap_err().to_string(),
+        "`--no-default-features` in the extra args conflicts with `--all-features`"
+    );
+
+    let mut config = cargo_command();
+    if let FlycheckCommand::CargoCommand { profile, extra_args, .. } = &mut config.command {
+        *profile = Some("release".to_string());
+        *extra_args = strings(&["--profile=bench"]);
+    }
+    assert_eq!(
+        config.validate().unwrap_err().to_string(),
+        "`--profile` in the extra args conflicts with the configured profile"
+    );
+}
+
+#[test]
+fn custom_commands_are_not_validated() {
+    let config = FlycheckConfig {
+        command: FlycheckCommand::CustomCommand {
+            command: "cargo".to_string(),
+            args: strings(&["check", "--message-format=json", "--message-format=json"]),
+        },
+        ..cargo_command()
+    };
+    assert_eq!(config.validate(), Ok(()));
+}
+
+#[test]
+#[cfg(unix)]
+fn cargo_error_includes_stderr() {
+    let dir = TempDir::new().unwrap();
+    let cmd = fake_cargo(
+        &dir,
+        r#"
+echo 'error: failed to run `rustc` to learn about target-specific information' >&2
+echo 'error: Error loading target specification: Could not find specification for target "bogus"' >&2
+exit 101
+"#,
+    );
+    let err = run_cargo(
+        cmd,
+        &mut |_| true,
+        &mut |_| (),
+        &CancellationToken::default(),
+        RunOptions::default(),
+        None,
+    )
+    .unwrap_err();
+    let err = err.to_string();
+    assert!(err.contains(r#"Could not find specification for target "bogus""#), "{}", err);
+}
+
+#[test]
+#[cfg(unix)]
+fn cargo_error_includes_the_end_of_long_stderr() {
+    let dir = TempDir::new().unwrap();
+    let cmd = fake_cargo(
+        &dir,
+        r#"
+i=1
+while [ $i -le 100 ]; do
+    echo "warning: line-$i" >&2
+    i=$((i + 1))
+done
+echo 'error: could not compile `foo`' >&2
+exit 101
+"#,
+    );
+    let err = run_cargo(
+        cmd,
+        &mut |_| true,
+        &mut |_| (),
+        &CancellationToken::default(),
+        RunOptions::default(),
+        None,
+    )
+    .unwrap_err()
+    .to_string();
+    assert!(err.contains("(51 earlier lines omitted)\nwarning: line-52\n"), "{}", err);
+    assert!(!err.contains("line-51\n"), "{}", err);
+    assert!(err.ends_with("warning: line-100\nerror: could not compile `foo`"), "{}", err);
+}
+
+#[test]
+#[cfg(unix)]
+fn stdout_and_stderr_are_read_concurrently() {
+    let dir = TempDir::new().unwrap();
+    // Both streams get far more than a pipe buffer worth of output.
+    let cmd = fake_cargo(
+        &dir,
+        &format!(
+            r#"
+padding=$(printf '%0512d' 0)
+i=1
+while [ $i -le 500 ]; do
+    echo '{}'
+    echo "   Checking foo-$i $padding" >&2
+    i=$((i + 1))
+done
+"#,
+            UNUSED_VARIABLE
+        ),
+    );
+    let mut messages = 0;
+    let mut stderr_lines = Vec::new();
+    run_cargo(
+        cmd,
+        &mut |_| {
+            messages += 1;
+            true
+        },
+        &mut |line| stderr_lines.push(line.to_string()),
+        &CancellationToken::default(),
+        RunOptions::default(),
+        None,
+    )
+    .unwrap();
+    assert_eq!(messages, 500);
+    assert_eq!(stderr_lines.len(), 500);
+    assert!(stderr_lines[499].starts_with("   Checking foo-500 000"), "{}", stderr_lines[499]);
+}
+
+#[test]
+#[cfg(unix)]
+fn missing_offline_dependencies_suggest_fetching() {
+    let dir = TempDir::new().unwrap();
+    let cmd = fake_cargo(
+        &dir,
+        r#"
+echo 'error: failed to download `serde v1.0.104`' >&2
+echo '' >&2
+echo 'Caused by:' >&2
+echo '  attempting to make an HTTP request, but --offline was specified' >&2
+exit 101
+"#,
+    );
+    let err = run_cargo(
+        cmd,
+        &mut |_| true,
+        &mut |_| (),
+        &CancellationToken::default(),
+        RunOptions::default(),
+        None,
+    )
+    .unwrap_err()
+    .to_string();
+    assert!(!err.contains("no valid metadata"), "{}", err);
+    assert!(err.contains("but --offline was specified"), "{}", err);
+    assert!(err.contains("run `cargo fetch`"), "{}", err);
+}
+
+#[test]
+#[cfg(unix)]
+fn outdated_lockfile_suggests_updating() {
+    let dir = TempDir::new().unwrap();
+    let cmd = fake_cargo(
+        &dir,
+        r#"
+echo 'error: the lock file /ws/Cargo.lock needs to be updated but --locked was passed to prevent this' >&2
+exit 101
+"#,
+    );
+    let err = run_cargo(
+        cmd,
+        &mut |_| true,
+        &mut |_| (),
+        &CancellationToken::default(),
+        RunOptions::default(),
+        None,
+    )
+    .unwrap_err()
+    .to_string();
+    assert!(err.contains("needs to be updated but --locked was passed"), "{}", err);
+    assert!(err.contains("run `cargo update`"), "{}", err);
+}
+
+#[test]
+#[cfg(unix)]
+fn extra_env_is_set_for_the_check_process_only() {
+    let dir = TempDir::new().unwrap();
+    let config = FlycheckConfig {
+        command: FlycheckCommand::CustomCommand {
+            command: "sh".to_string(),
+            args: strings(&["-c", "echo \"$RA_FLYCHECK_TEST_URL $RA_FLYCHECK_TEST_DIR\""]),
+        },
+        extra_env: vec![
+            ("RA_FLYCHECK_TEST_URL".to_string(), "postgres://localhost/db".to_string()),
+            ("RA_FLYCHECK_TEST_DIR".to_string(), "/tmp/sccache".to_string()),
+        ],
+        ..cargo_command()
+    };
+    let output = config.to_command(dir.path()).unwrap().output().unwrap();
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "postgres://localhost/db /tmp/sccache\n");
+    assert!(env::var_os("RA_FLYCHECK_TEST_URL").is_none());
+}
+
+#[test]
+#[cfg(unix)]
+fn unset_env_removes_inherited_variables() {
+    env::set_var("RA_FLYCHECK_TEST_INHERITED", "inherited");
+    env::set_var("RA_FLYCHECK_TEST_OVERRIDDEN", "inherited");
+    let dir = TempDir::new().unwrap();
+    let config = FlycheckConfig {
+        command: FlycheckCommand::CustomCommand {
+            command: "sh".to_string(),
+            args: strings(&[
+                "-c",
+                "echo \"${RA_FLYCHECK_TEST_INHERITED-unset} ${RA_FLYCHECK_TEST_OVERRIDDEN-unset}\"",
+            ]),
+        },
+        extra_env: vec![("RA_FLYCHECK_TEST_OVERRIDDEN".to_string(), "explicit".to_string())],
+        unset_env: strings(&["RA_FLYCHECK_TEST_INHERITED", "RA_FLYCHECK_TEST_OVERRIDDEN"]),
+        ..cargo_command()
+    };
+    let output = config.to_command(dir.path()).unwrap().output().unwrap();
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "unset explicit\n");
+}
+
+#[test]
+#[cfg(unix)]
+fn incremental_compilation_can_be_forced() {
+    let dir = TempDir::new().unwrap();
+    let inherited = env::var("CARGO_INCREMENTAL").unwrap_or_else(|_| "unset".to_string());
+    for &(incremental, expected) in
+        &[(Some(false), "0"), (Some(true), "1"), (None, inherited.as_str())]
+    {
+        let config = FlycheckConfig {
+            command: FlycheckCommand::CustomCommand {
+                command: "sh".to_string(),
+                args: strings(&["-c", "env | grep '^CARGO_INCREMENTAL=' || echo unset"]),
+            },
+            incremental,
+            ..cargo_command()
+        };
+        let output = config.to_command(dir.path()).unwrap().output().unwrap();
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        assert_eq!(stdout.trim_end().trim_start_matches("CARGO_INCREMENTAL="), expected);
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn colors_are_turned_off() {
+    let dir = TempDir::new().unwrap();
+    let mut config = FlycheckConfig {
+        command: FlycheckCommand::CustomCommand {
+            command: "sh".to_string(),
+            args: strings(&["-c", "echo \"${CARGO_TERM_COLOR-unset}\""]),
+        },
+        unset_env: strings(&["CARGO_TERM_COLOR"]),
+        ..cargo_command()
+    };
+    let output = config.to_command(dir.path()).unwrap().output().unwrap();
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "never\n");
+
+    config.sanitize_output = false;
+    let output = config.to_command(dir.path()).unwrap().output().unwrap();
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "unset\n");
+}
+
+#[test]
+fn rustflags_are_appended() {
+    let mut config = cargo_command();
+    assert_eq!(config.effective_rustflags(Some("-C opt-level=1".to_string())), None);
+
+    config.rustflags = Some("-W unused-crate-dependencies --cfg editor".to_string());
+    assert_eq!(
+        config.effective_rustflags(None).unwrap(),
+        "-W unused-crate-dependencies --cfg editor"
+    );
+    assert_eq!(
+        config.effective_rustflags(Some("-C opt-level=1 ".to_string())).unwrap(),
+        "-C opt-level=1 -W unused-crate-dependencies --cfg editor"
+    );
+
+    config.extra_env = vec![("RUSTFLAGS".to_string(), "-D warnings".to_string())];
+    assert_eq!(
+        config.effective_rustflags(Some("-C opt-level=1".to_string())).unwrap(),
+        "-D warnings -W unused-crate-dependencies --cfg editor"
+    );
+
+    config.extra_env = Vec::new();
+    config.unset_env = strings(&["RUSTFLAGS"]);
+    assert_eq!(
+        config.effective_rustflags(Some("-C opt-level=1".to_string())).unwrap(),
+        "-W unused-crate-dependencies --cfg editor"
+    );
+}
+
+#[test]
+fn rustflags_can_replace() {
+    let mut config = cargo_command();
+    config.rustflags = Some("--cfg editor".to_string());
+    config.replace_rustflags = true;
+    config.extra_env = vec![("RUSTFLAGS".to_string(), "-D warnings".to_string())];
+    assert_eq!(
+        config.effective_rustflags(Some("-C opt-level=1".to_string())).unwrap(),
+        "--cfg editor"
+    );
+}
+
+#[test]
+fn target_dir_is_resolved_against_the_workspace_root() {
+    let workspace_root = Path::new("/ws");
+    let mut config = cargo_command();
+    assert_eq!(config.target_dir(workspace_root), None);
+
+    config.use_separate_target_dir = true;
+    assert_eq!(
+        config.target_dir(workspace_root),
+        Some(workspace_root.join("target/rust-analyzer"))
+    );
+
+    config.target_dir = Some("build/check".into());
+    assert_eq!(config.target_dir(workspace_root), Some(workspace_root.join("build/check")));
+
+    let absolute = env::temp_dir().join("ra-check");
+    config.target_dir = Some(absolute.clone());
+    assert_eq!(config.target_dir(workspace_root), Some(absolute));
+}
+
+#[test]
+#[cfg(unix)]
+fn separate_target_dir_is_created_and_passed_to_the_check() {
+    let dir = TempDir::new().unwrap();
+    let config = FlycheckConfig {
+        command: FlycheckCommand::CustomCommand {
+            command: "sh".to_string(),
+            args: strings(&["-c", "echo \"$CARGO_TARGET_DIR\""]),
+        },
+        use_separate_target_dir: true,
+        ..cargo_command()
+    };
+    let output = config.to_command(dir.path()).unwrap().output().unwrap();
+    let target_dir = String::from_utf8(output.stdout).unwrap();
+    let target_dir = Path::new(target_dir.trim_end());
+    assert!(target_dir.is_absolute());
+    assert_eq!(target_dir, dir.path().join("target/rust-analyzer"));
+    assert!(target_dir.is_dir());
+}
+
+#[test]
+#[cfg(unix)]
+fn check_runs_through_wrapper() {
+    let dir = TempDir::new().unwrap();
+    let wrapper = dir.path().join("wrapper.sh");
+    fs::write(&wrapper, "echo '{\"reason\":\"wrapper-started\"}'\nexec \"$@\"\n").unwrap();
+    let config = FlycheckConfig {
+        command: FlycheckCommand::CustomCommand {
+            command: "sh".to_string(),
+            args: strings(&["-c", r#"echo '{"reason":"build-finished","success":true}'"#]),
+        },
+        wrapper: vec!["sh".to_string(), wrapper.to_str().unwrap().to_string()],
+        ..cargo_command()
+    };
+    let mut messages = 0;
+    run_cargo(
+        config.to_command(dir.path()).unwrap(),
+        &mut |_| {
+            messages += 1;
+            true
+        },
+        &mut |_| (),
+        &CancellationToken::default(),
+        RunOptions::default(),
+        None,
+    )
+    .unwrap();
+    assert_eq!(messages, 2);
+}
+
+/// An unused variable warning in `src/lib.rs`, as printed by
+/// `cargo check --message-format=json`.
+const UNUSED_VARIABLE: &str = r#"{"reason":"compiler-message","package_id":"foo 0.1.0 (path+file:///ws)","target":{"kind":["lib"],"crate_types":["lib"],"name":"foo","src_path":"/ws/src/lib.rs","edition":"2018","doctest":true},"message":{"message":"unused variable: `x`","code":{"code":"unused_variables","explanation":null},"level":"warning","spans":[{"file_name":"src/lib.rs","byte_start":17,"byte_end":18,"line_start":2,"line_end":2,"column_start":9,"column_end":10,"is_primary":true,"text":[{"text":"    let x = 1;","highlight_start":9,"highlight_end":10}],"label":null,"suggested_replacement":null,"suggestion_applicability":null,"expansion":null}],"children":[],"rendered":null}}"#;
+
+/// `UNUSED_VARIABLE` about a variable named after `$i`, for scripts printing
+/// distinct warnings in a loop, as identical ones are only reported once.
+fn numbered_unused_variable() -> String {
+    UNUSED_VARIABLE.replace("`x`", "`x'\"$i\"'`")
+}
+
+/// Returns the first diagnostic reported by `flycheck`.
+fn first_diagnostic(flycheck: &Flycheck) -> (Url, lsp_types::Diagnostic) {
+    loop {
+        match flycheck.task_recv.recv_timeout(Duration::from_secs(10)).unwrap() {
+            CheckTask::AddDiagnostics { url, mut diagnostics, .. } => {
+                return (url, diagnostics.remove(0).0)
+            }
+            CheckTask::Error { message, .. } => panic!("check failed: {}", message),
+            _ => {}
+        }
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn custom_command_output_is_turned_into_diagnostics() {
+    let dir = TempDir::new().unwrap();
+    let script = dir.path().join("check.sh");
+    fs::write(&script, format!("echo 'not a message'\necho '{}'\n", UNUSED_VARIABLE)).unwrap();
+    let config = FlycheckConfig {
+        command: FlycheckCommand::CustomCommand {
+            command: "sh".to_string(),
+            args: vec![script.to_str().unwrap().to_string()],
+        },
+        ..cargo_command()
+    };
+    let flycheck = Flycheck::new(config, dir.path().to_path_buf());
+    flycheck.update();
+
+    let (url, diagnostic) = first_diagnostic(&flycheck);
+    assert_eq!(url, Url::from_file_path(dir.path().join("src/lib.rs")).unwrap());
+    assert_eq!(diagnostic.message, "unused variable: `x`");
+}
+
+/// Waits until `flycheck` finished a run.
+fn wait_for_check(flycheck: &Flycheck) {
+    let mut started = false;
+    loop {
+        match flycheck.task_recv.recv_timeout(Duration::from_secs(10)).unwrap() {
+            CheckTask::Status(WorkDoneProgress::Begin(_)) => started = true,
+            CheckTask::Status(WorkDoneProgress::End(_)) if started => return,
+            CheckTask::Error { message, .. } => panic!("check failed: {}", message),
+            _ => {}
+        }
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn scope_can_change_between_runs() {
+    let dir = TempDir::new().unwrap();
+    fs::write(dir.path().join("Cargo.toml"), "").unwrap();
+    let log = dir.path().join("args.log");
+    let wrapper = dir.path().join("wrapper.sh");
+    fs::write(&wrapper, format!("shift\necho \"$@\" >> '{}'\n", log.display())).unwrap();
+    let config = FlycheckConfig {
+        wrapper: vec!["sh".to_string(), wrapper.to_str().unwrap().to_string()],
+        ..cargo_command()
+    };
+    let flycheck = Flycheck::new(config, dir.path().to_path_buf());
+    flycheck.update();
+    wait_for_check(&flycheck);
+    flycheck.set_scope(CheckScope::LibOnly);
+    flycheck.update();
+    wait_for_check(&flycheck);
+
+    let log = fs::read_to_string(log).unwrap();
+    let runs: Vec<&str> = log.lines().collect();
+    assert_eq!(runs.len(), 2);
+    assert!(!runs[0].contains("--lib"), "{}", runs[0]);
+    assert!(runs[1].ends_with("--lib --bins"), "{}", runs[1]);
+}
+
+#[test]
+fn invalid_config_is_reported_instead_of_running_the_check() {
+    let dir = TempDir::new().unwrap();
+    let mut config = cargo_command();
+    if let FlycheckCommand::CargoCommand { jobs, .. } = &mut config.command {
+        *jobs = Some(0);
+    }
+    let flycheck = Flycheck::new(config, dir.path().to_path_buf());
+    flycheck.update();
+    loop {
+        match flycheck.task_recv.recv_timeout(Duration::from_secs(10)).unwrap() {
+            CheckTask::InvalidConfig(err) => {
+                assert_eq!(err, ConfigError::InvalidJobs(0));
+                break;
+            }
+            CheckTask::Status(WorkDoneProgress::Begin(_)) => panic!("check was started"),
+            _ => {}
+        }
+    }
+}
+
+#[test]
+#[cfg(feature = "serde")]
+fn config_round_trips_through_toml() {
+    let configs = vec![
+        FlycheckConfig::builder()
+            .command("clippy")
+            .features(strings(&["foo", "bar"]))
+            .targets(vec![CheckTarget::Lib, CheckTarget::Tests])
+            .scope(CheckScope::LibOnly)
+            .profile("release")
+            .jobs(2)
+            .extra_env(vec![("RUST_LOG".to_string(), "warn".to_string())])
+            .target_dir(PathBuf::from("target/check"))
+            .build(),
+        FlycheckConfig::builder()
+            .custom_command("./x.py", strings(&["check", "--json-output"]))
+            .wrapper(strings(&["docker", "exec", "builder"]))
+            .build(),
+    ];
+    for config in configs {
+        // Going through `Value` puts plain values before tables, as TOML
+        // requires.
+        let toml = toml::Value::try_from(&config).unwrap().to_string();
+        assert_eq!(FlycheckConfig::from_toml_str(&toml).unwrap(), config, "{}", toml);
+    }
+}
+
+#[test]
+#[cfg(feature = "serde")]
+fn old_config_files_keep_defaults_for_new_options() {
+    assert_eq!(FlycheckConfig::from_toml_str("").unwrap(), FlycheckConfig::default());
+
+    let config = FlycheckConfig::from_toml_str(
+        r#"
+extra_env = [["RUST_LOG", "warn"]]
+
+[command]
+kind = "cargo_command"
+command = "clippy"
+all_targets = true
+"#,
+    )
+    .unwrap();
+    assert_eq!(
+        config,
+        FlycheckConfig::builder()
+            .command("clippy")
+            .all_targets(true)
+            .extra_env(vec![("RUST_LOG".to_string(), "warn".to_string())])
+            .build()
+    );
+}
+
+#[test]
+#[cfg(unix)]
+fn rendered_ansi_diagnostics_are_parsed() {
+    let dir = TempDir::new().unwrap();
+    let message = UNUSED_VARIABLE.replace(
+        r#""rendered":null"#,
+        r#""rendered":"\u001b[0m\u001b[1m\u001b[33mwarning\u001b[0m: unused variable: `x`\n""#,
+    );
+    let cmd = fake_cargo(&dir, &format!("printf '%s\\n' '{}'\n", message));
+    let mut rendered = Vec::new();
+    run_cargo(
+        cmd,
+        &mut |message| {
+            if let CargoMessage::Cargo(Message::CompilerMessage(msg)) = message {
+                rendered.push(msg.message.rendered.unwrap());
+            }
+            true
+        },
+        &mut |_| (),
+        &CancellationToken::default(),
+        RunOptions::default(),
+        None,
+    )
+    .unwrap();
+    assert_eq!(rendered, vec!["warning: unused variable: `x`\n"]);
+}
+
+#[test]
+#[cfg(unix)]
+fn check_is_retried_without_unsupported_keep_going() {
+    let dir = TempDir::new().unwrap();
+    fs::write(dir.path().join("Cargo.toml"), "").unwrap();
+    let log = dir.path().join("args.log");
+    let wrapper = dir.path().join("wrapper.sh");
+    fs::write(
+        &wrapper,
+        format!(
+            r#"shift
+echo "$@" >> '{}'
+case "$*" in
+    *--keep-going*)
+        echo "error: Found argument '--keep-going' which wasn't expected, or isn't valid in this context" >&2
+        exit 1
+        ;;
+esac
+echo '{}'
+"#,
+            log.display(),
+            UNUSED_VARIABLE
+        ),
+    )
+    .unwrap();
+    let mut config = FlycheckConfig::builder().keep_going(true).build();
+    config.wrapper = vec!["sh".to_string(), wrapper.to_str().unwrap().to_string()];
+    let flycheck = Flycheck::new(config, dir.path().to_path_buf());
+    flycheck.update();
+
+    let (_url, diagnostic) = first_diagnostic(&flycheck);
+    assert_eq!(diagnostic.message, "unused variable: `x`");
+    let log = fs::read_to_string(log).unwrap();
+    let runs: Vec<&str> = log.lines().collect();
+    assert_eq!(runs.len(), 2);
+    assert!(runs[0].ends_with("--keep-going"), "{}", runs[0]);
+    assert!(!runs[1].contains("--keep-going"), "{}", runs[1]);
+}
+
+#[test]
+#[cfg(unix)]
+fn working_dir_can_differ_from_workspace_root() {
+    let dir = TempDir::new().unwrap();
+    let workspace_root = dir.path().join("rust");
+    fs::create_dir(&workspace_root).unwrap();
+    let script = dir.path().join("check.sh");
+    fs::write(&script, format!("pwd > cwd.log\necho '{}'\n", UNUSED_VARIABLE)).unwrap();
+    let config = FlycheckConfig::builder()
+        .custom_command("sh", vec![script.to_str().unwrap().to_string()])
+        .working_dir("..")
+        .build();
+    let flycheck = Flycheck::new(config, workspace_root.clone());
+    flycheck.update();
+
+    let (url, _diagnostic) = first_diagnostic(&flycheck);
+    assert_eq!(url, Url::from_file_path(workspace_root.join("src/lib.rs")).unwrap());
+    let cwd = fs::read_to_string(dir.path().join("cwd.log")).unwrap();
+    assert_eq!(fs::canonicalize(cwd.trim_end()).unwrap(), fs::canonicalize(dir.path()).unwrap());
+}
+
+/// A primary check reporting `UNUSED_VARIABLE` followed by `secondary_script`
+/// as the secondary check.
+fn two_tier_config(dir: &TempDir, secondary_script: &str) -> FlycheckConfig {
+    let primary = dir.path().join("primary.sh");
+    fs::write(&primary, format!("echo '{}'\n", UNUSED_VARIABLE)).unwrap();
+    let secondary = dir.path().join("secondary.sh");
+    fs::write(&secondary, secondary_script).unwrap();
+    FlycheckConfig::builder()
+        .custom_command("sh", vec![primary.to_str().unwrap().to_string()])
+        .idle_delay(Duration::from_millis(10))
+        .secondary(
+            FlycheckConfig::builder()
+                .custom_command("sh", vec![secondary.to_str().unwrap().to_string()])
+                .build(),
+        )
+        .build()
+}
+
+#[test]
+#[cfg(unix)]
+fn secondary_check_runs_when_idle() {
+    let dir = TempDir::new().unwrap();
+    let config = two_tier_config(&dir, &format!("echo '{}'\n", UNUSED_VARIABLE));
+    let flycheck = Flycheck::new(config, dir.path().to_path_buf());
+    flycheck.update();
+
+    let (_url, primary) = first_diagnostic(&flycheck);
+    assert_eq!(primary.source.as_deref(), Some("rustc"));
+    let (_url, secondary) = first_diagnostic(&flycheck);
+    assert_eq!(secondary.source.as_deref(), Some("sh"));
+    assert_eq!(secondary.message, primary.message);
+}
+
+#[test]
+#[cfg(unix)]
+fn secondary_check_is_cancelled_by_updates() {
+    let dir = TempDir::new().unwrap();
+    // Only the first secondary run reports something, after it was cancelled.
+    let config = two_tier_config(
+        &dir,
+        &format!("[ -e started ] && exit 0\ntouch started\nsleep 1\necho '{}'\n", UNUSED_VARIABLE),
+    );
+    let flycheck = Flycheck::new(config, dir.path().to_path_buf());
+    flycheck.update();
+    // Skip the tasks of the primary check until the secondary one is running.
+    let mut begins = 0;
+    while begins < 2 {
+        match flycheck.task_recv.recv_timeout(Duration::from_secs(10)).unwrap() {
+            CheckTask::Status(WorkDoneProgress::Begin(_)) => begins += 1,
+            CheckTask::Error { message, .. } => panic!("check failed: {}", message),
+            _ => {}
+        }
+    }
+    while !dir.path().join("started").exists() {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    flycheck.update();
+
+    // Wait for the restarted primary check and the following secondary one.
+    let mut ends = 0;
+    let mut restarted = false;
+    while ends < 2 {
+        match flycheck.task_recv.recv_timeout(Duration::from_secs(10)).unwrap() {
+            CheckTask::Status(WorkDoneProgress::Begin(_)) => restarted = true,
+            CheckTask::Status(WorkDoneProgress::End(_)) if restarted => ends += 1,
+            CheckTask::AddDiagnostics { diagnostics, .. } => {
+                for (diagnostic, _fixes) in diagnostics {
+                    assert_ne!(diagnostic.source.as_deref(), Some("sh"))
+                }
+            }
+            CheckTask::Error { message, .. } => panic!("check failed: {}", message),
+            _ => {}
+        }
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn update_args_are_passed_to_the_next_check_only() {
+    let dir = TempDir::new().unwrap();
+    fs::write(dir.path().join("Cargo.toml"), "").unwrap();
+    let log = dir.path().join("args.log");
+    let wrapper = dir.path().join("wrapper.sh");
+    fs::write(&wrapper, format!("shift\necho \"$@\" >> '{}'\n", log.display())).unwrap();
+    let config = FlycheckConfig {
+        wrapper: vec!["sh".to_string(), wrapper.to_str().unwrap().to_string()],
+        ..cargo_command()
+    };
+    let flycheck = Flycheck::new(config, dir.path().to_path_buf());
+    flycheck.update_with_args(strings(&["--features", "experimental"]));
+    wait_for_check(&flycheck);
+    flycheck.update();
+    wait_for_check(&flycheck);
+
+    let log = fs::read_to_string(log).unwrap();
+    let runs: Vec<&str> = log.lines().collect();
+    assert_eq!(runs.len(), 2);
+    assert!(runs[0].ends_with("--features experimental"), "{}", runs[0]);
+    assert!(!runs[1].contains("experimental"), "{}", runs[1]);
+}
+
+#[test]
+#[cfg(unix)]
+fn tests_are_checked_without_unsupported_profile() {
+    let dir = TempDir::new().unwrap();
+    fs::write(dir.path().join("Cargo.toml"), "").unwrap();
+    let log = dir.path().join("args.log");
+    let wrapper = dir.path().join("wrapper.sh");
+    fs::write(
+        &wrapper,
+        format!(
+            r#"shift
+echo "$@" >> '{}'
+case "$*" in
+    *--profile*)
+        echo "error: Found argument '--profile' which wasn't expected, or isn't valid in this context" >&2
+        exit 1
+        ;;
+esac
+echo '{}'
+"#,
+            log.display(),
+            UNUSED_VARIABLE
+        ),
+    )
+    .unwrap();
+    let mut config = FlycheckConfig::builder().tests_only(true).keep_going(true).build();
+    config.wrapper = vec!["sh".to_string(), wrapper.to_str().unwrap().to_string()];
+    let flycheck = Flycheck::new(config, dir.path().to_path_buf());
+    flycheck.update();
+
+    let (_url, diagnostic) = first_diagnostic(&flycheck);
+    assert_eq!(diagnostic.message, "unused variable: `x`");
+    let log = fs::read_to_string(log).unwrap();
+    let runs: Vec<&str> = log.lines().collect();
+    assert_eq!(runs.len(), 2);
+    assert!(runs[0].contains("--tests --profile test"), "{}", runs[0]);
+    assert!(runs[1].contains("--tests --keep-going"), "{}", runs[1]);
+}
+
+/// Returns a flycheck running a fake cargo which rejects `--workspace` like
+/// cargo before 1.39 if `old` is set. It logs its arguments to `args.log`.
+#[cfg(unix)]
+fn flycheck_with_cargo_version(dir: &TempDir, old: bool) -> Flycheck {
+    Flycheck::new(config_with_cargo_version(dir, old), dir.path().to_path_buf())
+}
+
+/// The config of [`flycheck_with_cargo_version`].
+#[cfg(unix)]
+fn config_with_cargo_version(dir: &TempDir, old: bool) -> FlycheckConfig {
+    fs::write(dir.path().join("Cargo.toml"), "").unwrap();
+    let log = dir.path().join("args.log");
+    let rejection = if old {
+        "case \"$*\" in\n    *--workspace*)\n        echo \"error: Found argument '--workspace' which wasn't expected, or isn't valid in this context\" >&2\n        exit 1\n        ;;\nesac\n"
+    } else {
+        ""
+    };
+    let wrapper = dir.path().join("wrapper.sh");
+    fs::write(
+        &wrapper,
+        format!(
+            "shift\necho \"$@\" >> '{}'\n{}echo '{}'\n",
+            log.display(),
+            rejection,
+            UNUSED_VARIABLE
+        ),
+    )
+    .unwrap();
+    FlycheckConfig::builder()
+        .wrapper(vec!["sh".to_string(), wrapper.to_str().unwrap().to_string()])
+        .build()
+}
+
+/// Runs two checks, returning the arguments cargo was run with.
+#[cfg(unix)]
+fn args_of_two_checks(old_cargo: bool) -> Vec<String> {
+    let dir = TempDir::new().unwrap();
+    let flycheck = flycheck_with_cargo_version(&dir, old_cargo);
+    flycheck.update();
+    wait_for_check(&flycheck);
+    flycheck.update();
+    wait_for_check(&flycheck);
+    let log = fs::read_to_string(dir.path().join("args.log")).unwrap();
+    log.lines().map(|line| line.split(' ').nth(1).unwrap().to_string()).collect()
+}
+
+#[test]
+#[cfg(unix)]
+fn old_cargo_checks_the_workspace_with_all() {
+    // Only the first check tries `--workspace`.
+    assert_eq!(args_of_two_checks(true), vec!["--workspace", "--all", "--all"]);
+}
+
+#[test]
+#[cfg(unix)]
+fn rejected_flags_leave_the_config_unchanged() {
+    let dir = TempDir::new().unwrap();
+    let config = config_with_cargo_version(&dir, true);
+    let flycheck = Flycheck::new(config.clone(), dir.path().to_path_buf());
+    flycheck.update();
+    wait_for_check(&flycheck);
+    // Would restart the check if the config had changed.
+    flycheck.reconfigure(&config);
+    flycheck.update();
+    wait_for_check(&flycheck);
+    let log = fs::read_to_string(dir.path().join("args.log")).unwrap();
+    let args: Vec<&str> = log.lines().map(|line| line.split(' ').nth(1).unwrap()).collect();
+    assert_eq!(args, vec!["--workspace", "--all", "--all"]);
+}
+
+#[test]
+#[cfg(unix)]
+fn new_cargo_checks_the_workspace_with_workspace() {
+    assert_eq!(args_of_two_checks(false), vec!["--workspace", "--workspace"]);
+}
+
+/// Whether the process `pid` is still running, zombies count as dead.
+#[cfg(unix)]
+fn is_running(pid: &str) -> bool {
+    let output = Command::new("ps").arg("-o").arg("stat=").arg("-p").arg(pid).output().unwrap();
+    let stat = String::from_utf8(output.stdout).unwrap();
+    !stat.trim().is_empty() && !stat.trim().starts_with('Z')
+}
+
+#[test]
+#[cfg(unix)]
+fn processes_spawned_by_the_check_are_killed() {
+    let dir = TempDir::new().unwrap();
+    let script = dir.path().join("check.sh");
+    fs::write(
+        &script,
+        format!(
+            "sleep 1000 &\necho $! > grandchild.pid\nwhile true; do\n    echo '{}'\n    sleep 0.05\ndone\n",
+            UNUSED_VARIABLE
+        ),
+    )
+    .unwrap();
+    let config = FlycheckConfig::builder()
+        .custom_command("sh", vec![script.to_str().unwrap().to_string()])
+        .build();
+    let flycheck = Flycheck::new(config, dir.path().to_path_buf());
+    flycheck.update();
+    first_diagnostic(&flycheck);
+    let pid = fs::read_to_string(dir.path().join("grandchild.pid")).unwrap();
+    let pid = pid.trim();
+    assert!(is_running(pid));
+
+    drop(flycheck);
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while is_running(pid) {
+        assert!(Instant::now() < deadline, "grandchild {} is still running", pid);
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn processes_left_behind_by_successful_checks_keep_running() {
+    let dir = TempDir::new().unwrap();
+    let pid_file = dir.path().join("daemon.pid");
+    let script = format!(
+        "sleep 1000 > /dev/null 2>&1 &\necho $! > '{}'\necho '{}'\n",
+        pid_file.display(),
+        UNUSED_VARIABLE
+    );
+    let cmd = fake_cargo(&dir, &script);
+    let cancel = CancellationToken::default();
+    let res = run_cargo(cmd, &mut |_| true, &mut |_| (), &cancel, RunOptions::default(), None);
+    assert_eq!(res.unwrap().exit_code, Some(0));
+    let pid = fs::read_to_string(&pid_file).unwrap();
+    let pid = pid.trim();
+    // Give a stray signal time to arrive.
+    thread::sleep(Duration::from_millis(100));
+    let running = is_running(pid);
+    let _ = Command::new("kill").arg("-9").arg(pid).status();
+    assert!(running, "{} was stopped after the check finished", pid);
+}
+
+/// Spawns a process running `setup` and then looping forever, returning once
+/// it's done with the setup.
+#[cfg(unix)]
+fn spawn_after_setup(dir: &TempDir, setup: &str) -> ProcessGroupChild {
+    let ready = dir.path().join("ready");
+    let script =
+        format!("{}\ntouch '{}'\nwhile true; do sleep 0.05; done\n", setup, ready.display());
+    let child = ProcessGroupChild::spawn(&mut fake_cargo(dir, &script), false).unwrap();
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !ready.exists() {
+        assert!(Instant::now() < deadline, "the setup didn't finish");
+        thread::sleep(Duration::from_millis(10));
+    }
+    child
+}
+
+#[test]
+#[cfg(unix)]
+fn terminated_processes_can_exit_cleanly() {
+    let dir = TempDir::new().unwrap();
+    let cleaned_up = dir.path().join("cleaned-up");
+    let mut child =
+        spawn_after_setup(&dir, &format!("trap \"touch '{}'; exit 0\" TERM", cleaned_up.display()));
+    let started = Instant::now();
+    child.terminate(Duration::from_secs(10)).unwrap();
+    assert!(started.elapsed() < Duration::from_secs(5));
+    assert_eq!(child.wait().unwrap().code(), Some(0));
+    assert!(cleaned_up.exists());
+}
+
+#[test]
+#[cfg(unix)]
+fn descendants_of_terminated_processes_are_killed_after_they_exit() {
+    let dir = TempDir::new().unwrap();
+    let pid_file = dir.path().join("grandchild.pid");
+    let setup = format!(
+        "sh -c \"trap '' TERM; while true; do sleep 0.05; done\" &\necho $! > '{}'\ntrap 'exit 0' TERM",
+        pid_file.display()
+    );
+    let mut child = spawn_after_setup(&dir, &setup);
+    let started = Instant::now();
+    child.terminate(Duration::from_secs(10)).unwrap();
+    // The child exited right away, but isn't waited for by `terminate`.
+    assert!(started.elapsed() < Duration::from_secs(5));
+    assert_eq!(child.wait().unwrap().code(), Some(0));
+    let pid = fs::read_to_string(&pid_file).unwrap();
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while is_running(pid.trim()) {
+        assert!(Instant::now() < deadline, "grandchild {} is still running", pid);
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn terminated_processes_are_killed_after_the_grace_period() {
+    use std::os::unix::process::ExitStatusExt;
+
+    let dir = TempDir::new().unwrap();
+    let mut child = spawn_after_setup(&dir, "trap '' TERM");
+    let started = Instant::now();
+    child.terminate(Duration::from_millis(300)).unwrap();
+    assert!(started.elapsed() >= Duration::from_millis(300));
+    assert_eq!(child.wait().unwrap().signal(), Some(libc::SIGKILL));
+}
+
+#[test]
+#[cfg(unix)]
+fn running_checks_can_be_cancelled() {
+    let dir = TempDir::new().unwrap();
+    let cmd = fake_cargo(&dir, "sleep 1000\n");
+    let cancel = CancellationToken::default();
+    let canceller = {
+        let cancel = cancel.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            cancel.cancel();
+        })
+    };
+    let started = Instant::now();
+    let res = run_cargo(cmd, &mut |_| true, &mut |_| (), &cancel, RunOptions::default(), None);
+    assert!(matches!(res, Err(CargoError::Cancelled)), "{:?}", res);
+    assert!(started.elapsed() < Duration::from_secs(10));
+    canceller.join().unwrap();
+}
+
+#[test]
+#[cfg(unix)]
+fn restarting_does_not_wait_for_quiet_checks() {
+    let dir = TempDir::new().unwrap();
+    let config =
+        FlycheckConfig::builder().custom_command("sh", strings(&["-c", "sleep 1000"])).build();
+    let flycheck = Flycheck::new(config, dir.path().to_path_buf());
+    for _ in 0..2 {
+        flycheck.update();
+        loop {
+            match flycheck.task_recv.recv_timeout(Duration::from_secs(10)).unwrap() {
+                CheckTask::Status(WorkDoneProgress::Begin(_)) => break,
+                CheckTask::Error { message, .. } => panic!("check failed: {}", message),
+                _ => {}
+            }
+        }
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn diagnostics_of_replaced_runs_can_be_told_apart() {
+    let dir = TempDir::new().unwrap();
+    let script = dir.path().join("check.sh");
+    fs::write(&script, format!("echo '{}'\nsleep 1000\n", UNUSED_VARIABLE)).unwrap();
+    let config = FlycheckConfig::builder()
+        .custom_command("sh", vec![script.to_str().unwrap().to_string()])
+        .build();
+    let flycheck = Flycheck::new(config, dir.path().to_path_buf());
+    let mut runs = Vec::new();
+    for i in 0..2 {
+        flycheck.update();
+        let mut cleared = None;
+        let diagnostic_run = loop {
+            match flycheck.task_recv.recv_timeout(Duration::from_secs(10)).unwrap() {
+                CheckTask::ClearForFile { run, .. } => cleared = Some(run),
+                CheckTask::AddDiagnostics { run, .. } => break run,
+                CheckTask::Error { message, .. } => panic!("check failed: {}", message),
+                _ => {}
+            }
+        };
+        // The diagnostics belong to the run which cleared the previous ones.
+        if i == 0 {
+            assert_eq!(cleared, None);
+        } else {
+            assert_eq!(cleared, Some(diagnostic_run));
+        }
+        runs.push(diagnostic_run);
+    }
+    assert!(runs[0] < runs[1], "{:?}", runs);
+}
+
+/// Runs a check printing two diagnostics with a pause in between, and
+/// updates it again once the first one arrived. Returns the diagnostics,
+/// clears and summaries after that until the second update finished.
+#[cfg(unix)]
+fn update_during_check(cancel_on_update: bool) -> Vec<(&'static str, CheckRunId)> {
+    let dir = TempDir::new().unwrap();
+    let script = dir.path().join("check.sh");
+    let second = UNUSED_VARIABLE.replace("`x`", "`y`");
+    fs::write(&script, format!("echo '{}'\nsleep 0.5\necho '{}'\n", UNUSED_VARIABLE, second))
+        .unwrap();
+    let config = FlycheckConfig::builder()
+        .custom_command("sh", vec![script.to_str().unwrap().to_string()])
+        .cancel_on_update(cancel_on_update)
+        .build();
+    let flycheck = Flycheck::new(config, dir.path().to_path_buf());
+    flycheck.update();
+    loop {
+        match flycheck.task_recv.recv_timeout(Duration::from_secs(10)).unwrap() {
+            CheckTask::AddDiagnostics { .. } => break,
+            CheckTask::Error { message, .. } => panic!("check failed: {}", message),
+            _ => {}
+        }
+    }
+    flycheck.update();
+    let mut events = Vec::new();
+    loop {
+        match flycheck.task_recv.recv_timeout(Duration::from_secs(10)).unwrap() {
+            CheckTask::ClearForFile { run, .. } => events.push(("clear", run)),
+            CheckTask::AddDiagnostics { run, .. } => events.push(("diagnostic", run)),
+            CheckTask::Finished(summary) => {
+                events.push(("finished", summary.run));
+                if summary.run == CheckRunId(2) {
+                    return events;
+                }
+            }
+            CheckTask::Error { message, .. } => panic!("check failed: {}", message),
+            _ => {}
+        }
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn updates_cancel_the_running_check() {
+    assert_eq!(
+        update_during_check(true),
+        vec![
+            ("clear", CheckRunId(2)),
+            ("diagnostic", CheckRunId(2)),
+            ("diagnostic", CheckRunId(2)),
+            ("finished", CheckRunId(2)),
+        ]
+    );
+}
+
+#[test]
+#[cfg(unix)]
+fn updates_can_wait_for_the_running_check() {
+    assert_eq!(
+        update_during_check(false),
+        vec![
+            ("diagnostic", CheckRunId(1)),
+            ("finished", CheckRunId(1)),
+            ("clear", CheckRunId(2)),
+            ("diagnostic", CheckRunId(2)),
+            ("diagnostic", CheckRunId(2)),
+            ("finished", CheckRunId(2)),
+        ]
+    );
+}
+
+/// Sends `updates` to a check with `debounce` right after each other, and
+/// returns how many checks were started and how long the first one took to
+/// start.
+#[cfg(unix)]
+fn debounced_checks(debounce: Duration, updates: usize) -> (usize, Duration) {
+    let dir = TempDir::new().unwrap();
+    let runs = dir.path().join("runs");
+    let script = dir.path().join("check.sh");
+    fs::write(&script, format!("echo run >> '{}'\necho '{}'\n", runs.display(), UNUSED_VARIABLE))
+        .unwrap();
+    let config = FlycheckConfig::builder()
+        .custom_command("sh", vec![script.to_str().unwrap().to_string()])
+        .debounce(debounce)
+        .build();
+    let flycheck = Flycheck::new(config, dir.path().to_path_buf());
+    let started = Instant::now();
+    for _ in 0..updates {
+        flycheck.update();
+        thread::sleep(Duration::from_millis(20));
+    }
+    let mut first_start = None;
+    loop {
+        match flycheck.task_recv.recv_timeout(Duration::from_secs(10)).unwrap() {
+            CheckTask::Status(WorkDoneProgress::Begin(_)) if first_start.is_none() => {
+                first_start = Some(started.elapsed())
+            }
+            CheckTask::Finished(_) => break,
+            CheckTask::Error { message, .. } => panic!("check failed: {}", message),
+            _ => {}
+        }
+    }
+    // Another check would have started by now.
+    thread::sleep(debounce * 2);
+    let runs = fs::read_to_string(&runs).unwrap().lines().count();
+    (runs, first_start.unwrap())
+}
+
+#[test]
+#[cfg(unix)]
+fn rapid_updates_are_debounced() {
+    let (runs, _) = debounced_checks(Duration::from_millis(300), 3);
+    assert_eq!(runs, 1);
+}
+
+#[test]
+#[cfg(unix)]
+fn single_updates_are_checked_after_the_debounce_delay() {
+    let (runs, first_start) = debounced_checks(Duration::from_millis(300), 1);
+    assert_eq!(runs, 1);
+    assert!(first_start >= Duration::from_millis(300), "{:?}", first_start);
+    assert!(first_start < Duration::from_secs(5), "{:?}", first_start);
+
+    let (_, first_start) = debounced_checks(Duration::from_secs(0), 1);
+    assert!(first_start < Duration::from_millis(300), "{:?}", first_start);
+}
+
+fn fixed(delay: Duration) -> Debounce {
+    Debounce::new(delay, delay)
+}
+
+#[test]
+fn updates_during_a_check_are_coalesced_into_one_rerun() {
+    let start = Instant::now();
+    let mut schedule = Schedule::new(fixed(Duration::from_secs(0)), false);
+    assert!(!schedule.start_due(start));
+    schedule.update(start);
+    assert!(schedule.start_due(start));
+    schedule.started();
+    assert_eq!(schedule.state(), CheckState::Running);
+
+    // Nothing starts while the check is running, however many updates arrive.
+    let last_update = start + Duration::from_secs(5);
+    for seconds in 1..=5 {
+        schedule.update(start + Duration::from_secs(seconds));
+        assert!(!schedule.start_due(start + Duration::from_secs(seconds)));
+    }
+    assert_eq!(schedule.state(), CheckState::RunningWithPendingRerun { last_update });
+
+    schedule.finished(last_update);
+    assert_eq!(schedule.state(), CheckState::Pending { last_update });
+    assert!(schedule.start_due(last_update));
+    schedule.started();
+    assert!(!schedule.start_due(last_update));
+    schedule.finished(last_update);
+    assert_eq!(schedule.state(), CheckState::Idle);
+    assert!(!schedule.start_due(last_update));
+}
+
+#[test]
+fn updates_during_a_check_restart_it_with_cancel_on_update() {
+    let start = Instant::now();
+    let mut schedule = Schedule::new(fixed(Duration::from_secs(0)), true);
+    schedule.update(start);
+    assert!(schedule.start_due(start));
+    schedule.update(start);
+    assert!(schedule.start_due(start));
+    assert_eq!(schedule.state(), CheckState::Running);
+    schedule.finished(start);
+    assert_eq!(schedule.state(), CheckState::Idle);
+}
+
+#[test]
+fn checks_start_once_updates_stop_for_the_debounce_delay() {
+    let start = Instant::now();
+    let debounce = Duration::from_millis(300);
+    let mut schedule = Schedule::new(fixed(debounce), true);
+    schedule.update(start);
+    schedule.update(start + Duration::from_millis(200));
+    assert!(!schedule.start_due(start + debounce));
+    assert!(schedule.start_due(start + Duration::from_millis(200) + debounce));
+}
+
+#[test]
+fn updates_are_kept_while_other_checks_run() {
+    let start = Instant::now();
+    let mut schedule = Schedule::new(fixed(Duration::from_millis(300)), false);
+    schedule.update(start);
+    // F.x. the secondary check, started before the debounce delay passed.
+    schedule.started();
+    assert_eq!(schedule.state(), CheckState::RunningWithPendingRerun { last_update: start });
+    assert!(!schedule.start_due(start + Duration::from_secs(1)));
+    schedule.finished(start + Duration::from_secs(1));
+    assert!(schedule.start_due(start + Duration::from_secs(1)));
+}
+
+/// The delays of `debounce` for updates at the given milliseconds.
+fn debounce_delays(debounce: &mut Debounce, start: Instant, updates: &[u64]) -> Vec<u64> {
+    updates
+        .iter()
+        .map(|&millis| debounce.update(start + Duration::from_millis(millis)).as_millis() as u64)
+        .collect()
+}
+
+#[test]
+fn fixed_debounce_delays_never_grow() {
+    let start = Instant::now();
+    let mut debounce = Debounce::new(Duration::from_millis(300), Duration::from_millis(100));
+    assert_eq!(debounce_delays(&mut debounce, start, &[0, 10, 20, 30]), vec![300; 4]);
+    let mut debounce = Debounce::new(Duration::from_secs(0), Duration::from_secs(0));
+    assert_eq!(debounce_delays(&mut debounce, start, &[0, 10, 20, 30]), vec![0; 4]);
+}
+
+#[test]
+fn debounce_delays_grow_during_bursts() {
+    let start = Instant::now();
+    let mut debounce = Debounce::new(Duration::from_millis(50), Duration::from_secs(2));
+    assert_eq!(
+        debounce_delays(&mut debounce, start, &[0, 10, 80, 200, 500, 1000, 1700, 2800, 4500]),
+        vec![50, 100, 200, 400, 800, 1600, 2000, 2000, 2000]
+    );
+    assert_eq!(debounce.delay(), Duration::from_secs(2));
+}
+
+#[test]
+fn debounce_delays_snap_back_once_bursts_end() {
+    let start = Instant::now();
+    let mut debounce = Debounce::new(Duration::from_millis(50), Duration::from_secs(2));
+    assert_eq!(debounce_delays(&mut debounce, start, &[0, 10, 20, 30]), vec![50, 100, 200, 400]);
+    // The last delay passed without updates.
+    assert_eq!(debounce_delays(&mut debounce, start, &[430, 440]), vec![50, 100]);
+    // Single saves keep the base delay.
+    assert_eq!(debounce_delays(&mut debounce, start, &[1000, 2000, 3000]), vec![50, 50, 50]);
+}
+
+#[test]
+fn bursts_are_detected_without_a_base_delay() {
+    let start = Instant::now();
+    let mut debounce = Debounce::new(Duration::from_secs(0), Duration::from_secs(1));
+    assert_eq!(debounce_delays(&mut debounce, start, &[0, 50, 99]), vec![0, 100, 200]);
+    assert_eq!(debounce_delays(&mut debounce, start, &[300, 310]), vec![0, 100]);
+}
+
+#[test]
+fn adaptive_debounce_delays_the_schedule() {
+    let start = Instant::now();
+    let debounce = Debounce::new(Duration::from_millis(50), Duration::from_secs(2));
+    let mut schedule = Schedule::new(debounce, true);
+    assert_eq!(schedule.update(start), Duration::from_millis(50));
+    let last_update = start + Duration::from_millis(10);
+    assert_eq!(schedule.update(last_update), Duration::from_millis(100));
+    assert!(!schedule.start_due(last_update + Duration::from_millis(50)));
+    assert!(schedule.start_due(last_update + Duration::from_millis(100)));
+}
+
+#[test]
+fn paused_schedules_keep_updates_until_resumed() {
+    let start = Instant::now();
+    let mut schedule = Schedule::new(fixed(Duration::from_secs(0)), true);
+    schedule.set_paused(true);
+    assert!(!schedule.start_due(start));
+    schedule.update(start);
+    schedule.update(start);
+    assert!(!schedule.start_due(start));
+    schedule.set_paused(false);
+    assert!(schedule.start_due(start));
+    assert!(!schedule.start_due(start));
+}
+
+#[test]
+fn checks_start_no_sooner_than_the_min_interval() {
+    let start = Instant::now();
+    let at = |seconds| start + Duration::from_secs(seconds);
+    let mut schedule = Schedule::new(fixed(Duration::from_secs(1)), false);
+    schedule.set_min_interval(Some(Duration::from_secs(10)));
+    // Nothing ran yet, so only the debounce delay counts.
+    schedule.update(start);
+    assert_eq!(schedule.due_in(start), Some(Duration::from_secs(1)));
+    assert!(schedule.start_due(at(1)));
+    schedule.finished(at(3));
+
+    // Updates are held until the interval after the end of the check is over.
+    schedule.update(at(4));
+    schedule.update(at(5));
+    assert_eq!(schedule.due_in(at(6)), Some(Duration::from_secs(7)));
+    assert!(!schedule.start_due(at(12)));
+    assert!(schedule.start_due(at(13)));
+    assert_eq!(schedule.due_in(at(13)), None);
+
+    // The debounce delay still applies once the interval is over.
+    schedule.finished(at(14));
+    schedule.update(at(30));
+    assert_eq!(schedule.due_in(at(30)), Some(Duration::from_secs(1)));
+}
+
+#[test]
+fn min_interval_wins_over_cancel_on_update() {
+    let start = Instant::now();
+    let at = |seconds| start + Duration::from_secs(seconds);
+    let mut schedule = Schedule::new(fixed(Duration::from_secs(0)), true);
+    schedule.set_min_interval(Some(Duration::from_secs(10)));
+    schedule.update(start);
+    assert!(schedule.start_due(start));
+
+    // The running check is only replaced once it ran for the interval.
+    schedule.update(at(2));
+    assert_eq!(schedule.due_in(at(2)), Some(Duration::from_secs(8)));
+    assert!(!schedule.start_due(at(9)));
+    assert!(schedule.start_due(at(10)));
+    assert_eq!(schedule.state(), CheckState::Running);
+
+    // Paused schedules are never due.
+    schedule.update(at(11));
+    schedule.set_paused(true);
+    assert_eq!(schedule.due_in(at(30)), None);
+}
+
+#[test]
+fn held_reruns_wait_for_the_running_check() {
+    let start = Instant::now();
+    let mut schedule = Schedule::new(fixed(Duration::from_secs(0)), true);
+    schedule.started();
+    schedule.hold_reruns();
+    schedule.update(start);
+    assert_eq!(schedule.due_in(start), None);
+    assert!(!schedule.start_due(start));
+    schedule.finished(start);
+    assert!(schedule.start_due(start));
+
+    // Only the check which was running is held.
+    schedule.update(start);
+    assert!(schedule.start_due(start));
+}
+
+#[test]
+fn immediate_updates_skip_the_debounce_delay_and_min_interval() {
+    let start = Instant::now();
+    let at = |seconds| start + Duration::from_secs(seconds);
+    let mut schedule = Schedule::new(fixed(Duration::from_secs(10)), true);
+    schedule.set_min_interval(Some(Duration::from_secs(60)));
+    schedule.update_now(start);
+    assert_eq!(schedule.due_in(start), Some(Duration::from_secs(0)));
+    assert!(schedule.start_due(start));
+    schedule.finished(at(1));
+
+    // Normal updates wait again.
+    schedule.update(at(2));
+    assert_eq!(schedule.due_in(at(2)), Some(Duration::from_secs(59)));
+    // Unless followed by an immediate one, which later updates don't delay.
+    schedule.update_now(at(3));
+    schedule.update(at(4));
+    assert!(schedule.start_due(at(4)));
+    schedule.update(at(5));
+    assert!(!schedule.start_due(at(6)));
+}
+
+#[test]
+fn immediate_updates_keep_running_checks_without_cancel_on_update() {
+    let start = Instant::now();
+    let mut schedule = Schedule::new(fixed(Duration::from_secs(10)), false);
+    schedule.update_now(start);
+    assert!(schedule.start_due(start));
+    schedule.update(start);
+    schedule.update_now(start);
+    schedule.update_now(start);
+    assert_eq!(schedule.due_in(start), None);
+    assert_eq!(schedule.state(), CheckState::RunningWithPendingRerun { last_update: start });
+    schedule.finished(start);
+    // A single rerun, right away.
+    assert!(schedule.start_due(start));
+    assert!(!schedule.start_due(start));
+
+    let mut schedule = Schedule::new(fixed(Duration::from_secs(10)), true);
+    schedule.started();
+    schedule.hold_reruns();
+    schedule.update_now(start);
+    assert_eq!(schedule.due_in(start), None);
+}
+
+#[test]
+fn idle_workspaces_are_checked_again_periodically() {
+    let start = Instant::now();
+    let at = |seconds| start + Duration::from_secs(seconds);
+    let mut schedule = Schedule::new(fixed(Duration::from_secs(1)), true);
+    schedule.set_recheck_interval(Some(Duration::from_secs(60)));
+    // Nothing ran yet.
+    assert_eq!(schedule.due_in(at(100)), None);
+    schedule.update(start);
+    assert!(schedule.start_due(at(1)));
+    assert_eq!(schedule.due_in(at(30)), None);
+    schedule.finished(at(10));
+
+    assert_eq!(schedule.due_in(at(10)), Some(Duration::from_secs(60)));
+    assert!(!schedule.start_due(at(69)));
+    assert!(schedule.start_due(at(70)));
+    assert_eq!(schedule.state(), CheckState::Running);
+    schedule.finished(at(75));
+    assert_eq!(schedule.due_in(at(75)), Some(Duration::from_secs(60)));
+
+    // Updates replace the periodic check instead of adding another one.
+    schedule.update(at(80));
+    assert_eq!(schedule.due_in(at(80)), Some(Duration::from_secs(1)));
+    assert!(schedule.start_due(at(81)));
+    schedule.finished(at(82));
+    assert_eq!(schedule.due_in(at(82)), Some(Duration::from_secs(60)));
+
+    schedule.set_paused(true);
+    assert_eq!(schedule.due_in(at(200)), None);
+    assert!(!schedule.start_due(at(200)));
+}
+
+/// Pids of the processes started by this one which run `program` or have
+/// exited without being waited for.
+#[cfg(target_os = "linux")]
+fn child_processes(program: &str) -> Vec<String> {
+    let parent = std::process::id().to_string();
+    let mut children = Vec::new();
+    for entry in fs::read_dir("/proc").unwrap() {
+        let entry = entry.unwrap();
+        // Processes may exit while they are looked at.
+        let stat = match fs::read_to_string(entry.path().join("stat")) {
+            Ok(stat) => stat,
+            Err(_) => continue,
+        };
+        // `pid (comm) state ppid ...`, the name may contain spaces.
+        let (name, rest) = match (stat.find('('), stat.rfind(')')) {
+            (Some(start), Some(end)) => (&stat[start + 1..end], &stat[end + 1..]),
+            _ => continue,
+        };
+        if name == program && rest.split_whitespace().nth(1) == Some(&parent) {
+            children.push(entry.file_name().to_string_lossy().into_owned());
+        }
+    }
+    children
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn restarts_leave_no_zombie_processes() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = TempDir::new().unwrap();
+    // Run directly instead of through `sh`, so that its processes have a name
+    // of their own.
+    let program = "zombie-check";
+    let script = dir.path().join(program);
+    fs::write(&script, format!("#!/bin/sh\necho '{}'\nsleep 1000\n", UNUSED_VARIABLE)).unwrap();
+    fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+    let config = FlycheckConfig::builder().custom_command(script.to_str().unwrap(), vec![]).build();
+    let flycheck = Flycheck::new(config, dir.path().to_path_buf());
+    for _ in 0..50 {
+        flycheck.update();
+        loop {
+            match flycheck.task_recv.recv_timeout(Duration::from_secs(10)).unwrap() {
+                CheckTask::AddDiagnostics { .. } => break,
+                CheckTask::Error { message, .. } => panic!("check failed: {}", message),
+                _ => {}
+            }
+        }
+    }
+    drop(flycheck);
+    assert_eq!(child_processes(program), Vec::<String>::new());
+}
+
+#[test]
+#[cfg(unix)]
+fn cancelled_checks_are_killed_until_the_next_update() {
+    let dir = TempDir::new().unwrap();
+    let script = dir.path().join("check.sh");
+    fs::write(&script, format!("echo $$ > check.pid\necho '{}'\nsleep 1000\n", UNUSED_VARIABLE))
+        .unwrap();
+    let config = FlycheckConfig::builder()
+        .custom_command("sh", vec![script.to_str().unwrap().to_string()])
+        .build();
+    let flycheck = Flycheck::new(config, dir.path().to_path_buf());
+    flycheck.update();
+    first_diagnostic(&flycheck);
+    let pid = fs::read_to_string(dir.path().join("check.pid")).unwrap();
+    let pid = pid.trim();
+    assert!(is_running(pid));
+
+    let cancelled = Instant::now();
+    flycheck.cancel();
+    let mut ended = false;
+    let summary = loop {
+        match flycheck.task_recv.recv_timeout(Duration::from_secs(10)).unwrap() {
+            CheckTask::Status(WorkDoneProgress::End(end)) => {
+                let message = end.message.unwrap();
+                assert!(message.contains("was cancelled"), "{}", message);
+                ended = true;
+            }
+            CheckTask::Finished(summary) => break summary,
+            CheckTask::Error { message, .. } => panic!("check failed: {}", message),
+            _ => {}
+        }
+    };
+    assert!(ended, "the progress should end before the summary");
+    assert!(summary.cancelled);
+    while is_running(pid) {
+        assert!(cancelled.elapsed() < Duration::from_secs(5), "check {} is still running", pid);
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    // Nothing is started until the next update, which is checked as usual.
+    assert!(flycheck.task_recv.recv_timeout(Duration::from_millis(200)).is_err());
+    flycheck.update();
+    first_diagnostic(&flycheck);
+}
+
+#[test]
+#[cfg(unix)]
+fn paused_checks_run_once_resumed() {
+    let dir = TempDir::new().unwrap();
+    let runs = dir.path().join("runs");
+    let script = dir.path().join("check.sh");
+    fs::write(
+        &script,
+        format!("echo run >> '{}'\necho '{}'\nsleep 1000\n", runs.display(), UNUSED_VARIABLE),
+    )
+    .unwrap();
+    let config = FlycheckConfig::builder()
+        .custom_command("sh", vec![script.to_str().unwrap().to_string()])
+        .build();
+    let flycheck = Flycheck::new(config, dir.path().to_path_buf());
+    let runs = || fs::read_to_string(&runs).unwrap().lines().count();
+    flycheck.update();
+    first_diagnostic(&flycheck);
+
+    // Pausing stops the running check.
+    flycheck.set_enabled(false);
+    loop {
+        match flycheck.task_recv.recv_timeout(Duration::from_secs(10)).unwrap() {
+            CheckTask::Finished(summary) => {
+                assert!(summary.cancelled);
+                break;
+            }
+            CheckTask::Error { message, .. } => panic!("check failed: {}", message),
+            _ => {}
+        }
+    }
+    flycheck.update();
+    flycheck.update();
+    assert!(flycheck.task_recv.recv_timeout(Duration::from_millis(200)).is_err());
+    assert_eq!(runs(), 1);
+
+    // The updates while paused are checked once.
+    flycheck.set_enabled(true);
+    first_diagnostic(&flycheck);
+    assert!(flycheck.task_recv.recv_timeout(Duration::from_millis(200)).is_err());
+    assert_eq!(runs(), 2);
+}
+
+#[test]
+#[cfg(unix)]
+fn hung_checks_time_out() {
+    let dir = TempDir::new().unwrap();
+    let cmd = fake_cargo(&dir, "sleep 1000\n");
+    let started = Instant::now();
+    let res = run_cargo(
+        cmd,
+        &mut |_| true,
+        &mut |_| (),
+        &CancellationToken::default(),
+        RunOptions { timeout: Some(Duration::from_millis(200)), ..RunOptions::default() },
+        None,
+    );
+    match res {
+        Err(CargoError::TimedOut(elapsed)) => assert!(elapsed >= Duration::from_millis(200)),
+        res => panic!("expected a timeout, got {:?}", res),
+    }
+    assert!(started.elapsed() < Duration::from_secs(10));
+}
+
+#[test]
+#[cfg(unix)]
+fn timed_out_checks_are_reported_and_later_updates_run() {
+    let dir = TempDir::new().unwrap();
+    let script = dir.path().join("check.sh");
+    // Only the first run hangs.
+    fs::write(
+        &script,
+        format!(
+            "if [ -e ran ]; then\n    echo '{}'\nelse\n    touch ran\n    sleep 1000\nfi\n",
+            UNUSED_VARIABLE
+        ),
+    )
+    .unwrap();
+    let config = FlycheckConfig::builder()
+        .custom_command("sh", vec![script.to_str().unwrap().to_string()])
+        .timeout(Duration::from_millis(200))
+        .build();
+    let flycheck = Flycheck::new(config, dir.path().to_path_buf());
+    flycheck.update();
+    loop {
+        match flycheck.task_recv.recv_timeout(Duration::from_secs(10)).unwrap() {
+            CheckTask::Error { message, .. } => {
+                assert!(message.contains("killed after running for"), "{}", message);
+                break;
+            }
+            CheckTask::AddDiagnostics { .. } => panic!("the first check should hang"),
+            _ => {}
+        }
+    }
+
+    flycheck.update();
+    let (url, _diagnostic) = first_diagnostic(&flycheck);
+    assert!(url.path().ends_with("src/lib.rs"));
+}
+
+#[test]
+fn missing_check_commands_are_reported() {
+    let dir = TempDir::new().unwrap();
+    let config = FlycheckConfig::builder().custom_command("no-such-check-command", vec![]).build();
+    let flycheck = Flycheck::new(config, dir.path().to_path_buf());
+    // The check thread survives the failure and reports the next one as well.
+    for _ in 0..2 {
+        flycheck.update();
+        loop {
+            match flycheck.task_recv.recv_timeout(Duration::from_secs(10)).unwrap() {
+                CheckTask::Error { message, .. } => {
+                    assert!(message.starts_with("cargo check could not be started"), "{}", message);
+                    assert!(message.contains("no-such-check-command"), "{}", message);
+                    assert!(message.contains("os error"), "{}", message);
+                    assert!(message.contains("in `PATH`"), "{}", message);
+                    break;
+                }
+                CheckTask::AddDiagnostics { .. } => panic!("nothing should have been checked"),
+                _ => {}
+            }
+        }
+    }
+}
+
+#[test]
+fn the_check_thread_survives_panics() {
+    let (sender, task_recv) = unbounded();
+    let task_send = TaskSender { sender, disconnected: Cell::new(false) };
+    let mut runs = 0;
+    run_restarting(&task_send, |_| {
+        runs += 1;
+        if runs == 1 {
+            panic!("deliberate panic for testing");
+        }
+    });
+    assert_eq!(runs, 2);
+    match task_recv.try_recv().unwrap() {
+        CheckTask::Error { message, kind } => {
+            assert_eq!(
+                message,
+                "cargo check crashed and was restarted: deliberate panic for testing"
+            );
+            assert_eq!(kind, CheckErrorKind::Internal);
+        }
+        task => panic!("expected an error, got {:?}", task),
+    }
+    assert!(task_recv.try_recv().is_err());
+}
+
+#[test]
+#[cfg(unix)]
+fn checks_survive_panics() {
+    let dir = TempDir::new().unwrap();
+    let script = dir.path().join("check.sh");
+    fs::write(&script, format!("echo '{}'\n", UNUSED_VARIABLE)).unwrap();
+    let panicked = AtomicBool::new(false);
+    let config = FlycheckConfig::builder()
+        .custom_command("sh", vec![script.to_str().unwrap().to_string()])
+        .command_hook(move |_| {
+            if !panicked.swap(true, Ordering::SeqCst) {
+                panic!("deliberate panic for testing");
+            }
+        })
+        .build();
+    let flycheck = Flycheck::new(config, dir.path().to_path_buf());
+    flycheck.update();
+    let message = first_error(&flycheck);
+    assert_eq!(message, "cargo check crashed: deliberate panic for testing");
+    flycheck.update();
+    let (_, diagnostic) = first_diagnostic(&flycheck);
+    assert!(diagnostic.message.starts_with("unused variable: `x`"), "{}", diagnostic.message);
+}
+
+#[test]
+fn end_messages() {
+    let elapsed = Duration::from_millis(12_340);
+    let none = Duration::from_secs(0);
+    assert_eq!(
+        end_message(CheckOutcome::Finished, elapsed, 3, 7, none),
+        "cargo check finished in 12.3s (3 errors, 7 warnings)"
+    );
+    assert_eq!(
+        end_message(CheckOutcome::Finished, elapsed, 1, 0, none),
+        "cargo check finished in 12.3s (1 error, 0 warnings)"
+    );
+    assert_eq!(
+        end_message(CheckOutcome::Finished, elapsed, 0, 1, Duration::from_millis(2_500)),
+        "cargo check finished in 12.3s (0 errors, 1 warning), waited 2.5s for cargo lock"
+    );
+    assert_eq!(
+        end_message(CheckOutcome::Failed, elapsed, 2, 0, none),
+        "cargo check failed after 12.3s"
+    );
+    assert_eq!(
+        end_message(CheckOutcome::Cancelled, elapsed, 2, 0, none),
+        "cargo check was cancelled after 12.3s"
+    );
+}
+
+#[test]
+fn failed_checks_end_as_failed() {
+    let dir = TempDir::new().unwrap();
+    let config = FlycheckConfig::builder().custom_command("no-such-check-command", vec![]).build();
+    let flycheck = Flycheck::new(config, dir.path().to_path_buf());
+    flycheck.update();
+    let mut started = false;
+    let end = loop {
+        match flycheck.task_recv.recv_timeout(Duration::from_secs(10)).unwrap() {
+            CheckTask::Status(WorkDoneProgress::Begin(_)) => started = true,
+            CheckTask::Status(WorkDoneProgress::End(end)) if started => break end,
+            _ => {}
+        }
+    };
+    let message = end.message.unwrap();
+    assert!(message.starts_with("cargo check failed after "), "{}", message);
+}
+
+/// A script printing `count` messages, then creating `done`.
+#[cfg(unix)]
+fn flood_script(count: usize, done: &Path) -> String {
+    format!(
+        "i=0\nwhile [ $i -lt {} ]; do\n    echo '{}'\n    i=$((i + 1))\ndone\ntouch '{}'\n",
+        count,
+        numbered_unused_variable(),
+        done.display()
+    )
+}
+
+#[test]
+#[cfg(unix)]
+fn full_channels_make_cargo_wait() {
+    let dir = TempDir::new().unwrap();
+    let done = dir.path().join("done");
+    let cmd = fake_cargo(&dir, &flood_script(50_000, &done));
+    let (message_send, message_recv) = bounded(16);
+    let check = thread::spawn(move || {
+        run_cargo(
+            cmd,
+            &mut |message| message_send.send(message).is_ok(),
+            &mut |_| (),
+            &CancellationToken::default(),
+            RunOptions { message_buffer: 16, ..RunOptions::default() },
+            None,
+        )
+        .unwrap()
+    });
+    thread::sleep(Duration::from_millis(500));
+    assert!(!done.exists(), "all messages were buffered");
+    assert_eq!(message_recv.iter().count(), 50_000);
+    assert_eq!(check.join().unwrap().messages, 50_000);
+    assert!(done.exists());
+}
+
+#[test]
+#[cfg(unix)]
+fn flooded_checks_can_be_dropped() {
+    let dir = TempDir::new().unwrap();
+    let script = dir.path().join("check.sh");
+    fs::write(&script, flood_script(50_000, &dir.path().join("done"))).unwrap();
+    let config = FlycheckConfig::builder()
+        .custom_command("sh", vec![script.to_str().unwrap().to_string()])
+        .message_buffer(16)
+        .build();
+    let flycheck = Flycheck::new(config, dir.path().to_path_buf());
+    flycheck.update();
+    first_diagnostic(&flycheck);
+    let started = Instant::now();
+    drop(flycheck);
+    assert!(started.elapsed() < Duration::from_secs(5), "{:?}", started.elapsed());
+    assert!(!dir.path().join("done").exists());
+}
+
+/// Collects the diagnostics and warnings of a single check.
+fn diagnostics_and_warnings(flycheck: &Flycheck) -> (Vec<Url>, Vec<String>) {
+    let mut started = false;
+    let mut urls = Vec::new();
+    let mut warnings = Vec::new();
+    loop {
+        match flycheck.task_recv.recv_timeout(Duration::from_secs(10)).unwrap() {
+            CheckTask::Status(WorkDoneProgress::Begin(_)) => started = true,
+            CheckTask::Status(WorkDoneProgress::End(_)) if started => break,
+            CheckTask::AddDiagnostics { url, diagnostics, .. } => {
+                urls.extend(diagnostics.iter().map(|_| url.clone()))
+            }
+            CheckTask::Warning(warning) => warnings.push(warning),
+            CheckTask::Error { message, .. } => panic!("check failed: {}", message),
+            _ => {}
+        }
+    }
+    // The warning follows the end of the progress.
+    while let Ok(task) = flycheck.task_recv.recv_timeout(Duration::from_millis(100)) {
+        if let CheckTask::Warning(warning) = task {
+            warnings.push(warning);
+        }
+    }
+    (urls, warnings)
+}
+
+#[test]
+#[cfg(unix)]
+fn diagnostics_are_limited_across_files() {
+    let dir = TempDir::new().unwrap();
+    let warning = numbered_unused_variable();
+    let other_file = warning.replace("src/lib.rs", "src/main.rs");
+    let script = dir.path().join("check.sh");
+    fs::write(
+        &script,
+        format!(
+            "for i in 1 2 3; do echo '{}'; done\nfor i in 1 2 3; do echo '{}'; done\n",
+            warning, other_file
+        ),
+    )
+    .unwrap();
+    let config = FlycheckConfig::builder()
+        .custom_command("sh", vec![script.to_str().unwrap().to_string()])
+        .max_diagnostics(4)
+        .build();
+    let flycheck = Flycheck::new(config, dir.path().to_path_buf());
+    flycheck.update();
+    let (urls, warnings) = diagnostics_and_warnings(&flycheck);
+    let files: Vec<_> = urls.iter().map(|url| url.path().rsplit('/').next().unwrap()).collect();
+    assert_eq!(files, vec!["lib.rs", "lib.rs", "lib.rs", "main.rs"]);
+    assert_eq!(
+        warnings,
+        vec!["cargo check reported 2 more diagnostics, only the first 4 are shown"]
+    );
+}
+
+#[test]
+#[cfg(unix)]
+fn checks_can_stop_at_the_diagnostic_limit() {
+    let dir = TempDir::new().unwrap();
+    let done = dir.path().join("done");
+    let script = dir.path().join("check.sh");
+    fs::write(&script, flood_script(50_000, &done)).unwrap();
+    let config = FlycheckConfig::builder()
+        .custom_command("sh", vec![script.to_str().unwrap().to_string()])
+        .max_diagnostics(5)
+        .stop_at_diagnostic_limit(true)
+        .message_buffer(16)
+        .build();
+    let flycheck = Flycheck::new(config, dir.path().to_path_buf());
+    flycheck.update();
+    let (urls, warnings) = diagnostics_and_warnings(&flycheck);
+    assert_eq!(urls.len(), 5);
+    assert_eq!(warnings, vec!["cargo check was stopped after reporting 5 diagnostics"]);
+    assert!(!done.exists());
+}
+
+/// Runs a check printing a future incompatibility report about a registry
+/// dependency, returning the files diagnostics were reported for.
+#[cfg(unix)]
+fn future_incompat_diagnostics(dir: &TempDir, report_future_incompat: bool) -> Vec<Url> {
+    let report = r#"{"reason":"future-incompat-report","id":3,"future_incompat_report":[{"package_id":"baz 0.2.3 (registry+https://github.com/rust-lang/crates.io-index)","future_breakage_date":null,"diagnostic":{"message":"the type `bool` does not permit being left uninitialized","code":null,"level":"warning","spans":[],"children":[],"rendered":null}}]}"#;
+    let script = dir.path().join("check.sh");
+    fs::write(&script, format!("echo '{}'\n", report)).unwrap();
+    let config = FlycheckConfig::builder()
+        .custom_command("sh", vec![script.to_str().unwrap().to_string()])
+        .report_future_incompat(report_future_incompat)
+        .build();
+    let flycheck = Flycheck::new(config, dir.path().to_path_buf());
+    flycheck.update();
+    diagnostics_and_warnings(&flycheck).0
+}
+
+#[test]
+#[cfg(unix)]
+fn future_incompat_reports_count_towards_the_diagnostic_limit() {
+    let dir = TempDir::new().unwrap();
+    let report = r#"{"reason":"future-incompat-report","id":3,"future_incompat_report":[{"package_id":"baz 0.2.3 (registry+https://github.com/rust-lang/crates.io-index)","future_breakage_date":null,"diagnostic":{"message":"the type `bool` does not permit being left uninitialized","code":null,"level":"warning","spans":[],"children":[],"rendered":null}}]}"#;
+    let script = dir.path().join("check.sh");
+    fs::write(&script, format!("echo '{}'\necho '{}'\n", UNUSED_VARIABLE, report)).unwrap();
+    let config = FlycheckConfig::builder()
+        .custom_command("sh", vec![script.to_str().unwrap().to_string()])
+        .report_future_incompat(true)
+        .max_diagnostics(1)
+        .build();
+    let flycheck = Flycheck::new(config, dir.path().to_path_buf());
+    flycheck.update();
+    let (urls, warnings) = diagnostics_and_warnings(&flycheck);
+    assert_eq!(urls, vec![Url::from_file_path(dir.path().join("src/lib.rs")).unwrap()]);
+    assert_eq!(
+        warnings,
+        vec!["cargo check reported 1 more diagnostics, only the first 1 are shown"]
+    );
+}
+
+#[test]
+#[cfg(unix)]
+fn future_incompat_reports_are_shown_on_the_workspace_manifest() {
+    let dir = TempDir::new().unwrap();
+    let manifest = Url::from_file_path(dir.path().join("Cargo.toml")).unwrap();
+    assert_eq!(future_incompat_diagnostics(&dir, true), vec![manifest]);
+}
+
+#[test]
+#[cfg(unix)]
+fn future_incompat_reports_are_ignored_by_default() {
+    let dir = TempDir::new().unwrap();
+    assert_eq!(future_incompat_diagnostics(&dir, false), Vec::<Url>::new());
+}
+
+const FOO_ID: &str = "foo 0.1.0 (path+file:///ws)";
+
+/// `cargo metadata` output for a workspace with the member `foo`, which has a
+/// build script, and its dependency `bar`.
+fn workspace_metadata() -> String {
+    let package = |name: &str, id: &str, kinds: &[&str]| {
+        let targets: Vec<String> = kinds
+            .iter()
+            .map(|kind| {
+                format!(
+                    r#"{{"name":"{}","kind":["{}"],"crate_types":["lib"],"required-features":[],"src_path":"/ws/{}.rs","edition":"2018","doctest":false}}"#,
+                    name, kind, kind
+                )
+            })
+            .collect();
+        format!(
+            r#"{{"name":"{}","version":"0.1.0","authors":[],"id":"{}","source":null,"description":null,"dependencies":[],"license":null,"license_file":null,"targets":[{}],"features":{{}},"manifest_path":"/ws/{}/Cargo.toml","categories":[],"keywords":[],"readme":null,"repository":null,"edition":"2018","metadata":null,"links":null}}"#,
+            name,
+            id,
+            targets.join(","),
+            name
+        )
+    };
+    format!(
+        r#"{{"packages":[{},{}],"workspace_members":["{}"],"resolve":null,"target_directory":"/ws/target","version":1,"workspace_root":"/ws"}}"#,
+        package("foo", FOO_ID, &["lib", "bin", "test", "custom-build"]),
+        package("bar", "bar 0.1.0 (path+file:///bar)", &["lib", "example", "custom-build"]),
+        FOO_ID
+    )
+}
+
+#[test]
+fn units_are_counted_from_metadata() {
+    let metadata: cargo_metadata::Metadata = serde_json::from_str(&workspace_metadata()).unwrap();
+    assert_eq!(cargo_command().count_units(&metadata), 5);
+    assert_eq!(FlycheckConfig::builder().all_targets(true).build().count_units(&metadata), 6);
+    let clippy =
+        FlycheckConfig::builder().command("clippy").extra_args(strings(&["--no-deps"])).build();
+    assert_eq!(clippy.count_units(&metadata), 3);
+}
+
+#[test]
+fn percentages_are_capped() {
+    assert_eq!(percentage(3, Some(4)), Some(75.0));
+    assert_eq!(percentage(5, Some(4)), Some(100.0));
+    assert_eq!(percentage(3, Some(0)), None);
+    assert_eq!(percentage(3, None), None);
+}
+
+/// A `compiler-artifact` message for a `kind` target of `foo`.
+#[cfg(unix)]
+fn artifact(kind: &str, fresh: bool) -> String {
+    format!(
+        r#"{{"reason":"compiler-artifact","package_id":"{}","target":{{"kind":["{}"],"crate_types":["lib"],"name":"foo","src_path":"/ws/src/lib.rs","edition":"2018","doctest":true}},"profile":{{"opt_level":"0","debuginfo":2,"debug_assertions":true,"overflow_checks":true,"test":false}},"features":[],"filenames":[],"executable":null,"fresh":{}}}"#,
+        FOO_ID, kind, fresh
+    )
+}
+
+/// Runs a check answering `cargo metadata` with `metadata_script` and
+/// printing `artifacts`, returning the percentages of its progress.
+#[cfg(unix)]
+fn progress_percentages(metadata_script: &str, artifacts: &[String]) -> Vec<Option<f64>> {
+    let dir = TempDir::new().unwrap();
+    fs::write(dir.path().join("Cargo.toml"), "").unwrap();
+    let mut script = format!("shift\nif [ \"$1\" = metadata ]; then\n{}\nfi\n", metadata_script);
+    for artifact in artifacts {
+        script.push_str(&format!("echo '{}'\n", artifact));
+    }
+    let wrapper = dir.path().join("wrapper.sh");
+    fs::write(&wrapper, script).unwrap();
+    let config = FlycheckConfig::builder()
+        .wrapper(vec!["sh".to_string(), wrapper.to_str().unwrap().to_string()])
+        .estimate_progress(true)
+        .build();
+    let flycheck = Flycheck::new(config, dir.path().to_path_buf());
+    flycheck.update();
+    let mut started = false;
+    let mut percentages = Vec::new();
+    loop {
+        match flycheck.task_recv.recv_timeout(Duration::from_secs(10)).unwrap() {
+            CheckTask::Status(WorkDoneProgress::Begin(begin)) => {
+                started = true;
+                percentages.push(begin.percentage);
+            }
+            CheckTask::Status(WorkDoneProgress::Report(report)) => {
+                percentages.push(report.percentage)
+            }
+            CheckTask::Status(WorkDoneProgress::End(_)) if started => return percentages,
+            CheckTask::Error { message, .. } => panic!("check failed: {}", message),
+            _ => {}
+        }
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn progress_is_estimated_from_metadata() {
+    let metadata_script = format!("echo '{}'\nexit", workspace_metadata());
+    // The dependency is fresh already.
+    let artifacts = vec![
+        artifact("custom-build", true),
+        artifact("lib", true),
+        artifact("custom-build", false),
+        artifact("lib", false),
+        artifact("bin", false),
+    ];
+    assert_eq!(
+        progress_percentages(&metadata_script, &artifacts),
+        vec![Some(0.0), Some(60.0), Some(80.0), Some(100.0)]
+    );
+}
+
+#[test]
+#[cfg(unix)]
+fn checks_run_without_a_percentage_if_metadata_fails() {
+    let artifacts = vec![artifact("lib", false), artifact("bin", false)];
+    assert_eq!(progress_percentages("exit 101", &artifacts), vec![Some(0.0), None, None]);
+}
+
+/// Cargo's output on stderr while checking a workspace for the first time.
+const CHECK_TRANSCRIPT: &str = "    Updating crates.io index
+  Downloaded serde v1.0.104
+  Downloaded 1 crate (76.5 KB) in 0.50s
+   Compiling proc-macro2 v1.0.9
+   Compiling serde v1.0.104
+    Checking foo v0.1.0 (/ws)
+warning: unused variable: `x`
+ --> src/lib.rs:2:9
+    Blocking waiting for file lock on build directory
+    Finished dev [unoptimized + debuginfo] target(s) in 1.02s
+";
+
+#[test]
+fn started_crates_are_parsed_from_stderr() {
+    let started: Vec<_> = CHECK_TRANSCRIPT.lines().filter_map(started_crate).collect();
+    assert_eq!(started, vec!["proc-macro2", "serde", "foo"]);
+    assert_eq!(started_crate("    Checking"), None);
+    assert_eq!(started_crate("   Compiling foo"), None);
+    assert_eq!(started_crate("Checking the workspace again"), None);
+}
+
+#[test]
+#[cfg(unix)]
+fn started_crates_are_reported_as_progress() {
+    let dir = TempDir::new().unwrap();
+    let script = dir.path().join("check.sh");
+    fs::write(
+        &script,
+        format!("cat >&2 <<'EOF'\n{}EOF\necho '{}'\n", CHECK_TRANSCRIPT, artifact("lib", false)),
+    )
+    .unwrap();
+    let config = FlycheckConfig::builder()
+        .custom_command("sh", vec![script.to_str().unwrap().to_string()])
+        .build();
+    let flycheck = Flycheck::new(config, dir.path().to_path_buf());
+    flycheck.update();
+    let mut started = false;
+    let mut messages = Vec::new();
+    loop {
+        match flycheck.task_recv.recv_timeout(Duration::from_secs(10)).unwrap() {
+            CheckTask::Status(WorkDoneProgress::Begin(_)) => started = true,
+            CheckTask::Status(WorkDoneProgress::Report(report)) => {
+                messages.push(report.message.unwrap())
+            }
+            CheckTask::Status(WorkDoneProgress::End(_)) if started => break,
+            CheckTask::Error { message, .. } => panic!("check failed: {}", message),
+            _ => {}
+        }
+    }
+    // Stdout and stderr are read independently, only the order of the lines
+    // of each is kept.
+    let checking: Vec<_> = messages.iter().filter(|message| message.ends_with("...")).collect();
+    assert_eq!(
+        checking,
+        vec![
+            "checking proc-macro2...",
+            "checking serde...",
+            "checking foo...",
+            "waiting for cargo lock...",
+        ]
+    );
+    // Finished crates are still reported by their artifact.
+    assert!(messages.contains(&"foo".to_string()), "{:?}", messages);
+}
+
+fn first_error(flycheck: &Flycheck) -> String {
+    loop {
+        match flycheck.task_recv.recv_timeout(Duration::from_secs(10)).unwrap() {
+            CheckTask::Error { message, .. } => return message,
+            CheckTask::AddDiagnostics { .. } => panic!("nothing should have been checked"),
+            _ => {}
+        }
+    }
+}
+
+#[test]
+fn missing_cargo_suggests_installing_rust() {
+    let dir = TempDir::new().unwrap();
+    fs::write(dir.path().join("Cargo.toml"), "").unwrap();
+    let config = FlycheckConfig::builder().cargo_binary(dir.path().join("no-such-cargo")).build();
+    let flycheck = Flycheck::new(config, dir.path().to_path_buf());
+    flycheck.update();
+    let message = first_error(&flycheck);
+    assert!(message.starts_with("cargo check could not be started"), "{}", message);
+    assert!(message.contains("install Rust with rustup"), "{}", message);
+    assert!(message.contains("`PATH`"), "{}", message);
+}
+
+#[test]
+#[cfg(unix)]
+fn broken_manifests_are_quoted() {
+    let dir = TempDir::new().unwrap();
+    let manifest = dir.path().join("Cargo.toml");
+    fs::write(&manifest, "[package").unwrap();
+    let cargo = dir.path().join("cargo.sh");
+    fs::write(
+        &cargo,
+        "#!/bin/sh\n\
+         echo 'error: failed to parse manifest at `/ws/member/Cargo.toml`' >&2\n\
+         echo '' >&2\n\
+         echo 'Caused by:' >&2\n\
+         echo '  missing field `name`' >&2\n\
+         exit 101\n",
+    )
+    .unwrap();
+    Command::new("chmod").arg("+x").arg(&cargo).status().unwrap();
+    let config = FlycheckConfig::builder().cargo_binary(&cargo).build();
+    let flycheck = Flycheck::new(config, dir.path().to_path_buf());
+    flycheck.update();
+    let message = first_error(&flycheck);
+    assert!(message.contains(manifest.to_str().unwrap()), "{}", message);
+    assert!(message.contains("failed to parse manifest at `/ws/member/Cargo.toml`"), "{}", message);
+    assert!(message.contains("missing field `name`"), "{}", message);
+}
+
+#[test]
+fn missing_clippy_is_detected() {
+    let toolchain = || Some("stable-x86_64-unknown-linux-gnu".to_string());
+    assert_eq!(
+        missing_clippy_message("error: no such subcommand: `clippy`", toolchain).unwrap(),
+        "cargo clippy is not installed, install it with \
+         `rustup component add clippy --toolchain stable-x86_64-unknown-linux-gnu`"
+    );
+    assert_eq!(
+        missing_clippy_message("error: no such command: `clippy`", || None).unwrap(),
+        "cargo clippy is not installed, install it with `rustup component add clippy`"
+    );
+    let unrelated = missing_clippy_message("error: no such subcommand: `udeps`", || {
+        panic!("the toolchain is only needed for clippy")
+    });
+    assert_eq!(unrelated, None);
+}
+
+#[test]
+#[cfg(unix)]
+fn missing_clippy_suggests_installing_it() {
+    let dir = TempDir::new().unwrap();
+    fs::write(dir.path().join("Cargo.toml"), "").unwrap();
+    let cargo = dir.path().join("cargo.sh");
+    fs::write(
+        &cargo,
+        "#!/bin/sh\n\
+         echo 'error: no such subcommand: `clippy`' >&2\n\
+         echo '' >&2\n\
+         echo '\tView all installed commands with `cargo --list`' >&2\n\
+         exit 101\n",
+    )
+    .unwrap();
+    Command::new("chmod").arg("+x").arg(&cargo).status().unwrap();
+    let config = FlycheckConfig::builder()
+        .cargo_binary(&cargo)
+        .command("clippy")
+        .toolchain("nightly")
+        .build();
+    let flycheck = Flycheck::new(config, dir.path().to_path_buf());
+    flycheck.update();
+    let message = first_error(&flycheck);
+    assert_eq!(
+        message,
+        "cargo clippy is not installed, install it with \
+         `rustup component add clippy --toolchain nightly`"
+    );
+}
+
+#[test]
+#[cfg(unix)]
+fn waiting_for_the_cargo_lock_is_reported() {
+    let dir = TempDir::new().unwrap();
+    let script = dir.path().join("check.sh");
+    fs::write(
+        &script,
+        format!(
+            "echo '    Blocking waiting for file lock on build directory' >&2\nsleep 0.2\necho '{}'\n",
+            UNUSED_VARIABLE
+        ),
+    )
+    .unwrap();
+    let config = FlycheckConfig::builder()
+        .custom_command("sh", vec![script.to_str().unwrap().to_string()])
+        .build();
+    let flycheck = Flycheck::new(config, dir.path().to_path_buf());
+    flycheck.update();
+    let mut started = false;
+    let mut reports = Vec::new();
+    let mut diagnostics = 0;
+    let end = loop {
+        match flycheck.task_recv.recv_timeout(Duration::from_secs(10)).unwrap() {
+            CheckTask::Status(WorkDoneProgress::Begin(_)) => started = true,
+            CheckTask::Status(WorkDoneProgress::Report(report)) => reports.extend(report.message),
+            CheckTask::Status(WorkDoneProgress::End(end)) if started => break end,
+            CheckTask::AddDiagnostics { diagnostics: batch, .. } => diagnostics += batch.len(),
+            CheckTask::Error { message, .. } => panic!("check failed: {}", message),
+            _ => {}
+        }
+    };
+    assert_eq!(reports, vec!["waiting for cargo lock...".to_string()]);
+    assert_eq!(diagnostics, 1);
+    let message = end.message.unwrap();
+    assert!(message.contains(", waited ") && message.ends_with("s for cargo lock"), "{}", message);
+}
+
+const TARGET_DIR_IN_USE: &str = "echo 'error: failed to open: /ws/target/debug/.cargo-lock' >&2\necho '' >&2\necho 'Caused by:' >&2\necho '  Resource temporarily unavailable (os error 11)' >&2\nexit 101";
+
+/// Returns a flycheck running a script which fails like cargo does when the
+/// target directory is in use for the first `failures` runs, and counts its
+/// runs in `runs.log`.
+fn contended_flycheck(dir: &TempDir, failures: u32, retry_delay: Duration) -> Flycheck {
+    let script = dir.path().join("check.sh");
+    fs::write(
+        &script,
+        format!(
+            "echo run >> runs.log\nif [ $(wc -l < runs.log) -le {} ]; then\n{}\nfi\necho '{}'\n",
+            failures, TARGET_DIR_IN_USE, UNUSED_VARIABLE
+        ),
+    )
+    .unwrap();
+    let config = FlycheckConfig::builder()
+        .custom_command("sh", vec![script.to_str().unwrap().to_string()])
+        .retry_delay(retry_delay)
+        .build();
+    Flycheck::new(config, dir.path().to_path_buf())
+}
+
+fn runs(dir: &TempDir) -> usize {
+    fs::read_to_string(dir.path().join("runs.log")).unwrap_or_default().lines().count()
+}
+
+#[test]
+#[cfg(unix)]
+fn checks_failing_due_to_contention_are_retried() {
+    let dir = TempDir::new().unwrap();
+    let flycheck = contended_flycheck(&dir, 1, Duration::from_millis(50));
+    flycheck.update();
+    first_diagnostic(&flycheck);
+    assert_eq!(runs(&dir), 2);
+}
+
+#[test]
+#[cfg(unix)]
+fn checks_are_retried_only_once() {
+    let dir = TempDir::new().unwrap();
+    let flycheck = contended_flycheck(&dir, 2, Duration::from_millis(50));
+    flycheck.update();
+    loop {
+        match flycheck.task_recv.recv_timeout(Duration::from_secs(10)).unwrap() {
+            CheckTask::Error { message, .. } => {
+                assert!(message.contains(".cargo-lock"), "{}", message);
+                break;
+            }
+            CheckTask::AddDiagnostics { .. } => panic!("the retry should have failed as well"),
+            _ => {}
+        }
+    }
+    assert_eq!(runs(&dir), 2);
+}
+
+#[test]
+#[cfg(unix)]
+fn updates_replace_pending_retries() {
+    let dir = TempDir::new().unwrap();
+    let flycheck = contended_flycheck(&dir, 1, Duration::from_millis(300));
+    flycheck.update();
+    // Waits until the first check failed, so that its retry is pending.
+    let mut started = false;
+    loop {
+        match flycheck.task_recv.recv_timeout(Duration::from_secs(10)).unwrap() {
+            CheckTask::Status(WorkDoneProgress::Begin(_)) => started = true,
+            CheckTask::Status(WorkDoneProgress::End(_)) if started => break,
+            CheckTask::Error { message, .. } => panic!("check failed: {}", message),
+            _ => {}
+        }
+    }
+    flycheck.update();
+    first_diagnostic(&flycheck);
+    std::thread::sleep(Duration::from_millis(600));
+    assert_eq!(runs(&dir), 2);
+}
+
+#[test]
+fn only_contention_failures_are_retried() {
+    let failure =
+        |stderr: &str| CargoError::NonZeroExit { code: Some(101), stderr: stderr.to_string() };
+    assert!(failure("error: failed to open: /ws/target/debug/.cargo-lock").is_contention());
+    assert!(failure("The process cannot access the file because it is being used by another process. (os error 32)").is_contention());
+    assert!(!failure("error: could not compile `foo`").is_contention());
+    assert!(!CargoError::Cancelled.is_contention());
+}
+
+#[test]
+#[cfg(unix)]
+fn low_priority_checks_are_niced() {
+    let dir = TempDir::new().unwrap();
+    // Prints the niceness of the check and of a process it started.
+    let cmd = fake_cargo(&dir, "ps -o ni= -p $$ >&2\nsh -c 'ps -o ni= -p $$' >&2\nexit 1\n");
+    let err = run_cargo(
+        cmd,
+        &mut |_| true,
+        &mut |_| (),
+        &CancellationToken::default(),
+        RunOptions { low_priority: true, ..RunOptions::default() },
+        None,
+    )
+    .unwrap_err();
+    match err {
+        CargoError::NonZeroExit { stderr, .. } => {
+            let niceness: Vec<_> = stderr.lines().map(|it| it.trim()).collect();
+            assert_eq!(niceness, vec!["10", "10"]);
+        }
+        err => panic!("unexpected error {:?}", err),
+    }
+}
+
+#[test]
+#[cfg(windows)]
+fn checks_have_no_console_window() {
+    use winapi::um::winbase::{BELOW_NORMAL_PRIORITY_CLASS, CREATE_NO_WINDOW};
+
+    assert_eq!(crate::process::creation_flags(false), CREATE_NO_WINDOW);
+    assert_eq!(
+        crate::process::creation_flags(true),
+        CREATE_NO_WINDOW | BELOW_NORMAL_PRIORITY_CLASS
+    );
+}
+
+#[test]
+#[cfg(windows)]
+fn low_priority_checks_run_below_normal() {
+    let mut cmd = Command::new("powershell");
+    cmd.arg("-NoProfile")
+        .arg("-Command")
+        .arg("[Console]::Error.WriteLine((Get-Process -Id $PID).PriorityClass); exit 1");
+    let err = run_cargo(
+        cmd,
+        &mut |_| true,
+        &mut |_| (),
+        &CancellationToken::default(),
+        RunOptions { low_priority: true, ..RunOptions::default() },
+        None,
+    )
+    .unwrap_err();
+    match err {
+        CargoError::NonZeroExit { stderr, .. } => assert_eq!(stderr, "BelowNormal"),
+        err => panic!("unexpected error {:?}", err),
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn finished_checks_are_summarized() {
+    let dir = TempDir::new().unwrap();
+    let error =
+        UNUSED_VARIABLE.replace(r#""level":"warning""#, r#""level":"error""#).replace("`x`", "`y`");
+    let other_warning = UNUSED_VARIABLE.replace("`x`", "`z`");
+    let script = dir.path().join("check.sh");
+    fs::write(
+        &script,
+        format!(
+            "echo '{}'\necho 'not a message'\necho '{}'\necho '{}'\nexit 101\n",
+            UNUSED_VARIABLE, error, other_warning
+        ),
+    )
+    .unwrap();
+    let config = FlycheckConfig::builder()
+        .custom_command("sh", vec![script.to_str().unwrap().to_string()])
+        .build();
+    let flycheck = Flycheck::new(config, dir.path().to_path_buf());
+    flycheck.update();
+    let mut ended = false;
+    let summary = loop {
+        match flycheck.task_recv.recv_timeout(Duration::from_secs(10)).unwrap() {
+            CheckTask::Status(WorkDoneProgress::End(_)) => ended = true,
+            CheckTask::Finished(summary) => break summary,
+            CheckTask::Error { message, .. } => panic!("check failed: {}", message),
+            _ => {}
+        }
+    };
+    assert!(ended, "the progress should end before the summary");
+    assert_eq!(summary.exit_code, Some(101));
+    assert_eq!((summary.errors, summary.warnings, summary.skipped_lines), (1, 2, 1));
+    assert_eq!(summary.messages, 3);
+    assert_eq!(summary.run, CheckRunId(1));
+    assert!(summary.duration < Duration::from_secs(10));
+}
+
+#[test]
+#[cfg(unix)]
+fn megabytes_of_interleaved_output_keep_their_order() {
+    let dir = TempDir::new().unwrap();
+    // Each stream gets a few megabytes, written alternately.
+    let cmd = fake_cargo(
+        &dir,
+        &format!(
+            r#"
+padding=$(printf '%01000d' 0)
+i=1
+while [ $i -le 3000 ]; do
+    echo '{}'
+    echo "line-$i $padding" >&2
+    i=$((i + 1))
+done
+"#,
+            UNUSED_VARIABLE
+        ),
+    );
+    #[derive(Debug, PartialEq)]
+    enum Output {
+        Stdout,
+        Stderr(usize),
+    }
+    let output = RefCell::new(Vec::new());
+    run_cargo(
+        cmd,
+        &mut |_| {
+            output.borrow_mut().push(Output::Stdout);
+            true
+        },
+        &mut |line| {
+            let number = line["line-".len()..line.find(' ').unwrap()].parse().unwrap();
+            output.borrow_mut().push(Output::Stderr(number));
+        },
+        &CancellationToken::default(),
+        RunOptions::default(),
+        None,
+    )
+    .unwrap();
+    let output = output.into_inner();
+    assert_eq!(output.iter().filter(|it| **it == Output::Stdout).count(), 3000);
+    let stderr: Vec<_> = output
+        .iter()
+        .filter_map(|it| match it {
+            Output::Stderr(number) => Some(*number),
+            Output::Stdout => None,
+        })
+        .collect();
+    assert_eq!(stderr, (1..=3000).collect::<Vec<_>>());
+}
+
+/// Returns a flycheck running a script which logs to `started.log` and
+/// `finished.log`, taking `duration` in between.
+fn slow_flycheck(dir: &TempDir, duration: &str, on_restart: RestartBehavior) -> Flycheck {
+    let script = dir.path().join("check.sh");
+    fs::write(
+        &script,
+        format!(
+            "echo $$ >> started.log\nsleep {}\necho $$ >> finished.log\necho '{}'\n",
+            duration, UNUSED_VARIABLE
+        ),
+    )
+    .unwrap();
+    let config = FlycheckConfig::builder()
+        .custom_command("sh", vec![script.to_str().unwrap().to_string()])
+        .on_restart(on_restart)
+        .build();
+    Flycheck::new(config, dir.path().to_path_buf())
+}
+
+fn logged_pids(dir: &TempDir, log: &str) -> Vec<String> {
+    let log = fs::read_to_string(dir.path().join(log)).unwrap_or_default();
+    log.lines().map(|it| it.to_string()).collect()
+}
+
+/// Waits until `log` lists `count` processes.
+fn wait_for_pids(dir: &TempDir, log: &str, count: usize) -> Vec<String> {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        let pids = logged_pids(dir, log);
+        if pids.len() >= count {
+            return pids;
+        }
+        assert!(Instant::now() < deadline, "{} lists {:?}", log, pids);
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn restarted_checks_are_killed_by_default() {
+    let dir = TempDir::new().unwrap();
+    let flycheck = slow_flycheck(&dir, "0.5", RestartBehavior::KillImmediately);
+    flycheck.update();
+    wait_for_pids(&dir, "started.log", 1);
+    flycheck.update();
+    first_diagnostic(&flycheck);
+    assert_eq!(logged_pids(&dir, "finished.log").len(), 1);
+}
+
+#[test]
+#[cfg(unix)]
+fn restarted_checks_end_as_cancelled() {
+    let dir = TempDir::new().unwrap();
+    let flycheck = slow_flycheck(&dir, "0.5", RestartBehavior::KillImmediately);
+    flycheck.update();
+    wait_for_pids(&dir, "started.log", 1);
+    flycheck.update();
+    let mut ends = Vec::new();
+    while ends.len() < 3 {
+        if let CheckTask::Status(WorkDoneProgress::End(end)) =
+            flycheck.task_recv.recv_timeout(Duration::from_secs(10)).unwrap()
+        {
+            ends.push(end.message);
+        }
+    }
+    // The first one is sent when the thread starts.
+    assert_eq!(ends[0], None);
+    let cancelled = ends[1].as_ref().unwrap();
+    assert!(cancelled.starts_with("cargo check was cancelled after "), "{}", cancelled);
+    let finished = ends[2].as_ref().unwrap();
+    assert!(finished.starts_with("cargo check finished in "), "{}", finished);
+    assert!(finished.ends_with("s (0 errors, 1 warning)"), "{}", finished);
+}
+
+#[test]
+#[cfg(unix)]
+fn restarted_checks_can_finish() {
+    let dir = TempDir::new().unwrap();
+    let on_restart = RestartBehavior::WaitForFinish { max: Duration::from_secs(10) };
+    let flycheck = slow_flycheck(&dir, "0.5", on_restart);
+    flycheck.update();
+    wait_for_pids(&dir, "started.log", 1);
+    flycheck.update();
+    first_diagnostic(&flycheck);
+    let started = logged_pids(&dir, "started.log");
+    let finished = wait_for_pids(&dir, "finished.log", 2);
+    assert_eq!(started, finished);
+}
+
+#[test]
+#[cfg(unix)]
+fn dropping_does_not_wait_for_finishing_checks() {
+    let dir = TempDir::new().unwrap();
+    let on_restart = RestartBehavior::WaitForFinish { max: Duration::from_secs(10) };
+    let flycheck = slow_flycheck(&dir, "1", on_restart);
+    flycheck.update();
+    wait_for_pids(&dir, "started.log", 1);
+    let dropped = Instant::now();
+    drop(flycheck);
+    assert!(dropped.elapsed() < Duration::from_secs(1));
+    wait_for_pids(&dir, "finished.log", 1);
+}
+
+#[test]
+#[cfg(unix)]
+fn finishing_checks_are_killed_after_their_time_is_up() {
+    let dir = TempDir::new().unwrap();
+    let on_restart = RestartBehavior::WaitForFinish { max: Duration::from_millis(100) };
+    let flycheck = slow_flycheck(&dir, "1000", on_restart);
+    flycheck.update();
+    let pid = wait_for_pids(&dir, "started.log", 1).remove(0);
+    flycheck.update();
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while is_running(&pid) {
+        assert!(Instant::now() < deadline, "{} is still running", pid);
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn command_hook_can_change_the_command() {
+    let dir = TempDir::new().unwrap();
+    let script = dir.path().join("check.sh");
+    fs::write(&script, format!("echo \"$HOOKED\" > hooked.log\necho '{}'\n", UNUSED_VARIABLE))
+        .unwrap();
+    let config = FlycheckConfig::builder()
+        .custom_command("sh", vec![script.to_str().unwrap().to_string()])
+        .command_hook(|cmd| {
+            cmd.env("HOOKED", "yes");
+        })
+        .build();
+    let flycheck = Flycheck::new(config, dir.path().to_path_buf());
+    flycheck.update();
+    first_diagnostic(&flycheck);
+    assert_eq!(fs::read_to_string(dir.path().join("hooked.log")).unwrap(), "yes\n");
+}
+
+#[test]
+#[cfg(unix)]
+fn hooked_commands_without_messages_are_reported() {
+    let dir = TempDir::new().unwrap();
+    let cmd = fake_cargo(&dir, "echo 'not a message'\n");
+    let err = run_cargo(
+        cmd,
+        &mut |_| true,
+        &mut |_| (),
+        &CancellationToken::default(),
+        RunOptions::default(),
+        Some(&|cmd: &mut Command| {
+            cmd.arg("--hooked");
+        }),
+    )
+    .unwrap_err();
+    match &err {
+        CargoError::HookedCommandFailed { command, code, .. } => {
+            assert!(command.contains("--hooked"), "{}", command);
+            assert_eq!(*code, Some(0));
+        }
+        err => panic!("expected a failed hooked command, got {:?}", err),
+    }
+    assert!(err.to_string().contains("changed by the command hook"), "{}", err);
+}
+
+#[test]
+#[cfg(unix)]
+fn overlong_lines_are_skipped() {
+    let dir = TempDir::new().unwrap();
+    let cmd = fake_cargo(
+        &dir,
+        &format!("printf '%05000d\\n' 0\nprintf '%05000d\\n' 0 >&2\necho '{}'\n", UNUSED_VARIABLE),
+    );
+    let mut messages = 0;
+    let mut stderr_lines = 0;
+    let summary = run_cargo(
+        cmd,
+        &mut |_| {
+            messages += 1;
+            true
+        },
+        &mut |_| stderr_lines += 1,
+        &CancellationToken::default(),
+        RunOptions { max_line_length: 1000, ..RunOptions::default() },
+        None,
+    )
+    .unwrap();
+    assert_eq!((messages, stderr_lines), (1, 0));
+    assert_eq!(summary.skipped_lines, 1);
+}
+
+#[test]
+fn bounded_lines_are_split_like_lines() {
+    let mut input = io::BufReader::with_capacity(4, &b"ab\r\n\nabcdefgh\nabcd\nlast"[..]);
+    let mut lines = Vec::new();
+    loop {
+        match read_bounded_line(&mut input, 4).unwrap() {
+            BoundedLine::Line(line) => lines.push(String::from_utf8(line).unwrap()),
+            BoundedLine::Unterminated(line) => {
+                lines.push(format!("{} (unterminated)", String::from_utf8(line).unwrap()))
+            }
+            BoundedLine::TooLong(length) => lines.push(format!("<{} bytes>", length)),
+            BoundedLine::Eof => break,
+        }
+    }
+    assert_eq!(lines, vec!["ab", "", "<8 bytes>", "abcd", "last (unterminated)"]);
+}
+
+/// Runs `script` and returns the number of messages and skipped lines.
+#[cfg(unix)]
+fn count_lines(script: &str) -> (usize, usize) {
+    let dir = TempDir::new().unwrap();
+    let mut messages = 0;
+    let summary = run_cargo(
+        fake_cargo(&dir, script),
+        &mut |_| {
+            messages += 1;
+            true
+        },
+        &mut |_| (),
+        &CancellationToken::default(),
+        RunOptions::default(),
+        None,
+    )
+    .unwrap();
+    (messages, summary.skipped_lines)
+}
+
+#[test]
+#[cfg(unix)]
+fn truncated_last_messages_are_not_skipped_lines() {
+    let partial = &UNUSED_VARIABLE[..UNUSED_VARIABLE.len() / 2];
+    let killed = format!("echo '{}'\nprintf '%s' '{}'\nkill -9 $$\n", UNUSED_VARIABLE, partial);
+    assert_eq!(count_lines(&killed), (1, 0));
+    // Without a line break the message is still used.
+    let complete =
+        format!("echo '{}'\nprintf '%s' '{}'\nkill -9 $$\n", UNUSED_VARIABLE, UNUSED_VARIABLE);
+    assert_eq!(count_lines(&complete), (2, 0));
+    // Cargo exiting normally doesn't excuse an invalid last line.
+    let exited = format!("echo '{}'\nprintf '%s' '{}'\n", UNUSED_VARIABLE, partial);
+    assert_eq!(count_lines(&exited), (1, 1));
+}
+
+#[test]
+#[cfg(unix)]
+fn truncated_messages_in_the_middle_are_skipped_lines() {
+    // A process killed in the middle of printing, while cargo carries on.
+    let partial = &UNUSED_VARIABLE[..UNUSED_VARIABLE.len() / 2];
+    let script = format!(
+        "echo '{0}'\nprintf '%s' '{1}'\necho '{0}'\necho '{0}'\nkill -9 $$\n",
+        UNUSED_VARIABLE, partial
+    );
+    assert_eq!(count_lines(&script), (2, 1));
+}
+
+#[test]
+#[cfg(unix)]
+fn output_which_is_not_cargo_json_is_reported() {
+    let dir = TempDir::new().unwrap();
+    let script = dir.path().join("check.sh");
+    fs::write(
+        &script,
+        "i=1\nwhile [ $i -le 5 ]; do\n    echo \"garbage-$i\"\n    i=$((i + 1))\ndone\n",
+    )
+    .unwrap();
+    let config = FlycheckConfig::builder()
+        .custom_command("sh", vec![script.to_str().unwrap().to_string()])
+        .build();
+    let flycheck = Flycheck::new(config, dir.path().to_path_buf());
+    flycheck.update();
+    let warning = loop {
+        match flycheck.task_recv.recv_timeout(Duration::from_secs(10)).unwrap() {
+            CheckTask::Warning(warning) => break warning,
+            CheckTask::Finished(_) => panic!("the check finished without a warning"),
+            _ => {}
+        }
+    };
+    assert!(warning.starts_with("the check command printed 5 lines"), "{}", warning);
+    assert!(warning.ends_with("\n    garbage-1\n    garbage-2\n    garbage-3"), "{}", warning);
+}
+
+#[test]
+#[cfg(unix)]
+fn mostly_valid_output_is_not_reported() {
+    let dir = TempDir::new().unwrap();
+    let run = |script: &str| {
+        run_cargo(
+            fake_cargo(&dir, script),
+            &mut |_| true,
+            &mut |_| (),
+            &CancellationToken::default(),
+            RunOptions::default(),
+            None,
+        )
+        .unwrap()
+    };
+    let mostly_valid =
+        run(&format!("echo '   Compiling foo'\necho '{0}'\necho '{0}'\n", UNUSED_VARIABLE));
+    assert_eq!((mostly_valid.messages, mostly_valid.skipped_lines), (2, 1));
+    assert_eq!(mostly_valid.skipped_samples, vec!["   Compiling foo".to_string()]);
+    assert_eq!(unparsed_output_warning(&mostly_valid), None);
+
+    let mostly_garbage =
+        run(&format!("echo 'a'\necho 'b'\necho '{}'\nprintf '%0300d\\n' 0\n", UNUSED_VARIABLE));
+    assert_eq!((mostly_garbage.messages, mostly_garbage.skipped_lines), (1, 3));
+    assert_eq!(mostly_garbage.skipped_samples[2].len(), 200);
+    assert!(unparsed_output_warning(&mostly_garbage).is_some());
+}
+
+#[test]
+#[cfg(unix)]
+fn runs_are_summarized() {
+    let dir = TempDir::new().unwrap();
+    let run = |script: &str, stop_after: usize| {
+        let mut messages = 0;
+        run_cargo(
+            fake_cargo(&dir, script),
+            &mut |_| {
+                messages += 1;
+                messages < stop_after
+            },
+            &mut |_| (),
+            &CancellationToken::default(),
+            RunOptions::default(),
+            None,
+        )
+        .unwrap()
+    };
+
+    // Failed checks with messages are summarized like successful ones.
+    let failed = run(
+        &format!("echo '{0}'\necho 'not a message'\necho '{0}'\nexit 101\n", UNUSED_VARIABLE),
+        usize::MAX,
+    );
+    assert_eq!(failed.exit_code, Some(101));
+    assert_eq!((failed.messages, failed.skipped_lines), (2, 1));
+    assert!(!failed.stopped_early);
+    assert!(failed.duration < Duration::from_secs(10));
+
+    let finished = run(&format!("echo '{}'\n", UNUSED_VARIABLE), usize::MAX);
+    assert_eq!(finished.exit_code, Some(0));
+    assert_eq!((finished.messages, finished.skipped_lines), (1, 0));
+    assert!(!finished.stopped_early);
+
+    let stopped = run(&format!("echo '{0}'\necho '{0}'\nsleep 1000\n", UNUSED_VARIABLE), 1);
+    assert_eq!(stopped.exit_code, None);
+    assert_eq!(stopped.messages, 1);
+    assert!(stopped.stopped_early);
+    assert!(stopped.duration < Duration::from_secs(10));
+}
+
+/// `cargo metadata --no-deps` output for a workspace at `root` with the
+/// `members`, each in the directory of the same name.
+fn members_metadata(root: &Path, members: &[&str]) -> String {
+    let id = |name: &str| format!("{} 0.1.0 (path+file://{})", name, root.join(name).display());
+    let packages: Vec<String> = members
+        .iter()
+        .map(|name| {
+            format!(
+                r#"{{"name":"{}","version":"0.1.0","authors":[],"id":"{}","source":null,"description":null,"dependencies":[],"license":null,"license_file":null,"targets":[],"features":{{}},"manifest_path":"{}","categories":[],"keywords":[],"readme":null,"repository":null,"edition":"2018","metadata":null,"links":null}}"#,
+                name,
+                id(name),
+                root.join(name).join("Cargo.toml").display()
+            )
+        })
+        .collect();
+    let workspace_members: Vec<String> =
+        members.iter().map(|name| format!(r#""{}""#, id(name))).collect();
+    format!(
+        r#"{{"packages":[{}],"workspace_members":[{}],"resolve":null,"target_directory":"{}","version":1,"workspace_root":"{}"}}"#,
+        packages.join(","),
+        workspace_members.join(","),
+        root.join("target").display(),
+        root.display()
+    )
+}
+
+#[test]
+fn changes_are_merged() {
+    let file = |name: &str| PathBuf::from("/ws").join(name);
+    let mut changes = Changes::Nothing;
+    changes.add(Changes::of(vec![file("a.rs")]));
+    changes.add(Changes::of(vec![file("b.rs"), file("a.rs")]));
+    assert_eq!(changes, Changes::Files(vec![file("a.rs"), file("b.rs")]));
+    changes.add(Changes::Nothing);
+    assert_eq!(changes, Changes::Files(vec![file("a.rs"), file("b.rs")]));
+    changes.add(Changes::of(Vec::new()));
+    assert_eq!(changes, Changes::Everything);
+    changes.add(Changes::of(vec![file("c.rs")]));
+    assert_eq!(changes, Changes::Everything);
+}
+
+#[test]
+fn manifest_changes_include_everything() {
+    let mut changes = Changes::of(vec![PathBuf::from("/ws/src/lib.rs")]);
+    assert!(!changes.include_manifest());
+    changes.add(Changes::of(vec![PathBuf::from("/ws/Cargo.lock")]));
+    assert!(changes.include_manifest());
+    changes.add(Changes::of(vec![PathBuf::from("/ws/src/main.rs")]));
+    assert!(changes.include_manifest());
+    assert!(Changes::Everything.include_manifest());
+    assert!(!Changes::Nothing.include_manifest());
+}
+
+#[test]
+fn changed_files_are_resolved_to_their_packages() {
+    let root = Path::new("/ws");
+    let metadata: cargo_metadata::Metadata =
+        serde_json::from_str(&members_metadata(root, &["app", "core", "core/macros"])).unwrap();
+    let index = PackageIndex::from_metadata(&metadata);
+    let names = |files: &[&str]| -> Option<Vec<String>> {
+        let files: Vec<PathBuf> = files.iter().map(|file| root.join(file)).collect();
+        let owners = index.owners(&files)?;
+        Some(owners.into_iter().map(|package| package.name).collect())
+    };
+    assert_eq!(names(&["app/src/main.rs"]), Some(strings(&["app"])));
+    // Nested members own their files.
+    assert_eq!(names(&["core/macros/src/lib.rs"]), Some(strings(&["core/macros"])));
+    assert_eq!(
+        names(&["core/src/lib.rs", "app/src/main.rs", "core/src/util.rs"]),
+        Some(strings(&["app", "core"]))
+    );
+    // Shared files check the whole workspace.
+    assert_eq!(names(&["app/src/main.rs", "build/shared.rs"]), None);
+    assert_eq!(names(&["app/Cargo.toml"]), None);
+    assert_eq!(names(&["Cargo.lock"]), None);
+}
+
+#[test]
+fn dependencies_are_not_indexed() {
+    let metadata: cargo_metadata::Metadata = serde_json::from_str(&workspace_metadata()).unwrap();
+    let index = PackageIndex::from_metadata(&metadata);
+    assert_eq!(index.owners(&[PathBuf::from("/ws/bar/src/lib.rs")]), None);
+    assert!(index.owners(&[PathBuf::from("/ws/foo/src/lib.rs")]).is_some());
+}
+
+/// Updates `flycheck` after `files` changed, returning the scope of the
+/// check once it ended.
+#[cfg(unix)]
+fn check_changed_files(flycheck: &Flycheck, files: Vec<PathBuf>) -> Option<Vec<PathBuf>> {
+    flycheck.update_with_files(files);
+    let mut started = false;
+    loop {
+        match flycheck.task_recv.recv_timeout(Duration::from_secs(10)).unwrap() {
+            CheckTask::Status(WorkDoneProgress::Begin(_)) => started = true,
+            CheckTask::Finished(summary) if started => return summary.scope,
+            CheckTask::Error { message, .. } => panic!("check failed: {}", message),
+            _ => {}
+        }
+    }
+}
+
+/// Creates a flycheck for a workspace with the members `app` and `core`,
+/// whose checks and `cargo metadata` calls are logged to `calls.log` in
+/// `root`, each check with the packages it's limited to.
+#[cfg(unix)]
+fn scoped_flycheck(root: &Path, debounce: Duration) -> Flycheck {
+    fs::write(root.join("Cargo.toml"), "").unwrap();
+    fs::write(root.join("metadata.json"), members_metadata(root, &["app", "core"])).unwrap();
+    let wrapper = root.join("wrapper.sh");
+    fs::write(
+        &wrapper,
+        "shift\n\
+         if [ \"$1\" = metadata ]; then\n\
+         echo metadata >> calls.log\n\
+         cat metadata.json\n\
+         exit\n\
+         fi\n\
+         for arg in \"$@\"; do\n\
+         case $prev in -p) printf '%s ' \"$arg\";; esac\n\
+         case $arg in -p|--workspace) printf '%s ' \"$arg\";; esac\n\
+         prev=$arg\n\
+         done >> calls.log\n\
+         echo >> calls.log\n",
+    )
+    .unwrap();
+    let config = FlycheckConfig::builder()
+        .wrapper(vec!["sh".to_string(), wrapper.to_str().unwrap().to_string()])
+        .check_changed_packages(true)
+        .debounce(debounce)
+        .build();
+    Flycheck::new(config, root.to_path_buf())
+}
+
+#[cfg(unix)]
+fn logged_calls(root: &Path) -> Vec<String> {
+    let calls = fs::read_to_string(root.join("calls.log")).unwrap();
+    calls.lines().map(|line| line.trim_end().to_string()).collect()
+}
+
+#[test]
+#[cfg(unix)]
+fn checks_are_limited_to_the_packages_of_the_changed_files() {
+    let dir = TempDir::new().unwrap();
+    let root = dir.path();
+    let flycheck = scoped_flycheck(root, Duration::from_secs(0));
+
+    let core_file = root.join("core/src/lib.rs");
+    let app_file = root.join("app/src/main.rs");
+    assert_eq!(
+        check_changed_files(&flycheck, vec![core_file.clone()]),
+        Some(vec![root.join("core")])
+    );
+    assert_eq!(
+        check_changed_files(&flycheck, vec![app_file.clone(), core_file]),
+        Some(vec![root.join("app"), root.join("core")])
+    );
+    // The members are looked up again once a manifest changed.
+    assert_eq!(check_changed_files(&flycheck, vec![root.join("app/Cargo.toml")]), None);
+    assert_eq!(check_changed_files(&flycheck, vec![app_file]), Some(vec![root.join("app")]));
+    assert_eq!(check_changed_files(&flycheck, Vec::new()), None);
+
+    assert_eq!(
+        logged_calls(root),
+        vec![
+            "metadata",
+            "-p core",
+            "-p app -p core",
+            "--workspace",
+            "metadata",
+            "-p app",
+            "--workspace",
+        ]
+    );
+}
+
+#[test]
+#[cfg(unix)]
+fn custom_commands_check_the_whole_workspace() {
+    let dir = TempDir::new().unwrap();
+    let script = dir.path().join("check.sh");
+    fs::write(&script, "exit 0\n").unwrap();
+    let config = FlycheckConfig::builder()
+        .custom_command("sh", vec![script.to_str().unwrap().to_string()])
+        .check_changed_packages(true)
+        .build();
+    let flycheck = Flycheck::new(config, dir.path().to_path_buf());
+    assert_eq!(check_changed_files(&flycheck, vec![dir.path().join("src/lib.rs")]), None);
+}
+
+/// Creates a flycheck whose checks append a line to `runs.log` in `dir`, and
+/// returns the number of checks which ran once `wait` is over.
+#[cfg(unix)]
+fn runs_after_start(dir: &TempDir, check_on_start: bool, wait: Duration) -> usize {
+    let script = dir.path().join("check.sh");
+    fs::write(&script, "echo run >> runs.log\n").unwrap();
+    let config = FlycheckConfig::builder()
+        .custom_command("sh", vec![script.to_str().unwrap().to_string()])
+        .check_on_start(check_on_start)
+        .build();
+    let _flycheck = Flycheck::new(config, dir.path().to_path_buf());
+    thread::sleep(wait);
+    match fs::read_to_string(dir.path().join("runs.log")) {
+        Ok(runs) => runs.lines().count(),
+        Err(_) => 0,
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn checks_wait_for_the_first_update_by_default() {
+    let dir = TempDir::new().unwrap();
+    assert_eq!(runs_after_start(&dir, false, Duration::from_millis(500)), 0);
+}
+
+#[test]
+#[cfg(unix)]
+fn initial_checks_run_once() {
+    let dir = TempDir::new().unwrap();
+    assert_eq!(runs_after_start(&dir, true, Duration::from_millis(500)), 1);
+}
+
+#[test]
+#[cfg(unix)]
+fn killed_checks_do_not_block_the_following_commands() {
+    let dir = TempDir::new().unwrap();
+    let script = dir.path().join("check.sh");
+    // Only dies once it's killed after the grace period.
+    fs::write(&script, format!("trap '' TERM\necho '{}'\nsleep 1000\n", UNUSED_VARIABLE)).unwrap();
+    let config = FlycheckConfig::builder()
+        .custom_command("sh", vec![script.to_str().unwrap().to_string()])
+        .termination_grace_period(Duration::from_secs(3))
+        .build();
+    let flycheck = Flycheck::new(config, dir.path().to_path_buf());
+    flycheck.update();
+    first_diagnostic(&flycheck);
+
+    let updated = Instant::now();
+    flycheck.update();
+    first_diagnostic(&flycheck);
+    assert!(updated.elapsed() < Duration::from_secs(2), "{:?}", updated.elapsed());
+
+    let cancelled = Instant::now();
+    flycheck.cancel();
+    loop {
+        if let CheckTask::Finished(summary) =
+            flycheck.task_recv.recv_timeout(Duration::from_secs(10)).unwrap()
+        {
+            assert!(summary.cancelled);
+            break;
+        }
+    }
+    assert!(cancelled.elapsed() < Duration::from_secs(2), "{:?}", cancelled.elapsed());
+}
+
+#[test]
+#[cfg(unix)]
+fn checks_stop_once_their_tasks_are_not_received() {
+    let dir = TempDir::new().unwrap();
+    let script = dir.path().join("check.sh");
+    fs::write(&script, format!("echo '{}'\n", UNUSED_VARIABLE)).unwrap();
+    let config = FlycheckConfig::builder()
+        .custom_command("sh", vec![script.to_str().unwrap().to_string()])
+        .build();
+    let mut flycheck = Flycheck::new(config, dir.path().to_path_buf());
+    drop(std::mem::replace(&mut flycheck.task_recv, never()));
+    flycheck.update();
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while flycheck.cmd_send.send(CheckCommand::Resume).is_ok() {
+        assert!(Instant::now() < deadline, "the check thread is still running");
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    // Commands are dropped from now on.
+    flycheck.update();
+    flycheck.set_scope(CheckScope::LibOnly);
+    flycheck.cancel();
+    flycheck.set_enabled(false);
+    drop(flycheck);
+}
+
+/// Waits until the status of `flycheck` satisfies `f`, returning it.
+#[cfg(unix)]
+fn wait_for_status(flycheck: &Flycheck, f: impl Fn(&CheckStatus) -> bool) -> CheckStatus {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        let status = flycheck.status();
+        if f(&status) {
+            return status;
+        }
+        assert!(Instant::now() < deadline, "unexpected status {:?}", status);
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn status_follows_the_checks() {
+    let dir = TempDir::new().unwrap();
+    let script = dir.path().join("check.sh");
+    fs::write(
+        &script,
+        format!(
+            "echo '    Checking foo v0.1.0 (/ws)' >&2\n\
+             while [ ! -e go ]; do sleep 0.01; done\n\
+             echo '{}'\n",
+            UNUSED_VARIABLE
+        ),
+    )
+    .unwrap();
+    let config = FlycheckConfig::builder()
+        .custom_command("sh", vec![script.to_str().unwrap().to_string()])
+        .debounce(Duration::from_millis(300))
+        .build();
+    let flycheck = Flycheck::new(config, dir.path().to_path_buf());
+    assert_eq!(flycheck.status(), CheckStatus::default());
+
+    flycheck.update();
+    wait_for_status(&flycheck, |status| status.activity == CheckActivity::Queued);
+    let running = wait_for_status(&flycheck, |status| status.current_crate.is_some());
+    assert_eq!(running.activity, CheckActivity::Running);
+    assert_eq!(running.current_crate.as_deref(), Some("foo"));
+    assert!(running.started_at.is_some());
+    assert_eq!(running.last_summary, None);
+
+    fs::write(dir.path().join("go"), "").unwrap();
+    let idle = wait_for_status(&flycheck, |status| status.activity == CheckActivity::Idle);
+    assert_eq!((idle.current_crate, idle.started_at), (None, None));
+    assert!(idle.last_finished_at.is_some());
+    let summary = idle.last_summary.unwrap();
+    assert_eq!((summary.run, summary.warnings, summary.cancelled), (CheckRunId(1), 1, false));
+}
+
+#[test]
+fn status_is_described() {
+    let now = Instant::now();
+    let summary = CheckSummary { errors: 1, warnings: 2, ..CheckSummary::default() };
+    let idle = CheckStatus {
+        last_summary: Some(summary.clone()),
+        last_finished_at: Some(now),
+        ..CheckStatus::default()
+    };
+    assert_eq!(idle.to_string(), "idle, last check finished 0.0s ago (1 errors, 2 warnings)");
+    let queued = CheckStatus { activity: CheckActivity::Queued, ..CheckStatus::default() };
+    assert_eq!(queued.to_string(), "queued");
+    let running = CheckStatus {
+        activity: CheckActivity::Running,
+        current_crate: Some("foo".to_string()),
+        started_at: Some(now),
+        ..CheckStatus::default()
+    };
+    assert_eq!(running.to_string(), "running for 0.0s, checking foo");
+    let cancelled = CheckStatus {
+        last_summary: Some(CheckSummary { cancelled: true, ..summary }),
+        last_finished_at: Some(now),
+        ..CheckStatus::default()
+    };
+    assert!(cancelled.to_string().starts_with("idle, last check cancelled"));
+}
+
+#[test]
+fn only_changes_to_the_diagnostics_require_a_restart() {
+    let old = cargo_command();
+    let timing = FlycheckConfig {
+        debounce: Duration::from_secs(1),
+        cancel_on_update: true,
+        low_priority: true,
+        on_restart: RestartBehavior::WaitForFinish { max: Duration::from_secs(5) },
+        ..cargo_command()
+    };
+    assert!(!FlycheckConfig::requires_restart(&old, &old));
+    assert!(!FlycheckConfig::requires_restart(&old, &timing));
+    let clippy = FlycheckConfig::from_command_line("cargo clippy").unwrap();
+    assert!(FlycheckConfig::requires_restart(&old, &clippy));
+    let env = FlycheckConfig { extra_env: vec![("FOO".into(), "1".into())], ..cargo_command() };
+    assert!(FlycheckConfig::requires_restart(&old, &env));
+}
+
+/// Collects the tasks of `flycheck` until it's quiet for `quiet`.
+#[cfg(unix)]
+fn tasks_until_quiet(flycheck: &Flycheck, quiet: Duration) -> Vec<CheckTask> {
+    let mut tasks = Vec::new();
+    while let Ok(task) = flycheck.task_recv.recv_timeout(quiet) {
+        tasks.push(task);
+    }
+    tasks
+}
+
+#[test]
+#[cfg(unix)]
+fn reconfiguring_restarts_checks_only_if_needed() {
+    let dir = TempDir::new().unwrap();
+    let slow = dir.path().join("slow.sh");
+    fs::write(&slow, format!("echo '{}'\nsleep 1000\n", UNUSED_VARIABLE)).unwrap();
+    let fast = dir.path().join("fast.sh");
+    fs::write(&fast, format!("echo '{}'\n", UNUSED_VARIABLE)).unwrap();
+    let config = FlycheckConfig::builder()
+        .custom_command("sh", vec![slow.to_str().unwrap().to_string()])
+        .build();
+    let flycheck = Flycheck::new(config.clone(), dir.path().to_path_buf());
+    flycheck.update();
+    first_diagnostic(&flycheck);
+
+    flycheck.reconfigure(&FlycheckConfig { debounce: Duration::from_millis(10), ..config });
+    assert!(tasks_until_quiet(&flycheck, Duration::from_millis(500)).is_empty());
+    assert_eq!(flycheck.status().activity, CheckActivity::Running);
+
+    let fast_config = FlycheckConfig::builder()
+        .custom_command("sh", vec![fast.to_str().unwrap().to_string()])
+        .build();
+    flycheck.reconfigure(&fast_config);
+    let mut cancelled = false;
+    let mut cleared = false;
+    loop {
+        match flycheck.task_recv.recv_timeout(Duration::from_secs(10)).unwrap() {
+            CheckTask::Finished(summary) if summary.cancelled => cancelled = true,
+            CheckTask::ClearForFile { .. } => cleared = cancelled,
+            CheckTask::Finished(summary) => {
+                assert_eq!(summary.warnings, 1);
+                break;
+            }
+            _ => {}
+        }
+    }
+    assert!(cancelled && cleared);
+}
+
+#[test]
+fn only_files_which_can_change_the_diagnostics_are_relevant() {
+    let root = Path::new("/ws");
+    let filter = ChangeFilter::new(root, &[]);
+    assert!(filter.is_relevant(&root.join("src/lib.rs")));
+    assert!(filter.is_relevant(&root.join("app/src/main.rs")));
+    assert!(filter.is_relevant(&root.join("Cargo.toml")));
+    assert!(filter.is_relevant(&root.join("Cargo.lock")));
+    assert!(!filter.is_relevant(&root.join("README.md")));
+    assert!(!filter.is_relevant(&root.join(".vscode/settings.json")));
+
+    // Path dependencies outside the workspace root.
+    assert!(filter.is_relevant(Path::new("/dep/Cargo.toml")));
+    assert!(filter.is_relevant(Path::new("/dep/build.rs")));
+    assert!(!filter.is_relevant(Path::new("/dep/src/lib.rs")));
+}
+
+#[test]
+fn watched_globs_are_relative_to_the_workspace_root() {
+    let root = Path::new("/ws");
+    let filter = ChangeFilter::new(root, &strings(&["migrations/*.sql", "**/*.proto"]));
+    assert!(filter.is_relevant(&root.join("migrations/1_init.sql")));
+    assert!(filter.is_relevant(&root.join("api/v1/service.proto")));
+    assert!(!filter.is_relevant(&root.join("queries/select.sql")));
+    assert!(!filter.is_relevant(Path::new("/elsewhere/migrations/1_init.sql")));
+
+    let filter = ChangeFilter::new(root, &strings(&["[", "*.sql"]));
+    assert!(filter.is_relevant(&root.join("select.sql")));
+    let config = FlycheckConfig::builder().watched_globs(strings(&["["])).build();
+    assert!(matches!(config.validate(), Err(ConfigError::InvalidGlob { .. })));
+}
+
+#[test]
+#[cfg(unix)]
+fn updates_of_unrelated_files_are_ignored() {
+    let dir = TempDir::new().unwrap();
+    let script = dir.path().join("check.sh");
+    fs::write(&script, "echo run >> runs.log\n").unwrap();
+    let config = FlycheckConfig::builder()
+        .custom_command("sh", vec![script.to_str().unwrap().to_string()])
+        .build();
+    let flycheck = Flycheck::new(config, dir.path().to_path_buf());
+    let runs = || fs::read_to_string(dir.path().join("runs.log")).unwrap_or_default();
+
+    flycheck.update_with_files(vec![dir.path().join("README.md")]);
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(runs(), "");
+
+    flycheck.update_with_files(vec![dir.path().join("README.md"), dir.path().join("src/lib.rs")]);
+    wait_for_check(&flycheck);
+    assert_eq!(runs(), "run\n");
+}
+
+#[test]
+#[cfg(unix)]
+fn rust_files_of_path_dependencies_outside_the_workspace_root_are_ignored() {
+    let dir = TempDir::new().unwrap();
+    let root = dir.path().join("ws");
+    let dep = dir.path().join("dep");
+    fs::create_dir(&root).unwrap();
+    let script = dir.path().join("check.sh");
+    fs::write(&script, format!("echo run >> '{}'\n", dir.path().join("runs.log").display()))
+        .unwrap();
+    let config = FlycheckConfig::builder()
+        .custom_command("sh", vec![script.to_str().unwrap().to_string()])
+        .build();
+    let flycheck = Flycheck::new(config, root);
+    let runs = || fs::read_to_string(dir.path().join("runs.log")).unwrap_or_default();
+
+    flycheck.update_with_files(vec![dep.join("src/lib.rs")]);
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(runs(), "");
+
+    flycheck.update_with_files(vec![dep.join("build.rs")]);
+    wait_for_check(&flycheck);
+    assert_eq!(runs(), "run\n");
+}
+
+#[test]
+#[cfg(unix)]
+fn manifest_changes_check_the_whole_workspace() {
+    let dir = TempDir::new().unwrap();
+    let root = dir.path();
+    let flycheck = scoped_flycheck(root, Duration::from_millis(300));
+    let core_file = root.join("core/src/lib.rs");
+    let app_file = root.join("app/src/main.rs");
+
+    // Both updates arrive before the check starts.
+    flycheck.update_with_files(vec![core_file.clone()]);
+    assert_eq!(check_changed_files(&flycheck, vec![root.join("Cargo.toml")]), None);
+    assert_eq!(check_changed_files(&flycheck, vec![app_file]), Some(vec![root.join("app")]));
+    flycheck.update_with_files(vec![root.join("core/Cargo.toml")]);
+    assert_eq!(check_changed_files(&flycheck, vec![core_file.clone()]), None);
+    assert_eq!(check_changed_files(&flycheck, vec![core_file]), Some(vec![root.join("core")]));
+
+    // The members are only looked up for checks limited to some of them.
+    assert_eq!(
+        logged_calls(root),
+        vec!["--workspace", "metadata", "-p app", "--workspace", "metadata", "-p core"]
+    );
+}
+
+/// Waits until a check of `flycheck` ends, returning its summary.
+#[cfg(unix)]
+fn next_summary(flycheck: &Flycheck) -> CheckSummary {
+    loop {
+        match flycheck.task_recv.recv_timeout(Duration::from_secs(10)).unwrap() {
+            CheckTask::Finished(summary) => return summary,
+            CheckTask::Error { message, .. } => panic!("check failed: {}", message),
+            _ => {}
+        }
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn one_off_checks_do_not_change_the_following_checks() {
+    let dir = TempDir::new().unwrap();
+    let script = dir.path().join("check.sh");
+    fs::write(
+        &script,
+        format!(
+            "echo \"$1\" >> runs.log\n\
+             while [ \"$1\" = one-off ] && [ ! -e go ]; do sleep 0.01; done\n\
+             echo '{}'\n",
+            UNUSED_VARIABLE
+        ),
+    )
+    .unwrap();
+    let script = script.to_str().unwrap().to_string();
+    let config = FlycheckConfig::builder()
+        .custom_command("sh", vec![script.clone(), "configured".to_string()])
+        .build();
+    let flycheck = Flycheck::new(config, dir.path().to_path_buf());
+    let one_off = FlycheckConfig::builder().custom_command("sh", vec![script, "one-off".into()]);
+    flycheck.run_once(&one_off.build());
+
+    // The update waits for the one-off check, even with `cancel_on_update`.
+    wait_for_status(&flycheck, |status| status.activity == CheckActivity::Running);
+    flycheck.update();
+    thread::sleep(Duration::from_millis(300));
+    fs::write(dir.path().join("go"), "").unwrap();
+    let summary = next_summary(&flycheck);
+    assert_eq!((summary.one_off, summary.cancelled, summary.warnings), (true, false, 1));
+    let summary = next_summary(&flycheck);
+    assert_eq!((summary.one_off, summary.cancelled, summary.warnings), (false, false, 1));
+
+    flycheck.update();
+    assert!(!next_summary(&flycheck).one_off);
+    let runs = fs::read_to_string(dir.path().join("runs.log")).unwrap();
+    assert_eq!(runs, "one-off\nconfigured\nconfigured\n");
+}
+
+#[test]
+#[cfg(feature = "fs-watch")]
+fn ignored_files_follow_the_gitignore() {
+    let root = Path::new("/ws");
+    let ignored = IgnoredFiles::with_gitignore(
+        root,
+        "# generated\n/docs/\n*.log\nnode_modules\nsrc/gen/\n!keep.log\n",
+    );
+    assert!(!ignored.is_ignored(&root.join("src/lib.rs")));
+    assert!(!ignored.is_ignored(&root.join("Cargo.toml")));
+    assert!(!ignored.is_ignored(&root.join("src/target.rs")));
+    assert!(ignored.is_ignored(&root.join("target/debug/build/out.rs")));
+    assert!(ignored.is_ignored(&root.join(".git/index")));
+    assert!(ignored.is_ignored(Path::new("/elsewhere/src/lib.rs")));
+
+    assert!(ignored.is_ignored(&root.join("docs/index.md")));
+    assert!(!ignored.is_ignored(&root.join("app/docs/lib.rs")));
+    assert!(ignored.is_ignored(&root.join("runs.log")));
+    assert!(ignored.is_ignored(&root.join("app/keep.log")));
+    assert!(ignored.is_ignored(&root.join("web/node_modules/x/index.rs")));
+    assert!(ignored.is_ignored(&root.join("src/gen/bindings.rs")));
+    assert!(!ignored.is_ignored(&root.join("app/src/gen/bindings.rs")));
+}
+
+#[test]
+#[cfg(all(unix, feature = "fs-watch"))]
+fn changed_files_are_watched() {
+    let dir = TempDir::new().unwrap();
+    fs::create_dir(dir.path().join("src")).unwrap();
+    fs::create_dir(dir.path().join("target")).unwrap();
+    let script = dir.path().join("check.sh");
+    fs::write(&script, "echo run >> target/runs.log\n").unwrap();
+    let config = FlycheckConfig::builder()
+        .custom_command("sh", vec![script.to_str().unwrap().to_string()])
+        .build();
+    let flycheck = Flycheck::with_file_watching(config, dir.path().to_path_buf()).unwrap();
+    let runs = || fs::read_to_string(dir.path().join("target/runs.log")).unwrap_or_default();
+
+    fs::write(dir.path().join("README.md"), "").unwrap();
+    fs::write(dir.path().join("target/out.rs"), "").unwrap();
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(runs(), "");
+
+    fs::write(dir.path().join("src/lib.rs"), "").unwrap();
+    wait_for_check(&flycheck);
+    assert_eq!(runs(), "run\n");
+}
+
+#[test]
+#[cfg(unix)]
+fn cancelled_progress_stops_the_check() {
+    let dir = TempDir::new().unwrap();
+    let script = dir.path().join("check.sh");
+    fs::write(&script, format!("echo '{}'\nsleep 1000\n", UNUSED_VARIABLE)).unwrap();
+    let config = FlycheckConfig::builder()
+        .custom_command("sh", vec![script.to_str().unwrap().to_string()])
+        .build();
+    let flycheck = Flycheck::new(config, dir.path().to_path_buf());
+    flycheck.update();
+    loop {
+        match flycheck.task_recv.recv_timeout(Duration::from_secs(10)).unwrap() {
+            CheckTask::Status(WorkDoneProgress::Begin(begin)) => {
+                assert_eq!(begin.cancellable, Some(true))
+            }
+            CheckTask::AddDiagnostics { .. } => break,
+            _ => {}
+        }
+    }
+
+    flycheck.cancel();
+    let mut end_message = None;
+    loop {
+        match flycheck.task_recv.recv_timeout(Duration::from_secs(10)).unwrap() {
+            CheckTask::Status(WorkDoneProgress::End(end)) => end_message = end.message,
+            CheckTask::Finished(summary) => {
+                assert!(summary.cancelled);
+                assert_eq!(summary.warnings, 1);
+                break;
+            }
+            CheckTask::ClearDiagnostics { .. } => panic!("the diagnostics should be kept"),
+            _ => {}
+        }
+    }
+    let end_message = end_message.unwrap();
+    assert!(end_message.starts_with("cargo check was cancelled"), "{}", end_message);
+}
+
+#[test]
+fn unchanged_contents_are_detected() {
+    let dir = TempDir::new().unwrap();
+    let file = dir.path().join("lib.rs");
+    let files = vec![file.clone()];
+    fs::write(&file, "fn main() {}").unwrap();
+    let mut snapshots = Snapshots::default();
+    assert!(snapshots.update(&files));
+    // Already pending.
+    assert!(!snapshots.update(&files));
+    snapshots.started();
+    snapshots.succeeded();
+    assert!(!snapshots.update(&files));
+
+    fs::write(&file, "fn main() { }").unwrap();
+    assert!(snapshots.update(&files));
+    snapshots.started();
+    // The contents of a failed check are checked again.
+    snapshots.invalidate();
+    assert!(snapshots.update(&files));
+    snapshots.started();
+    snapshots.succeeded();
+    assert!(!snapshots.update(&files));
+
+    assert!(snapshots.update(&[dir.path().join("missing.rs")]));
+}
+
+#[test]
+#[cfg(unix)]
+fn saves_without_changes_are_skipped() {
+    let dir = TempDir::new().unwrap();
+    fs::create_dir(dir.path().join("src")).unwrap();
+    let file = dir.path().join("src/lib.rs");
+    fs::write(&file, "fn main() {}").unwrap();
+    let script = dir.path().join("check.sh");
+    fs::write(&script, "echo run >> runs.log\n").unwrap();
+    let config = FlycheckConfig::builder()
+        .custom_command("sh", vec![script.to_str().unwrap().to_string()])
+        .skip_unchanged_files(true)
+        .build();
+    let flycheck = Flycheck::new(config, dir.path().to_path_buf());
+    let runs = || fs::read_to_string(dir.path().join("runs.log")).unwrap_or_default();
+
+    flycheck.update_with_files(vec![file.clone()]);
+    wait_for_check(&flycheck);
+    flycheck.update_with_files(vec![file.clone()]);
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(runs(), "run\n");
+
+    fs::write(&file, "fn main() { }").unwrap();
+    flycheck.update_with_files(vec![file]);
+    wait_for_check(&flycheck);
+    assert_eq!(runs(), "run\nrun\n");
+}
+
+#[test]
+#[cfg(unix)]
+fn immediate_updates_start_right_away() {
+    let dir = TempDir::new().unwrap();
+    let script = dir.path().join("check.sh");
+    fs::write(&script, format!("echo '{}'\n", UNUSED_VARIABLE)).unwrap();
+    let config = FlycheckConfig::builder()
+        .custom_command("sh", vec![script.to_str().unwrap().to_string()])
+        .debounce(Duration::from_secs(60))
+        .min_interval(Duration::from_secs(60))
+        .build();
+    let flycheck = Flycheck::new(config, dir.path().to_path_buf());
+    let started = Instant::now();
+    flycheck.update();
+    flycheck.update_now();
+    first_diagnostic(&flycheck);
+    assert!(started.elapsed() < Duration::from_secs(5), "{:?}", started.elapsed());
+}
+
+#[test]
+#[cfg(unix)]
+fn diagnostics_are_cleared_on_request() {
+    let dir = TempDir::new().unwrap();
+    let script = dir.path().join("check.sh");
+    fs::write(&script, format!("echo '{}'\nsleep 1000\n", UNUSED_VARIABLE)).unwrap();
+    let config = FlycheckConfig::builder()
+        .custom_command("sh", vec![script.to_str().unwrap().to_string()])
+        .build();
+    let flycheck = Flycheck::new(config, dir.path().to_path_buf());
+    flycheck.update();
+    first_diagnostic(&flycheck);
+
+    // The running check goes on.
+    flycheck.clear_diagnostics(false);
+    let tasks = tasks_until_quiet(&flycheck, Duration::from_millis(500));
+    match tasks.as_slice() {
+        [CheckTask::ClearDiagnostics { run }] => assert_eq!(*run, CheckRunId(1)),
+        _ => panic!("unexpected tasks {:?}", tasks),
+    }
+    assert_eq!(flycheck.status().activity, CheckActivity::Running);
+
+    flycheck.clear_diagnostics(true);
+    let mut cancelled = false;
+    loop {
+        match flycheck.task_recv.recv_timeout(Duration::from_secs(10)).unwrap() {
+            CheckTask::Finished(summary) => cancelled = summary.cancelled,
+            CheckTask::ClearDiagnostics { .. } => break,
+            _ => {}
+        }
+    }
+    assert!(cancelled);
+}
+
+/// Collects what happens to the diagnostics of the files until the next run
+/// finished, as the name of the file and whether it was cleared.
+#[cfg(unix)]
+fn file_events_of_run(flycheck: &Flycheck) -> Vec<(&'static str, String)> {
+    let file_name = |url: &Url| url.path().rsplit('/').next().unwrap().to_string();
+    let mut events = Vec::new();
+    loop {
+        match flycheck.task_recv.recv_timeout(Duration::from_secs(10)).unwrap() {
+            CheckTask::ClearForFile { url, .. } => events.push(("clear", file_name(&url))),
+            CheckTask::AddDiagnostics { url, .. } => events.push(("diagnostic", file_name(&url))),
+            CheckTask::ClearDiagnostics { .. } => panic!("all diagnostics were cleared"),
+            CheckTask::Finished(_) => return events,
+            CheckTask::Error { message, .. } => panic!("check failed: {}", message),
+            _ => {}
+        }
+    }
+}
+
+/// Creates a flycheck whose first check reports a warning in `src/lib.rs` and
+/// one in `src/main.rs`, and whose following checks only report the first.
+#[cfg(unix)]
+fn fixing_flycheck(dir: &Path, publish_mode: PublishMode) -> Flycheck {
+    let script = dir.join("check.sh");
+    let main_warning = UNUSED_VARIABLE.replace("src/lib.rs", "src/main.rs");
+    fs::write(
+        &script,
+        format!(
+            "echo '{}'\n[ -e fixed ] || echo '{}'\ntouch fixed\n",
+            UNUSED_VARIABLE, main_warning
+        ),
+    )
+    .unwrap();
+    let config = FlycheckConfig::builder()
+        .custom_command("sh", vec![script.to_str().unwrap().to_string()])
+        .publish_mode(publish_mode)
+        .build();
+    let flycheck = Flycheck::new(config, dir.to_path_buf());
+    // The diagnostics of the thread's start.
+    assert!(matches!(
+        flycheck.task_recv.recv_timeout(Duration::from_secs(10)).unwrap(),
+        CheckTask::ClearDiagnostics { .. }
+    ));
+    flycheck
+}
+
+#[test]
+#[cfg(unix)]
+fn diagnostics_are_replaced_file_by_file() {
+    let dir = TempDir::new().unwrap();
+    let flycheck = fixing_flycheck(dir.path(), PublishMode::Incremental);
+
+    flycheck.update();
+    assert_eq!(
+        file_events_of_run(&flycheck),
+        vec![("diagnostic", "lib.rs".to_string()), ("diagnostic", "main.rs".to_string())]
+    );
+    // `main.rs` has no diagnostics anymore, which is only known at the end.
+    flycheck.update();
+    assert_eq!(
+        file_events_of_run(&flycheck),
+        vec![
+            ("clear", "lib.rs".to_string()),
+            ("diagnostic", "lib.rs".to_string()),
+            ("clear", "main.rs".to_string()),
+        ]
+    );
+    flycheck.update();
+    assert_eq!(
+        file_events_of_run(&flycheck),
+        vec![("clear", "lib.rs".to_string()), ("diagnostic", "lib.rs".to_string())]
+    );
+}
+
+/// Waits for the next run to finish, returning the number of diagnostics of
+/// each file it set, in the order of the file names.
+#[cfg(unix)]
+fn set_diagnostics_of_run(flycheck: &Flycheck) -> Vec<(String, usize)> {
+    let mut set = None;
+    loop {
+        match flycheck.task_recv.recv_timeout(Duration::from_secs(10)).unwrap() {
+            CheckTask::SetDiagnostics { diagnostics, .. } => {
+                assert!(set.is_none(), "the diagnostics were set twice");
+                let mut files: Vec<(String, usize)> = diagnostics
+                    .iter()
+                    .map(|(url, diagnostics)| {
+                        let name = url.path().rsplit('/').next().unwrap().to_string();
+                        (name, diagnostics.len())
+                    })
+                    .collect();
+                files.sort();
+                set = Some(files);
+            }
+            CheckTask::AddDiagnostic { .. }
+            | CheckTask::AddDiagnostics { .. }
+            | CheckTask::ClearForFile { .. } => {
+                panic!("the diagnostics were sent before the end of the run")
+            }
+            CheckTask::Finished(_) => return set.expect("the diagnostics weren't set"),
+            CheckTask::Error { message, .. } => panic!("check failed: {}", message),
+            _ => {}
+        }
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn diagnostics_can_be_replaced_at_the_end_of_the_run() {
+    let dir = TempDir::new().unwrap();
+    let flycheck = fixing_flycheck(dir.path(), PublishMode::AtEnd);
+
+    flycheck.update();
+    assert_eq!(
+        set_diagnostics_of_run(&flycheck),
+        vec![("lib.rs".to_string(), 1), ("main.rs".to_string(), 1)]
+    );
+    flycheck.update();
+    assert_eq!(
+        set_diagnostics_of_run(&flycheck),
+        vec![("lib.rs".to_string(), 1), ("main.rs".to_string(), 0)]
+    );
+    flycheck.update();
+    assert_eq!(set_diagnostics_of_run(&flycheck), vec![("lib.rs".to_string(), 1)]);
+}
+
+#[test]
+#[cfg(unix)]
+fn cancelled_runs_publishing_at_the_end_change_nothing() {
+    let dir = TempDir::new().unwrap();
+    let script = dir.path().join("check.sh");
+    fs::write(&script, format!("echo '{}'\nsleep 1000\n", UNUSED_VARIABLE)).unwrap();
+    let config = FlycheckConfig::builder()
+        .custom_command("sh", vec![script.to_str().unwrap().to_string()])
+        .publish_mode(PublishMode::AtEnd)
+        .build();
+    let flycheck = Flycheck::new(config, dir.path().to_path_buf());
+    flycheck.update();
+    let mut tasks = tasks_until_quiet(&flycheck, Duration::from_millis(500));
+    assert_eq!(flycheck.status().activity, CheckActivity::Running);
+
+    flycheck.cancel();
+    loop {
+        match flycheck.task_recv.recv_timeout(Duration::from_secs(10)).unwrap() {
+            CheckTask::Finished(summary) => {
+                assert!(summary.cancelled);
+                break;
+            }
+            task => tasks.push(task),
+        }
+    }
+    tasks.extend(tasks_until_quiet(&flycheck, Duration::from_millis(200)));
+    for task in tasks {
+        match task {
+            CheckTask::AddDiagnostic { .. }
+            | CheckTask::AddDiagnostics { .. }
+            | CheckTask::ClearForFile { .. }
+            | CheckTask::SetDiagnostics { .. } => panic!("unexpected task {:?}", task),
+            _ => {}
+        }
+    }
+}
+
+/// A diagnostic with `message` and no fixes, for tests which only look at
+/// where diagnostics go.
+fn diagnostic_of(message: &str) -> (lsp_types::Diagnostic, Vec<lsp_types::CodeActionOrCommand>) {
+    let diagnostic = lsp_types::Diagnostic { message: message.to_string(), ..Default::default() };
+    (diagnostic, Vec::new())
+}
+
+#[test]
+fn diagnostics_are_batched_until_another_file_or_the_delay() {
+    let start = Instant::now();
+    let at = |millis| start + Duration::from_millis(millis);
+    let lib = Url::parse("file:///ws/src/lib.rs").unwrap();
+    let main = Url::parse("file:///ws/src/main.rs").unwrap();
+    let run = CheckRunId(1);
+    let mut batcher = Batcher::new(Duration::from_millis(50));
+    assert_eq!(batcher.due_in(start), None);
+
+    assert!(batcher.add(run, lib.clone(), true, diagnostic_of("a"), at(0)).is_none());
+    assert!(batcher.add(run, lib.clone(), false, diagnostic_of("b"), at(10)).is_none());
+    // The delay counts from the first diagnostic.
+    assert_eq!(batcher.due_in(at(10)), Some(Duration::from_millis(40)));
+    assert!(batcher.take_due(at(49)).is_none());
+
+    let batch = batcher.add(run, main.clone(), false, diagnostic_of("c"), at(20)).unwrap();
+    assert_eq!(batch.url, lib);
+    assert!(batch.clear);
+    let messages: Vec<&str> =
+        batch.diagnostics.iter().map(|(diagnostic, _)| diagnostic.message.as_str()).collect();
+    assert_eq!(messages, vec!["a", "b"]);
+    assert_eq!(batcher.due_in(at(20)), Some(Duration::from_millis(50)));
+
+    let batch = batcher.take_due(at(70)).unwrap();
+    assert_eq!(batch.url, main);
+    assert!(!batch.clear);
+    assert_eq!(batch.diagnostics.len(), 1);
+    assert_eq!(batcher.due_in(at(70)), None);
+
+    // Diagnostics of another run are never sent together.
+    batcher.add(run, lib.clone(), false, diagnostic_of("d"), at(80));
+    let batch = batcher.add(CheckRunId(2), lib, true, diagnostic_of("e"), at(81)).unwrap();
+    assert_eq!(batch.run, run);
+    let batch = batcher.flush().unwrap();
+    assert_eq!((batch.run, batch.diagnostics.len()), (CheckRunId(2), 1));
+    assert!(batcher.flush().is_none());
+}
+
+/// Runs a check reporting three warnings in `src/lib.rs` and one in
+/// `src/main.rs`, returning the number of diagnostics of each task adding
+/// some, with the name of their file.
+#[cfg(unix)]
+fn added_diagnostics(batch_delay: Option<Duration>) -> Vec<(String, usize)> {
+    let dir = TempDir::new().unwrap();
+    let script = dir.path().join("check.sh");
+    let main_warning = UNUSED_VARIABLE.replace("src/lib.rs", "src/main.rs");
+    fs::write(
+        &script,
+        format!(
+            "for i in 1 2 3; do echo '{}'; done\necho '{}'\n",
+            numbered_unused_variable(),
+            main_warning
+        ),
+    )
+    .unwrap();
+    let config = FlycheckConfig {
+        batch_delay,
+        ..FlycheckConfig::builder()
+            .custom_command("sh", vec![script.to_str().unwrap().to_string()])
+            .build()
+    };
+    let flycheck = Flycheck::new(config, dir.path().to_path_buf());
+    flycheck.update();
+    let file_name = |url: &Url| url.path().rsplit('/').next().unwrap().to_string();
+    let mut added = Vec::new();
+    loop {
+        match flycheck.task_recv.recv_timeout(Duration::from_secs(10)).unwrap() {
+            CheckTask::AddDiagnostic { url, .. } => added.push((file_name(&url), 1)),
+            CheckTask::AddDiagnostics { url, diagnostics, .. } => {
+                added.push((file_name(&url), diagnostics.len()))
+            }
+            CheckTask::Finished(_) => return added,
+            CheckTask::Error { message, .. } => panic!("check failed: {}", message),
+            _ => {}
+        }
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn diagnostics_of_a_file_are_sent_together() {
+    assert_eq!(
+        added_diagnostics(Some(Duration::from_secs(10))),
+        vec![("lib.rs".to_string(), 3), ("main.rs".to_string(), 1)]
+    );
+    assert_eq!(
+        added_diagnostics(None),
+        vec![
+            ("lib.rs".to_string(), 1),
+            ("lib.rs".to_string(), 1),
+            ("lib.rs".to_string(), 1),
+            ("main.rs".to_string(), 1),
+        ]
+    );
+}
+
+/// Clippy's warning about `return 1;` in `src/lib.rs` of the library `foo`.
+const NEEDLESS_RETURN: &str = r#"{"reason":"compiler-message","package_id":"foo 0.1.0 (path+file:///ws)","target":{"kind":["lib"],"crate_types":["lib"],"name":"foo","src_path":"/ws/src/lib.rs","edition":"2018","doctest":true},"message":{"message":"unneeded `return` statement","code":{"code":"clippy::needless_return","explanation":null},"level":"warning","spans":[{"file_name":"src/lib.rs","byte_start":21,"byte_end":30,"line_start":2,"line_end":2,"column_start":5,"column_end":14,"is_primary":true,"text":[{"text":"    return 1;","highlight_start":5,"highlight_end":14}],"label":null,"suggested_replacement":null,"suggestion_applicability":null,"expansion":null}],"children":[{"message":"`#[warn(clippy::needless_return)]` on by default","code":null,"level":"note","spans":[],"children":[],"rendered":null},{"message":"remove `return`","code":null,"level":"help","spans":[{"file_name":"src/lib.rs","byte_start":21,"byte_end":30,"line_start":2,"line_end":2,"column_start":5,"column_end":14,"is_primary":true,"text":[{"text":"    return 1;","highlight_start":5,"highlight_end":14}],"label":null,"suggested_replacement":"1","suggestion_applicability":"MachineApplicable","expansion":null}],"children":[],"rendered":null}],"rendered":null}}"#;
+
+/// `NEEDLESS_RETURN` in `tests/common/mod.rs`, as reported for the
+/// integration test `name`.
+fn needless_return_in_test(name: &str) -> String {
+    NEEDLESS_RETURN.replace("src/lib.rs", "tests/common/mod.rs").replace(
+        r#""kind":["lib"],"crate_types":["lib"],"name":"foo""#,
+        &format!(r#""kind":["test"],"crate_types":["bin"],"name":"{}""#, name),
+    )
+}
+
+/// Returns a flycheck whose checks print `warnings`.
+#[cfg(unix)]
+fn flycheck_printing(
+    dir: &TempDir,
+    warnings: &[String],
+    merge_duplicate_targets: bool,
+) -> Flycheck {
+    let script = dir.path().join("check.sh");
+    let echoes: Vec<String> = warnings.iter().map(|it| format!("echo '{}'\n", it)).collect();
+    fs::write(&script, echoes.concat()).unwrap();
+    let config = FlycheckConfig::builder()
+        .custom_command("sh", vec![script.to_str().unwrap().to_string()])
+        .merge_duplicate_targets(merge_duplicate_targets)
+        .build();
+    Flycheck::new(config, dir.path().to_path_buf())
+}
+
+/// Applies the tasks of the next run of `flycheck` to the messages of the
+/// diagnostics `shown` for each file, returning the summary of the run.
+#[cfg(unix)]
+fn apply_run(flycheck: &Flycheck, shown: &mut HashMap<Url, Vec<String>>) -> CheckSummary {
+    let messages = |diagnostics: Vec<(lsp_types::Diagnostic, _)>| {
+        diagnostics.into_iter().map(|(diagnostic, _)| diagnostic.message).collect::<Vec<_>>()
+    };
+    loop {
+        match flycheck.task_recv.recv_timeout(Duration::from_secs(10)).unwrap() {
+            CheckTask::ClearDiagnostics { .. } => shown.clear(),
+            CheckTask::ClearForFile { url, .. } => {
+                shown.remove(&url);
+            }
+            CheckTask::AddDiagnostics { url, diagnostics, .. } => {
+                shown.entry(url).or_default().extend(messages(diagnostics));
+            }
+            CheckTask::SetDiagnostics { diagnostics, .. } => {
+                for (url, diagnostics) in diagnostics {
+                    if diagnostics.is_empty() {
+                        shown.remove(&url);
+                    } else {
+                        shown.insert(url, messages(diagnostics));
+                    }
+                }
+            }
+            CheckTask::Finished(summary) => return summary,
+            CheckTask::Error { message, .. } => panic!("check failed: {}", message),
+            _ => {}
+        }
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn diagnostics_reported_again_are_hidden() {
+    let dir = TempDir::new().unwrap();
+    // With `--all-targets`, the library and its unit tests report the same.
+    let other_lint = NEEDLESS_RETURN.replace("unneeded `return` statement", "unneeded unit");
+    let warnings = vec![NEEDLESS_RETURN.to_string(), NEEDLESS_RETURN.to_string(), other_lint];
+    let flycheck = flycheck_printing(&dir, &warnings, false);
+    let lib = Url::from_file_path(dir.path().join("src/lib.rs")).unwrap();
+    let mut shown = HashMap::new();
+    // Nothing is left out of the second run because of the first one.
+    for _ in 0..2 {
+        flycheck.update();
+        let summary = apply_run(&flycheck, &mut shown);
+        assert_eq!(shown.len(), 1);
+        let messages = &shown[&lib];
+        assert_eq!(messages.len(), 2);
+        assert!(messages[1].starts_with("unneeded unit"), "{:?}", messages);
+        assert_eq!(summary.warnings, 2);
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn targets_reporting_the_same_diagnostic_can_be_merged() {
+    let dir = TempDir::new().unwrap();
+    let warnings = vec![needless_return_in_test("a"), needless_return_in_test("b")];
+    let module = Url::from_file_path(dir.path().join("tests/common/mod.rs")).unwrap();
+
+    let flycheck = flycheck_printing(&dir, &warnings, false);
+    flycheck.update();
+    let mut shown = HashMap::new();
+    let summary = apply_run(&flycheck, &mut shown);
+    assert_eq!(shown[&module].len(), 1);
+    assert!(shown[&module][0].ends_with("on by default"), "{:?}", shown);
+    assert_eq!(summary.warnings, 1);
+
+    let flycheck = flycheck_printing(&dir, &warnings, true);
+    flycheck.update();
+    let mut shown = HashMap::new();
+    let summary = apply_run(&flycheck, &mut shown);
+    assert_eq!(shown.len(), 1);
+    assert_eq!(shown[&module].len(), 1);
+    let message = &shown[&module][0];
+    assert!(message.starts_with("unneeded `return` statement"), "{}", message);
+    assert!(message.ends_with("(test `a`, test `b`)"), "{}", message);
+    assert_eq!(summary.warnings, 1);
+}
+
+#[test]
+#[cfg(unix)]
+fn diagnostics_are_counted_by_tool_and_severity() {
+    let dir = TempDir::new().unwrap();
+    let error =
+        UNUSED_VARIABLE.replace(r#""level":"warning""#, r#""level":"error""#).replace("`x`", "`y`");
+    let note =
+        UNUSED_VARIABLE.replace(r#""level":"warning""#, r#""level":"note""#).replace("`x`", "`z`");
+    let script = dir.path().join("check.sh");
+    let messages =
+        [UNUSED_VARIABLE, error.as_str(), note.as_str(), NEEDLESS_RETURN, NEEDLESS_RETURN];
+    let echoes: Vec<String> = messages.iter().map(|it| format!("echo '{}'\n", it)).collect();
+    fs::write(&script, echoes.concat()).unwrap();
+    let config = FlycheckConfig::builder()
+        .custom_command("sh", vec![script.to_str().unwrap().to_string()])
+        .max_diagnostics(1)
+        .build();
+    let flycheck = Flycheck::new(config, dir.path().to_path_buf());
+    flycheck.update();
+    let mut shown = HashMap::new();
+    let summary = apply_run(&flycheck, &mut shown);
+    assert_eq!(shown.values().map(Vec::len).sum::<usize>(), 1);
+    // Left out because of the limit, but counted, unlike the repeated lint.
+    assert_eq!(
+        summary.counts,
+        DiagnosticCounts {
+            rustc: SeverityCounts { errors: 1, warnings: 1, infos: 1, hints: 0 },
+            clippy: SeverityCounts { errors: 0, warnings: 1, infos: 0, hints: 0 },
+        }
+    );
+    assert_eq!((summary.errors, summary.warnings), (1, 2));
+}
+
+#[test]
+#[cfg(unix)]
+fn run_scopes_include_the_files_of_their_members() {
+    let dir = TempDir::new().unwrap();
+    let root = dir.path();
+    let metadata: cargo_metadata::Metadata =
+        serde_json::from_str(&members_metadata(root, &["app", "core", "core/macros"])).unwrap();
+    let index = PackageIndex::from_metadata(&metadata);
+    let core = index.owners(&[root.join("core/src/lib.rs")]).unwrap();
+    let scope = RunScope::new(index, core);
+    for file in &["app/src/main.rs", "core/src/lib.rs", "core/macros/src/lib.rs", "build/shared.rs"]
+    {
+        let file = root.join(file);
+        fs::create_dir_all(file.parent().unwrap()).unwrap();
+        fs::write(file, "").unwrap();
+    }
+    assert!(scope.includes(&root.join("core/src/lib.rs")));
+    assert!(!scope.includes(&root.join("app/src/main.rs")));
+    // Nested members own their files.
+    assert!(!scope.includes(&root.join("core/macros/src/lib.rs")));
+    assert!(!scope.includes(&root.join("build/shared.rs")));
+    // Nothing reports diagnostics for deleted files anymore.
+    assert!(scope.includes(&root.join("app/src/gone.rs")));
+}
+
+/// Creates a flycheck for a workspace with the members `app` and `core`,
+/// whose checks report a warning in each file of the packages they check
+/// which contains `warn`, starting with `app/src/main.rs`, `app/src/gone.rs`
+/// and `core/src/lib.rs`.
+#[cfg(unix)]
+fn warning_members_flycheck(root: &Path, publish_mode: PublishMode) -> Flycheck {
+    fs::write(root.join("Cargo.toml"), "").unwrap();
+    fs::write(root.join("metadata.json"), members_metadata(root, &["app", "core"])).unwrap();
+    let mut script =
+        "shift\nif [ \"$1\" = metadata ]; then\ncat metadata.json\nexit\nfi\n".to_string();
+    for (package, path) in
+        &[("app", "app/src/main.rs"), ("app", "app/src/gone.rs"), ("core", "core/src/lib.rs")]
+    {
+        fs::create_dir_all(root.join(path).parent().unwrap()).unwrap();
+        fs::write(root.join(path), "warn").unwrap();
+        let warning = UNUSED_VARIABLE.replace("src/lib.rs", path);
+        script += &format!(
+            "case \" $* \" in\n*\" -p {} \"*|*\" --workspace \"*) grep -qs warn {} && echo '{}';;\nesac\n",
+            package, path, warning
+        );
+    }
+    script += "exit 0\n";
+    fs::write(root.join("wrapper.sh"), script).unwrap();
+    let config = FlycheckConfig::builder()
+        .wrapper(strings(&["sh", root.join("wrapper.sh").to_str().unwrap()]))
+        .check_changed_packages(true)
+        .debounce(Duration::from_secs(0))
+        .publish_mode(publish_mode)
+        .build();
+    Flycheck::new(config, root.to_path_buf())
+}
+
+#[test]
+#[cfg(unix)]
+fn scoped_runs_keep_the_diagnostics_of_other_packages() {
+    for &publish_mode in &[PublishMode::Incremental, PublishMode::AtEnd] {
+        let dir = TempDir::new().unwrap();
+        let root = dir.path();
+        let flycheck = warning_members_flycheck(root, publish_mode);
+        let mut shown = HashMap::new();
+        let shown_files = |shown: &HashMap<Url, Vec<String>>| {
+            let mut files: Vec<String> = shown
+                .keys()
+                .map(|url| {
+                    let path = url.to_file_path().unwrap();
+                    path.strip_prefix(root).unwrap().to_str().unwrap().to_string()
+                })
+                .collect();
+            files.sort();
+            files
+        };
+
+        flycheck.update();
+        apply_run(&flycheck, &mut shown);
+        assert_eq!(
+            shown_files(&shown),
+            strings(&["app/src/gone.rs", "app/src/main.rs", "core/src/lib.rs"])
+        );
+
+        // `core` is fixed and a file of `app` deleted, but only `core` is
+        // checked again.
+        fs::write(root.join("core/src/lib.rs"), "").unwrap();
+        fs::remove_file(root.join("app/src/gone.rs")).unwrap();
+        flycheck.update_with_files(vec![root.join("core/src/lib.rs")]);
+        let summary = apply_run(&flycheck, &mut shown);
+        assert_eq!(summary.scope, Some(vec![root.join("core")]));
+        assert_eq!(shown_files(&shown), strings(&["app/src/main.rs"]));
+    }
+}
+
+/// Every order of `items`.
+fn permutations<T: Clone>(items: &[T]) -> Vec<Vec<T>> {
+    if items.is_empty() {
+        return vec![Vec::new()];
+    }
+    let mut res = Vec::new();
+    for (i, item) in items.iter().enumerate() {
+        let mut rest = items.to_vec();
+        rest.remove(i);
+        for mut permutation in permutations(&rest) {
+            permutation.insert(0, item.clone());
+            res.push(permutation);
+        }
+    }
+    res
+}
+
+#[test]
+fn diagnostics_are_ordered_whatever_order_they_come_in() {
+    let lib = Url::parse("file:///ws/src/lib.rs").unwrap();
+    let main = Url::parse("file:///ws/src/main.rs").unwrap();
+    let diagnostic = |line, character, code: Option<&str>, message: &str| lsp_types::Diagnostic {
+        range: lsp_types::Range::new(
+            lsp_types::Position::new(line, character),
+            lsp_types::Position::new(line, character + 1),
+        ),
+        code: code.map(|code| lsp_types::NumberOrString::String(code.to_string())),
+        message: message.to_string(),
+        ..Default::default()
+    };
+    let diagnostics = vec![
+        (main.clone(), diagnostic(0, 0, None, "a")),
+        (lib.clone(), diagnostic(3, 0, None, "b")),
+        (lib.clone(), diagnostic(1, 4, Some("unused_variables"), "c")),
+        (lib.clone(), diagnostic(1, 4, Some("dead_code"), "d")),
+        (lib.clone(), diagnostic(1, 4, Some("dead_code"), "a")),
+        (lib, diagnostic(1, 2, None, "e")),
+    ];
+    let sorted = |mut diagnostics: Vec<(Url, lsp_types::Diagnostic)>| {
+        diagnostics.sort_by(|(a_url, a), (b_url, b)| compare_diagnostics((a_url, a), (b_url, b)));
+        diagnostics
+    };
+    let expected = sorted(diagnostics.clone());
+    let messages: Vec<&str> = expected.iter().map(|(_, it)| it.message.as_str()).collect();
+    assert_eq!(messages, vec!["e", "a", "d", "c", "b", "a"]);
+    for permutation in permutations(&diagnostics) {
+        assert_eq!(sorted(permutation), expected);
+    }
+}
+
+/// Runs two checks reporting the same warnings in `src/lib.rs` in different
+/// orders, returning the messages shown after each.
+#[cfg(unix)]
+fn messages_of_reordered_runs(publish_mode: PublishMode) -> (Vec<String>, Vec<String>) {
+    let dir = TempDir::new().unwrap();
+    let warning = |line: u32, name: &str| {
+        UNUSED_VARIABLE
+            .replace(
+                r#""line_start":2,"line_end":2"#,
+                &format!(r#""line_start":{0},"line_end":{0}"#, line),
+            )
+            .replace("`x`", &format!("`{}`", name))
+    };
+    let (a, b, c) = (warning(3, "a"), warning(2, "b"), warning(2, "c"));
+    let script = dir.path().join("check.sh");
+    fs::write(
+        &script,
+        format!(
+            "if [ -e second ]; then\necho '{0}'\necho '{2}'\necho '{1}'\nelse\necho '{1}'\necho '{0}'\necho '{2}'\nfi\ntouch second\n",
+            a, b, c
+        ),
+    )
+    .unwrap();
+    let config = FlycheckConfig::builder()
+        .custom_command("sh", vec![script.to_str().unwrap().to_string()])
+        .publish_mode(publish_mode)
+        .build();
+    let flycheck = Flycheck::new(config, dir.path().to_path_buf());
+    let lib = Url::from_file_path(dir.path().join("src/lib.rs")).unwrap();
+    let mut shown = HashMap::new();
+    flycheck.update();
+    apply_run(&flycheck, &mut shown);
+    let first = shown[&lib].clone();
+    flycheck.update();
+    apply_run(&flycheck, &mut shown);
+    (first, shown[&lib].clone())
+}
+
+#[test]
+#[cfg(unix)]
+fn diagnostics_are_sent_in_a_stable_order() {
+    for &publish_mode in &[PublishMode::Incremental, PublishMode::AtEnd] {
+        let (first, second) = messages_of_reordered_runs(publish_mode);
+        let names: Vec<&str> =
+            first.iter().map(|message| message.split('`').nth(1).unwrap()).collect();
+        assert_eq!(names, vec!["b", "c", "a"]);
+        assert_eq!(first, second);
+    }
+}
+
+#[test]
+fn cargo_errors_are_shown_with_their_kind() {
+    let root = Path::new("/ws");
+    let cargo = cargo_command();
+    let error = |err: CargoError, config: &FlycheckConfig| check_error(&err, config, root);
+    let exit =
+        |stderr: &str| CargoError::NonZeroExit { code: Some(101), stderr: stderr.to_string() };
+
+    let not_found = || CargoError::SpawnFailed(io::Error::from(io::ErrorKind::NotFound));
+    let (message, kind) = error(not_found(), &cargo);
+    assert!(message.starts_with("cargo check could not be started"), "{}", message);
+    assert!(message.contains("install Rust with rustup"), "{}", message);
+    assert_eq!(kind, CheckErrorKind::Environment);
+    let custom = FlycheckConfig::builder().custom_command("no-such-check-command", vec![]).build();
+    let (message, kind) = error(not_found(), &custom);
+    assert!(message.contains("make sure the program is installed"), "{}", message);
+    assert_eq!(kind, CheckErrorKind::Config);
+
+    let clippy = FlycheckConfig::builder().command("clippy").toolchain("nightly").build();
+    let (message, kind) = error(exit("error: no such command: `clippy`"), &clippy);
+    assert!(message.contains("rustup component add clippy --toolchain nightly"), "{}", message);
+    assert_eq!(kind, CheckErrorKind::Config);
+
+    let (message, kind) =
+        error(exit("error: failed to parse manifest at `/ws/Cargo.toml`"), &cargo);
+    assert!(message.contains("failed to read the manifest of the workspace at"), "{}", message);
+    assert_eq!(kind, CheckErrorKind::Environment);
+
+    let (message, kind) = error(exit("error: unexpected argument '--no-such-flag' found"), &cargo);
+    assert!(message.starts_with("cargo check failed: "), "{}", message);
+    assert!(message.contains("--no-such-flag"), "{}", message);
+    assert_eq!(kind, CheckErrorKind::Config);
+
+    let (message, kind) = error(exit("error: no matching package named `foo` found"), &cargo);
+    assert!(message.contains("no matching package"), "{}", message);
+    assert_eq!(kind, CheckErrorKind::Environment);
+
+    let hooked = CargoError::HookedCommandFailed {
+        command: "cargo check".to_string(),
+        code: Some(1),
+        stderr: String::new(),
+    };
+    let (message, kind) = error(hooked, &cargo);
+    assert!(message.contains("changed by the command hook"), "{}", message);
+    assert_eq!(kind, CheckErrorKind::Config);
+
+    let (message, kind) = error(CargoError::TimedOut(Duration::from_secs(3)), &cargo);
+    assert_eq!(message, "cargo check timed out: the check was killed after running for 3.0s");
+    assert_eq!(kind, CheckErrorKind::Environment);
+
+    let broken_pipe = CargoError::Io(io::Error::from(io::ErrorKind::BrokenPipe));
+    assert_eq!(error(broken_pipe, &cargo).1, CheckErrorKind::Internal);
+}
+
+#[test]
+fn failed_checks_still_end_their_progress() {
+    let dir = TempDir::new().unwrap();
+    let config = FlycheckConfig::builder().custom_command("no-such-check-command", vec![]).build();
+    let flycheck = Flycheck::new(config, dir.path().to_path_buf());
+    flycheck.update();
+    let mut error = None;
+    loop {
+        match flycheck.task_recv.recv_timeout(Duration::from_secs(10)).unwrap() {
+            CheckTask::Error { message, kind } => error = Some((message, kind)),
+            CheckTask::Status(WorkDoneProgress::End(_)) => break,
+            _ => {}
+        }
+    }
+    let (message, kind) = error.expect("the failure should be reported before the progress ends");
+    assert!(message.contains("no-such-check-command"), "{}", message);
+    assert_eq!(kind, CheckErrorKind::Config);
+}
+
+#[test]
+#[cfg(unix)]
+fn diagnostics_beyond_the_cap_of_their_file_are_suppressed() {
+    for &publish_mode in &[PublishMode::Incremental, PublishMode::AtEnd] {
+        let dir = TempDir::new().unwrap();
+        let script = dir.path().join("check.sh");
+        let main_warning = UNUSED_VARIABLE.replace("src/lib.rs", "src/main.rs");
+        fs::write(
+            &script,
+            format!(
+                "for i in 1 2 3 4 5 6 7; do echo '{}'; done\necho '{}'\n",
+                numbered_unused_variable(),
+                main_warning
+            ),
+        )
+        .unwrap();
+        let config = FlycheckConfig::builder()
+            .custom_command("sh", vec![script.to_str().unwrap().to_string()])
+            .max_diagnostics_per_file(5)
+            .publish_mode(publish_mode)
+            .build();
+        let flycheck = Flycheck::new(config, dir.path().to_path_buf());
+        flycheck.update();
+        let mut shown = HashMap::new();
+        let summary = apply_run(&flycheck, &mut shown);
+        let lib = Url::from_file_path(dir.path().join("src/lib.rs")).unwrap();
+        let main = Url::from_file_path(dir.path().join("src/main.rs")).unwrap();
+        let marker =
+            "2 additional diagnostics were suppressed (raise checkOnSave.maxDiagnosticsPerFile)";
+        assert_eq!(shown[&lib].len(), 6, "{:?}", shown[&lib]);
+        assert!(shown[&lib].iter().any(|message| message == marker), "{:?}", shown[&lib]);
+        for i in 1..=5 {
+            let name = format!("`x{}`", i);
+            assert!(shown[&lib].iter().any(|message| message.contains(&name)), "{}", name);
+        }
+        assert!(!shown[&lib].iter().any(|message| message.contains("`x6`")));
+        assert_eq!(shown[&main].len(), 1);
+        // Suppressed, but still counted.
+        assert_eq!(summary.warnings, 8);
+    }
+}
+
+#[test]
+fn suppressed_diagnostics_are_marked_at_the_top_of_the_file() {
+    let marker = suppressed_diagnostics_marker(1, "clippy");
+    assert_eq!(
+        marker.message,
+        "1 additional diagnostic was suppressed (raise checkOnSave.maxDiagnosticsPerFile)"
+    );
+    assert_eq!(marker.range.start, lsp_types::Position::new(0, 0));
+    assert_eq!(marker.range.end, lsp_types::Position::new(0, 0));
+    assert_eq!(marker.severity, Some(lsp_types::DiagnosticSeverity::Information));
+    assert_eq!(
+        marker.code,
+        Some(lsp_types::NumberOrString::String(SUPPRESSED_DIAGNOSTICS_CODE.to_string()))
+    );
+    assert_eq!(marker.source.as_deref(), Some("clippy"));
+}
@@ -0,0 +1,132 @@
+//! Watches the workspace for changed files, for users of the checks which
+//! aren't told about saved files, see [`Flycheck::with_file_watching`].
+//!
+//! [`Flycheck::with_file_watching`]: crate::Flycheck::with_file_watching
+use std::{
+    fs, mem,
+    path::{Path, PathBuf},
+    sync::mpsc::{channel, Receiver, RecvTimeoutError},
+    time::Duration,
+};
+
+use crossbeam_channel::Sender;
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use notify::{raw_watcher, RawEvent, RecommendedWatcher, RecursiveMode, Watcher};
+
+use crate::CheckCommand;
+
+/// A save often consists of several raw events, f.x. a write followed by a
+/// rename, which are collected until there are none for this long.
+const EVENT_DEBOUNCE: Duration = Duration::from_millis(50);
+
+/// Watches the workspace root and sends an update for the changed files
+/// which aren't ignored. Files which can't change the diagnostics are left
+/// out by the check thread, see `FlycheckConfig::watched_globs`.
+pub(crate) struct FileWatcher {
+    // XXX: drop order is significant, dropping the watcher stops the thread
+    _watcher: RecommendedWatcher,
+    _thread: jod_thread::JoinHandle<()>,
+}
+
+impl FileWatcher {
+    pub(crate) fn new(
+        workspace_root: &Path,
+        cmd_send: Sender<CheckCommand>,
+    ) -> Result<FileWatcher, notify::Error> {
+        let (event_send, event_recv) = channel();
+        let mut watcher = raw_watcher(event_send)?;
+        watcher.watch(workspace_root, RecursiveMode::Recursive)?;
+        let ignored = IgnoredFiles::new(workspace_root);
+        let thread = jod_thread::spawn(move || forward_changes(&event_recv, &ignored, &cmd_send));
+        Ok(FileWatcher { _watcher: watcher, _thread: thread })
+    }
+}
+
+fn forward_changes(
+    event_recv: &Receiver<RawEvent>,
+    ignored: &IgnoredFiles,
+    cmd_send: &Sender<CheckCommand>,
+) {
+    let mut changed_files: Vec<PathBuf> = Vec::new();
+    loop {
+        let event = if changed_files.is_empty() {
+            event_recv.recv().map_err(|_| RecvTimeoutError::Disconnected)
+        } else {
+            event_recv.recv_timeout(EVENT_DEBOUNCE)
+        };
+        match event {
+            Ok(RawEvent { path: Some(path), op: Ok(_), .. }) => {
+                if !ignored.is_ignored(&path) && !changed_files.contains(&path) {
+                    changed_files.push(path);
+                }
+            }
+            Ok(RawEvent { op: Err(err), .. }) => log::warn!("can't watch the workspace: {}", err),
+            Ok(_) => {}
+            Err(RecvTimeoutError::Timeout) => {
+                let changed_files = mem::take(&mut changed_files);
+                let update = CheckCommand::Update { extra_args: Vec::new(), changed_files };
+                if cmd_send.send(update).is_err() {
+                    break;
+                }
+            }
+            // The watcher was dropped.
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+}
+
+/// The files of the workspace whose changes aren't forwarded: the ones in
+/// `target`, hidden ones like `.git` and the ones matched by the
+/// `.gitignore` of the workspace root.
+pub(crate) struct IgnoredFiles {
+    workspace_root: PathBuf,
+    gitignore: GlobSet,
+}
+
+impl IgnoredFiles {
+    pub(crate) fn new(workspace_root: &Path) -> IgnoredFiles {
+        let gitignore = fs::read_to_string(workspace_root.join(".gitignore")).unwrap_or_default();
+        IgnoredFiles::with_gitignore(workspace_root, &gitignore)
+    }
+
+    /// Supports the common subset of the `.gitignore` format, negated
+    /// patterns are skipped.
+    pub(crate) fn with_gitignore(workspace_root: &Path, gitignore: &str) -> IgnoredFiles {
+        let mut builder = GlobSetBuilder::new();
+        for line in gitignore.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') || line.starts_with('!') {
+                continue;
+            }
+            let pattern = line.trim_end_matches('/');
+            // Patterns without a slash match at any depth.
+            let pattern = if pattern.starts_with('/') {
+                pattern[1..].to_string()
+            } else if pattern.contains('/') {
+                pattern.to_string()
+            } else {
+                format!("**/{}", pattern)
+            };
+            // Directories match their contents as well.
+            for glob in &[pattern.clone(), format!("{}/**", pattern)] {
+                match GlobBuilder::new(glob).literal_separator(true).build() {
+                    Ok(glob) => {
+                        builder.add(glob);
+                    }
+                    Err(err) => log::debug!("skipping the .gitignore pattern `{}`: {}", line, err),
+                }
+            }
+        }
+        let gitignore = builder.build().unwrap_or_else(|_| GlobSet::empty());
+        IgnoredFiles { workspace_root: workspace_root.to_path_buf(), gitignore }
+    }
+
+    pub(crate) fn is_ignored(&self, path: &Path) -> bool {
+        let relative = match path.strip_prefix(&self.workspace_root) {
+            Ok(relative) => relative,
+            Err(_) => return true,
+        };
+        let hidden =
+            relative.components().any(|it| it.as_os_str().to_string_lossy().starts_with('.'));
+        hidden || relative.starts_with("target") || self.gitignore.is_match(relative)
+    }
+}
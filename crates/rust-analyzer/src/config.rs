@@ -7,8 +7,12 @@
 //! configure the server itself, feature flags are passed into analysis, and
 //! tweak things like automatic insertion of `()` in completions.
 
+use std::{collections::BTreeMap, time::Duration};
+
 use lsp_types::TextDocumentClientCapabilities;
-use ra_flycheck::FlycheckConfig;
+use ra_flycheck::{
+    CheckScope, CheckTarget, FlycheckCommand, FlycheckConfig, PublishMode, RestartBehavior,
+};
 use ra_ide::{CompletionConfig, InlayHintsConfig};
 use ra_project_model::CargoConfig;
 use serde::Deserialize;
@@ -86,11 +90,7 @@ impl Default for Config {
 
             cargo: CargoConfig::default(),
             rustfmt: RustfmtConfig::Rustfmt { extra_args: Vec::new() },
-            check: Some(FlycheckConfig::CargoCommand {
-                command: "check".to_string(),
-                all_targets: true,
-                extra_args: Vec::new(),
-            }),
+            check: Some(FlycheckConfig::builder().all_targets(true).build()),
 
             inlay_hints: InlayHintsConfig {
                 type_hints: true,
@@ -149,28 +149,143 @@ impl Config {
         if let Some(false) = get(value, "/checkOnSave/enable") {
             // check is disabled
             self.check = None;
-        } else {
+        } else if let Some(check) = &mut self.check {
             // check is enabled
             match get::<Vec<String>>(value, "/checkOnSave/overrideCommand") {
                 // first see if the user has completely overridden the command
                 Some(mut args) if !args.is_empty() => {
                     let command = args.remove(0);
-                    self.check = Some(FlycheckConfig::CustomCommand {
+                    check.command = FlycheckCommand::CustomCommand {
                         command,
                         args,
-                    });
+                    };
                 }
                 // otherwise configure command customizations
                 _ => {
-                    if let Some(FlycheckConfig::CargoCommand { command, extra_args, all_targets })
-                        = &mut self.check
+                    if let FlycheckCommand::CargoCommand {
+                        command, extra_args, all_targets, target_triple, profile,
+                        no_default_features, all_features, features, packages, exclude,
+                        jobs, offline, locked, frozen, cargo_binary, toolchain, targets,
+                        scope, trailing_args, inject_message_format, keep_going,
+                        tests_only, ..
+                    } = &mut check.command
                     {
                         set(value, "/checkOnSave/extraArgs", extra_args);
                         set(value, "/checkOnSave/command", command);
                         set(value, "/checkOnSave/allTargets", all_targets);
+                        *scope = match get(value, "/checkOnSave/scope") {
+                            Some("libOnly") => CheckScope::LibOnly,
+                            Some("allTargets") => CheckScope::AllTargets,
+                            Some("workspace") | _ => CheckScope::Workspace,
+                        };
+                        set(value, "/checkOnSave/target", target_triple);
+                        if let Some(names) = get::<Vec<String>>(value, "/checkOnSave/targets") {
+                            *targets = names.iter().filter_map(|name| match name.as_str() {
+                                "lib" => Some(CheckTarget::Lib),
+                                "bins" => Some(CheckTarget::Bins),
+                                "tests" => Some(CheckTarget::Tests),
+                                "examples" => Some(CheckTarget::Examples),
+                                "benches" => Some(CheckTarget::Benches),
+                                _ => {
+                                    log::warn!("unknown check target {:?}", name);
+                                    None
+                                }
+                            }).collect();
+                            if !targets.is_empty() {
+                                *all_targets = false;
+                            }
+                        }
+                        *no_default_features = get(value, "/checkOnSave/noDefaultFeatures").unwrap_or(self.cargo.no_default_features);
+                        *all_features = get(value, "/checkOnSave/allFeatures").unwrap_or(self.cargo.all_features);
+                        *features = get(value, "/checkOnSave/features").unwrap_or_else(|| self.cargo.features.clone());
+                        set(value, "/checkOnSave/profile", profile);
+                        set(value, "/checkOnSave/packages", packages);
+                        set(value, "/checkOnSave/exclude", exclude);
+                        set(value, "/checkOnSave/jobs", jobs);
+                        set(value, "/checkOnSave/offline", offline);
+                        set(value, "/checkOnSave/locked", locked);
+                        set(value, "/checkOnSave/frozen", frozen);
+                        set(value, "/checkOnSave/cargoBinary", cargo_binary);
+                        set(value, "/checkOnSave/toolchain", toolchain);
+                        set(value, "/checkOnSave/trailingArgs", trailing_args);
+                        set(value, "/checkOnSave/injectMessageFormat", inject_message_format);
+                        set(value, "/checkOnSave/keepGoing", keep_going);
+                        set(value, "/checkOnSave/testsOnly", tests_only);
                     }
                 }
             };
+            set(value, "/checkOnSave/unsetEnv", &mut check.unset_env);
+            set(value, "/checkOnSave/rustflags", &mut check.rustflags);
+            set(value, "/checkOnSave/replaceRustflags", &mut check.replace_rustflags);
+            set(value, "/checkOnSave/targetDir", &mut check.target_dir);
+            set(value, "/checkOnSave/useSeparateTargetDir", &mut check.use_separate_target_dir);
+            set(value, "/checkOnSave/wrapper", &mut check.wrapper);
+            set(value, "/checkOnSave/workingDir", &mut check.working_dir);
+            set(value, "/checkOnSave/incremental", &mut check.incremental);
+            set(value, "/checkOnSave/sanitizeOutput", &mut check.sanitize_output);
+            set(value, "/checkOnSave/lowPriority", &mut check.low_priority);
+            set(value, "/checkOnSave/cancelOnUpdate", &mut check.cancel_on_update);
+            set(value, "/checkOnSave/maxDiagnostics", &mut check.max_diagnostics);
+            set(value, "/checkOnSave/maxDiagnosticsPerFile", &mut check.max_diagnostics_per_file);
+            set(value, "/checkOnSave/stopAtDiagnosticLimit", &mut check.stop_at_diagnostic_limit);
+            set(value, "/checkOnSave/estimateProgress", &mut check.estimate_progress);
+            set(value, "/checkOnSave/reportFutureIncompat", &mut check.report_future_incompat);
+            set(value, "/checkOnSave/forwardStderrToLog", &mut check.forward_stderr_to_log);
+            set(value, "/checkOnSave/checkChangedPackages", &mut check.check_changed_packages);
+            set(value, "/checkOnSave/watchedGlobs", &mut check.watched_globs);
+            set(value, "/checkOnSave/skipUnchangedFiles", &mut check.skip_unchanged_files);
+            match get(value, "/checkOnSave/publishMode") {
+                Some("incremental") => check.publish_mode = PublishMode::Incremental,
+                Some("atEnd") => check.publish_mode = PublishMode::AtEnd,
+                _ => {}
+            }
+            set(value, "/checkOnSave/mergeDuplicateTargets", &mut check.merge_duplicate_targets);
+            if let Some(extra_env) = get::<BTreeMap<String, String>>(value, "/checkOnSave/extraEnv") {
+                check.extra_env = extra_env.into_iter().collect();
+            }
+            if let Some(idle_delay) = get(value, "/checkOnSave/idleDelay") {
+                check.idle_delay = Duration::from_millis(idle_delay);
+            }
+            if let Some(debounce) = get(value, "/checkOnSave/debounce") {
+                check.debounce = Duration::from_millis(debounce);
+            }
+            if let Some(max_debounce) = get(value, "/checkOnSave/maxDebounce") {
+                check.max_debounce = Duration::from_millis(max_debounce);
+            }
+            if let Some(min_interval) = get::<Option<u64>>(value, "/checkOnSave/minInterval") {
+                check.min_interval = min_interval.map(Duration::from_secs);
+            }
+            if let Some(recheck) = get::<Option<u64>>(value, "/checkOnSave/recheckInterval") {
+                check.recheck_interval = recheck.map(Duration::from_secs);
+            }
+            if let Some(timeout) = get(value, "/checkOnSave/timeout") {
+                check.timeout = Some(Duration::from_secs(timeout));
+            }
+            if let Some(grace_period) = get(value, "/checkOnSave/terminationGracePeriod") {
+                check.termination_grace_period = Duration::from_millis(grace_period);
+            }
+            if let Some(retry_delay) = get::<Option<u64>>(value, "/checkOnSave/retryDelay") {
+                check.retry_delay = retry_delay.map(Duration::from_millis);
+            }
+            if let Some(batch_delay) = get::<Option<u64>>(value, "/checkOnSave/batchDelay") {
+                check.batch_delay = batch_delay.map(Duration::from_millis);
+            }
+            if let Some(max) = get::<Option<u64>>(value, "/checkOnSave/finishOnRestart") {
+                check.on_restart = match max {
+                    Some(max) => RestartBehavior::WaitForFinish { max: Duration::from_secs(max) },
+                    None => RestartBehavior::KillImmediately,
+                };
+            }
+            if let Some(secondary_command) = get::<String>(value, "/checkOnSave/secondaryCommand") {
+                // The secondary check shares all other options with the primary one.
+                let mut secondary = check.clone();
+                if let FlycheckCommand::CargoCommand { command, .. } = &mut secondary.command {
+                    *command = secondary_command;
+                    check.secondary = Some(Box::new(secondary));
+                } else {
+                    log::warn!("`checkOnSave.secondaryCommand` is ignored for custom commands");
+                }
+            }
         }
 
         set(value, "/inlayHints/typeHints", &mut self.inlay_hints.type_hints);
@@ -23,7 +23,13 @@ pub struct Fix {
 #[derive(Debug)]
 pub enum DiagnosticTask {
     ClearCheck,
+    /// Clears the check diagnostics of a single file.
+    ClearCheckFor(FileId),
     AddCheck(FileId, Diagnostic, Vec<CodeActionOrCommand>),
+    /// Adds several check diagnostics of a file at once.
+    AddChecks(FileId, Vec<(Diagnostic, Vec<CodeActionOrCommand>)>),
+    /// Replaces the check diagnostics of the given files at once.
+    SetCheck(Vec<(FileId, Vec<(Diagnostic, Vec<CodeActionOrCommand>)>)>),
     SetNative(FileId, Vec<Diagnostic>),
 }
 
@@ -33,6 +39,11 @@ impl DiagnosticCollection {
         self.check.drain().map(|(key, _value)| key).collect()
     }
 
+    pub fn clear_check_for(&mut self, file_id: FileId) -> Vec<FileId> {
+        Arc::make_mut(&mut self.check_fixes).remove(&file_id);
+        self.check.remove(&file_id).map(|_| file_id).into_iter().collect()
+    }
+
     pub fn add_check_diagnostic(
         &mut self,
         file_id: FileId,
@@ -54,6 +65,17 @@ impl DiagnosticCollection {
         diagnostics.push(diagnostic);
     }
 
+    pub fn set_check_diagnostics(
+        &mut self,
+        file_id: FileId,
+        diagnostics: Vec<(Diagnostic, Vec<CodeActionOrCommand>)>,
+    ) {
+        self.clear_check_for(file_id);
+        for (diagnostic, fixes) in diagnostics {
+            self.add_check_diagnostic(file_id, diagnostic, fixes);
+        }
+    }
+
     pub fn set_native_diagnostics(&mut self, file_id: FileId, diagnostics: Vec<Diagnostic>) {
         self.native.insert(file_id, diagnostics);
     }
@@ -67,10 +89,24 @@ impl DiagnosticCollection {
     pub fn handle_task(&mut self, task: DiagnosticTask) -> Vec<FileId> {
         match task {
             DiagnosticTask::ClearCheck => self.clear_check(),
+            DiagnosticTask::ClearCheckFor(file_id) => self.clear_check_for(file_id),
             DiagnosticTask::AddCheck(file_id, diagnostic, fixes) => {
                 self.add_check_diagnostic(file_id, diagnostic, fixes);
                 vec![file_id]
             }
+            DiagnosticTask::AddChecks(file_id, diagnostics) => {
+                for (diagnostic, fixes) in diagnostics {
+                    self.add_check_diagnostic(file_id, diagnostic, fixes);
+                }
+                vec![file_id]
+            }
+            DiagnosticTask::SetCheck(files) => files
+                .into_iter()
+                .map(|(file_id, diagnostics)| {
+                    self.set_check_diagnostics(file_id, diagnostics);
+                    file_id
+                })
+                .collect(),
             DiagnosticTask::SetNative(file_id, diagnostics) => {
                 self.set_native_diagnostics(file_id, diagnostics);
                 vec![file_id]
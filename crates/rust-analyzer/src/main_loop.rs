@@ -17,10 +17,10 @@ use std::{
 use crossbeam_channel::{never, select, unbounded, RecvError, Sender};
 use lsp_server::{Connection, ErrorCode, Message, Notification, Request, RequestId, Response};
 use lsp_types::{
-    NumberOrString, WorkDoneProgress, WorkDoneProgressBegin, WorkDoneProgressCreateParams,
+    NumberOrString, Url, WorkDoneProgress, WorkDoneProgressBegin, WorkDoneProgressCreateParams,
     WorkDoneProgressEnd, WorkDoneProgressReport,
 };
-use ra_flycheck::{url_from_path_with_drive_lowercasing, CheckTask};
+use ra_flycheck::{url_from_path_with_drive_lowercasing, CheckRunId, CheckTask};
 use ra_ide::{Canceled, FileId, LibraryData, SourceRootId};
 use ra_prof::profile;
 use ra_project_model::{PackageRoot, ProjectWorkspace};
@@ -289,6 +289,8 @@ struct LoopState {
     roots_scanned: usize,
     roots_total: usize,
     configuration_request_id: Option<RequestId>,
+    /// The check run whose diagnostics are shown.
+    check_run: CheckRunId,
 }
 
 impl LoopState {
@@ -334,7 +336,9 @@ fn loop_turn(
             loop_state.in_flight_libraries -= 1;
             loop_state.roots_scanned += 1;
         }
-        Event::CheckWatcher(task) => on_check_task(task, world_state, task_sender)?,
+        Event::CheckWatcher(task) => {
+            on_check_task(task, world_state, &mut loop_state.check_run, task_sender)?
+        }
         Event::Msg(msg) => match msg {
             Message::Request(req) => on_request(
                 world_state,
@@ -553,6 +557,17 @@ fn on_notification(
         }
         Err(not) => not,
     };
+    let not = match notification_cast::<req::WorkDoneProgressCancel>(not) {
+        Ok(params) => {
+            if params.token == check_progress_token() {
+                if let Some(flycheck) = &state.flycheck {
+                    flycheck.cancel();
+                }
+            }
+            return Ok(());
+        }
+        Err(not) => not,
+    };
     let not = match notification_cast::<req::DidOpenTextDocument>(not) {
         Ok(params) => {
             let uri = params.text_document.uri;
@@ -578,9 +593,12 @@ fn on_notification(
         Err(not) => not,
     };
     let not = match notification_cast::<req::DidSaveTextDocument>(not) {
-        Ok(_params) => {
+        Ok(params) => {
             if let Some(flycheck) = &state.flycheck {
-                flycheck.update();
+                match params.text_document.uri.to_file_path() {
+                    Ok(path) => flycheck.update_with_files(vec![path]),
+                    Err(()) => flycheck.update(),
+                }
             }
             return Ok(());
         }
@@ -641,43 +659,109 @@ fn on_notification(
     Ok(())
 }
 
+/// Identifies the progress of the checks, clients can cancel the running check
+/// through it.
+fn check_progress_token() -> req::ProgressToken {
+    req::ProgressToken::String("rustAnalyzer/cargoWatcher".to_string())
+}
+
 fn on_check_task(
     task: CheckTask,
     world_state: &mut WorldState,
+    check_run: &mut CheckRunId,
     task_sender: &Sender<Task>,
 ) -> Result<()> {
     match task {
-        CheckTask::ClearDiagnostics => {
+        CheckTask::ClearDiagnostics { run } => {
+            *check_run = run;
             task_sender.send(Task::Diagnostic(DiagnosticTask::ClearCheck))?;
         }
 
-        CheckTask::AddDiagnostic { url, diagnostic, fixes } => {
-            let path = url.to_file_path().map_err(|()| format!("invalid uri: {}", url))?;
-            let file_id = match world_state.vfs.read().path2file(&path) {
-                Some(file) => FileId(file.0),
-                None => {
-                    log::error!("File with cargo diagnostic not found in VFS: {}", path.display());
-                    return Ok(());
-                }
-            };
+        // Left over from a run which was replaced already.
+        CheckTask::ClearForFile { run, .. }
+        | CheckTask::AddDiagnostic { run, .. }
+        | CheckTask::AddDiagnostics { run, .. }
+        | CheckTask::SetDiagnostics { run, .. }
+            if run < *check_run => {}
+
+        CheckTask::ClearForFile { run, url } => {
+            *check_run = run;
+            if let Some(file_id) = check_file_id(world_state, &url)? {
+                task_sender.send(Task::Diagnostic(DiagnosticTask::ClearCheckFor(file_id)))?;
+            }
+        }
 
-            task_sender
-                .send(Task::Diagnostic(DiagnosticTask::AddCheck(file_id, diagnostic, fixes)))?;
+        CheckTask::AddDiagnostic { run, url, diagnostic, fixes } => {
+            *check_run = run;
+            if let Some(file_id) = check_file_id(world_state, &url)? {
+                task_sender
+                    .send(Task::Diagnostic(DiagnosticTask::AddCheck(file_id, diagnostic, fixes)))?;
+            }
+        }
+
+        CheckTask::AddDiagnostics { run, url, diagnostics } => {
+            *check_run = run;
+            if let Some(file_id) = check_file_id(world_state, &url)? {
+                task_sender
+                    .send(Task::Diagnostic(DiagnosticTask::AddChecks(file_id, diagnostics)))?;
+            }
+        }
+
+        CheckTask::SetDiagnostics { run, diagnostics } => {
+            *check_run = run;
+            let mut files = Vec::new();
+            for (url, diagnostics) in diagnostics {
+                if let Some(file_id) = check_file_id(world_state, &url)? {
+                    files.push((file_id, diagnostics));
+                }
+            }
+            task_sender.send(Task::Diagnostic(DiagnosticTask::SetCheck(files)))?;
         }
 
         CheckTask::Status(progress) => {
             let params = req::ProgressParams {
-                token: req::ProgressToken::String("rustAnalyzer/cargoWatcher".to_string()),
+                token: check_progress_token(),
                 value: req::ProgressParamsValue::WorkDone(progress),
             };
             let not = notification_new::<req::Progress>(params);
             task_sender.send(Task::Notify(not)).unwrap();
         }
+
+        CheckTask::Error { message, .. } => {
+            let params = req::ShowMessageParams { typ: req::MessageType::Error, message };
+            let not = notification_new::<req::ShowMessage>(params);
+            task_sender.send(Task::Notify(not)).unwrap();
+        }
+
+        CheckTask::Warning(message) => {
+            let params = req::ShowMessageParams { typ: req::MessageType::Warning, message };
+            let not = notification_new::<req::ShowMessage>(params);
+            task_sender.send(Task::Notify(not)).unwrap();
+        }
+
+        CheckTask::InvalidConfig(err) => {
+            let message = format!("invalid checkOnSave configuration: {}", err);
+            let params = req::ShowMessageParams { typ: req::MessageType::Error, message };
+            let not = notification_new::<req::ShowMessage>(params);
+            task_sender.send(Task::Notify(not)).unwrap();
+        }
+
+        CheckTask::Finished(summary) => log::info!("check finished: {:?}", summary),
     };
 
     Ok(())
 }
 
+/// The file a check diagnostic belongs to, `None` if it isn't known.
+fn check_file_id(world_state: &WorldState, url: &Url) -> Result<Option<FileId>> {
+    let path = url.to_file_path().map_err(|()| format!("invalid uri: {}", url))?;
+    let file_id = world_state.vfs.read().path2file(&path).map(|file| FileId(file.0));
+    if file_id.is_none() {
+        log::error!("File with cargo diagnostic not found in VFS: {}", path.display());
+    }
+    Ok(file_id)
+}
+
 fn on_diagnostic_task(task: DiagnosticTask, msg_sender: &Sender<Message>, state: &mut WorldState) {
     let subscriptions = state.diagnostics.handle_task(task);
 
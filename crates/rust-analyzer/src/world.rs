@@ -11,7 +11,7 @@ use std::{
 use crossbeam_channel::{unbounded, Receiver};
 use lsp_types::Url;
 use parking_lot::RwLock;
-use ra_flycheck::{url_from_path_with_drive_lowercasing, Flycheck, FlycheckConfig};
+use ra_flycheck::{url_from_path_with_drive_lowercasing, CheckStatus, Flycheck, FlycheckConfig};
 use ra_ide::{
     Analysis, AnalysisChange, AnalysisHost, CrateGraph, FileId, LibraryData, SourceRootId,
 };
@@ -73,6 +73,7 @@ pub struct WorldSnapshot {
     pub analysis: Analysis,
     pub latest_requests: Arc<RwLock<LatestRequests>>,
     pub check_fixes: CheckFixes,
+    pub check_status: Option<CheckStatus>,
     vfs: Arc<RwLock<Vfs>>,
 }
 
@@ -198,8 +199,13 @@ impl WorldState {
     pub fn update_configuration(&mut self, config: Config) {
         self.analysis_host.update_lru_capacity(config.lru_capacity);
         if config.check != self.config.check {
-            self.flycheck =
-                config.check.as_ref().and_then(|it| create_flycheck(&self.workspaces, it));
+            match (&self.flycheck, &config.check) {
+                (Some(flycheck), Some(check)) => flycheck.reconfigure(check),
+                _ => {
+                    self.flycheck =
+                        config.check.as_ref().and_then(|it| create_flycheck(&self.workspaces, it))
+                }
+            }
         }
 
         self.config = config;
@@ -264,6 +270,7 @@ impl WorldState {
             vfs: Arc::clone(&self.vfs),
             latest_requests: Arc::clone(&self.latest_requests),
             check_fixes: Arc::clone(&self.diagnostics.check_fixes),
+            check_status: self.flycheck.as_ref().map(Flycheck::status),
         }
     }
 
@@ -338,6 +345,9 @@ impl WorldSnapshot {
                 .status()
                 .unwrap_or_else(|_| "Analysis retrieval was cancelled".to_owned()),
         );
+        if let Some(check_status) = &self.check_status {
+            format_to!(buf, "\ncheck: {}\n", check_status);
+        }
         buf
     }
 